@@ -0,0 +1,190 @@
+//! Fixed ACPI Description Table.
+//! https://wiki.osdev.org/FADT
+
+use crate::memory::PhysAddr;
+
+use super::tables::{GenericAddress, read_table, SdtHeader};
+
+/// The reset register is supported
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+/// Legacy devices (e.g. a PS/2 controller) are present, from the boot
+/// architecture flags
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+struct RawFadt {
+    header: SdtHeader,
+    firmware_control: u32,
+    dsdt: u32,
+    _reserved: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command_port: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_request: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_length: u8,
+    gpe1_length: u8,
+    gpe1_base: u8,
+    c_state_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    boot_architecture_flags: u16,
+    _reserved2: u8,
+    flags: u32,
+    reset_register: GenericAddress,
+    reset_value: u8,
+    arm_boot_architecture_flags: u16,
+    minor_version: u8,
+    x_firmware_control: u64,
+    x_dsdt: u64,
+}
+
+/// The parts of the FADT the kernel uses.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` to, to switch from legacy to ACPI mode.
+    /// 0 if the firmware is always in ACPI mode.
+    pub smi_command_port: u16,
+    pub acpi_enable: u8,
+    pub pm1a_control_block: u16,
+    pub pm1b_control_block: u16,
+    pub pm_timer_block: u16,
+    /// CMOS register holding the century, 0 if there is none
+    pub century_register: u8,
+    pub has_8042: bool,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub unsafe fn parse(address: PhysAddr) -> Fadt {
+        Fadt::from_raw(read_table(address))
+    }
+
+    fn from_raw(raw: RawFadt) -> Fadt {
+        let revision = raw.header.revision;
+
+        // The 64 bit DSDT address takes precedence when present
+        let dsdt = if raw.x_dsdt != 0 { raw.x_dsdt } else { raw.dsdt as u64 };
+        // The reset register only exists since ACPI 2.0, which is revision 3
+        // of the FADT
+        let reset_register = if revision >= 3 && raw.flags & FLAG_RESET_REG_SUP != 0 {
+            Some(raw.reset_register)
+        } else {
+            None
+        };
+        // Revision 1 FADTs predate the boot architecture flags and always
+        // have an 8042
+        let has_8042 = revision < 2 || raw.boot_architecture_flags & BOOT_ARCH_8042 != 0;
+
+        Fadt {
+            revision,
+            dsdt: PhysAddr(dsdt),
+            sci_interrupt: raw.sci_interrupt,
+            smi_command_port: raw.smi_command_port as u16,
+            acpi_enable: raw.acpi_enable,
+            pm1a_control_block: raw.pm1a_control_block as u16,
+            pm1b_control_block: raw.pm1b_control_block as u16,
+            pm_timer_block: raw.pm_timer_block as u16,
+            century_register: raw.century,
+            has_8042,
+            reset_register,
+            reset_value: raw.reset_value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(revision: u8) -> RawFadt {
+        RawFadt {
+            header: SdtHeader { signature: *b"FACP", revision, ..SdtHeader::default() },
+            dsdt: 0x7FE0_0040,
+            sci_interrupt: 9,
+            smi_command_port: 0xB2,
+            acpi_enable: 0xF1,
+            pm1a_control_block: 0x604,
+            pm_timer_block: 0x608,
+            century: 0x32,
+            reset_register: GenericAddress {
+                address_space: GenericAddress::SYSTEM_IO,
+                bit_width: 8,
+                address: 0xCF9,
+                ..GenericAddress::default()
+            },
+            reset_value: 0x0F,
+            ..RawFadt::default()
+        }
+    }
+
+    #[test_case]
+    fn revision_1_fields_are_parsed() {
+        let fadt = Fadt::from_raw(raw(1));
+        assert_eq!(fadt.dsdt, PhysAddr(0x7FE0_0040));
+        assert_eq!(fadt.sci_interrupt, 9);
+        assert_eq!(fadt.smi_command_port, 0xB2);
+        assert_eq!(fadt.acpi_enable, 0xF1);
+        assert_eq!(fadt.pm1a_control_block, 0x604);
+        assert_eq!(fadt.pm1b_control_block, 0);
+        assert_eq!(fadt.pm_timer_block, 0x608);
+        assert_eq!(fadt.century_register, 0x32);
+        // No boot architecture flags nor reset register before revision 2
+        // and 3
+        assert!(fadt.has_8042);
+        assert!(fadt.reset_register.is_none());
+    }
+
+    #[test_case]
+    fn later_revision_fields_are_parsed() {
+        let mut table = raw(3);
+        assert!(!Fadt::from_raw(table).has_8042);
+        assert!(Fadt::from_raw(table).reset_register.is_none());
+
+        table.boot_architecture_flags = BOOT_ARCH_8042;
+        table.flags = FLAG_RESET_REG_SUP;
+        table.x_dsdt = 0x1_0000_0000;
+        let fadt = Fadt::from_raw(table);
+        assert!(fadt.has_8042);
+        let register = fadt.reset_register.expect("no reset register");
+        let address = register.address;
+        assert_eq!(register.address_space, GenericAddress::SYSTEM_IO);
+        assert_eq!(address, 0xCF9);
+        assert_eq!(fadt.reset_value, 0x0F);
+        assert_eq!(fadt.dsdt, PhysAddr(0x1_0000_0000));
+    }
+
+    #[test_case]
+    fn firmware_fadt_is_parsed() {
+        let fadt = crate::acpi::info().and_then(|acpi| acpi.fadt.as_ref()).expect("no FADT");
+        assert_ne!(fadt.dsdt, PhysAddr(0));
+        assert_ne!(fadt.pm1a_control_block, 0);
+        assert_ne!(fadt.pm_timer_block, 0);
+    }
+}
@@ -0,0 +1,46 @@
+//! High Precision Event Timer description table.
+//! https://wiki.osdev.org/HPET
+
+use crate::memory::PhysAddr;
+
+use super::tables::{GenericAddress, read_table, SdtHeader};
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+struct RawHpet {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub address: PhysAddr,
+    pub hpet_number: u8,
+    pub pci_vendor_id: u16,
+    /// Number of comparators
+    pub comparators: u8,
+    pub counter_64_bit: bool,
+    pub legacy_replacement_capable: bool,
+    /// Minimum tick in periodic mode, in main counter ticks
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub unsafe fn parse(address: PhysAddr) -> Hpet {
+        let raw: RawHpet = read_table(address);
+        let id = raw.event_timer_block_id;
+        Hpet {
+            address: PhysAddr(raw.base_address.address),
+            hpet_number: raw.hpet_number,
+            pci_vendor_id: (id >> 16) as u16,
+            comparators: ((id >> 8) & 0x1F) as u8 + 1,
+            counter_64_bit: id & (1 << 13) != 0,
+            legacy_replacement_capable: id & (1 << 15) != 0,
+            minimum_tick: raw.minimum_tick,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Multiple APIC Description Table.
+//! https://wiki.osdev.org/MADT
+
+use alloc::vec::Vec;
+
+use crate::apic::{ApicConfig, InterruptSourceOverride, IoApicInfo};
+use crate::memory::PhysAddr;
+
+use super::tables::{physical_bytes, read_physical, SdtHeader};
+
+const ENTRY_PROCESSOR_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// MPS INTI flags of the interrupt source overrides
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MODE_MASK: u16 = 0b11 << 2;
+const TRIGGER_MODE_LEVEL: u16 = 0b11 << 2;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// 0xFF for all processors
+    pub acpi_processor_id: u8,
+    pub lint: u8,
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether 8259 PICs are installed too
+    pub pc_at_compatible: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptSourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    pub unsafe fn parse(address: PhysAddr) -> Madt {
+        let madt: MadtHeader = read_physical(address);
+        let mut result = Madt {
+            local_apic_address: PhysAddr(madt.local_apic_address as u64),
+            pc_at_compatible: madt.flags & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let bytes = physical_bytes(address, madt.header.length as usize);
+        let mut offset = core::mem::size_of::<MadtHeader>();
+        while offset + 2 <= bytes.len() {
+            let entry_type = bytes[offset];
+            let length = bytes[offset + 1] as usize;
+            if length < 2 || offset + length > bytes.len() {
+                break;
+            }
+            let entry = &bytes[offset..offset + length];
+            match entry_type {
+                ENTRY_PROCESSOR_LOCAL_APIC if length >= 8 => {
+                    let flags = u32_at(entry, 4);
+                    result.processors.push(Processor {
+                        acpi_id: entry[2],
+                        apic_id: entry[3],
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_IO_APIC if length >= 12 => {
+                    result.io_apics.push(IoApicInfo {
+                        id: entry[2],
+                        address: PhysAddr(u32_at(entry, 4) as u64),
+                        gsi_base: u32_at(entry, 8),
+                    });
+                }
+                ENTRY_INTERRUPT_SOURCE_OVERRIDE if length >= 10 => {
+                    let flags = u16_at(entry, 8);
+                    result.overrides.push(InterruptSourceOverride {
+                        isa_irq: entry[3],
+                        gsi: u32_at(entry, 4),
+                        active_low: flags & POLARITY_MASK == POLARITY_ACTIVE_LOW,
+                        level_triggered: flags & TRIGGER_MODE_MASK == TRIGGER_MODE_LEVEL,
+                    });
+                }
+                ENTRY_LOCAL_APIC_NMI if length >= 6 => {
+                    result.nmis.push(LocalApicNmi {
+                        acpi_processor_id: entry[2],
+                        flags: u16_at(entry, 3),
+                        lint: entry[5],
+                    });
+                }
+                ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+                    result.local_apic_address = PhysAddr(u64_at(entry, 4));
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        result
+    }
+
+    pub fn apic_config(&self) -> ApicConfig {
+        ApicConfig {
+            local_apic_address: Some(self.local_apic_address),
+            io_apics: self.io_apics.clone(),
+            overrides: self.overrides.clone(),
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
@@ -0,0 +1,38 @@
+//! PCI Express memory mapped configuration space description table.
+//! https://wiki.osdev.org/PCI_Express
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::memory::PhysAddr;
+
+use super::tables::{read_physical, SdtHeader};
+
+/// Configuration space of the buses `start_bus..=end_bus` of a segment.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    _reserved: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    pub unsafe fn parse(address: PhysAddr) -> Mcfg {
+        let header: SdtHeader = read_physical(address);
+        // The entries follow the header and 8 reserved bytes
+        let entries_start = address.0 + size_of::<SdtHeader>() as u64 + 8;
+        let count = (header.length as u64).saturating_sub(entries_start - address.0) / size_of::<McfgEntry>() as u64;
+        let entries = (0..count)
+            .map(|i| read_physical(PhysAddr(entries_start + i * size_of::<McfgEntry>() as u64)))
+            .collect();
+        Mcfg { entries }
+    }
+}
@@ -0,0 +1,234 @@
+//! ACPI table discovery and parsing.
+//!
+//! The RSDP is found by scanning the BIOS areas, then the RSDT or XSDT leads
+//! to the other tables. Only the tables the kernel uses are parsed into typed
+//! structures, the others are just listed.
+//! https://wiki.osdev.org/RSDP
+//! https://wiki.osdev.org/RSDT
+
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::memory::PhysAddr;
+
+use self::fadt::Fadt;
+use self::hpet::Hpet;
+use self::madt::Madt;
+use self::mcfg::Mcfg;
+use self::tables::{ascii, checksum_valid, physical_bytes, read_physical, Rsdp, Rsdp2, SdtHeader};
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+pub mod power;
+pub mod tables;
+
+/// Segment of the Extended BIOS Data Area is stored at this address
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+static ACPI: Once<Acpi> = Once::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub signature: [u8; 4],
+    pub address: PhysAddr,
+    pub length: u32,
+    pub revision: u8,
+}
+
+#[derive(Debug)]
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub tables: Vec<TableInfo>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<&TableInfo> {
+        self.tables.iter().find(|table| &table.signature == signature)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    InvalidRootTable,
+}
+
+/// Parsed tables, once `init` succeeded.
+pub fn info() -> Option<&'static Acpi> {
+    ACPI.r#try()
+}
+
+/// Looks for the RSDP in the first KiB of the EBDA, then in the BIOS area.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = unsafe { read_physical::<u16>(PhysAddr(EBDA_SEGMENT_POINTER)) } as u64 * 16;
+    let areas = [(ebda, ebda + 1024), (BIOS_AREA_START, BIOS_AREA_END)];
+    for &(start, end) in areas.iter() {
+        if start == 0 {
+            continue;
+        }
+        // The RSDP is always 16 bytes aligned
+        let mut address = start;
+        while address + size_of::<Rsdp>() as u64 <= end {
+            let bytes = unsafe { physical_bytes(PhysAddr(address), size_of::<Rsdp>()) };
+            if &bytes[..8] == b"RSD PTR " && checksum_valid(bytes) {
+                return Some(PhysAddr(address));
+            }
+            address += 16;
+        }
+    }
+    None
+}
+
+/// Reads the header of the table at `address` and checks its checksum.
+fn read_header(address: PhysAddr) -> Option<SdtHeader> {
+    let header: SdtHeader = unsafe { read_physical(address) };
+    let bytes = unsafe { physical_bytes(address, header.length as usize) };
+    if checksum_valid(bytes) {
+        Some(header)
+    } else {
+        println!("ACPI: table {} at {} has an invalid checksum", ascii(&header.signature), address);
+        None
+    }
+}
+
+/// Addresses of the tables listed by the RSDT (32 bit entries) or the XSDT
+/// (64 bit entries).
+fn root_table_entries(root: PhysAddr, header: &SdtHeader, entry_size: usize) -> Vec<PhysAddr> {
+    let count = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    let entries = PhysAddr(root.0 + size_of::<SdtHeader>() as u64);
+    (0..count)
+        .map(|i| {
+            let entry = PhysAddr(entries.0 + (i * entry_size) as u64);
+            PhysAddr(unsafe {
+                if entry_size == 8 {
+                    read_physical::<u64>(entry)
+                } else {
+                    read_physical::<u32>(entry) as u64
+                }
+            })
+        })
+        .collect()
+}
+
+pub fn init() -> Result<&'static Acpi, AcpiError> {
+    let rsdp_address = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp: Rsdp = unsafe { read_physical(rsdp_address) };
+
+    // Prefer the XSDT when the ACPI 2.0 RSDP is valid
+    let (root, entry_size) = if rsdp.revision >= 2 {
+        let rsdp2: Rsdp2 = unsafe { read_physical(rsdp_address) };
+        let bytes = unsafe { physical_bytes(rsdp_address, rsdp2.length as usize) };
+        if checksum_valid(bytes) && rsdp2.xsdt_address != 0 {
+            (PhysAddr(rsdp2.xsdt_address), 8)
+        } else {
+            (PhysAddr(rsdp.rsdt_address as u64), 4)
+        }
+    } else {
+        (PhysAddr(rsdp.rsdt_address as u64), 4)
+    };
+    let root_header = read_header(root).ok_or(AcpiError::InvalidRootTable)?;
+
+    let mut acpi = Acpi {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        tables: Vec::new(),
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+    };
+    for address in root_table_entries(root, &root_header, entry_size) {
+        let header = match read_header(address) {
+            Some(header) => header,
+            None => continue,
+        };
+        acpi.tables.push(TableInfo {
+            signature: header.signature,
+            address,
+            length: header.length,
+            revision: header.revision,
+        });
+        unsafe {
+            match &header.signature {
+                b"APIC" => acpi.madt = Some(Madt::parse(address)),
+                b"FACP" => acpi.fadt = Some(Fadt::parse(address)),
+                b"HPET" => acpi.hpet = Some(Hpet::parse(address)),
+                b"MCFG" => acpi.mcfg = Some(Mcfg::parse(address)),
+                _ => {}
+            }
+        }
+    }
+    // The DSDT isn't listed in the root table
+    if let Some(fadt) = acpi.fadt.as_ref() {
+        if let Some(header) = read_header(fadt.dsdt) {
+            acpi.tables.push(TableInfo {
+                signature: header.signature,
+                address: fadt.dsdt,
+                length: header.length,
+                revision: header.revision,
+            });
+        }
+    }
+
+    let acpi = ACPI.call_once(|| acpi);
+    print_summary(acpi);
+    Ok(acpi)
+}
+
+fn print_summary(acpi: &Acpi) {
+    println!("ACPI: revision {}, OEM {}", acpi.revision, ascii(&acpi.oem_id));
+    for table in acpi.tables.iter() {
+        println!("  {} at {}, {} bytes, revision {}",
+                 ascii(&table.signature), table.address, table.length, table.revision);
+    }
+    if let Some(madt) = acpi.madt.as_ref() {
+        println!("MADT: local APIC at {}, PC-AT compatible: {}", madt.local_apic_address, madt.pc_at_compatible);
+        for processor in madt.processors.iter() {
+            println!("  CPU {}: APIC ID {}, enabled: {}", processor.acpi_id, processor.apic_id, processor.enabled);
+        }
+        for io_apic in madt.io_apics.iter() {
+            println!("  I/O APIC {} at {}, GSI base {}", io_apic.id, io_apic.address, io_apic.gsi_base);
+        }
+        for o in madt.overrides.iter() {
+            println!("  IRQ {} -> GSI {}, active {}, {} triggered",
+                     o.isa_irq, o.gsi,
+                     if o.active_low { "low" } else { "high" },
+                     if o.level_triggered { "level" } else { "edge" });
+        }
+    }
+    if let Some(fadt) = acpi.fadt.as_ref() {
+        println!("FADT: revision {}, SCI IRQ {}, PM1a control {:#x}, PM1b control {:#x}, PM timer {:#x}, century register {:#x}",
+                 fadt.revision, fadt.sci_interrupt, fadt.pm1a_control_block, fadt.pm1b_control_block,
+                 fadt.pm_timer_block, fadt.century_register);
+    }
+    if let Some(hpet) = acpi.hpet.as_ref() {
+        println!("HPET: at {}, {} comparators, 64 bit counter: {}, minimum tick {}",
+                 hpet.address, hpet.comparators, hpet.counter_64_bit, hpet.minimum_tick);
+    }
+    if let Some(mcfg) = acpi.mcfg.as_ref() {
+        for entry in mcfg.entries.iter() {
+            let (base, segment) = (entry.base_address, entry.segment_group);
+            println!("MCFG: segment {} buses {}-{} at {:#x}", segment, entry.start_bus, entry.end_bus, base);
+        }
+    }
+}
+
+/// Powers the machine off. Returns if ACPI shutdown isn't possible.
+pub fn shutdown() -> power::PowerError {
+    power::shutdown(info().and_then(|acpi| acpi.fadt.as_ref()))
+}
+
+pub fn reboot() -> ! {
+    power::reboot(info().and_then(|acpi| acpi.fadt.as_ref()))
+}
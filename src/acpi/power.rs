@@ -0,0 +1,210 @@
+//! Shutdown and reboot through the FADT.
+//! https://wiki.osdev.org/Shutdown
+//! https://wiki.osdev.org/Reboot
+
+use crate::inline_asm::{disable_interrupts, hlt_loop, inb, int3, inw, lidt, outb, outw};
+use crate::memory::PhysAddr;
+use crate::pit::busy_wait_ms;
+use crate::serial;
+
+use super::fadt::Fadt;
+use super::tables::{GenericAddress, physical_bytes, read_physical, SdtHeader};
+
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+// AML encoding
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0A;
+
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    NoFadt,
+    /// The DSDT doesn't define a usable `\_S5` object
+    NoS5Object,
+    AcpiEnableFailed,
+    /// The sleep registers were written but the machine didn't power off
+    StillRunning,
+}
+
+/// Sleep type values of the S5 (soft off) state, for PM1a and PM1b.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SleepType {
+    a: u16,
+    b: u16,
+}
+
+/// Finds `Name(_S5_, Package() { SLP_TYPa, SLP_TYPb, ... })` in the AML of
+/// the DSDT.
+///
+/// This is a byte pattern search rather than a real AML interpreter, which
+/// works with the DSDT of QEMU and of most firmwares.
+fn find_s5(aml: &[u8]) -> Option<SleepType> {
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    // The name is preceded by NameOp, possibly with a root prefix
+    let name_op_found = position >= 1 && aml[position - 1] == NAME_OP
+        || position >= 2 && aml[position - 2] == NAME_OP && aml[position - 1] == b'\\';
+    let mut i = position + 4;
+    if !name_op_found || aml.get(i) != Some(&PACKAGE_OP) {
+        return None;
+    }
+    i += 1;
+    // Skip the PkgLength, whose size is encoded in its two top bits
+    let pkg_length_bytes = (*aml.get(i)? >> 6) as usize + 1;
+    i += pkg_length_bytes;
+    // NumElements
+    i += 1;
+
+    let mut read_value = || -> Option<u16> {
+        if *aml.get(i)? == BYTE_PREFIX {
+            i += 1;
+        }
+        let value = *aml.get(i)? as u16;
+        i += 1;
+        Some(value)
+    };
+    let a = read_value()?;
+    let b = read_value()?;
+    Some(SleepType { a, b })
+}
+
+/// Switches the firmware to ACPI mode if it isn't already.
+fn enable_acpi(fadt: &Fadt) -> Result<(), PowerError> {
+    if inw(fadt.pm1a_control_block) & SCI_EN != 0 || fadt.smi_command_port == 0 {
+        return Ok(());
+    }
+    outb(fadt.smi_command_port, fadt.acpi_enable);
+    for _ in 0..300 {
+        if inw(fadt.pm1a_control_block) & SCI_EN != 0 {
+            return Ok(());
+        }
+        busy_wait_ms(10);
+    }
+    Err(PowerError::AcpiEnableFailed)
+}
+
+/// Puts the machine in the S5 state. Only returns on failure.
+pub fn shutdown(fadt: Option<&Fadt>) -> PowerError {
+    let fadt = match fadt {
+        Some(fadt) => fadt,
+        None => return PowerError::NoFadt,
+    };
+    let dsdt = unsafe {
+        let header: SdtHeader = read_physical(fadt.dsdt);
+        physical_bytes(fadt.dsdt, header.length as usize)
+    };
+    let sleep_type = match find_s5(dsdt) {
+        Some(sleep_type) => sleep_type,
+        None => return PowerError::NoS5Object,
+    };
+    if let Err(error) = enable_acpi(fadt) {
+        return error;
+    }
+
+    serial::flush_all();
+    disable_interrupts();
+    enter_sleep_state(fadt.pm1a_control_block, sleep_type.a);
+    if fadt.pm1b_control_block != 0 {
+        enter_sleep_state(fadt.pm1b_control_block, sleep_type.b);
+    }
+    // Give the chipset some time before declaring failure
+    busy_wait_ms(100);
+    PowerError::StillRunning
+}
+
+/// Sets SLP_TYP and SLP_EN in a PM1 control register, keeping its other bits.
+fn enter_sleep_state(port: u16, sleep_type: u16) {
+    let control = inw(port) & !SLP_TYP_MASK;
+    outw(port, control | ((sleep_type << SLP_TYP_SHIFT) & SLP_TYP_MASK) | SLP_EN);
+}
+
+/// Resets the machine with the FADT reset register, the keyboard controller
+/// or, as a last resort, a triple fault.
+pub fn reboot(fadt: Option<&Fadt>) -> ! {
+    serial::flush_all();
+    disable_interrupts();
+
+    if let Some(register) = fadt.and_then(|fadt| fadt.reset_register) {
+        let value = fadt.unwrap().reset_value;
+        match register.address_space {
+            GenericAddress::SYSTEM_IO => outb(register.address as u16, value),
+            GenericAddress::SYSTEM_MEMORY => unsafe {
+                core::ptr::write_volatile(PhysAddr(register.address).to_virt().as_mut_ptr::<u8>(), value);
+            },
+            _ => {}
+        }
+        busy_wait_ms(100);
+    }
+
+    if fadt.map_or(true, |fadt| fadt.has_8042) {
+        // Wait for the input buffer to be empty, then pulse the reset line
+        while inb(KEYBOARD_CONTROLLER_COMMAND) & 0b10 != 0 {}
+        outb(KEYBOARD_CONTROLLER_COMMAND, KEYBOARD_CONTROLLER_RESET);
+        busy_wait_ms(100);
+    }
+
+    // Triple fault: any exception with an empty IDT
+    #[repr(C, packed)]
+    struct IDTPointer {
+        size: u16,
+        address: u64,
+    }
+    lidt(&IDTPointer { size: 0, address: 0 });
+    int3();
+    hlt_loop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn s5_package_is_found() {
+        // Name (\_S5, Package (0x04) { 0x05, 0x05, Zero, Zero }) as iasl
+        // encodes it
+        let aml = [0x10, 0x0C, b'\\', b'_', b'S', b'B', b'_',
+                   NAME_OP, b'\\', b'_', b'S', b'5', b'_', PACKAGE_OP, 0x08, 0x04,
+                   BYTE_PREFIX, 0x05, BYTE_PREFIX, 0x05, 0x00, 0x00];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 5, b: 5 }));
+
+        // Without the root prefix and with Zero opcodes, as in QEMU's DSDT
+        let aml = [NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 0, b: 0 }));
+
+        // Two bytes PkgLength
+        let aml = [NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x40, 0x01, 0x02, 0x07, BYTE_PREFIX, 0x03];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 7, b: 3 }));
+    }
+
+    #[test_case]
+    fn invalid_s5_objects_are_rejected() {
+        // A reference to _S5_ rather than its definition
+        let aml = [0x70, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(find_s5(&aml), None);
+        // Not a package
+        let aml = [NAME_OP, b'_', b'S', b'5', b'_', BYTE_PREFIX, 0x05];
+        assert_eq!(find_s5(&aml), None);
+        // Truncated
+        let aml = [NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06, 0x04, BYTE_PREFIX, 0x05];
+        assert_eq!(find_s5(&aml), None);
+        assert_eq!(find_s5(b"no sleep states"), None);
+    }
+
+    #[test_case]
+    fn firmware_defines_s5() {
+        let fadt = crate::acpi::info().and_then(|acpi| acpi.fadt.as_ref()).expect("no FADT");
+        let dsdt = unsafe {
+            let header: SdtHeader = read_physical(fadt.dsdt);
+            assert_eq!(&header.signature, b"DSDT");
+            physical_bytes(fadt.dsdt, header.length as usize)
+        };
+        assert!(find_s5(dsdt).is_some());
+    }
+}
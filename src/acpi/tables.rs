@@ -0,0 +1,93 @@
+//! Raw layouts shared by the ACPI tables.
+
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::str;
+
+use crate::memory::PhysAddr;
+
+/// Root System Description Pointer, ACPI 1.0 part.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+}
+
+/// Root System Description Pointer, fields added by ACPI 2.0.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp2 {
+    pub rsdp: Rsdp,
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    pub _reserved: [u8; 3],
+}
+
+/// Header common to all the System Description Tables.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Location of a register in some address space.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIGURATION: u8 = 2;
+}
+
+/// Reads a `T` at a physical address through the physical memory mapping.
+pub unsafe fn read_physical<T: Copy>(address: PhysAddr) -> T {
+    read_unaligned(address.to_virt().as_ptr::<T>())
+}
+
+/// Bytes of physical memory, through the physical memory mapping.
+pub unsafe fn physical_bytes(address: PhysAddr, length: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(address.to_virt().as_ptr::<u8>(), length)
+}
+
+/// Reads a table whose tail may be missing in older revisions: whatever
+/// lies past `header.length` is left zeroed.
+pub unsafe fn read_table<T: Copy + Default>(address: PhysAddr) -> T {
+    let header: SdtHeader = read_physical(address);
+    let mut table = T::default();
+    let length = (header.length as usize).min(size_of::<T>());
+    core::ptr::copy_nonoverlapping(address.to_virt().as_ptr::<u8>(),
+                                   &mut table as *mut T as *mut u8,
+                                   length);
+    table
+}
+
+/// ACPI checksums make all the bytes of a structure sum up to 0.
+pub fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Signatures and OEM IDs are ASCII, padded with spaces.
+pub fn ascii(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("?").trim_end()
+}
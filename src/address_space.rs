@@ -0,0 +1,181 @@
+//! Per-process virtual address spaces.
+//!
+//! Every address space has its own PML4. The entries covering user space are
+//! private to it, the others point to the kernel's page tables, so the kernel
+//! is mapped the same way everywhere. Kernel mappings must therefore not add
+//! PML4 entries once processes exist, see `paging::init`.
+
+use crate::memory::{FRAME_ALLOCATOR, FRAME_SIZE, FrameAllocator, PhysAddr, PhysFrame, VirtAddr};
+use crate::paging::{kernel_pml4, Mapper, MapError, Page, PageSize, PageTableFlags, table_at, UnmapError};
+use crate::task::user::{is_user_range, USER_SPACE_END, USER_SPACE_START};
+
+/// PML4 entries private to each address space
+const USER_PML4_ENTRIES: core::ops::Range<usize> =
+    (USER_SPACE_START >> 39) as usize..(USER_SPACE_END >> 39) as usize;
+
+pub struct AddressSpace {
+    pml4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user space.
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let frame = FRAME_ALLOCATOR.lock().allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+        let kernel_table = table_at(kernel_pml4());
+        let table = table_at(frame.start);
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            *entry = kernel_table.entries[i];
+            if USER_PML4_ENTRIES.contains(&i) {
+                entry.set_unused();
+            }
+        }
+        Ok(AddressSpace { pml4: frame })
+    }
+
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4.start
+    }
+
+    pub fn mapper(&self) -> Mapper {
+        Mapper::new(self.pml4.start)
+    }
+
+    /// Maps zeroed memory at `start..start + size`, see `map_user_pages`.
+    pub fn map_user(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        map_user_pages(&mut self.mapper(), start, size, flags)
+    }
+
+    /// Copies `bytes` to `address`, which doesn't need to be the active
+    /// address space. The destination must be mapped.
+    pub fn write(&self, address: VirtAddr, bytes: &[u8]) -> Result<(), UnmapError> {
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < bytes.len() {
+            let destination = address.as_u64() + written as u64;
+            let page_remaining = (FRAME_SIZE - destination % FRAME_SIZE) as usize;
+            let count = page_remaining.min(bytes.len() - written);
+            let frame = mapper.translate_addr(VirtAddr(destination)).ok_or(UnmapError::PageNotMapped)?;
+            unsafe {
+                frame.to_virt().as_mut_ptr::<u8>()
+                    .copy_from_nonoverlapping(bytes[written..].as_ptr(), count);
+            }
+            written += count;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the user memory and page tables. The address space must not be
+    /// active anymore, and it must be dropped in task context since this
+    /// takes the frame allocator lock: a process's is freed by `task::reap`.
+    fn drop(&mut self) {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let table = table_at(self.pml4.start);
+        for i in USER_PML4_ENTRIES {
+            let entry = table.entries[i];
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                free_table(entry.address(), 3, &mut *allocator);
+            }
+        }
+        allocator.deallocate_frame(self.pml4);
+    }
+}
+
+/// Frees a page table of the given level, the tables below it and the frames
+/// they map. User memory is only mapped with 4 KiB pages.
+fn free_table(address: PhysAddr, level: usize, allocator: &mut impl FrameAllocator) {
+    for entry in table_at(address).entries.iter() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level > 1 {
+            free_table(entry.address(), level - 1, allocator);
+        } else {
+            allocator.deallocate_frame(PhysFrame::containing_address(entry.address()));
+        }
+    }
+    allocator.deallocate_frame(PhysFrame::containing_address(address));
+}
+
+/// Maps zeroed frames at `start..start + size`, accessible from user mode.
+///
+/// `flags` comes on top of `PRESENT | USER_ACCESSIBLE`, typically `WRITABLE`.
+/// Nothing stays mapped on failure.
+pub fn map_user_pages(mapper: &mut Mapper, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    if !is_user_range(start.as_u64(), size) {
+        panic!("{:#x}-{:#x} is not in user space", start.as_u64(), start.as_u64() + size);
+    }
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let first = start.as_u64() & !(FRAME_SIZE - 1);
+    let mut address = first;
+    while address < start.as_u64() + size {
+        if let Err(error) = map_zeroed_page(mapper, address, flags, &mut *frame_allocator) {
+            // Free the pages mapped so far, they were all unused before
+            for mapped in (first..address).step_by(FRAME_SIZE as usize) {
+                if let Ok(frame) = mapper.unmap(Page::containing_address(VirtAddr(mapped), PageSize::Size4KiB)) {
+                    frame_allocator.deallocate_frame(PhysFrame::containing_address(frame));
+                }
+            }
+            return Err(error);
+        }
+        address += FRAME_SIZE;
+    }
+    Ok(())
+}
+
+fn map_zeroed_page(mapper: &mut Mapper, address: u64, flags: PageTableFlags,
+                   frame_allocator: &mut impl FrameAllocator) -> Result<(), MapError> {
+    let frame = frame_allocator.allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+    unsafe {
+        frame.start.to_virt().as_mut_ptr::<u8>().write_bytes(0, FRAME_SIZE as usize);
+    }
+    let page = Page::containing_address(VirtAddr(address), PageSize::Size4KiB);
+    if let Err(error) = mapper.map_to(page, frame.start, flags, frame_allocator) {
+        frame_allocator.deallocate_frame(frame);
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn address_space_memory_is_freed() {
+        let free_frames = FRAME_ALLOCATOR.lock().free_frames();
+        {
+            let mut address_space = AddressSpace::new().expect("can't create address space");
+            let address = VirtAddr(USER_SPACE_START);
+            address_space.map_user(address, 3 * FRAME_SIZE, PageTableFlags::WRITABLE)
+                .expect("can't map user memory");
+            address_space.write(VirtAddr(address.as_u64() + FRAME_SIZE - 2), b"krill")
+                .expect("can't write user memory");
+            // Not mapped in the active address space
+            assert_eq!(Mapper::active().translate_addr(address), None);
+        }
+        assert_eq!(FRAME_ALLOCATOR.lock().free_frames(), free_frames);
+    }
+
+    #[test_case]
+    fn failed_mapping_is_undone() {
+        let mut address_space = AddressSpace::new().expect("can't create address space");
+        let start = USER_SPACE_START;
+        address_space.map_user(VirtAddr(start + 2 * FRAME_SIZE), FRAME_SIZE, PageTableFlags::WRITABLE)
+            .expect("can't map user memory");
+        let free_frames = FRAME_ALLOCATOR.lock().free_frames();
+
+        // The third page is already mapped
+        let existing = address_space.mapper().translate_addr(VirtAddr(start + 2 * FRAME_SIZE))
+            .expect("user page vanished");
+        let result = address_space.map_user(VirtAddr(start), 4 * FRAME_SIZE, PageTableFlags::WRITABLE);
+        assert_eq!(result, Err(MapError::PageAlreadyMapped(existing)));
+        let mapper = address_space.mapper();
+        assert_eq!(mapper.translate_addr(VirtAddr(start)), None);
+        assert_eq!(mapper.translate_addr(VirtAddr(start + FRAME_SIZE)), None);
+        assert_eq!(mapper.translate_addr(VirtAddr(start + 2 * FRAME_SIZE)), Some(existing));
+        assert_eq!(FRAME_ALLOCATOR.lock().free_frames(), free_frames);
+    }
+}
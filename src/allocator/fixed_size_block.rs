@@ -0,0 +1,90 @@
+//! An allocator that serves small allocations from per-size free lists and
+//! falls back to the linked list allocator for everything else.
+
+use core::alloc::Layout;
+use core::mem;
+use core::ptr::NonNull;
+
+use super::linked_list::LinkedListAllocator;
+
+/// Block sizes, which are also used as block alignments, so they must be
+/// powers of two. Blocks are never smaller than 8 bytes because they hold a
+/// `ListNode` while they are free.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+    /// Blocks currently sitting in the free lists, in bytes.
+    cached_bytes: usize,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> FixedSizeBlockAllocator {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+            cached_bytes: 0,
+        }
+    }
+
+    /// Hands the region `heap_start..heap_start + heap_size` to the allocator.
+    ///
+    /// Unsafe because the region must be mapped, unused and only given once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    self.cached_bytes -= BLOCK_SIZES[index];
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // No block left in the list, carve a new one
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                    self.fallback_allocator.allocate(layout)
+                }
+            },
+            None => self.fallback_allocator.allocate(layout),
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node = ListNode { next: self.list_heads[index].take() };
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+                self.cached_bytes += BLOCK_SIZES[index];
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback_allocator.deallocate(ptr.as_ptr(), layout);
+            }
+        }
+    }
+
+    /// Free bytes, counting the blocks cached in the free lists.
+    pub fn free_bytes(&self) -> usize {
+        self.fallback_allocator.free_bytes() + self.cached_bytes
+    }
+}
+
+/// Index of the smallest block size that fits `layout`.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required_block_size)
+}
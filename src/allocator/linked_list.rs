@@ -0,0 +1,162 @@
+//! A first-fit allocator that keeps the free regions in a sorted linked list.
+//! Freed regions are merged with their neighbours to limit fragmentation.
+
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+
+use super::align_up;
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> ListNode {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+pub struct LinkedListAllocator {
+    head: ListNode,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> LinkedListAllocator {
+        LinkedListAllocator { head: ListNode::new(0) }
+    }
+
+    /// Hands the region `heap_start..heap_start + heap_size` to the allocator.
+    ///
+    /// Unsafe because the region must be mapped, unused and only given once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    /// Inserts a free region, keeping the list sorted by address and merging
+    /// it with adjacent free regions.
+    unsafe fn add_free_region(&mut self, address: usize, size: usize) {
+        assert_eq!(align_up(address, mem::align_of::<ListNode>()), address);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        let mut current = &mut self.head;
+        while let Some(ref next) = current.next {
+            if next.start_addr() > address {
+                break;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+
+        // Merge with the following region
+        let mut node = ListNode::new(size);
+        if let Some(next) = current.next.take() {
+            if address + size == next.start_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+
+        // Merge with the preceding region
+        if current.size != 0 && current.end_addr() == address {
+            current.size += node.size;
+            current.next = node.next.take();
+            return;
+        }
+
+        let node_ptr = address as *mut ListNode;
+        node_ptr.write(node);
+        current.next = Some(&mut *node_ptr);
+    }
+
+    /// Removes the first region that fits `size` and `align` from the list.
+    /// Returns the region and the aligned start address of the allocation.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let found = current.next.take().unwrap();
+                current.next = next;
+                return Some((found, alloc_start));
+            }
+            current = current.next.as_mut().unwrap();
+        }
+        None
+    }
+
+    /// Checks that the allocation fits in `region` and that the leftovers on
+    /// both sides of it are either empty or large enough to hold a `ListNode`.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        if alloc_start != region.start_addr() && alloc_start - region.start_addr() < mem::size_of::<ListNode>() {
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+        Ok(alloc_start)
+    }
+
+    /// Adjusts the layout so that the freed block can always hold a `ListNode`.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let alloc_end = alloc_start + size;
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            unsafe {
+                // Alignment can leave a gap in front of the allocation
+                if alloc_start > region_start {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+                if region_end > alloc_end {
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                }
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+
+    /// Sum of the sizes of all the free regions.
+    pub fn free_bytes(&self) -> usize {
+        let mut total = 0;
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            total += region.size;
+            current = region;
+        }
+        total
+    }
+}
@@ -0,0 +1,167 @@
+//! Kernel heap.
+//!
+//! The heap lives at a fixed virtual address and is backed by frames from the
+//! frame allocator. The allocation strategy is picked at build time: the
+//! fixed-size block allocator is used by default and the linked list
+//! allocator with `--features linked_list_allocator`.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+#[cfg(not(feature = "linked_list_allocator"))]
+use self::fixed_size_block::FixedSizeBlockAllocator as Backend;
+#[cfg(feature = "linked_list_allocator")]
+use self::linked_list::LinkedListAllocator as Backend;
+use crate::memory::{FRAME_ALLOCATOR, FrameAllocator, VirtAddr};
+use crate::paging::{MapError, Mapper, Page, PageSize, PageTableFlags};
+
+pub mod linked_list;
+#[cfg(not(feature = "linked_list_allocator"))]
+pub mod fixed_size_block;
+
+pub const HEAP_START: u64 = 0x4444_4444_0000;
+pub const HEAP_SIZE: u64 = 8 * 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    backend: Mutex::new(Backend::new()),
+    allocated_bytes: AtomicUsize::new(0),
+    peak_allocated_bytes: AtomicUsize::new(0),
+    allocations: AtomicUsize::new(0),
+    failed_allocations: AtomicUsize::new(0),
+};
+
+struct KernelAllocator {
+    backend: Mutex<Backend>,
+    allocated_bytes: AtomicUsize,
+    peak_allocated_bytes: AtomicUsize,
+    /// Live allocations
+    allocations: AtomicUsize,
+    failed_allocations: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.backend.lock().allocate(layout);
+        if ptr.is_null() {
+            self.failed_allocations.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let allocated = self.allocated_bytes.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            self.peak_allocated_bytes.fetch_max(allocated, Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.backend.lock().deallocate(ptr, layout);
+        self.allocated_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub allocated_bytes: usize,
+    pub peak_allocated_bytes: usize,
+    pub free_bytes: usize,
+    pub allocations: usize,
+    pub failed_allocations: usize,
+}
+
+/// Reads the heap statistics. Must not be called while the heap is locked,
+/// e.g. from an interrupt handler that interrupted an allocation.
+pub fn stats() -> HeapStats {
+    HeapStats {
+        size: HEAP_SIZE as usize,
+        allocated_bytes: ALLOCATOR.allocated_bytes.load(Ordering::Relaxed),
+        peak_allocated_bytes: ALLOCATOR.peak_allocated_bytes.load(Ordering::Relaxed),
+        free_bytes: ALLOCATOR.backend.lock().free_bytes(),
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed),
+        failed_allocations: ALLOCATOR.failed_allocations.load(Ordering::Relaxed),
+    }
+}
+
+/// Maps the heap region and hands it to the allocator.
+pub fn init() -> Result<(), MapError> {
+    let mut mapper = Mapper::active();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let mut address = HEAP_START;
+    while address < HEAP_START + HEAP_SIZE {
+        let frame = frame_allocator.allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+        let page = Page::containing_address(VirtAddr(address), PageSize::Size4KiB);
+        mapper.map_to(page, frame.start,
+                      PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                      &mut *frame_allocator)?;
+        address += PageSize::Size4KiB.bytes();
+    }
+    unsafe {
+        ALLOCATOR.backend.lock().init(HEAP_START as usize, HEAP_SIZE as usize);
+    }
+    Ok(())
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    let stats = stats();
+    println!("Heap allocation of {} bytes (align {}) failed", layout.size(), layout.align());
+    println!("  heap size:       {} bytes", stats.size);
+    println!("  allocated:       {} bytes in {} allocations", stats.allocated_bytes, stats.allocations);
+    println!("  peak allocated:  {} bytes", stats.peak_allocated_bytes);
+    println!("  free:            {} bytes", stats.free_bytes);
+    println!("  failed:          {}", stats.failed_allocations);
+    panic!("allocation error: {:?}", layout)
+}
+
+/// Aligns `address` upwards. `align` must be a power of two.
+fn align_up(address: usize, align: usize) -> usize {
+    (address + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test_case]
+    fn box_and_vec() {
+        let value = Box::new(41);
+        let mut vec = Vec::new();
+        for i in 0..1000 {
+            vec.push(i);
+        }
+        assert_eq!(*value + 1, 42);
+        assert_eq!(vec.iter().sum::<u64>(), 999 * 1000 / 2);
+    }
+
+    #[test_case]
+    fn string_and_btree_map() {
+        let mut map = BTreeMap::new();
+        map.insert(String::from("krill"), 1);
+        map.insert(String::from("heap"), 2);
+        assert_eq!(map.get("krill"), Some(&1));
+    }
+
+    #[test_case]
+    fn memory_is_reused() {
+        // Twice the heap size in total, which only fits if freed memory is
+        // reused
+        const ALLOCATION_SIZE: usize = 4096;
+        const ALLOCATIONS: usize = 2 * HEAP_SIZE as usize / ALLOCATION_SIZE;
+
+        let allocated = stats().allocated_bytes;
+        for i in 0..ALLOCATIONS {
+            let x = vec![i as u8; ALLOCATION_SIZE];
+            assert_eq!(x[ALLOCATION_SIZE - 1], i as u8);
+        }
+        assert_eq!(stats().allocated_bytes, allocated);
+    }
+}
@@ -0,0 +1,164 @@
+//! I/O APIC, which routes external interrupts to the local APICs.
+//! https://wiki.osdev.org/IOAPIC
+
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::memory::{PhysAddr, VirtAddr};
+use crate::paging::{map_mmio, MapError};
+
+// Memory mapped registers
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+// Indirect registers
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+// Redirection entry bits
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+pub struct IoApic {
+    base: VirtAddr,
+    /// First global system interrupt handled by this I/O APIC
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    pub fn new(address: PhysAddr, gsi_base: u32) -> Result<IoApic, MapError> {
+        let mut io_apic = IoApic {
+            base: map_mmio(address, 0x20)?,
+            gsi_base,
+            redirection_entries: 0,
+        };
+        io_apic.redirection_entries = ((io_apic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        Ok(io_apic)
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            write_volatile((self.base.0 + IOREGSEL) as *mut u32, register);
+            read_volatile((self.base.0 + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            write_volatile((self.base.0 + IOREGSEL) as *mut u32, register);
+            write_volatile((self.base.0 + IOWIN) as *mut u32, value);
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        ((self.read(IOAPICID) >> 24) & 0xF) as u8
+    }
+
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    pub fn redirection_entries(&self) -> u32 {
+        self.redirection_entries
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    fn read_entry(&self, gsi: u32) -> u64 {
+        let register = IOREDTBL + 2 * (gsi - self.gsi_base);
+        self.read(register) as u64 | (self.read(register + 1) as u64) << 32
+    }
+
+    fn write_entry(&mut self, gsi: u32, entry: u64) {
+        let register = IOREDTBL + 2 * (gsi - self.gsi_base);
+        // Write the low half last, it holds the mask bit
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    /// Routes `gsi` to `vector` on the local APIC `destination`, in fixed
+    /// delivery mode. The entry starts out masked.
+    pub fn route(&mut self, gsi: u32, vector: u8, destination: u8, active_low: bool, level_triggered: bool) {
+        let mut entry = vector as u64 | MASKED | (destination as u64) << 56;
+        if active_low {
+            entry |= ACTIVE_LOW;
+        }
+        if level_triggered {
+            entry |= LEVEL_TRIGGERED;
+        }
+        self.write_entry(gsi, entry);
+    }
+
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) {
+        let entry = self.read_entry(gsi);
+        self.write_entry(gsi, if masked { entry | MASKED } else { entry & !MASKED });
+    }
+
+    pub fn mask_all(&mut self) {
+        for i in 0..self.redirection_entries {
+            self.set_masked(self.gsi_base + i, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::irq::{Irq, IRQ_COUNT};
+
+    use super::super::{local, APIC};
+    use super::*;
+
+    #[test_case]
+    fn isa_irqs_are_routed_to_the_boot_cpu() {
+        let madt = crate::acpi::info().and_then(|acpi| acpi.madt.as_ref()).expect("no MADT");
+        let mut apic = APIC.lock();
+        let apic = apic.as_mut().expect("APIC not initialized");
+        for line in 0..IRQ_COUNT as u8 {
+            let gsi = match apic.isa_gsis[line as usize] {
+                Some(gsi) => gsi,
+                None => continue,
+            };
+            let entry = apic.io_apic_for(gsi).expect("ISA IRQ without I/O APIC").read_entry(gsi);
+            assert_eq!(entry as u8, Irq(line).vector());
+            assert_eq!((entry >> 56) as u8, local::id());
+            let o = madt.overrides.iter().find(|o| o.isa_irq == line);
+            assert_eq!(entry & ACTIVE_LOW != 0, o.map_or(false, |o| o.active_low));
+            assert_eq!(entry & LEVEL_TRIGGERED != 0, o.map_or(false, |o| o.level_triggered));
+        }
+        // The PIT interrupt is replaced by the APIC timer
+        let gsi = apic.isa_gsis[Irq::TIMER.0 as usize].expect("PIT not routed");
+        assert_ne!(apic.io_apic_for(gsi).unwrap().read_entry(gsi) & MASKED, 0);
+    }
+
+    #[test_case]
+    fn entries_are_written() {
+        // Interrupts stay disabled while the APIC is locked, nothing is
+        // delivered through the modified entry
+        let mut apic = APIC.lock();
+        let io_apic = &mut apic.as_mut().expect("APIC not initialized").io_apics[0];
+        // The last pin isn't used by ISA devices
+        let gsi = io_apic.gsi_base() + io_apic.redirection_entries() - 1;
+        assert!(io_apic.handles(gsi));
+        assert!(!io_apic.handles(gsi + 1));
+        let saved = io_apic.read_entry(gsi);
+
+        io_apic.route(gsi, 0x42, 3, true, true);
+        let entry = io_apic.read_entry(gsi);
+        assert_eq!(entry as u8, 0x42);
+        assert_eq!((entry >> 56) as u8, 3);
+        assert_eq!(entry & (ACTIVE_LOW | LEVEL_TRIGGERED | MASKED), ACTIVE_LOW | LEVEL_TRIGGERED | MASKED);
+
+        // Edge triggered and active high, the unconnected pin never fires
+        io_apic.route(gsi, 0x43, 0, false, false);
+        assert_eq!(io_apic.read_entry(gsi) & (ACTIVE_LOW | LEVEL_TRIGGERED), 0);
+        io_apic.set_masked(gsi, false);
+        assert_eq!(io_apic.read_entry(gsi) & MASKED, 0);
+        io_apic.set_masked(gsi, true);
+        assert_eq!(io_apic.read_entry(gsi), 0x43 | MASKED);
+        io_apic.write_entry(gsi, saved);
+    }
+}
@@ -0,0 +1,144 @@
+//! Local APIC of the current CPU.
+//! https://wiki.osdev.org/APIC
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::inline_asm::{rdmsr, wrmsr};
+use crate::memory::PhysAddr;
+use crate::paging::{map_mmio, MapError};
+use crate::pit;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Register offsets
+const ID: usize = 0x20;
+const VERSION: usize = 0x30;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xB0;
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0xF0;
+const ERROR_STATUS: usize = 0x280;
+const LVT_TIMER: usize = 0x320;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE_CONFIGURATION: usize = 0x3E0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Length of the PIT reference delay used to calibrate the timer
+const CALIBRATION_MS: u32 = 10;
+
+/// Virtual address of the register page
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Timer counts per millisecond with a divider of 16
+static TIMER_COUNTS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+fn read(register: usize) -> u32 {
+    unsafe { read_volatile((BASE.load(Ordering::Relaxed) as usize + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    unsafe { write_volatile((BASE.load(Ordering::Relaxed) as usize + register) as *mut u32, value) }
+}
+
+/// Physical address of the register page, as reported by the APIC base MSR.
+pub fn base_address() -> PhysAddr {
+    PhysAddr(rdmsr(IA32_APIC_BASE_MSR) & APIC_BASE_ADDRESS_MASK)
+}
+
+/// Maps the registers and enables the local APIC. `address` overrides the
+/// address from the APIC base MSR, e.g. with the one found in the MADT.
+pub fn init(address: Option<PhysAddr>, spurious_vector: u8, error_vector: u8) -> Result<(), MapError> {
+    let address = address.unwrap_or_else(base_address);
+    wrmsr(IA32_APIC_BASE_MSR, address.0 | APIC_BASE_ENABLE);
+    BASE.store(map_mmio(address, 0x1000)?.as_u64(), Ordering::Relaxed);
+
+    // Accept all interrupts
+    write(TASK_PRIORITY, 0);
+    write(LVT_ERROR, error_vector as u32);
+    write(SPURIOUS_INTERRUPT_VECTOR, SOFTWARE_ENABLE | spurious_vector as u32);
+    // Discard the errors from before the APIC was set up
+    error_status();
+    Ok(())
+}
+
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+pub fn version() -> u8 {
+    read(VERSION) as u8
+}
+
+pub fn send_eoi() {
+    write(EOI, 0);
+}
+
+/// Reads and clears the errors detected since the last call. The error
+/// status register only latches the errors when it's written to.
+pub fn error_status() -> u32 {
+    write(ERROR_STATUS, 0);
+    read(ERROR_STATUS)
+}
+
+/// Measures the timer frequency against PIT channel 2.
+pub fn calibrate_timer() -> u32 {
+    write(TIMER_DIVIDE_CONFIGURATION, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, LVT_MASKED);
+
+    let pit_counts = pit::BASE_FREQUENCY * CALIBRATION_MS / 1000;
+    pit::channel2_start(pit_counts as u16);
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    while !pit::channel2_expired() {}
+    let elapsed = u32::MAX - read(TIMER_CURRENT_COUNT);
+    write(TIMER_INITIAL_COUNT, 0);
+
+    let counts_per_ms = elapsed / CALIBRATION_MS;
+    TIMER_COUNTS_PER_MS.store(counts_per_ms, Ordering::Relaxed);
+    counts_per_ms
+}
+
+/// Fires `vector` periodically at `frequency` Hz. The timer must have been
+/// calibrated.
+pub fn start_periodic_timer(vector: u8, frequency: u32) {
+    let counts_per_ms = TIMER_COUNTS_PER_MS.load(Ordering::Relaxed);
+    if counts_per_ms == 0 {
+        panic!("APIC timer used before calibration");
+    }
+    let initial_count = (counts_per_ms as u64 * 1000 / frequency as u64).max(1);
+    write(TIMER_DIVIDE_CONFIGURATION, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+    write(TIMER_INITIAL_COUNT, initial_count.min(u32::MAX as u64) as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn no_errors_are_pending() {
+        assert_eq!(error_status(), 0);
+    }
+
+    #[test_case]
+    fn boot_cpu_is_in_the_madt() {
+        let madt = crate::acpi::info().and_then(|acpi| acpi.madt.as_ref()).expect("no MADT");
+        assert!(madt.processors.iter().any(|processor| processor.apic_id == id() && processor.enabled));
+        assert_eq!(base_address(), madt.local_apic_address);
+    }
+
+    #[test_case]
+    fn timer_is_running() {
+        assert_ne!(TIMER_COUNTS_PER_MS.load(Ordering::Relaxed), 0);
+        assert_eq!(read(LVT_TIMER) & LVT_MASKED, 0);
+        let count = read(TIMER_CURRENT_COUNT);
+        pit::busy_wait_us(100);
+        assert_ne!(read(TIMER_CURRENT_COUNT), count);
+    }
+}
@@ -0,0 +1,220 @@
+//! Advanced Programmable Interrupt Controller.
+//!
+//! Replaces the 8259 PIC: ISA IRQs are routed through the I/O APIC to the
+//! local APIC of the boot CPU, and the local APIC timer, calibrated against
+//! the PIT, drives the system tick.
+//! https://wiki.osdev.org/APIC
+
+use core::arch::x86_64::__cpuid;
+
+use alloc::vec::Vec;
+
+use crate::irq::{Controller, Irq, IRQ_COUNT};
+use crate::memory::PhysAddr;
+use crate::paging::MapError;
+use crate::sync::IrqMutex;
+use crate::{irq, pic, pit, task};
+
+use self::io::IoApic;
+
+pub mod io;
+pub mod local;
+
+pub const TIMER_VECTOR: u8 = 48;
+pub const ERROR_VECTOR: u8 = 49;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Default I/O APIC address on PC compatibles
+const DEFAULT_IO_APIC_ADDRESS: u64 = 0xFEC0_0000;
+
+/// An ISA IRQ that isn't identity mapped to a global system interrupt, or
+/// that doesn't use the ISA polarity and trigger mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptSourceOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// Describes the interrupt controllers of the machine.
+#[derive(Debug, Clone)]
+pub struct ApicConfig {
+    /// `None` to use the address of the APIC base MSR
+    pub local_apic_address: Option<PhysAddr>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+impl ApicConfig {
+    /// A single I/O APIC at its default address, for machines without a
+    /// MADT. The ISA IRQs are assumed to be identity mapped since only the
+    /// MADT describes the overrides, which doesn't matter for the PIT whose
+    /// interrupt is masked once the APIC timer runs.
+    pub fn legacy() -> ApicConfig {
+        let mut io_apics = Vec::new();
+        io_apics.push(IoApicInfo { id: 0, address: PhysAddr(DEFAULT_IO_APIC_ADDRESS), gsi_base: 0 });
+        ApicConfig {
+            local_apic_address: None,
+            io_apics,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+struct Apic {
+    io_apics: Vec<IoApic>,
+    /// Global system interrupt of each ISA IRQ, `None` for a line whose pin
+    /// was taken by an override, e.g. the cascade IRQ 2 once the PIT uses its
+    /// pin
+    isa_gsis: [Option<u32>; IRQ_COUNT],
+}
+
+impl Apic {
+    fn io_apic_for(&mut self, gsi: u32) -> Option<&mut IoApic> {
+        self.io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi))
+    }
+}
+
+static APIC: IrqMutex<Option<Apic>> = IrqMutex::new(None);
+
+#[derive(Debug)]
+pub enum ApicError {
+    NotSupported,
+    NoIoApic,
+    Map(MapError),
+}
+
+impl From<MapError> for ApicError {
+    fn from(error: MapError) -> ApicError {
+        ApicError::Map(error)
+    }
+}
+
+/// Whether the CPU has a local APIC, from CPUID leaf 1.
+pub fn is_supported() -> bool {
+    const CPUID_FEATURE_APIC: u32 = 1 << 9;
+    unsafe { __cpuid(1).edx & CPUID_FEATURE_APIC != 0 }
+}
+
+/// Switches interrupt delivery from the 8259 PIC to the APICs and starts the
+/// local APIC timer at `timer_frequency` Hz.
+///
+/// IRQ lines that already have a handler stay enabled. The PIT interrupt is
+/// masked since the APIC timer replaces it.
+pub fn init(config: &ApicConfig, timer_frequency: u32) -> Result<(), ApicError> {
+    if !is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    if config.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    local::init(config.local_apic_address, SPURIOUS_VECTOR, ERROR_VECTOR)?;
+    let destination = local::id();
+
+    let mut io_apics = Vec::new();
+    for info in config.io_apics.iter() {
+        let mut io_apic = IoApic::new(info.address, info.gsi_base)?;
+        io_apic.mask_all();
+        io_apics.push(io_apic);
+    }
+    let mut apic = Apic { io_apics, isa_gsis: [None; IRQ_COUNT] };
+
+    for line in 0..IRQ_COUNT as u8 {
+        // ISA interrupts are edge triggered and active high unless overridden
+        let (gsi, active_low, level_triggered) = match config.overrides.iter().find(|o| o.isa_irq == line) {
+            Some(o) => (o.gsi, o.active_low, o.level_triggered),
+            // Another line took the pin, e.g. the PIT that of the cascade
+            None if config.overrides.iter().any(|o| o.gsi == line as u32) => continue,
+            None => (line as u32, false, false),
+        };
+        apic.isa_gsis[line as usize] = Some(gsi);
+        if let Some(io_apic) = apic.io_apic_for(gsi) {
+            io_apic.route(gsi, Irq(line).vector(), destination, active_low, level_triggered);
+        }
+    }
+
+    pic::disable();
+    *APIC.lock() = Some(apic);
+    irq::set_controller(Controller::Apic);
+    irq::mask(Irq::TIMER);
+    pit::set_external_tick_source();
+
+    let counts_per_ms = local::calibrate_timer();
+    local::start_periodic_timer(TIMER_VECTOR, timer_frequency);
+    println!("APIC: local APIC {} (version {:#x}), timer at {} counts/ms",
+             destination, local::version(), counts_per_ms);
+    Ok(())
+}
+
+fn set_masked(irq: Irq, masked: bool) {
+    let mut apic = APIC.lock();
+    if let Some(apic) = apic.as_mut() {
+        let gsi = match apic.isa_gsis[irq.0 as usize] {
+            Some(gsi) => gsi,
+            None => return,
+        };
+        if let Some(io_apic) = apic.io_apic_for(gsi) {
+            io_apic.set_masked(gsi, masked);
+        }
+    }
+}
+
+pub fn mask(irq: Irq) {
+    set_masked(irq, true);
+}
+
+pub fn unmask(irq: Irq) {
+    set_masked(irq, false);
+}
+
+pub fn send_eoi() {
+    local::send_eoi();
+}
+
+/// Called by the timer vector of the IDT.
+pub(crate) fn timer_interrupt() {
+    pit::on_tick();
+    task::tick();
+    local::send_eoi();
+    task::reschedule_if_needed();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn madt_overrides_are_applied() {
+        let madt = crate::acpi::info().and_then(|acpi| acpi.madt.as_ref()).expect("no MADT");
+        let apic = APIC.lock();
+        let apic = apic.as_ref().expect("APIC not initialized");
+        for line in 0..IRQ_COUNT as u8 {
+            let gsi = match madt.overrides.iter().find(|o| o.isa_irq == line) {
+                Some(o) => Some(o.gsi),
+                None if madt.overrides.iter().any(|o| o.gsi == line as u32) => None,
+                None => Some(line as u32),
+            };
+            assert_eq!(apic.isa_gsis[line as usize], gsi);
+        }
+        // QEMU connects the PIT to pin 2, the cascade isn't routed
+        assert_eq!(apic.isa_gsis[Irq::TIMER.0 as usize], Some(2));
+        assert_eq!(apic.isa_gsis[2], None);
+    }
+
+    #[test_case]
+    fn legacy_config_identity_maps_isa_irqs() {
+        let config = ApicConfig::legacy();
+        assert!(config.overrides.is_empty());
+        assert_eq!(config.io_apics.len(), 1);
+        assert_eq!(config.io_apics[0].gsi_base, 0);
+    }
+}
@@ -0,0 +1,453 @@
+//! ATA disks on the legacy IDE channels, in PIO mode.
+//! https://wiki.osdev.org/ATA_PIO_Mode
+//!
+//! Each channel has a master and a slave drive and raises its own IRQ once a
+//! sector is ready to be read or was written. Commands wait for it instead of
+//! polling the status register, except during boot when the scheduler can't
+//! block yet. Tasks wanting a channel busy with a command sleep until it is
+//! done.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use crate::inline_asm::{are_interrupts_enabled, inb, inw, outb, outw};
+use crate::irq::Irq;
+use crate::sync::{InterruptGuard, IrqMutex, SleepMutex};
+use crate::task::TaskId;
+use crate::{irq, pit, task};
+
+use super::{check_range, format_size, BlockDevice, BlockError};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Registers, from the I/O base
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+// Device control register, at the control base
+const CONTROL_INTERRUPT_DISABLE: u8 = 1 << 1;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// Highest sector count of a single command, so that it fits in the 8-bit
+/// LBA28 count, where 0 means 256
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+/// Sectors past this one need LBA48
+const LBA28_LIMIT: u64 = 1 << 28;
+/// Status polls before giving up, about a second
+const POLL_ATTEMPTS: usize = 1_000_000;
+/// How long to wait for an IRQ
+const IRQ_TIMEOUT_MS: u64 = 5000;
+
+static CHANNELS: [Channel; 2] = [
+    Channel::new(0x1F0, 0x3F6, Irq::PRIMARY_ATA),
+    Channel::new(0x170, 0x376, Irq::SECONDARY_ATA),
+];
+
+struct Channel {
+    io_base: u16,
+    control_base: u16,
+    irq: Irq,
+    /// Held for the whole duration of a command, while waiting for its IRQs
+    lock: SleepMutex<()>,
+    irq_state: IrqMutex<IrqState>,
+}
+
+/// Shared with the IRQ handler.
+struct IrqState {
+    fired: bool,
+    /// Status read by the handler, which acknowledges the IRQ
+    status: u8,
+    waiter: Option<TaskId>,
+}
+
+impl Channel {
+    const fn new(io_base: u16, control_base: u16, irq: Irq) -> Channel {
+        Channel {
+            io_base,
+            control_base,
+            irq,
+            lock: SleepMutex::new(()),
+            irq_state: IrqMutex::new(IrqState { fired: false, status: 0, waiter: None }),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        inb(self.io_base + register)
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        outb(self.io_base + register, value);
+    }
+
+    /// Reads the status without acknowledging the IRQ.
+    fn alternate_status(&self) -> u8 {
+        inb(self.control_base)
+    }
+
+    /// Selects a drive and waits the 400ns it needs to show its status.
+    fn select(&self, slave: bool, high_bits: u8) {
+        self.write(DRIVE, 0xE0 | (slave as u8) << 4 | high_bits);
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        outb(self.control_base, if enabled { 0 } else { CONTROL_INTERRUPT_DISABLE });
+    }
+
+    /// Waits for the drive to be ready and checks for errors.
+    fn poll(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                self.irq_state.lock().fired = false;
+                return check_status(status, self.read(ERROR));
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits for the IRQ ending the current step of a command.
+    fn wait(&self) -> Result<u8, BlockError> {
+        // The idle task can't block, and the IRQ can't arrive with interrupts
+        // disabled
+        if task::is_idle() || !are_interrupts_enabled() {
+            return self.poll();
+        }
+        let deadline = task::ticks() + pit::ms_to_ticks(IRQ_TIMEOUT_MS);
+        loop {
+            // The IRQ must not fire between the check and the sleep, or the
+            // wake up would be lost
+            let _guard = InterruptGuard::new();
+            let now = task::ticks();
+            {
+                let mut state = self.irq_state.lock();
+                if state.fired {
+                    state.fired = false;
+                    return check_status(state.status, self.read(ERROR));
+                }
+                if now >= deadline {
+                    state.waiter = None;
+                    return Err(BlockError::Timeout);
+                }
+                state.waiter = Some(task::current_id());
+            }
+            task::sleep(deadline - now);
+        }
+    }
+
+    /// Sends a command to the selected drive, with LBA48 addressing if
+    /// `lba48` is set.
+    fn send(&self, slave: bool, command: u8, lba: u64, count: usize, lba48: bool) {
+        self.irq_state.lock().fired = false;
+        if lba48 {
+            self.select(slave, 0);
+            // High bytes first, the registers are FIFOs of two bytes
+            self.write(SECTOR_COUNT, (count >> 8) as u8);
+            self.write(LBA_LOW, (lba >> 24) as u8);
+            self.write(LBA_MID, (lba >> 32) as u8);
+            self.write(LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(slave, (lba >> 24) as u8 & 0x0F);
+        }
+        self.write(SECTOR_COUNT, count as u8);
+        self.write(LBA_LOW, lba as u8);
+        self.write(LBA_MID, (lba >> 8) as u8);
+        self.write(LBA_HIGH, (lba >> 16) as u8);
+        self.write(COMMAND, command);
+    }
+
+    fn read_sector(&self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_exact_mut(2) {
+            chunk.copy_from_slice(&inw(self.io_base + DATA).to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, buffer: &[u8]) {
+        for chunk in buffer.chunks_exact(2) {
+            outw(self.io_base + DATA, u16::from_le_bytes([chunk[0], chunk[1]]));
+        }
+    }
+
+    /// Identifies a drive, if there is one. Interrupts of the channel must be
+    /// disabled.
+    fn identify(&self, slave: bool) -> Option<Identify> {
+        self.select(slave, 0);
+        for register in SECTOR_COUNT..=LBA_HIGH {
+            self.write(register, 0);
+        }
+        self.write(COMMAND, COMMAND_IDENTIFY);
+        // No drive, or a floating bus without controller
+        let status = self.read(STATUS);
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+        (0..POLL_ATTEMPTS).find(|_| self.read(STATUS) & STATUS_BUSY == 0)?;
+        // ATAPI and SATA devices abort the command and leave their signature
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        let status = (0..POLL_ATTEMPTS)
+            .map(|_| self.read(STATUS))
+            .find(|status| status & (STATUS_ERROR | STATUS_DATA_REQUEST) != 0)?;
+        if status & STATUS_ERROR != 0 {
+            return None;
+        }
+        let mut data = [0; 256];
+        for word in data.iter_mut() {
+            *word = inw(self.io_base + DATA);
+        }
+        Some(Identify::parse(&data))
+    }
+}
+
+fn check_status(status: u8, error: u8) -> Result<u8, BlockError> {
+    if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+        Err(BlockError::Device(error))
+    } else {
+        Ok(status)
+    }
+}
+
+/// The interesting part of the 256 words returned by IDENTIFY DEVICE.
+#[derive(Debug, PartialEq)]
+struct Identify {
+    model: String,
+    sectors: u64,
+    lba48: bool,
+}
+
+impl Identify {
+    fn parse(data: &[u16; 256]) -> Identify {
+        // Every word of the model holds two characters, the first one in the
+        // high byte
+        let mut model = String::new();
+        for word in &data[27..47] {
+            model.push((word >> 8) as u8 as char);
+            model.push(*word as u8 as char);
+        }
+        let lba48 = data[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            data[100..104].iter().rev().fold(0, |sectors, &word| sectors << 16 | word as u64)
+        } else {
+            (data[61] as u64) << 16 | data[60] as u64
+        };
+        Identify { model: String::from(model.trim()), sectors, lba48 }
+    }
+}
+
+pub struct AtaDrive {
+    name: String,
+    channel: &'static Channel,
+    slave: bool,
+    identify: Identify,
+}
+
+impl AtaDrive {
+    pub fn model(&self) -> &str {
+        &self.identify.model
+    }
+
+    fn needs_lba48(&self, lba: u64, count: usize) -> bool {
+        self.identify.lba48 && lba + count as u64 > LBA28_LIMIT
+    }
+
+    fn read_command(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = buffer.len() / SECTOR_SIZE;
+        let lba48 = self.needs_lba48(lba, count);
+        let command = if lba48 { COMMAND_READ_SECTORS_EXT } else { COMMAND_READ_SECTORS };
+        self.channel.send(self.slave, command, lba, count, lba48);
+        // Every sector raises an IRQ once it can be read
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            self.channel.wait()?;
+            self.channel.read_sector(sector);
+        }
+        Ok(())
+    }
+
+    fn write_command(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let count = buffer.len() / SECTOR_SIZE;
+        let lba48 = self.needs_lba48(lba, count);
+        let command = if lba48 { COMMAND_WRITE_SECTORS_EXT } else { COMMAND_WRITE_SECTORS };
+        self.channel.send(self.slave, command, lba, count, lba48);
+        // The first sector is expected right away, every sector written
+        // raises an IRQ
+        let status = self.channel.poll()?;
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Err(BlockError::Device(status));
+        }
+        for sector in buffer.chunks_exact(SECTOR_SIZE) {
+            self.channel.write_sector(sector);
+            self.channel.wait()?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.identify.sectors
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, block, buffer.len())?;
+        let _lock = self.channel.lock.lock();
+        for (i, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            self.read_command(block + (i * MAX_SECTORS_PER_COMMAND) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_range(self, block, buffer.len())?;
+        let _lock = self.channel.lock.lock();
+        for (i, chunk) in buffer.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            self.write_command(block + (i * MAX_SECTORS_PER_COMMAND) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let _lock = self.channel.lock.lock();
+        let command = if self.identify.lba48 { COMMAND_CACHE_FLUSH_EXT } else { COMMAND_CACHE_FLUSH };
+        self.channel.send(self.slave, command, 0, 0, false);
+        self.channel.wait().map(|_| ())
+    }
+}
+
+fn irq_handler(irq: Irq) {
+    let channel = CHANNELS.iter().find(|channel| channel.irq == irq).unwrap();
+    let mut state = channel.irq_state.lock();
+    state.status = channel.read(STATUS);
+    state.fired = true;
+    if let Some(waiter) = state.waiter.take() {
+        task::wake(waiter);
+    }
+}
+
+/// Identifies the drives of both channels and registers them as `hda` to
+/// `hdd`.
+pub fn init() {
+    for (channel_index, channel) in CHANNELS.iter().enumerate() {
+        channel.set_interrupts(false);
+        let mut found = false;
+        for &slave in &[false, true] {
+            let identify = match channel.identify(slave) {
+                Some(identify) if identify.sectors > 0 => identify,
+                _ => continue,
+            };
+            let name = format!("hd{}", (b'a' + (channel_index * 2 + slave as usize) as u8) as char);
+            println!("ATA: /dev/{} is {}, {} ({})", name, identify.model, format_size(identify.sectors * SECTOR_SIZE as u64),
+                     if identify.lba48 { "LBA48" } else { "LBA28" });
+            super::register(Arc::new(AtaDrive { name, channel, slave, identify }));
+            found = true;
+        }
+        if found {
+            irq::register_handler(channel.irq, irq_handler);
+            channel.set_interrupts(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use alloc::vec;
+
+    use crate::block;
+
+    use super::*;
+
+    #[test_case]
+    fn identify_is_parsed() {
+        let mut data = [0; 256];
+        for (word, chunk) in data[27..47].iter_mut().zip(b"QEMU HARDDISK                           ".chunks(2)) {
+            *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        }
+        data[60] = 0x0000;
+        data[61] = 0x0010;
+        assert_eq!(Identify::parse(&data), Identify { model: String::from("QEMU HARDDISK"), sectors: 0x10_0000, lba48: false });
+
+        data[83] = 1 << 10;
+        data[100] = 0x0000;
+        data[101] = 0x0000;
+        data[102] = 0x0001;
+        assert_eq!(Identify::parse(&data).sectors, 1 << 32);
+    }
+
+    /// Reads the boot sector, and writes and reads back a pattern on the
+    /// scratch disk the test runner attaches as the primary slave, with
+    /// writes kept in a temporary snapshot.
+    fn round_trip() {
+        // QEMU boots from the primary master
+        let disk = block::find("hda").expect("no boot disk");
+        let mut sectors = vec![0; 2 * SECTOR_SIZE];
+        disk.read_blocks(0, &mut sectors).unwrap();
+        assert_eq!(&sectors[510..512], &[0x55, 0xAA]);
+
+        let scratch = block::find("hdb").expect("no scratch disk");
+        let last = scratch.block_count() - 2;
+        for (i, byte) in sectors.iter_mut().enumerate() {
+            *byte = i as u8 ^ 0xA5;
+        }
+        scratch.write_blocks(last, &sectors).unwrap();
+        scratch.flush().unwrap();
+        let mut read = vec![0; 2 * SECTOR_SIZE];
+        scratch.read_blocks(last, &mut read).unwrap();
+        assert_eq!(read, sectors);
+        assert_eq!(scratch.read_blocks(last + 2, &mut read[..SECTOR_SIZE]), Err(BlockError::OutOfRange));
+        assert_eq!(scratch.read_blocks(0, &mut read[..100]), Err(BlockError::BadBufferSize));
+    }
+
+    #[test_case]
+    fn disks_are_readable() {
+        // Polls the status, tests run on the idle task
+        round_trip();
+    }
+
+    #[test_case]
+    fn commands_complete_with_irqs() {
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+        // Two tasks other than the idle one sleep until the IRQs, and on the
+        // lock of the channel the disks share
+        for _ in 0..2 {
+            task::spawn("ata test", || {
+                round_trip();
+                DONE.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        while DONE.load(Ordering::SeqCst) < 2 {
+            task::yield_now();
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Buffer cache in front of a block device.
+//! https://wiki.osdev.org/Disk_Caching
+//!
+//! Blocks read or written stay in memory until the least recently used ones
+//! have to make room. Writes only mark blocks dirty, they reach the device when
+//! they are evicted or when the cache is flushed. Transfers larger than the
+//! cache go straight to the device.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::sync::SleepMutex;
+
+use super::{check_range, BlockDevice, BlockError};
+
+/// Memory each device can use for cached blocks
+const CACHE_SIZE: usize = 256 * 1024;
+
+/// Dirty blocks written back together, at most
+const MAX_WRITE_BACK: usize = 64;
+
+pub struct CachedDevice {
+    device: Arc<dyn BlockDevice>,
+    /// Number of blocks the cache holds
+    capacity: usize,
+    /// Held across device transfers, which sleep until the drive is done
+    state: SleepMutex<CacheState>,
+}
+
+struct CacheState {
+    buffers: BTreeMap<u64, Buffer>,
+    /// Cached blocks by time of last use
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+struct Buffer {
+    data: Box<[u8]>,
+    dirty: bool,
+    last_use: u64,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice>) -> CachedDevice {
+        let capacity = (CACHE_SIZE / device.block_size()).max(1);
+        CachedDevice {
+            device,
+            capacity,
+            state: SleepMutex::new(CacheState { buffers: BTreeMap::new(), lru: BTreeMap::new(), clock: 0 }),
+        }
+    }
+
+    /// Copies a cached block, and marks it as used.
+    fn lookup(&self, state: &mut CacheState, block: u64, buffer: &mut [u8]) -> bool {
+        let clock = state.clock;
+        match state.buffers.get_mut(&block) {
+            Some(cached) => {
+                buffer.copy_from_slice(&cached.data);
+                state.lru.remove(&cached.last_use);
+                state.lru.insert(clock, block);
+                cached.last_use = clock;
+                state.clock += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Caches the content of a block, making room for it if needed.
+    fn insert(&self, state: &mut CacheState, block: u64, data: &[u8], dirty: bool) -> Result<(), BlockError> {
+        let clock = state.clock;
+        state.clock += 1;
+        if let Some(cached) = state.buffers.get_mut(&block) {
+            cached.data.copy_from_slice(data);
+            cached.dirty |= dirty;
+            state.lru.remove(&cached.last_use);
+            state.lru.insert(clock, block);
+            cached.last_use = clock;
+            return Ok(());
+        }
+        while state.buffers.len() >= self.capacity {
+            self.evict(state)?;
+        }
+        state.buffers.insert(block, Buffer { data: data.into(), dirty, last_use: clock });
+        state.lru.insert(clock, block);
+        Ok(())
+    }
+
+    /// Drops the least recently used block, after writing it back if it is
+    /// dirty. It stays cached if that fails.
+    fn evict(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let (&last_use, &block) = state.lru.iter().next().expect("empty cache is full");
+        let buffer = &state.buffers[&block];
+        if buffer.dirty {
+            self.device.write_blocks(block, &buffer.data)?;
+        }
+        state.lru.remove(&last_use);
+        state.buffers.remove(&block);
+        Ok(())
+    }
+
+    /// Writes the dirty blocks back, runs of consecutive blocks in a single
+    /// transfer.
+    fn write_back(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let block_size = self.device.block_size();
+        let mut run = Vec::new();
+        let mut first = 0;
+        let dirty: Vec<u64> = state.buffers.iter()
+            .filter(|(_, buffer)| buffer.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+        for (i, &block) in dirty.iter().enumerate() {
+            if run.is_empty() {
+                first = block;
+            }
+            run.extend_from_slice(&state.buffers[&block].data);
+            let count = run.len() / block_size;
+            let last = i + 1 == dirty.len() || dirty[i + 1] != block + 1 || count == MAX_WRITE_BACK;
+            if last {
+                self.device.write_blocks(first, &run)?;
+                for written in first..first + count as u64 {
+                    state.buffers.get_mut(&written).unwrap().dirty = false;
+                }
+                run.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_range(self, block, buffer.len())? as usize;
+        let block_size = self.block_size();
+        let mut state = self.state.lock();
+        if count > self.capacity {
+            // Too large to cache, but dirty blocks are newer than the device
+            self.device.read_blocks(block, buffer)?;
+            for (&cached, cached_buffer) in state.buffers.range(block..block + count as u64) {
+                if cached_buffer.dirty {
+                    let offset = (cached - block) as usize * block_size;
+                    buffer[offset..offset + block_size].copy_from_slice(&cached_buffer.data);
+                }
+            }
+            return Ok(());
+        }
+        let mut i = 0;
+        while i < count {
+            if self.lookup(&mut state, block + i as u64, &mut buffer[i * block_size..(i + 1) * block_size]) {
+                i += 1;
+                continue;
+            }
+            // Read the missing blocks in one go
+            let mut end = i + 1;
+            while end < count && !state.buffers.contains_key(&(block + end as u64)) {
+                end += 1;
+            }
+            let run = &mut buffer[i * block_size..end * block_size];
+            self.device.read_blocks(block + i as u64, run)?;
+            for (j, data) in run.chunks(block_size).enumerate() {
+                self.insert(&mut state, block + (i + j) as u64, data, false)?;
+            }
+            i = end;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let count = check_range(self, block, buffer.len())? as usize;
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let block_size = self.block_size();
+        let mut state = self.state.lock();
+        if count > self.capacity {
+            self.device.write_blocks(block, buffer)?;
+            // The cached copies are stale now
+            for written in block..block + count as u64 {
+                if let Some(stale) = state.buffers.remove(&written) {
+                    state.lru.remove(&stale.last_use);
+                }
+            }
+            return Ok(());
+        }
+        for (i, data) in buffer.chunks(block_size).enumerate() {
+            self.insert(&mut state, block + i as u64, data, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.write_back(&mut self.state.lock())?;
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use alloc::vec;
+
+    use crate::block::ram::RamDisk;
+
+    use super::*;
+
+    /// A disk that counts its transfers.
+    struct CountingDisk {
+        disk: RamDisk,
+        reads: AtomicUsize,
+        writes: AtomicUsize,
+    }
+
+    impl BlockDevice for CountingDisk {
+        fn name(&self) -> &str {
+            self.disk.name()
+        }
+
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> u64 {
+            self.disk.block_count()
+        }
+
+        fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.disk.read_blocks(block, buffer)
+        }
+
+        fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.disk.write_blocks(block, buffer)
+        }
+    }
+
+    fn disk() -> Arc<CountingDisk> {
+        let disk = RamDisk::new("cache-test", 512, 4096);
+        Arc::new(CountingDisk { disk, reads: AtomicUsize::new(0), writes: AtomicUsize::new(0) })
+    }
+
+    fn counts(disk: &CountingDisk) -> (usize, usize) {
+        (disk.reads.load(Ordering::Relaxed), disk.writes.load(Ordering::Relaxed))
+    }
+
+    #[test_case]
+    fn blocks_are_cached() {
+        let disk = disk();
+        let cache = CachedDevice::new(disk.clone());
+        let mut buffer = vec![0; 4 * 512];
+        cache.read_blocks(10, &mut buffer).unwrap();
+        cache.read_blocks(12, &mut buffer[..512]).unwrap();
+        assert_eq!(counts(&disk), (1, 0));
+
+        // Writes stay in the cache until the flush, in a single transfer
+        cache.write_blocks(20, &[1; 3 * 512]).unwrap();
+        cache.write_blocks(23, &[2; 512]).unwrap();
+        cache.read_blocks(20, &mut buffer).unwrap();
+        assert_eq!(&buffer[3 * 512..], &[2; 512][..]);
+        assert_eq!(counts(&disk), (1, 0));
+        cache.flush().unwrap();
+        assert_eq!(counts(&disk), (1, 1));
+        disk.disk.read_blocks(20, &mut buffer).unwrap();
+        assert_eq!(&buffer[..512], &[1; 512][..]);
+        cache.flush().unwrap();
+        assert_eq!(counts(&disk), (1, 1));
+    }
+
+    #[test_case]
+    fn least_recently_used_blocks_are_evicted() {
+        let disk = disk();
+        let cache = CachedDevice::new(disk.clone());
+        let capacity = cache.capacity as u64;
+        let mut buffer = vec![0; 512];
+        cache.write_blocks(0, &[7; 512]).unwrap();
+        for block in 1..capacity {
+            cache.read_blocks(block, &mut buffer).unwrap();
+        }
+        // Block 1 is the oldest once block 0 is used again
+        cache.read_blocks(0, &mut buffer).unwrap();
+        cache.read_blocks(capacity, &mut buffer).unwrap();
+        let (reads, writes) = counts(&disk);
+        assert_eq!(writes, 0);
+        cache.read_blocks(0, &mut buffer).unwrap();
+        cache.read_blocks(1, &mut buffer).unwrap();
+        assert_eq!(counts(&disk), (reads + 1, 0));
+
+        // Evicting a dirty block writes it back
+        for block in capacity + 1..2 * capacity {
+            cache.read_blocks(block, &mut buffer).unwrap();
+        }
+        assert_eq!(counts(&disk).1, 1);
+        disk.disk.read_blocks(0, &mut buffer).unwrap();
+        assert_eq!(buffer, [7; 512]);
+    }
+
+    #[test_case]
+    fn large_transfers_bypass_the_cache() {
+        let disk = disk();
+        let cache = CachedDevice::new(disk.clone());
+        let size = (cache.capacity + 1) * 512;
+        cache.write_blocks(5, &[3; 512]).unwrap();
+        let mut buffer = vec![0; size];
+        cache.read_blocks(0, &mut buffer).unwrap();
+        // The dirty block is newer than the device
+        assert_eq!(&buffer[5 * 512..6 * 512], &[3; 512][..]);
+        assert_eq!(counts(&disk), (1, 0));
+
+        let data: Vec<u8> = (0..size).map(|i| (i / 512) as u8).collect();
+        cache.write_blocks(0, &data).unwrap();
+        assert_eq!(counts(&disk), (1, 1));
+        cache.read_blocks(5, &mut buffer[..512]).unwrap();
+        assert_eq!(&buffer[..512], &data[5 * 512..6 * 512]);
+        // Nothing stale is written back
+        cache.flush().unwrap();
+        assert_eq!(counts(&disk).1, 1);
+    }
+}
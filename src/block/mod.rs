@@ -0,0 +1,256 @@
+//! Block devices: disks and anything else read and written in fixed-size
+//! blocks.
+//!
+//! Drivers implement `BlockDevice` and `register` their disks, which then
+//! show up in `/dev` under their name, behind a cache, along with the
+//! partitions they hold. File systems only deal with the trait.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::fs::{devfs, FileType, FsError, Inode, Metadata};
+
+use self::cache::CachedDevice;
+
+pub mod ata;
+mod cache;
+mod partition;
+#[cfg(test)]
+pub mod ram;
+pub mod virtio;
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks are past the end of the device
+    OutOfRange,
+    /// The buffer isn't a multiple of the block size
+    BadBufferSize,
+    ReadOnly,
+    /// The device didn't answer in time
+    Timeout,
+    /// The device reported an error, with its status
+    Device(u8),
+    /// The request needs more descriptors than the device's queue has
+    RequestTooLarge,
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> FsError {
+        match error {
+            BlockError::OutOfRange | BlockError::BadBufferSize => FsError::InvalidArgument,
+            BlockError::ReadOnly => FsError::PermissionDenied,
+            BlockError::Timeout | BlockError::Device(_) | BlockError::RequestTooLarge => FsError::Io,
+        }
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Name of the device file in `/dev`
+    fn name(&self) -> &str;
+
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads the blocks starting at `block` into `buffer`, whose length must
+    /// be a multiple of the block size.
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer` to the blocks starting at `block`.
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure the written blocks reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+/// Checks that a transfer of `length` bytes at `block` fits in `device`.
+/// Returns the number of blocks.
+pub fn check_range(device: &dyn BlockDevice, block: u64, length: usize) -> Result<u64, BlockError> {
+    if length % device.block_size() != 0 {
+        return Err(BlockError::BadBufferSize);
+    }
+    let count = (length / device.block_size()) as u64;
+    match block.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Adds a disk to the registry behind a cache, with its partitions, and
+/// publishes them in `/dev`.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    let disk: Arc<dyn BlockDevice> = Arc::new(CachedDevice::new(device));
+    add(disk.clone());
+    match partition::scan(&disk) {
+        Ok(Some(table)) => {
+            let names: Vec<String> = table.partitions.iter()
+                .map(|partition| format!("{} ({})", partition.name(), format_size(partition.size())))
+                .collect();
+            println!("{}: {:?} partitions {}", disk.name(), table.scheme, names.join(", "));
+            for partition in table.partitions {
+                add(partition);
+            }
+        }
+        Ok(None) => {}
+        Err(error) => println!("{}: can't read the partition table: {:?}", disk.name(), error),
+    }
+}
+
+fn add(device: Arc<dyn BlockDevice>) {
+    let file = Arc::new(DeviceFile { inode: devfs::next_inode(), device: device.clone() });
+    if let Err(error) = devfs::register(device.name(), file) {
+        println!("Can't add /dev/{}: {:?}", device.name(), error);
+    }
+    DEVICES.lock().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+/// Human readable size, e.g. "128 MiB".
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size;
+    let mut unit = 0;
+    while size >= 1024 && unit < UNITS.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    format!("{} {}", size, UNITS[unit])
+}
+
+/// Reads `buffer.len()` bytes at any byte `offset` of a device. Whole blocks
+/// go straight to the buffer, partial ones through a bounce buffer.
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+    check_byte_range(device, offset, buffer.len())?;
+    let block_size = device.block_size();
+    let mut bounce = Vec::new();
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let block = position / block_size as u64;
+        let start = (position % block_size as u64) as usize;
+        let remaining = buffer.len() - done;
+        if start == 0 && remaining >= block_size {
+            let count = remaining - remaining % block_size;
+            device.read_blocks(block, &mut buffer[done..done + count])?;
+            done += count;
+        } else {
+            bounce.resize(block_size, 0);
+            device.read_blocks(block, &mut bounce)?;
+            let count = (block_size - start).min(remaining);
+            buffer[done..done + count].copy_from_slice(&bounce[start..start + count]);
+            done += count;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `buffer` at any byte `offset` of a device. The rest of partially
+/// written blocks is kept.
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, buffer: &[u8]) -> Result<(), BlockError> {
+    check_byte_range(device, offset, buffer.len())?;
+    let block_size = device.block_size();
+    let mut bounce = Vec::new();
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let block = position / block_size as u64;
+        let start = (position % block_size as u64) as usize;
+        let remaining = buffer.len() - done;
+        if start == 0 && remaining >= block_size {
+            let count = remaining - remaining % block_size;
+            device.write_blocks(block, &buffer[done..done + count])?;
+            done += count;
+        } else {
+            bounce.resize(block_size, 0);
+            device.read_blocks(block, &mut bounce)?;
+            let count = (block_size - start).min(remaining);
+            bounce[start..start + count].copy_from_slice(&buffer[done..done + count]);
+            device.write_blocks(block, &bounce)?;
+            done += count;
+        }
+    }
+    Ok(())
+}
+
+fn check_byte_range(device: &dyn BlockDevice, offset: u64, length: usize) -> Result<(), BlockError> {
+    match offset.checked_add(length as u64) {
+        Some(end) if end <= device.size() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// A block device as a file.
+struct DeviceFile {
+    inode: u64,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl DeviceFile {
+    /// Clamps an access of `length` bytes at `offset` to the end of the
+    /// device.
+    fn clamp(&self, offset: u64, length: usize) -> usize {
+        let size = self.device.size();
+        if offset >= size {
+            0
+        } else {
+            length.min((size - offset) as usize)
+        }
+    }
+}
+
+impl Inode for DeviceFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            file_type: FileType::BlockDevice,
+            size: self.device.size(),
+            mode: 0o660,
+            links: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let length = self.clamp(offset, buffer.len());
+        read_bytes(&*self.device, offset, &mut buffer[..length])?;
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let length = self.clamp(offset, buffer.len());
+        if length == 0 && !buffer.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        write_bytes(&*self.device, offset, &buffer[..length])?;
+        Ok(length)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(self.device.flush()?)
+    }
+}
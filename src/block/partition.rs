@@ -0,0 +1,398 @@
+//! Partition tables, MBR and GPT, and partitions as block devices.
+//! https://wiki.osdev.org/MBR_(x86)
+//! https://wiki.osdev.org/Extended_Partition
+//! https://wiki.osdev.org/GPT
+//!
+//! Partitions are numbered like Linux does: 1 to 4 for the primary MBR
+//! partitions and 5 on for the logical ones, the position in the table for
+//! GPT.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{check_range, read_bytes, BlockDevice, BlockError};
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+/// Types of extended partitions, which hold a chain of logical ones
+const TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Bound on the chain of logical partitions, which could loop
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+/// Largest partition entry array read, 4 times the usual one
+const MAX_GPT_ENTRIES_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+}
+
+pub struct PartitionTable {
+    pub scheme: Scheme,
+    pub partitions: Vec<Arc<dyn BlockDevice>>,
+}
+
+/// A range of blocks of a disk.
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+}
+
+impl Partition {
+    fn new(disk: &Arc<dyn BlockDevice>, number: usize, start: u64, count: u64) -> Partition {
+        // `nvme0n1` has `nvme0n1p1`, `hda` has `hda1`
+        let separator = if disk.name().ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+        let name = format!("{}{}{}", disk.name(), separator, number);
+        Partition { name, disk: disk.clone(), start, count }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, block, buffer.len())?;
+        self.disk.read_blocks(self.start + block, buffer)
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_range(self, block, buffer.len())?;
+        self.disk.write_blocks(self.start + block, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+}
+
+/// Reads the partition table of a disk. Returns `None` if there is none.
+pub fn scan(disk: &Arc<dyn BlockDevice>) -> Result<Option<PartitionTable>, BlockError> {
+    if disk.size() < 512 {
+        return Ok(None);
+    }
+    let mut mbr = vec![0; 512];
+    read_bytes(&**disk, 0, &mut mbr)?;
+    let entries = match parse_mbr(&mbr) {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+    if entries.iter().any(|entry| entry.partition_type == TYPE_GPT_PROTECTIVE) {
+        return Ok(scan_gpt(disk)?.map(|partitions| PartitionTable { scheme: Scheme::Gpt, partitions }));
+    }
+
+    let mut partitions: Vec<Arc<dyn BlockDevice>> = Vec::new();
+    let mut logical_number = 5;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.partition_type == 0 || !fits(disk, entry.start, entry.count) {
+            continue;
+        }
+        if TYPES_EXTENDED.contains(&entry.partition_type) {
+            for (start, count) in scan_extended(disk, entry.start, entry.count)? {
+                partitions.push(Arc::new(Partition::new(disk, logical_number, start, count)));
+                logical_number += 1;
+            }
+        } else {
+            partitions.push(Arc::new(Partition::new(disk, i + 1, entry.start, entry.count)));
+        }
+    }
+    Ok(Some(PartitionTable { scheme: Scheme::Mbr, partitions }))
+}
+
+struct MbrEntry {
+    partition_type: u8,
+    start: u64,
+    count: u64,
+}
+
+/// Parses the 4 entries of a master or extended boot record. Boot sectors
+/// of unpartitioned disks also end with the signature, their code rarely
+/// passes for valid entries.
+fn parse_mbr(sector: &[u8]) -> Option<Vec<MbrEntry>> {
+    if read_u16(sector, 510) != MBR_SIGNATURE {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for i in 0..4 {
+        let entry = &sector[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        // The boot indicator is 0 or 0x80
+        if entry[0] & 0x7F != 0 {
+            return None;
+        }
+        entries.push(MbrEntry {
+            partition_type: entry[4],
+            start: read_u32(entry, 8) as u64,
+            count: read_u32(entry, 12) as u64,
+        });
+    }
+    Some(entries)
+}
+
+/// Whether a partition is non-empty and within the disk, past its first
+/// block.
+fn fits(disk: &Arc<dyn BlockDevice>, start: u64, count: u64) -> bool {
+    start > 0 && count > 0 && start.checked_add(count).map_or(false, |end| end <= disk.block_count())
+}
+
+/// Follows the chain of extended boot records of an extended partition.
+/// Each one describes a logical partition, relative to itself, and links to
+/// the next, relative to the extended partition.
+fn scan_extended(disk: &Arc<dyn BlockDevice>, start: u64, count: u64) -> Result<Vec<(u64, u64)>, BlockError> {
+    let block_size = disk.block_size() as u64;
+    let mut partitions = Vec::new();
+    let mut record = start;
+    let mut sector = vec![0; 512];
+    while partitions.len() < MAX_LOGICAL_PARTITIONS {
+        read_bytes(&**disk, record * block_size, &mut sector)?;
+        let entries = match parse_mbr(&sector) {
+            Some(entries) => entries,
+            None => break,
+        };
+        let logical = &entries[0];
+        let logical_start = record + logical.start;
+        if logical.partition_type != 0 && logical_start + logical.count <= start + count
+            && fits(disk, logical_start, logical.count) {
+            partitions.push((logical_start, logical.count));
+        }
+        let next = &entries[1];
+        if !TYPES_EXTENDED.contains(&next.partition_type) || next.start == 0 || next.start >= count {
+            break;
+        }
+        record = start + next.start;
+    }
+    Ok(partitions)
+}
+
+/// Reads the GPT from its primary header, or from the backup one at the end of
+/// the disk if the primary one is damaged.
+fn scan_gpt(disk: &Arc<dyn BlockDevice>) -> Result<Option<Vec<Arc<dyn BlockDevice>>>, BlockError> {
+    for &header_block in &[1, disk.block_count() - 1] {
+        if let Some(partitions) = read_gpt(disk, header_block)? {
+            return Ok(Some(partitions));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the GPT whose header is at `header_block`. Returns `None` if the
+/// header or the entries don't match their checksum.
+fn read_gpt(disk: &Arc<dyn BlockDevice>, header_block: u64) -> Result<Option<Vec<Arc<dyn BlockDevice>>>, BlockError> {
+    let block_size = disk.block_size();
+    if header_block < 1 || header_block >= disk.block_count() {
+        return Ok(None);
+    }
+    let mut header = vec![0; block_size];
+    disk.read_blocks(header_block, &mut header)?;
+    let header_size = read_u32(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || header_size < GPT_HEADER_SIZE || header_size > block_size
+        || read_u64(&header, 24) != header_block {
+        return Ok(None);
+    }
+    let checksum = read_u32(&header, 16);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header[..header_size]) != checksum {
+        return Ok(None);
+    }
+
+    let first_usable = read_u64(&header, 40);
+    let last_usable = read_u64(&header, 48);
+    let entries_block = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    if entry_size < 128 || entry_size % 8 != 0 || entry_count.saturating_mul(entry_size) > MAX_GPT_ENTRIES_SIZE {
+        return Ok(None);
+    }
+    let mut entries = vec![0; entry_count * entry_size];
+    match entries_block.checked_mul(block_size as u64) {
+        Some(offset) if offset + entries.len() as u64 <= disk.size() => read_bytes(&**disk, offset, &mut entries)?,
+        _ => return Ok(None),
+    }
+    if crc32(&entries) != read_u32(&header, 88) {
+        return Ok(None);
+    }
+
+    let mut partitions: Vec<Arc<dyn BlockDevice>> = Vec::new();
+    for (i, entry) in entries.chunks(entry_size).enumerate() {
+        // Unused entries have no type
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let (first, last) = (read_u64(entry, 32), read_u64(entry, 40));
+        if first < first_usable || last > last_usable || first > last || !fits(disk, first, last - first + 1) {
+            continue;
+        }
+        partitions.push(Arc::new(Partition::new(disk, i + 1, first, last - first + 1)));
+    }
+    Ok(Some(partitions))
+}
+
+/// The CRC-32 of GPT headers and entries, the same as Ethernet and zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::ram::RamDisk;
+    use crate::fs::ext2::Ext2Fs;
+    use crate::fs::FileSystem;
+
+    use super::*;
+
+    const SECTOR_SIZE: usize = 512;
+
+    fn mbr_entry(sector: &mut [u8], index: usize, partition_type: u8, start: u32, count: u32) {
+        let entry = &mut sector[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&count.to_le_bytes());
+    }
+
+    fn boot_record() -> Vec<u8> {
+        let mut sector = vec![0; SECTOR_SIZE];
+        sector[510..].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+        sector
+    }
+
+    fn layout(partitions: &[Arc<dyn BlockDevice>]) -> Vec<(&str, u64)> {
+        partitions.iter().map(|partition| (partition.name(), partition.block_count())).collect()
+    }
+
+    #[test_case]
+    fn crc32_is_correct() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test_case]
+    fn mbr_partitions_are_found() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("hdz", SECTOR_SIZE, 4096));
+        let mut mbr = boot_record();
+        mbr_entry(&mut mbr, 0, 0x83, 2048, 1024);
+        mbr_entry(&mut mbr, 1, 0x0F, 3072, 1024);
+        // Past the end of the disk
+        mbr_entry(&mut mbr, 3, 0x0C, 4000, 1000);
+        disk.write_blocks(0, &mbr).unwrap();
+        // Two logical partitions, 1 block after their boot record
+        let mut ebr = boot_record();
+        mbr_entry(&mut ebr, 0, 0x83, 1, 99);
+        mbr_entry(&mut ebr, 1, 0x05, 100, 200);
+        disk.write_blocks(3072, &ebr).unwrap();
+        let mut ebr = boot_record();
+        mbr_entry(&mut ebr, 0, 0x83, 1, 199);
+        disk.write_blocks(3172, &ebr).unwrap();
+
+        let PartitionTable { scheme, partitions } = scan(&disk).unwrap().unwrap();
+        assert_eq!(scheme, Scheme::Mbr);
+        assert_eq!(layout(&partitions), [("hdz1", 1024), ("hdz5", 99), ("hdz6", 199)]);
+
+        // Partitions are windows on the disk
+        partitions[2].write_blocks(0, &[6; SECTOR_SIZE]).unwrap();
+        let mut sector = vec![0; SECTOR_SIZE];
+        disk.read_blocks(3173, &mut sector).unwrap();
+        assert_eq!(sector, [6; SECTOR_SIZE]);
+        assert_eq!(partitions[1].read_blocks(99, &mut sector), Err(BlockError::OutOfRange));
+
+        // A boot sector with code where the entries would be isn't a table
+        mbr[MBR_ENTRIES_OFFSET] = 0x31;
+        disk.write_blocks(0, &mbr).unwrap();
+        assert!(scan(&disk).unwrap().is_none());
+    }
+
+    /// Writes a GPT header and its entries, starting at `header_block` and
+    /// `entries_block`.
+    fn write_gpt(disk: &Arc<dyn BlockDevice>, header_block: u64, entries_block: u64, entries: &[u8]) {
+        let last = disk.block_count() - 1;
+        let mut header = vec![0; SECTOR_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&header_block.to_le_bytes());
+        header[32..40].copy_from_slice(&(if header_block == 1 { last } else { 1 }).to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&(last - 33).to_le_bytes());
+        header[72..80].copy_from_slice(&entries_block.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let checksum = crc32(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&checksum.to_le_bytes());
+        disk.write_blocks(header_block, &header).unwrap();
+        disk.write_blocks(entries_block, entries).unwrap();
+    }
+
+    #[test_case]
+    fn gpt_partitions_are_found() {
+        static IMAGE: &[u8] = include_bytes!("../../user/bin/test.ext2");
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("vdz", SECTOR_SIZE, 4096));
+        let mut mbr = boot_record();
+        mbr_entry(&mut mbr, 0, TYPE_GPT_PROTECTIVE, 1, 4095);
+        disk.write_blocks(0, &mbr).unwrap();
+        let mut entries = vec![0; 128 * 128];
+        for &(slot, first, last) in &[(0, 64, 127), (2, 2048, 2048 + IMAGE.len() as u64 / 512 - 1)] {
+            let entry = &mut entries[slot * 128..(slot + 1) * 128];
+            // The Linux file system type, as stored
+            entry[..16].copy_from_slice(&[
+                0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+            ]);
+            entry[32..40].copy_from_slice(&(first as u64).to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        write_gpt(&disk, 1, 2, &entries);
+        write_gpt(&disk, 4095, 4095 - 32, &entries);
+        disk.write_blocks(2048, IMAGE).unwrap();
+
+        let PartitionTable { scheme, partitions } = scan(&disk).unwrap().unwrap();
+        assert_eq!(scheme, Scheme::Gpt);
+        assert_eq!(layout(&partitions), [("vdz1", 64), ("vdz3", IMAGE.len() as u64 / 512)]);
+        let ext2 = Ext2Fs::new(partitions[1].clone()).unwrap();
+        assert!(ext2.root().lookup("bin").unwrap().lookup("hello").is_ok());
+
+        // The backup takes over when the primary table is damaged
+        disk.write_blocks(2, &[0xFF; SECTOR_SIZE]).unwrap();
+        assert_eq!(scan(&disk).unwrap().unwrap().partitions.len(), 2);
+        disk.write_blocks(4095 - 32, &[0xFF; SECTOR_SIZE]).unwrap();
+        assert!(scan(&disk).unwrap().is_none());
+    }
+}
@@ -0,0 +1,60 @@
+//! Disks kept in memory, for the tests of the file systems.
+//!
+//! Only the blocks written so far take memory, the others read as zeroes, so
+//! disks can be much larger than the heap as long as they are mostly empty.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+
+use spin::Mutex;
+
+use super::{check_range, BlockDevice, BlockError};
+
+pub struct RamDisk {
+    name: String,
+    block_size: usize,
+    block_count: u64,
+    blocks: Mutex<BTreeMap<u64, Box<[u8]>>>,
+}
+
+impl RamDisk {
+    pub fn new(name: &str, block_size: usize, block_count: u64) -> RamDisk {
+        RamDisk { name: name.to_string(), block_size, block_count, blocks: Mutex::new(BTreeMap::new()) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, block, buffer.len())?;
+        let blocks = self.blocks.lock();
+        for (i, chunk) in buffer.chunks_mut(self.block_size).enumerate() {
+            match blocks.get(&(block + i as u64)) {
+                Some(data) => chunk.copy_from_slice(data),
+                None => chunk.iter_mut().for_each(|byte| *byte = 0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_range(self, block, buffer.len())?;
+        let mut blocks = self.blocks.lock();
+        for (i, chunk) in buffer.chunks(self.block_size).enumerate() {
+            blocks.insert(block + i as u64, chunk.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+//! Virtio block devices, e.g. QEMU's `-drive if=virtio`.
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2390002
+//!
+//! Every request is a chain of a header, the data and a status byte. Several
+//! tasks can have requests in flight: each one sleeps until the interrupt
+//! handler sees its request in the used ring.
+
+use core::mem::size_of;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::inline_asm::are_interrupts_enabled;
+use crate::memory::{VirtAddr, FRAME_SIZE};
+use crate::pci;
+use crate::pci::{PciDevice, PciMatch};
+use crate::sync::{InterruptGuard, IrqMutex};
+use crate::task;
+use crate::task::TaskId;
+use crate::virtio::{Buffer, InterruptHandler, Transport, VirtQueue, VirtioError, DEVICE_TYPE_BLOCK,
+                    MAX_QUEUE_SIZE, MODERN_DEVICE_ID_BASE, TRANSITIONAL_DEVICE_ID_BASE, VENDOR_ID};
+use crate::virtio;
+
+use super::{check_range, format_size, BlockDevice, BlockError};
+
+/// Requests always address 512-byte sectors
+pub const SECTOR_SIZE: usize = 512;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+/// Number of sectors, in the device configuration
+const CONFIG_CAPACITY: u16 = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+
+/// Largest transfer of a single request, to bound the number of descriptors.
+/// Small queues lower it further.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+#[repr(C)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct VirtioBlock {
+    name: String,
+    transport: Transport,
+    capacity: u64,
+    read_only: bool,
+    can_flush: bool,
+    /// Largest transfer whose chain of descriptors fits in the queue
+    max_request_size: usize,
+    /// Length of the longest chain the queue takes
+    max_descriptors: usize,
+    /// Without an interrupt line, requests are polled for
+    has_interrupt: bool,
+    queue: IrqMutex<RequestQueue>,
+}
+
+/// Shared with the interrupt handler.
+struct RequestQueue {
+    queue: VirtQueue,
+    /// Heads of the requests the device is done with
+    completed: BTreeSet<u16>,
+    /// Tasks sleeping until their request completes
+    waiters: BTreeMap<u16, TaskId>,
+}
+
+impl RequestQueue {
+    fn collect_used(&mut self) {
+        while let Some((head, _)) = self.queue.pop_used() {
+            self.completed.insert(head);
+            if let Some(waiter) = self.waiters.remove(&head) {
+                task::wake(waiter);
+            }
+        }
+    }
+}
+
+impl VirtioBlock {
+    fn new(name: String, device: &PciDevice) -> Result<VirtioBlock, VirtioError> {
+        let transport = Transport::new(device)?;
+        let features = transport.begin_init(FEATURE_READ_ONLY | FEATURE_FLUSH)?;
+        let queue = transport.setup_queue(0, MAX_QUEUE_SIZE)?;
+        // The header lives on the stack and may cross a page boundary, so it
+        // takes up to two descriptors and the status one. A transfer of n
+        // pages may touch n + 1 pages when it doesn't start at a page boundary
+        let data_descriptors = (queue.size() as usize).saturating_sub(3);
+        if data_descriptors < 2 {
+            return Err(VirtioError::BadQueueSize(queue.size()));
+        }
+        let max_request_size = MAX_REQUEST_SIZE.min((data_descriptors - 1) * FRAME_SIZE as usize);
+        let max_descriptors = queue.size() as usize;
+        let capacity = transport.read_config_u64(CONFIG_CAPACITY);
+        Ok(VirtioBlock {
+            name,
+            transport,
+            capacity,
+            read_only: features & FEATURE_READ_ONLY != 0,
+            can_flush: features & FEATURE_FLUSH != 0,
+            max_request_size,
+            max_descriptors,
+            has_interrupt: virtio::has_interrupt(device),
+            queue: IrqMutex::new(RequestQueue { queue, completed: BTreeSet::new(), waiters: BTreeMap::new() }),
+        })
+    }
+
+    /// Sends a request and waits for its completion. `data` is read or
+    /// written by the device depending on the request type, and spans at
+    /// most `max_request_size` bytes so that the request fits in the queue.
+    fn request(&self, request_type: u32, sector: u64, data: VirtAddr, length: usize) -> Result<(), BlockError> {
+        let header = RequestHeader { request_type, reserved: 0, sector };
+        let mut status: u8 = 0xFF;
+        let mut buffers = Vec::new();
+        Buffer::from_memory(VirtAddr(&header as *const _ as u64), size_of::<RequestHeader>(), false, &mut buffers);
+        Buffer::from_memory(data, length, request_type == REQUEST_IN, &mut buffers);
+        Buffer::from_memory(VirtAddr(&mut status as *mut _ as u64), 1, true, &mut buffers);
+        // Waiting for other requests to complete wouldn't make room for it
+        if buffers.len() > self.max_descriptors {
+            return Err(BlockError::RequestTooLarge);
+        }
+
+        let head = loop {
+            let mut queue = self.queue.lock();
+            if queue.queue.free_descriptors() as usize >= buffers.len() {
+                // The header, data and status live until the request completes,
+                // this function waits for it
+                let head = unsafe { queue.queue.add(&buffers) }.expect("Not enough descriptors");
+                self.transport.notify(&queue.queue);
+                break head;
+            }
+            drop(queue);
+            task::yield_now();
+        };
+        self.wait(head);
+
+        match unsafe { core::ptr::read_volatile(&status) } {
+            STATUS_OK => Ok(()),
+            status => Err(BlockError::Device(status)),
+        }
+    }
+
+    /// Waits for the request starting at `head` and frees its descriptors.
+    fn wait(&self, head: u16) {
+        // The idle task can't block, and the interrupt can't arrive with
+        // interrupts disabled
+        let poll = !self.has_interrupt || task::is_idle() || !are_interrupts_enabled();
+        loop {
+            // The interrupt must not come between the check and `block`, or
+            // the wake up would be lost
+            let _guard = InterruptGuard::new();
+            {
+                let mut queue = self.queue.lock();
+                queue.collect_used();
+                if queue.completed.remove(&head) {
+                    queue.queue.free(head);
+                    return;
+                }
+                if !poll {
+                    queue.waiters.insert(head, task::current_id());
+                }
+            }
+            if poll {
+                core::sync::atomic::spin_loop_hint();
+            } else {
+                task::block();
+            }
+        }
+    }
+
+    fn transfer(&self, request_type: u32, block: u64, address: VirtAddr, length: usize) -> Result<(), BlockError> {
+        check_range(self, block, length)?;
+        let mut done = 0;
+        while done < length {
+            let count = (length - done).min(self.max_request_size);
+            let sector = block + (done / SECTOR_SIZE) as u64;
+            self.request(request_type, sector, VirtAddr(address.as_u64() + done as u64), count)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.transfer(REQUEST_IN, block, VirtAddr(buffer.as_mut_ptr() as u64), buffer.len())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.transfer(REQUEST_OUT, block, VirtAddr(buffer.as_ptr() as u64), buffer.len())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            // Writes go straight to the medium
+            return Ok(());
+        }
+        self.request(REQUEST_FLUSH, 0, VirtAddr(0), 0)
+    }
+}
+
+impl InterruptHandler for VirtioBlock {
+    fn handle_interrupt(&self) {
+        if self.transport.acknowledge_interrupt() & 1 != 0 {
+            self.queue.lock().collect_used();
+        }
+    }
+}
+
+/// Sets up the virtio block devices and registers them as `vda`, `vdb`...
+pub fn init() {
+    let transitional = PciMatch::device(VENDOR_ID, TRANSITIONAL_DEVICE_ID_BASE + DEVICE_TYPE_BLOCK - 1);
+    let modern = PciMatch::device(VENDOR_ID, MODERN_DEVICE_ID_BASE + DEVICE_TYPE_BLOCK);
+    for (i, device) in pci::find(&transitional).chain(pci::find(&modern)).enumerate() {
+        let name = format!("vd{}", (b'a' + i as u8) as char);
+        let disk = match VirtioBlock::new(name, device) {
+            Ok(disk) => Arc::new(disk),
+            Err(error) => {
+                println!("virtio-blk: can't set up {}: {:?}", device.address, error);
+                continue;
+            }
+        };
+        if disk.has_interrupt {
+            virtio::register_interrupt(device, disk.clone());
+        }
+        disk.transport.finish_init();
+        println!("virtio-blk: /dev/{} at {}, {} ({}, {} interface)", disk.name, device.address,
+                 format_size(disk.capacity * SECTOR_SIZE as u64),
+                 if disk.read_only { "read-only" } else { "read-write" },
+                 if disk.transport.is_modern() { "modern" } else { "legacy" });
+        super::register(disk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use alloc::vec;
+
+    use crate::block;
+
+    use super::*;
+
+    /// Writes a pattern and reads it back. The test runner attaches a scratch
+    /// image, with writes kept in a temporary snapshot.
+    fn round_trip() {
+        let disk = block::find("vda").expect("no virtio disk");
+        let mut sectors = vec![0; 3 * SECTOR_SIZE];
+        for (i, byte) in sectors.iter_mut().enumerate() {
+            *byte = i as u8 ^ 0x5A;
+        }
+        disk.write_blocks(1, &sectors).unwrap();
+        disk.flush().unwrap();
+        let mut read = vec![0; 3 * SECTOR_SIZE];
+        disk.read_blocks(1, &mut read).unwrap();
+        assert_eq!(read, sectors);
+
+        // Spans several requests and pages
+        let mut large = vec![0; 2 * MAX_REQUEST_SIZE + SECTOR_SIZE];
+        for (i, byte) in large.iter_mut().enumerate() {
+            *byte = (i / SECTOR_SIZE) as u8;
+        }
+        let start = disk.block_count() - (large.len() / SECTOR_SIZE) as u64;
+        disk.write_blocks(start, &large).unwrap();
+        let mut read = vec![0; large.len()];
+        disk.read_blocks(start, &mut read).unwrap();
+        assert!(read == large);
+        assert_eq!(disk.read_blocks(disk.block_count(), &mut sectors), Err(BlockError::OutOfRange));
+    }
+
+    #[test_case]
+    fn virtio_disk_is_readable() {
+        // Polls for the completions, tests run on the idle task
+        round_trip();
+    }
+
+    #[test_case]
+    fn requests_complete_with_interrupts() {
+        static DONE: AtomicBool = AtomicBool::new(false);
+        // A task other than the idle one sleeps until the interrupt
+        task::spawn("virtio test", || {
+            round_trip();
+            DONE.store(true, Ordering::SeqCst);
+        });
+        while !DONE.load(Ordering::SeqCst) {
+            task::yield_now();
+        }
+    }
+}
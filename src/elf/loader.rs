@@ -0,0 +1,203 @@
+//! Loads executables into a fresh address space and starts them.
+//!
+//! The initial stack follows the System V x86_64 ABI: from the stack pointer
+//! up, argc, the argv pointers, a null pointer, the envp pointers, a null
+//! pointer, the auxiliary vector and finally the strings.
+
+use core::mem::size_of;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::address_space::AddressSpace;
+use crate::fs;
+use crate::fs::{FileTable, FsError, OpenFlags};
+use crate::memory::{FRAME_SIZE, VirtAddr};
+use crate::paging::{MapError, PageTableFlags};
+use crate::task;
+use crate::task::TaskId;
+use crate::task::user::{is_user_range, USER_SPACE_END};
+
+use super::{Elf, ElfError, PF_W, PF_X, ProgramHeader, PT_LOAD};
+
+const USER_STACK_TOP: u64 = USER_SPACE_END;
+const USER_STACK_SIZE: u64 = 16 * FRAME_SIZE;
+
+// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    Elf(ElfError),
+    Map(MapError),
+    /// A segment or the entry point is outside of user space
+    NotInUserSpace,
+    /// The arguments and environment don't fit on the stack
+    ArgumentsTooLarge,
+    /// The standard input or output couldn't be opened
+    Fs(FsError),
+}
+
+impl From<ElfError> for ExecError {
+    fn from(error: ElfError) -> ExecError {
+        ExecError::Elf(error)
+    }
+}
+
+impl From<FsError> for ExecError {
+    fn from(error: FsError) -> ExecError {
+        ExecError::Fs(error)
+    }
+}
+
+impl From<MapError> for ExecError {
+    fn from(error: MapError) -> ExecError {
+        ExecError::Map(error)
+    }
+}
+
+/// Loads `image` in a new address space and runs it as a new task, with the
+/// keyboard as standard input and the screen as standard output and error.
+pub fn exec(name: &'static str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<TaskId, ExecError> {
+    let elf = Elf::parse(image)?;
+    if !is_user_range(elf.entry(), 1) {
+        return Err(ExecError::NotInUserSpace);
+    }
+    let mut address_space = AddressSpace::new()?;
+    load_segments(&elf, &mut address_space)?;
+    let stack = setup_stack(&elf, &mut address_space, argv, envp)?;
+    let mut files = FileTable::new();
+    files.insert(fs::open("/dev/keyboard", OpenFlags::READ)?)?;
+    let screen = fs::open("/dev/vga", OpenFlags::WRITE)?;
+    files.insert(screen.clone())?;
+    files.insert(screen)?;
+    Ok(task::spawn_process(name, address_space, files, VirtAddr(elf.entry()), stack))
+}
+
+fn segment_flags(segment: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if segment.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Maps the `PT_LOAD` segments and copies their contents. The rest of each
+/// segment, like .bss, is left zeroed.
+fn load_segments(elf: &Elf, address_space: &mut AddressSpace) -> Result<(), ExecError> {
+    // Segments can share a page, which then gets the union of their rights
+    let mut pages: BTreeMap<u64, PageTableFlags> = BTreeMap::new();
+    for segment in elf.program_headers().filter(|segment| segment.segment_type == PT_LOAD) {
+        if !is_user_range(segment.virtual_address, segment.memory_size) {
+            return Err(ExecError::NotInUserSpace);
+        }
+        let flags = segment_flags(&segment);
+        let start = segment.virtual_address & !(FRAME_SIZE - 1);
+        let end = segment.virtual_address + segment.memory_size;
+        for page in (start..end).step_by(FRAME_SIZE as usize) {
+            let merged = match pages.get(&page) {
+                Some(&existing) => {
+                    let mut merged = PageTableFlags::empty();
+                    if existing.contains(PageTableFlags::WRITABLE) || flags.contains(PageTableFlags::WRITABLE) {
+                        merged |= PageTableFlags::WRITABLE;
+                    }
+                    if existing.contains(PageTableFlags::NO_EXECUTE) && flags.contains(PageTableFlags::NO_EXECUTE) {
+                        merged |= PageTableFlags::NO_EXECUTE;
+                    }
+                    merged
+                }
+                None => flags,
+            };
+            pages.insert(page, merged);
+        }
+    }
+
+    for (&page, &flags) in pages.iter() {
+        address_space.map_user(VirtAddr(page), FRAME_SIZE, flags)?;
+    }
+    for segment in elf.program_headers().filter(|segment| segment.segment_type == PT_LOAD) {
+        address_space.write(VirtAddr(segment.virtual_address), elf.segment_data(&segment))
+            .expect("Segment was just mapped");
+    }
+    Ok(())
+}
+
+/// Maps the user stack and fills it in. Returns the initial stack pointer.
+fn setup_stack(elf: &Elf, address_space: &mut AddressSpace, argv: &[&str], envp: &[&str]) -> Result<VirtAddr, ExecError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    address_space.map_user(VirtAddr(stack_bottom), USER_STACK_SIZE,
+                           PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+
+    // Strings go at the very top, NUL terminated
+    let strings_size: usize = argv.iter().chain(envp.iter()).map(|string| string.len() + 1).sum();
+    let strings_start = USER_STACK_TOP - strings_size as u64;
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut pointers = Vec::with_capacity(argv.len() + envp.len());
+    for string in argv.iter().chain(envp.iter()) {
+        pointers.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+    let (argv_pointers, envp_pointers) = pointers.split_at(argv.len());
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(argv_pointers);
+    words.push(0);
+    words.extend_from_slice(envp_pointers);
+    words.push(0);
+    if let Some(program_headers) = elf.program_headers_address() {
+        words.extend_from_slice(&[AT_PHDR, program_headers]);
+    }
+    words.extend_from_slice(&[
+        AT_PHENT, size_of::<ProgramHeader>() as u64,
+        AT_PHNUM, elf.header().program_header_count as u64,
+        AT_PAGESZ, FRAME_SIZE,
+        AT_ENTRY, elf.entry(),
+        AT_NULL, 0,
+    ]);
+
+    // The ABI wants the stack pointer 16-byte aligned on entry
+    let words_size = (words.len() * size_of::<u64>()) as u64;
+    let stack_pointer = (strings_start - words_size) & !0xF;
+    if stack_pointer < stack_bottom {
+        return Err(ExecError::ArgumentsTooLarge);
+    }
+
+    let mut image = Vec::with_capacity((USER_STACK_TOP - stack_pointer) as usize);
+    for word in words.iter() {
+        image.extend_from_slice(&word.to_le_bytes());
+    }
+    image.resize((strings_start - stack_pointer) as usize, 0);
+    image.extend_from_slice(&strings);
+    address_space.write(VirtAddr(stack_pointer), &image).expect("Stack was just mapped");
+    Ok(VirtAddr(stack_pointer))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::FRAME_ALLOCATOR;
+    use crate::programs::HELLO;
+
+    use super::*;
+
+    #[test_case]
+    fn embedded_program_runs_to_completion() {
+        let free_frames = FRAME_ALLOCATOR.lock().free_frames();
+        let id = exec("hello", HELLO, &["hello", "from", "a test"], &["HOME=/"]).expect("exec failed");
+        while task::exists(id) {
+            task::yield_now();
+            task::reap();
+        }
+        // The address space went away with the task, once reaped
+        assert_eq!(FRAME_ALLOCATOR.lock().free_frames(), free_frames);
+    }
+}
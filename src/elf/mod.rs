@@ -0,0 +1,194 @@
+//! ELF64 executables.
+//! https://wiki.osdev.org/ELF
+//! https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+//!
+//! Only statically linked x86_64 executables (`ET_EXEC`) are supported.
+
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+pub use self::loader::{exec, ExecError};
+
+mod loader;
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+
+// Segment types
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+
+// Segment flags
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub section_header_offset: u64,
+    pub flags: u32,
+    pub header_size: u16,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+    pub section_header_size: u16,
+    pub section_header_count: u16,
+    pub section_names_index: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub physical_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    Not64Bit,
+    NotLittleEndian,
+    UnsupportedVersion,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    /// A segment's data lies outside of the file, or its size in memory is
+    /// smaller than in the file
+    BadSegment,
+    NoLoadableSegment,
+}
+
+/// A validated ELF image.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < size_of::<ElfHeader>() {
+            return Err(ElfError::TooShort);
+        }
+        let header = unsafe { read_unaligned(data.as_ptr() as *const ElfHeader) };
+        if header.ident[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != CLASS_64 {
+            return Err(ElfError::Not64Bit);
+        }
+        if header.ident[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if header.ident[6] != VERSION_CURRENT || header.version != VERSION_CURRENT as u32 {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        if header.elf_type != TYPE_EXECUTABLE {
+            return Err(ElfError::NotExecutable);
+        }
+        if header.machine != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if header.program_header_size as usize != size_of::<ProgramHeader>() {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let table_size = header.program_header_count as u64 * size_of::<ProgramHeader>() as u64;
+        match header.program_header_offset.checked_add(table_size) {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::BadProgramHeaders),
+        }
+
+        let elf = Elf { data, header };
+        let mut loadable = false;
+        for segment in elf.program_headers().filter(|segment| segment.segment_type == PT_LOAD) {
+            let in_file = segment.offset.checked_add(segment.file_size)
+                .map_or(false, |end| end <= data.len() as u64);
+            if !in_file || segment.file_size > segment.memory_size
+                || segment.virtual_address.checked_add(segment.memory_size).is_none() {
+                return Err(ElfError::BadSegment);
+            }
+            loadable = true;
+        }
+        if !loadable {
+            return Err(ElfError::NoLoadableSegment);
+        }
+        Ok(elf)
+    }
+
+    pub fn header(&self) -> &ElfHeader {
+        &self.header
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item=ProgramHeader> + 'a {
+        let data = self.data;
+        let offset = self.header.program_header_offset as usize;
+        (0..self.header.program_header_count as usize).map(move |i| unsafe {
+            let address = data.as_ptr().add(offset + i * size_of::<ProgramHeader>());
+            read_unaligned(address as *const ProgramHeader)
+        })
+    }
+
+    /// File contents of a segment.
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8] {
+        &self.data[segment.offset as usize..(segment.offset + segment.file_size) as usize]
+    }
+
+    /// Virtual address of the program headers once loaded, if they are.
+    pub fn program_headers_address(&self) -> Option<u64> {
+        if let Some(phdr) = self.program_headers().find(|segment| segment.segment_type == PT_PHDR) {
+            return Some(phdr.virtual_address);
+        }
+        let offset = self.header.program_header_offset;
+        self.program_headers()
+            .find(|segment| segment.segment_type == PT_LOAD
+                && offset >= segment.offset && offset < segment.offset + segment.file_size)
+            .map(|segment| segment.virtual_address + offset - segment.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::programs::HELLO;
+
+    use super::*;
+
+    #[test_case]
+    fn embedded_program_parses() {
+        let elf = Elf::parse(HELLO).expect("invalid ELF");
+        assert!(elf.program_headers().any(|segment| segment.segment_type == PT_LOAD
+            && segment.flags & PF_X != 0));
+        assert!(elf.program_headers_address().is_some());
+    }
+
+    #[test_case]
+    fn bad_images_are_rejected() {
+        assert_eq!(Elf::parse(&HELLO[..16]).err(), Some(ElfError::TooShort));
+        let mut image = [0u8; 64];
+        image.copy_from_slice(&HELLO[..64]);
+        image[0] = 0;
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadMagic));
+        image[0] = 0x7F;
+        // The program headers are past the end of the truncated image
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadProgramHeaders));
+    }
+}
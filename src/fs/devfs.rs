@@ -0,0 +1,199 @@
+//! Device files, mounted on `/dev`.
+//!
+//! Drivers publish their devices with `register`. The console devices are
+//! registered when the file system is created.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::{Mutex, Once};
+
+use crate::ps2;
+use crate::serial;
+use crate::serial::Serial;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata, Result};
+
+static DEVFS: Once<Arc<DevFs>> = Once::new();
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+pub struct DevFs {
+    root: Arc<DevDirectory>,
+}
+
+impl DevFs {
+    /// The device file system is a singleton, shared by all its mounts.
+    pub fn new() -> Arc<DevFs> {
+        DEVFS.call_once(|| {
+            let devfs = DevFs {
+                root: Arc::new(DevDirectory { inode: next_inode(), devices: Mutex::new(BTreeMap::new()) }),
+            };
+            let mut devices = devfs.root.devices.lock();
+            devices.insert("null".to_string(), Arc::new(NullDevice { inode: next_inode() }) as Arc<dyn Inode>);
+            devices.insert("vga".to_string(), Arc::new(VgaDevice { inode: next_inode() }));
+            devices.insert("keyboard".to_string(), Arc::new(KeyboardDevice { inode: next_inode() }));
+            for serial in serial::ports() {
+                devices.insert(serial.name().to_string(), Arc::new(SerialDevice { inode: next_inode(), port: serial.0 }));
+            }
+            drop(devices);
+            Arc::new(devfs)
+        }).clone()
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Inode number for a new device.
+pub fn next_inode() -> u64 {
+    NEXT_INODE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Publishes a device as `/dev/<name>`.
+pub fn register(name: &str, device: Arc<dyn Inode>) -> Result<()> {
+    let devfs = DevFs::new();
+    let mut devices = devfs.root.devices.lock();
+    if devices.contains_key(name) {
+        return Err(FsError::AlreadyExists);
+    }
+    devices.insert(name.to_string(), device);
+    Ok(())
+}
+
+pub fn unregister(name: &str) -> Result<()> {
+    DevFs::new().root.devices.lock().remove(name).map(|_| ()).ok_or(FsError::NotFound)
+}
+
+struct DevDirectory {
+    inode: u64,
+    devices: Mutex<BTreeMap<String, Arc<dyn Inode>>>,
+}
+
+impl Inode for DevDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata { inode: self.inode, file_type: FileType::Directory, size: 0, mode: 0o755, links: 2 }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.devices.lock().get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Ok(self.devices.lock().iter()
+            .map(|(name, device)| {
+                let metadata = device.metadata();
+                DirEntry { name: name.clone(), inode: metadata.inode, file_type: metadata.file_type }
+            })
+            .collect())
+    }
+}
+
+fn char_device_metadata(inode: u64, mode: u16) -> Metadata {
+    Metadata { inode, file_type: FileType::CharDevice, size: 0, mode, links: 1 }
+}
+
+/// Discards writes, reads nothing.
+struct NullDevice {
+    inode: u64,
+}
+
+impl Inode for NullDevice {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(self.inode, 0o666)
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize> {
+        Ok(buffer.len())
+    }
+}
+
+/// The first virtual terminal of the VGA text console, mirrored on COM1.
+/// Output only.
+struct VgaDevice {
+    inode: u64,
+}
+
+impl Inode for VgaDevice {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(self.inode, 0o620)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize> {
+        let text = String::from_utf8_lossy(buffer);
+        vga_print!("{}", text);
+        print!("{}", text);
+        Ok(buffer.len())
+    }
+}
+
+/// Text typed on the first virtual terminal. Reads block until something is
+/// typed.
+struct KeyboardDevice {
+    inode: u64,
+}
+
+impl Inode for KeyboardDevice {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(self.inode, 0o440)
+    }
+
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        Ok(ps2::read_input(0, buffer))
+    }
+}
+
+/// A serial port. Reads block until something is received.
+struct SerialDevice {
+    inode: u64,
+    port: u16,
+}
+
+impl Inode for SerialDevice {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(self.inode, 0o660)
+    }
+
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        Ok(Serial(self.port).read(buffer))
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize> {
+        Serial(self.port).write(buffer);
+        Ok(buffer.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::{open, OpenFlags};
+
+    #[test_case]
+    fn null_device_swallows_writes() {
+        let file = open("/dev/null", OpenFlags::READ | OpenFlags::WRITE).unwrap();
+        assert_eq!(file.write(b"krill"), Ok(5));
+        assert_eq!(file.read(&mut [0; 8]), Ok(0));
+    }
+}
@@ -0,0 +1,156 @@
+//! Directory entries, records chained by their length within each block.
+//! https://www.nongnu.org/ext2-doc/ext2.html#linked-directories
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::{FileType, FsError, Result};
+
+use super::{read_u16, read_u32};
+
+pub const TYPE_UNKNOWN: u8 = 0;
+pub const TYPE_REGULAR: u8 = 1;
+pub const TYPE_DIRECTORY: u8 = 2;
+pub const TYPE_CHAR_DEVICE: u8 = 3;
+pub const TYPE_BLOCK_DEVICE: u8 = 4;
+pub const TYPE_SYMLINK: u8 = 7;
+
+const HEADER_SIZE: usize = 8;
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// A record of a directory. Records with no inode are free space.
+#[derive(Debug)]
+pub struct Record {
+    pub inode: u32,
+    pub name: String,
+    pub file_type: u8,
+    /// Offset in the directory
+    pub offset: usize,
+    pub length: usize,
+    /// The record before in the same block, which absorbs this one when it is
+    /// removed
+    pub previous: Option<usize>,
+}
+
+impl Record {
+    /// Space taken by the name of the record, the rest can hold new records.
+    fn used_length(&self) -> usize {
+        if self.inode == 0 { 0 } else { record_length(self.name.len()) }
+    }
+}
+
+/// Space needed by a record for a name of `length` bytes.
+pub fn record_length(length: usize) -> usize {
+    (HEADER_SIZE + length + 3) & !3
+}
+
+/// Parses the records of a directory, free ones included.
+pub fn parse(data: &[u8], block_size: usize) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (i, block) in data.chunks(block_size).enumerate() {
+        let mut offset = 0;
+        let mut previous = None;
+        while offset < block.len() {
+            if block.len() - offset < HEADER_SIZE {
+                return Err(FsError::Io);
+            }
+            let length = read_u16(block, offset + 4) as usize;
+            let name_length = block[offset + 6] as usize;
+            // Records are aligned and can't cross blocks
+            if length < HEADER_SIZE || length % 4 != 0 || offset + length > block.len()
+                || HEADER_SIZE + name_length > length {
+                return Err(FsError::Io);
+            }
+            let name = &block[offset + HEADER_SIZE..offset + HEADER_SIZE + name_length];
+            let record_offset = i * block_size + offset;
+            records.push(Record {
+                inode: read_u32(block, offset),
+                name: String::from_utf8_lossy(name).into_owned(),
+                file_type: block[offset + 7],
+                offset: record_offset,
+                length,
+                previous,
+            });
+            previous = Some(record_offset);
+            offset += length;
+        }
+    }
+    Ok(records)
+}
+
+/// Finds room for a record of `length` bytes. Returns the record to split and
+/// where the new record starts in it.
+pub fn find_space(records: &[Record], length: usize) -> Option<(&Record, usize)> {
+    records.iter()
+        .find(|record| record.length - record.used_length() >= length)
+        .map(|record| (record, record.used_length()))
+}
+
+/// Encodes a record, without the padding up to `length`.
+pub fn encode(inode: u32, name: &str, file_type: u8, length: usize) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + name.len());
+    record.extend_from_slice(&inode.to_le_bytes());
+    record.extend_from_slice(&(length as u16).to_le_bytes());
+    record.push(name.len() as u8);
+    record.push(file_type);
+    record.extend_from_slice(name.as_bytes());
+    record
+}
+
+/// The type of a directory entry for a VFS file type.
+pub fn file_type_code(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Regular => TYPE_REGULAR,
+        FileType::Directory => TYPE_DIRECTORY,
+        FileType::Symlink => TYPE_SYMLINK,
+        FileType::CharDevice => TYPE_CHAR_DEVICE,
+        FileType::BlockDevice => TYPE_BLOCK_DEVICE,
+    }
+}
+
+/// The VFS file type of a directory entry type. `None` for the types the VFS
+/// lacks, and for unknown ones.
+pub fn file_type(code: u8) -> Option<FileType> {
+    match code {
+        TYPE_REGULAR => Some(FileType::Regular),
+        TYPE_DIRECTORY => Some(FileType::Directory),
+        TYPE_SYMLINK => Some(FileType::Symlink),
+        TYPE_CHAR_DEVICE => Some(FileType::CharDevice),
+        TYPE_BLOCK_DEVICE => Some(FileType::BlockDevice),
+        _ => None,
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH || name.contains('\0') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test_case]
+    fn records_are_chained() {
+        let mut block = vec![0; 64];
+        let dot = encode(2, ".", TYPE_DIRECTORY, 12);
+        let file = encode(12, "file", TYPE_REGULAR, 52);
+        block[..dot.len()].copy_from_slice(&dot);
+        block[12..12 + file.len()].copy_from_slice(&file);
+
+        let records = parse(&block, 32).err();
+        // The second record crosses the first block
+        assert_eq!(records, Some(FsError::Io));
+        let records = parse(&block, 64).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[1].inode, records[1].name.as_str(), records[1].previous), (12, "file", Some(0)));
+        // "file" takes 12 bytes of its 52
+        let (record, start) = find_space(&records, record_length(20)).unwrap();
+        assert_eq!((record.offset, start), (12, 12));
+        assert!(find_space(&records, 44).is_none());
+    }
+}
@@ -0,0 +1,296 @@
+//! On-disk inodes, and the mapping of their blocks through the direct,
+//! indirect, doubly and triply indirect block pointers.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fs::{FsError, Result};
+
+use super::{read_u16, read_u32, write_u16, write_u32, LockedVolume, TIMESTAMP};
+
+pub const S_IFMT: u16 = 0xF000;
+pub const S_IFLNK: u16 = 0xA000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFBLK: u16 = 0x6000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFCHR: u16 = 0x2000;
+
+/// The directory has a hash tree index, which this driver doesn't maintain
+pub const FLAG_INDEX: u32 = 0x1000;
+
+/// Symbolic links shorter than this are stored in the block pointers
+pub const FAST_SYMLINK_LENGTH: usize = 60;
+
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_POINTER: usize = 12;
+const DOUBLE_POINTER: usize = 13;
+const TRIPLE_POINTER: usize = 14;
+
+const BLOCK_POINTERS_OFFSET: usize = 40;
+/// Size of the fields past the first 128 bytes that Linux fills in
+const EXTRA_SIZE: u16 = 32;
+
+/// A copy of an inode as stored on the disk.
+pub struct DiskInode {
+    data: Vec<u8>,
+}
+
+impl DiskInode {
+    pub fn new(data: Vec<u8>) -> DiskInode {
+        DiskInode { data }
+    }
+
+    /// A fresh inode, to be written over a free one.
+    pub fn create(size: usize, mode: u16, links: u16) -> DiskInode {
+        let mut inode = DiskInode { data: vec![0; size] };
+        write_u16(&mut inode.data, 0, mode);
+        write_u16(&mut inode.data, 26, links);
+        for &offset in &[8, 12, 16] {
+            write_u32(&mut inode.data, offset, TIMESTAMP);
+        }
+        if size > 128 {
+            write_u16(&mut inode.data, 128, EXTRA_SIZE);
+        }
+        inode
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn mode(&self) -> u16 {
+        read_u16(&self.data, 0)
+    }
+
+    pub fn file_type(&self) -> u16 {
+        self.mode() & S_IFMT
+    }
+
+    /// Regular files have the upper 32 bits of their size in what used to be
+    /// the directory ACL.
+    pub fn size(&self) -> u64 {
+        let high = if self.file_type() == S_IFREG { read_u32(&self.data, 108) as u64 } else { 0 };
+        high << 32 | read_u32(&self.data, 4) as u64
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        write_u32(&mut self.data, 4, size as u32);
+        if self.file_type() == S_IFREG {
+            write_u32(&mut self.data, 108, (size >> 32) as u32);
+        }
+    }
+
+    pub fn links(&self) -> u16 {
+        read_u16(&self.data, 26)
+    }
+
+    pub fn set_links(&mut self, links: u16) {
+        write_u16(&mut self.data, 26, links);
+    }
+
+    /// Marks the inode as deleted.
+    pub fn set_deletion_time(&mut self) {
+        write_u32(&mut self.data, 20, TIMESTAMP);
+    }
+
+    pub fn flags(&self) -> u32 {
+        read_u32(&self.data, 32)
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        write_u32(&mut self.data, 32, flags);
+    }
+
+    /// Block holding the extended attributes, which this driver ignores
+    pub fn attribute_block(&self) -> u32 {
+        read_u32(&self.data, 104)
+    }
+
+    /// Adds `count` blocks of `block_size` bytes to the 512-byte sector
+    /// count.
+    fn add_blocks(&mut self, count: i64, block_size: u64) {
+        let sectors = read_u32(&self.data, 28) as i64 + count * (block_size / 512) as i64;
+        write_u32(&mut self.data, 28, sectors as u32);
+    }
+
+    fn sectors(&self) -> u32 {
+        read_u32(&self.data, 28)
+    }
+
+    /// Fast symbolic links keep their target in the block pointers, and have
+    /// no block but maybe the one of their extended attributes.
+    pub fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let attribute_sectors = if self.attribute_block() != 0 { (block_size / 512) as u32 } else { 0 };
+        self.file_type() == S_IFLNK && self.sectors() == attribute_sectors
+    }
+
+    /// The block pointers, as raw bytes for fast symbolic links.
+    pub fn inline_data(&self) -> &[u8] {
+        &self.data[BLOCK_POINTERS_OFFSET..BLOCK_POINTERS_OFFSET + FAST_SYMLINK_LENGTH]
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        self.data[BLOCK_POINTERS_OFFSET..BLOCK_POINTERS_OFFSET + data.len()].copy_from_slice(data);
+    }
+
+    fn pointer(&self, index: usize) -> u32 {
+        read_u32(&self.data, BLOCK_POINTERS_OFFSET + index * 4)
+    }
+
+    fn set_pointer(&mut self, index: usize, block: u32) {
+        write_u32(&mut self.data, BLOCK_POINTERS_OFFSET + index * 4, block);
+    }
+}
+
+/// Number of logical blocks a file can have.
+pub fn max_blocks(block_size: u64) -> u64 {
+    let per_block = block_size / 4;
+    DIRECT_BLOCKS + per_block + per_block * per_block + per_block * per_block * per_block
+}
+
+/// Where a logical block is: the inode block pointer leading to it, then
+/// the index in each level of indirect blocks.
+fn path(logical: u64, block_size: u64) -> Result<(usize, Vec<u64>)> {
+    let per_block = block_size / 4;
+    if logical < DIRECT_BLOCKS {
+        return Ok((logical as usize, Vec::new()));
+    }
+    let logical = logical - DIRECT_BLOCKS;
+    if logical < per_block {
+        return Ok((INDIRECT_POINTER, vec![logical]));
+    }
+    let logical = logical - per_block;
+    if logical < per_block * per_block {
+        return Ok((DOUBLE_POINTER, vec![logical / per_block, logical % per_block]));
+    }
+    let logical = logical - per_block * per_block;
+    if logical < per_block * per_block * per_block {
+        let per_double = per_block * per_block;
+        return Ok((TRIPLE_POINTER, vec![logical / per_double, logical / per_block % per_block, logical % per_block]));
+    }
+    Err(FsError::NoSpace)
+}
+
+fn check_block(volume: &LockedVolume, block: u32) -> Result<u32> {
+    if block >= volume.geometry().blocks_count {
+        return Err(FsError::Io);
+    }
+    Ok(block)
+}
+
+/// Physical block of a logical block of `inode`, 0 for a hole.
+pub fn map_block(volume: &LockedVolume, inode: &DiskInode, logical: u64) -> Result<u32> {
+    let (pointer, indexes) = path(logical, volume.geometry().block_size)?;
+    let mut block = check_block(volume, inode.pointer(pointer))?;
+    for &index in &indexes {
+        if block == 0 {
+            break;
+        }
+        let mut entry = [0; 4];
+        volume.read(volume.block_offset(block) + index * 4, &mut entry)?;
+        block = check_block(volume, u32::from_le_bytes(entry))?;
+    }
+    Ok(block)
+}
+
+/// Physical block of a logical block of `inode`, allocated in the group
+/// `goal` if it is a hole. Returns whether the block is new, its content is
+/// then undefined.
+pub fn allocate_block(volume: &mut LockedVolume, inode: &mut DiskInode, logical: u64, goal: u32) -> Result<(u32, bool)> {
+    let block_size = volume.geometry().block_size;
+    let (pointer, indexes) = path(logical, block_size)?;
+    let mut block = check_block(volume, inode.pointer(pointer))?;
+    let mut fresh = false;
+    if block == 0 {
+        block = volume.allocate_block(goal)?;
+        inode.set_pointer(pointer, block);
+        inode.add_blocks(1, block_size);
+        fresh = true;
+        if !indexes.is_empty() {
+            volume.zero_block(block)?;
+        }
+    }
+    for (depth, &index) in indexes.iter().enumerate() {
+        let offset = volume.block_offset(block) + index * 4;
+        let mut entry = [0; 4];
+        volume.read(offset, &mut entry)?;
+        block = check_block(volume, u32::from_le_bytes(entry))?;
+        fresh = false;
+        if block == 0 {
+            block = volume.allocate_block(goal)?;
+            volume.write(offset, &block.to_le_bytes())?;
+            inode.add_blocks(1, block_size);
+            fresh = true;
+            if depth + 1 < indexes.len() {
+                volume.zero_block(block)?;
+            }
+        }
+    }
+    Ok((block, fresh))
+}
+
+/// Frees the blocks of `inode` from the logical block `from` on, and the
+/// indirect blocks left empty.
+pub fn free_blocks(volume: &mut LockedVolume, inode: &mut DiskInode, from: u64) -> Result<()> {
+    let block_size = volume.geometry().block_size;
+    for index in from.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+        let block = inode.pointer(index as usize);
+        if block != 0 {
+            volume.free_block(block)?;
+            inode.set_pointer(index as usize, 0);
+            inode.add_blocks(-1, block_size);
+        }
+    }
+    let per_block = block_size / 4;
+    let mut first = DIRECT_BLOCKS;
+    let mut span = per_block;
+    for &(pointer, depth) in &[(INDIRECT_POINTER, 1), (DOUBLE_POINTER, 2), (TRIPLE_POINTER, 3)] {
+        let block = inode.pointer(pointer);
+        if block != 0 && first + span > from && free_tree(volume, inode, block, depth, first, from)? {
+            volume.free_block(block)?;
+            inode.set_pointer(pointer, 0);
+            inode.add_blocks(-1, block_size);
+        }
+        first += span;
+        span *= per_block;
+    }
+    Ok(())
+}
+
+/// Frees the blocks from `from` on under the indirect block `block`, of
+/// `depth` levels and starting at the logical block `first`. Returns whether
+/// the indirect block is left empty.
+fn free_tree(volume: &mut LockedVolume, inode: &mut DiskInode, block: u32, depth: u32, first: u64, from: u64) -> Result<bool> {
+    let block_size = volume.geometry().block_size;
+    let per_block = block_size / 4;
+    let span = per_block.pow(depth - 1);
+    let mut entries = volume.read_block(block)?;
+    let mut empty = true;
+    let mut changed = false;
+    for i in 0..per_block as usize {
+        let entry = read_u32(&entries, i * 4);
+        if entry == 0 {
+            continue;
+        }
+        let start = first + i as u64 * span;
+        let freed = if start + span <= from {
+            false
+        } else if depth == 1 {
+            true
+        } else {
+            free_tree(volume, inode, entry, depth - 1, start, from)?
+        };
+        if freed {
+            volume.free_block(entry)?;
+            inode.add_blocks(-1, block_size);
+            write_u32(&mut entries, i * 4, 0);
+            changed = true;
+        } else {
+            empty = false;
+        }
+    }
+    if changed && !empty {
+        volume.write(volume.block_offset(block), &entries)?;
+    }
+    Ok(empty)
+}
@@ -0,0 +1,703 @@
+//! Second extended file system.
+//! https://wiki.osdev.org/Ext2
+//! https://www.nongnu.org/ext2-doc/ext2.html
+//!
+//! The volume is split in block groups, each with a bitmap of its blocks, a
+//! bitmap of its inodes and a table of its inodes. Every change to the
+//! bitmaps, the group descriptors and the superblock is written through, so the
+//! volume is consistent after each operation, which `user/fsck.sh` checks
+//! with `e2fsck`. Volumes with features this
+//! driver doesn't know of are refused, or mounted read-only when they can
+//! still be read safely.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::{Mutex, MutexGuard};
+
+use crate::block;
+use crate::block::BlockDevice;
+
+use super::{FileSystem, FsError, Inode, Result};
+
+use self::node::Ext2Node;
+
+mod dir;
+mod inode;
+mod node;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+/// Directory entries have a file type
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Only some groups have a backup of the superblock
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+/// Files larger than 2 GiB
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+/// Other read-only compatible features are left alone by mounting read-only
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+const ROOT_INODE: u32 = 2;
+
+/// There is no clock, inodes are all dated 2020-01-01 00:00 UTC
+const TIMESTAMP: u32 = 1_577_836_800;
+
+/// Layout of a volume, from its superblock.
+#[derive(Debug)]
+struct Geometry {
+    block_size: u64,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    inode_size: u64,
+    /// First inode that isn't reserved
+    first_inode: u32,
+    group_count: u32,
+    has_file_type: bool,
+    /// Regular files can be larger than 2 GiB
+    large_files: bool,
+    read_only: bool,
+}
+
+impl Geometry {
+    fn parse(superblock: &[u8]) -> Result<Geometry> {
+        if read_u16(superblock, 56) != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+        let log_block_size = read_u32(superblock, 24);
+        // Record lengths of directories are 16 bits
+        if log_block_size > 5 {
+            return Err(FsError::InvalidArgument);
+        }
+        let block_size = 1024 << log_block_size;
+        let blocks_count = read_u32(superblock, 4);
+        let first_data_block = read_u32(superblock, 20);
+        let blocks_per_group = read_u32(superblock, 32);
+        let inodes_per_group = read_u32(superblock, 40);
+        let inodes_count = read_u32(superblock, 0);
+
+        // Revision 0 has fixed inode sizes and no features
+        let (inode_size, first_inode, incompat, ro_compat) = if read_u32(superblock, 76) == 0 {
+            (128, 11, 0, 0)
+        } else {
+            (read_u16(superblock, 88) as u64, read_u32(superblock, 84), read_u32(superblock, 96), read_u32(superblock, 100))
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::NotSupported);
+        }
+        if blocks_per_group == 0 || blocks_per_group as u64 > block_size * 8
+            || inodes_per_group == 0 || inodes_per_group as u64 > block_size * 8
+            || inode_size < 128 || inode_size > block_size || !inode_size.is_power_of_two()
+            || first_data_block >= blocks_count || first_inode <= ROOT_INODE {
+            return Err(FsError::InvalidArgument);
+        }
+        let group_count = (blocks_count - first_data_block + blocks_per_group - 1) / blocks_per_group;
+        if inodes_count > group_count * inodes_per_group {
+            return Err(FsError::InvalidArgument);
+        }
+
+        Ok(Geometry {
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inodes_count,
+            inode_size,
+            first_inode,
+            group_count,
+            has_file_type: incompat & INCOMPAT_FILETYPE != 0,
+            large_files: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: ro_compat & !SUPPORTED_RO_COMPAT != 0,
+        })
+    }
+
+    /// The descriptor table follows the superblock.
+    fn descriptor_offset(&self) -> u64 {
+        (self.first_data_block as u64 + 1) * self.block_size
+    }
+
+    fn group_of_block(&self, block: u32) -> u32 {
+        (block - self.first_data_block) / self.blocks_per_group
+    }
+
+    fn group_of_inode(&self, inode: u32) -> u32 {
+        (inode - 1) / self.inodes_per_group
+    }
+
+    /// The last group can be shorter than the others.
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        let start = group * self.blocks_per_group;
+        (self.blocks_count - self.first_data_block - start).min(self.blocks_per_group)
+    }
+}
+
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Node>,
+}
+
+impl Ext2Fs {
+    /// Mounts the ext2 volume of `device`. Fails with `InvalidArgument` if the
+    /// device holds no ext2 volume, and with `NotSupported` if it needs
+    /// features this driver lacks.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Ext2Fs>> {
+        let mut superblock = vec![0; SUPERBLOCK_SIZE];
+        if device.size() < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+        block::read_bytes(&*device, SUPERBLOCK_OFFSET, &mut superblock)?;
+        let geometry = Geometry::parse(&superblock)?;
+        if geometry.blocks_count as u64 * geometry.block_size > device.size() {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut groups = vec![0; geometry.group_count as usize * GROUP_DESCRIPTOR_SIZE];
+        block::read_bytes(&*device, geometry.descriptor_offset(), &mut groups)?;
+
+        let volume = Arc::new(Volume {
+            device,
+            geometry,
+            state: Mutex::new(VolumeState { superblock, groups, nodes: BTreeMap::new() }),
+        });
+        let root = volume.lock().node(ROOT_INODE)?;
+        if !root.is_directory() {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(Arc::new(Ext2Fs { volume, root }))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.volume.geometry.read_only
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.volume.device.flush()?)
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    state: Mutex<VolumeState>,
+}
+
+struct VolumeState {
+    superblock: Vec<u8>,
+    /// The group descriptor table
+    groups: Vec<u8>,
+    /// Nodes in use, so that an inode has a single node
+    nodes: BTreeMap<u32, Weak<Ext2Node>>,
+}
+
+impl Volume {
+    /// Locks the volume. Node states are locked after it.
+    fn lock(self: &Arc<Volume>) -> LockedVolume<'_> {
+        LockedVolume { volume: self, state: self.state.lock() }
+    }
+}
+
+/// Offsets of the fields of a group descriptor
+const GROUP_BLOCK_BITMAP: usize = 0;
+const GROUP_INODE_BITMAP: usize = 4;
+const GROUP_INODE_TABLE: usize = 8;
+const GROUP_FREE_BLOCKS: usize = 12;
+const GROUP_FREE_INODES: usize = 14;
+const GROUP_USED_DIRECTORIES: usize = 16;
+
+/// Offsets of the free counts in the superblock
+const SUPERBLOCK_FREE_BLOCKS: usize = 12;
+const SUPERBLOCK_FREE_INODES: usize = 16;
+
+/// A locked volume, through which all the accesses to the disk go.
+struct LockedVolume<'a> {
+    volume: &'a Arc<Volume>,
+    state: MutexGuard<'a, VolumeState>,
+}
+
+impl<'a> LockedVolume<'a> {
+    fn geometry(&self) -> &'a Geometry {
+        &self.volume.geometry
+    }
+
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        Ok(block::read_bytes(&*self.volume.device, offset, buffer)?)
+    }
+
+    fn write(&self, offset: u64, buffer: &[u8]) -> Result<()> {
+        if self.geometry().read_only {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(block::write_bytes(&*self.volume.device, offset, buffer)?)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.geometry().block_size
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>> {
+        let mut data = vec![0; self.geometry().block_size as usize];
+        self.read(self.block_offset(block), &mut data)?;
+        Ok(data)
+    }
+
+    fn zero_block(&self, block: u32) -> Result<()> {
+        self.write(self.block_offset(block), &vec![0; self.geometry().block_size as usize])
+    }
+
+    fn group_field(&self, group: u32, field: usize) -> u32 {
+        let offset = group as usize * GROUP_DESCRIPTOR_SIZE + field;
+        match field {
+            GROUP_FREE_BLOCKS | GROUP_FREE_INODES | GROUP_USED_DIRECTORIES => read_u16(&self.state.groups, offset) as u32,
+            _ => read_u32(&self.state.groups, offset),
+        }
+    }
+
+    /// Adds `delta` to a counter of a group descriptor and writes it back.
+    fn add_to_group(&mut self, group: u32, field: usize, delta: i32) -> Result<()> {
+        let value = (self.group_field(group, field) as i32 + delta) as u16;
+        let start = group as usize * GROUP_DESCRIPTOR_SIZE;
+        write_u16(&mut self.state.groups, start + field, value);
+        let offset = self.geometry().descriptor_offset() + start as u64;
+        self.write(offset, &self.state.groups[start..start + GROUP_DESCRIPTOR_SIZE])
+    }
+
+    /// Adds `delta` to a free count of the superblock and writes it back.
+    fn add_to_superblock(&mut self, field: usize, delta: i32) -> Result<()> {
+        let value = (read_u32(&self.state.superblock, field) as i32 + delta) as u32;
+        write_u32(&mut self.state.superblock, field, value);
+        self.write(SUPERBLOCK_OFFSET, &self.state.superblock)
+    }
+
+    /// Sets the first clear bit of a bitmap, among its first `count` ones.
+    fn take_bit(&self, bitmap_block: u32, count: u32, skip: u32) -> Result<Option<u32>> {
+        let mut bitmap = self.read_block(bitmap_block)?;
+        for bit in skip..count {
+            let (byte, mask) = ((bit / 8) as usize, 1 << (bit % 8));
+            if bitmap[byte] & mask == 0 {
+                bitmap[byte] |= mask;
+                self.write(self.block_offset(bitmap_block) + byte as u64, &bitmap[byte..byte + 1])?;
+                return Ok(Some(bit));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Clears a bit of a bitmap. A bit that is already clear means the volume
+    /// is corrupted.
+    fn clear_bit(&self, bitmap_block: u32, bit: u32) -> Result<()> {
+        let offset = self.block_offset(bitmap_block) + (bit / 8) as u64;
+        let mut byte = [0];
+        self.read(offset, &mut byte)?;
+        let mask = 1 << (bit % 8);
+        if byte[0] & mask == 0 {
+            return Err(FsError::Io);
+        }
+        byte[0] &= !mask;
+        self.write(offset, &byte)
+    }
+
+    /// Allocates a block, preferably in the group `goal`. Its content is
+    /// left as is.
+    fn allocate_block(&mut self, goal: u32) -> Result<u32> {
+        let geometry = self.geometry();
+        for i in 0..geometry.group_count {
+            let group = (goal + i) % geometry.group_count;
+            if self.group_field(group, GROUP_FREE_BLOCKS) == 0 {
+                continue;
+            }
+            let bitmap = self.group_field(group, GROUP_BLOCK_BITMAP);
+            if let Some(bit) = self.take_bit(bitmap, geometry.blocks_in_group(group), 0)? {
+                self.add_to_group(group, GROUP_FREE_BLOCKS, -1)?;
+                self.add_to_superblock(SUPERBLOCK_FREE_BLOCKS, -1)?;
+                return Ok(geometry.first_data_block + group * geometry.blocks_per_group + bit);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<()> {
+        let geometry = self.geometry();
+        if block < geometry.first_data_block || block >= geometry.blocks_count {
+            return Err(FsError::Io);
+        }
+        let group = geometry.group_of_block(block);
+        let bitmap = self.group_field(group, GROUP_BLOCK_BITMAP);
+        self.clear_bit(bitmap, (block - geometry.first_data_block) % geometry.blocks_per_group)?;
+        self.add_to_group(group, GROUP_FREE_BLOCKS, 1)?;
+        self.add_to_superblock(SUPERBLOCK_FREE_BLOCKS, 1)
+    }
+
+    /// Allocates an inode number, preferably in the group `goal`.
+    fn allocate_inode(&mut self, goal: u32, is_directory: bool) -> Result<u32> {
+        let geometry = self.geometry();
+        for i in 0..geometry.group_count {
+            let group = (goal + i) % geometry.group_count;
+            if self.group_field(group, GROUP_FREE_INODES) == 0 {
+                continue;
+            }
+            let bitmap = self.group_field(group, GROUP_INODE_BITMAP);
+            let first = group * geometry.inodes_per_group;
+            // Reserved inodes are marked as used, skip them anyway
+            let skip = (geometry.first_inode - 1).saturating_sub(first);
+            let count = geometry.inodes_per_group.min(geometry.inodes_count - first);
+            if let Some(bit) = self.take_bit(bitmap, count, skip)? {
+                self.add_to_group(group, GROUP_FREE_INODES, -1)?;
+                if is_directory {
+                    self.add_to_group(group, GROUP_USED_DIRECTORIES, 1)?;
+                }
+                self.add_to_superblock(SUPERBLOCK_FREE_INODES, -1)?;
+                return Ok(first + bit + 1);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&mut self, inode: u32, is_directory: bool) -> Result<()> {
+        let geometry = self.geometry();
+        let group = geometry.group_of_inode(inode);
+        let bitmap = self.group_field(group, GROUP_INODE_BITMAP);
+        self.clear_bit(bitmap, (inode - 1) % geometry.inodes_per_group)?;
+        self.add_to_group(group, GROUP_FREE_INODES, 1)?;
+        if is_directory {
+            self.add_to_group(group, GROUP_USED_DIRECTORIES, -1)?;
+        }
+        self.add_to_superblock(SUPERBLOCK_FREE_INODES, 1)
+    }
+
+    fn inode_offset(&self, inode: u32) -> Result<u64> {
+        let geometry = self.geometry();
+        if inode == 0 || inode > geometry.inodes_count {
+            return Err(FsError::Io);
+        }
+        let group = geometry.group_of_inode(inode);
+        let index = ((inode - 1) % geometry.inodes_per_group) as u64;
+        Ok(self.block_offset(self.group_field(group, GROUP_INODE_TABLE)) + index * geometry.inode_size)
+    }
+
+    fn read_inode(&self, inode: u32) -> Result<Vec<u8>> {
+        let mut data = vec![0; self.geometry().inode_size as usize];
+        self.read(self.inode_offset(inode)?, &mut data)?;
+        Ok(data)
+    }
+
+    fn write_inode(&self, inode: u32, data: &[u8]) -> Result<()> {
+        self.write(self.inode_offset(inode)?, data)
+    }
+
+    /// Returns the node of an inode, reading it if no one uses it yet.
+    fn node(&mut self, inode: u32) -> Result<Arc<Ext2Node>> {
+        if let Some(node) = self.state.nodes.get(&inode).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+        let node = Ext2Node::new(self.volume.clone(), inode, self.read_inode(inode)?);
+        self.state.nodes.insert(inode, Arc::downgrade(&node));
+        Ok(node)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use crate::fs;
+    use crate::fs::testing::{disk, fill, fsck_disk, names};
+    use crate::fs::{FileType, OpenFlags};
+    use crate::programs;
+
+    use super::inode::{DiskInode, S_IFDIR};
+    use super::*;
+
+    /// `user/build.sh` makes it from the initramfs tree, 1 KiB blocks in two
+    /// groups
+    static IMAGE: &[u8] = include_bytes!("../../../user/bin/test.ext2");
+
+    /// Checks the volume like `e2fsck`: directories link back to their parent,
+    /// link counts match the entries, blocks belong to a single inode and are
+    /// marked in the bitmaps, block counts and sizes match the blocks, and
+    /// the free counts agree with the bitmaps, on disk too. Returns the
+    /// number of inodes in use, and the number of blocks used by the
+    /// metadata, which never changes.
+    fn check(fs: &Ext2Fs) -> (usize, u32) {
+        let volume = fs.volume.lock();
+        let geometry = volume.geometry();
+        let block_size = geometry.block_size as usize;
+        let mut owner = vec![0; geometry.blocks_count as usize];
+        let mut references = BTreeMap::new();
+        let mut directories_per_group = vec![0; geometry.group_count as usize];
+        let mut directories = vec![(ROOT_INODE, ROOT_INODE)];
+        let mut seen = vec![ROOT_INODE];
+        while let Some((number, parent)) = directories.pop() {
+            directories_per_group[geometry.group_of_inode(number) as usize] += 1;
+            let inode = DiskInode::new(volume.read_inode(number).unwrap());
+            let mut data = vec![0; inode.size() as usize];
+            node::read_content(&volume, &inode, 0, &mut data).unwrap();
+            for record in dir::parse(&data, block_size).unwrap() {
+                if record.inode == 0 {
+                    continue;
+                }
+                *references.entry(record.inode).or_insert(0) += 1;
+                match record.name.as_str() {
+                    "." => assert_eq!(record.inode, number, "bad . in {}", number),
+                    ".." => assert_eq!(record.inode, parent, "bad .. in {}", number),
+                    _ if !seen.contains(&record.inode) => {
+                        seen.push(record.inode);
+                        let child = DiskInode::new(volume.read_inode(record.inode).unwrap());
+                        if child.file_type() == S_IFDIR {
+                            directories.push((record.inode, number));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for &number in &seen {
+            let inode = DiskInode::new(volume.read_inode(number).unwrap());
+            assert_eq!(inode.links(), references[&number], "inode {} has a bad link count", number);
+            if inode.is_fast_symlink(geometry.block_size) {
+                continue;
+            }
+            let mut claimed = 0;
+            let mut last = None;
+            let mut claim = |block: u32, logical: Option<u64>| {
+                assert_eq!(owner[block as usize], 0, "block {} is cross-linked", block);
+                owner[block as usize] = number;
+                claimed += 1;
+                if logical.is_some() {
+                    last = last.max(logical);
+                }
+            };
+            for logical in 0..12 {
+                let block = read_u32(inode.data(), 40 + logical * 4);
+                if block != 0 {
+                    claim(block, Some(logical as u64));
+                }
+            }
+            let per_block = geometry.block_size / 4;
+            let mut first = 12;
+            for depth in 1..4 {
+                let block = read_u32(inode.data(), 40 + (11 + depth) * 4);
+                if block != 0 {
+                    claim_tree(&volume, block, depth as u32, first, &mut claim);
+                }
+                first += per_block.pow(depth as u32);
+            }
+            assert_eq!(read_u32(inode.data(), 28), claimed * (geometry.block_size / 512) as u32,
+                "inode {} has a bad block count", number);
+            if let Some(last) = last {
+                assert!(last * geometry.block_size < inode.size(), "inode {} has blocks past its size", number);
+            }
+        }
+
+        let superblock = &volume.state.superblock;
+        let mut disk_superblock = vec![0; SUPERBLOCK_SIZE];
+        volume.read(SUPERBLOCK_OFFSET, &mut disk_superblock).unwrap();
+        assert!(disk_superblock == *superblock, "the superblock isn't written back");
+        let mut disk_groups = vec![0; volume.state.groups.len()];
+        volume.read(geometry.descriptor_offset(), &mut disk_groups).unwrap();
+        assert!(disk_groups == volume.state.groups, "the group descriptors aren't written back");
+
+        let (mut free_blocks, mut free_inodes, mut metadata) = (0, 0, 0);
+        for group in 0..geometry.group_count {
+            let bitmap = volume.read_block(volume.group_field(group, GROUP_BLOCK_BITMAP)).unwrap();
+            let mut free = 0;
+            for bit in 0..geometry.blocks_in_group(group) {
+                let block = geometry.first_data_block + group * geometry.blocks_per_group + bit;
+                let used = bitmap[bit as usize / 8] & 1 << (bit % 8) != 0;
+                if !used {
+                    free += 1;
+                }
+                assert!(used || owner[block as usize] == 0, "block {} is used but free", block);
+                if used && owner[block as usize] == 0 {
+                    metadata += 1;
+                }
+            }
+            assert_eq!(volume.group_field(group, GROUP_FREE_BLOCKS), free, "group {} has a bad free block count", group);
+            free_blocks += free;
+
+            let bitmap = volume.read_block(volume.group_field(group, GROUP_INODE_BITMAP)).unwrap();
+            let mut free = 0;
+            for bit in 0..geometry.inodes_per_group {
+                let number = group * geometry.inodes_per_group + bit + 1;
+                let used = bitmap[bit as usize / 8] & 1 << (bit % 8) != 0;
+                assert!(used || !seen.contains(&number), "inode {} is used but free", number);
+                assert!(!used || number < geometry.first_inode || seen.contains(&number), "inode {} is lost", number);
+                if !used {
+                    free += 1;
+                }
+            }
+            assert_eq!(volume.group_field(group, GROUP_FREE_INODES), free, "group {} has a bad free inode count", group);
+            assert_eq!(volume.group_field(group, GROUP_USED_DIRECTORIES), directories_per_group[group as usize]);
+            free_inodes += free;
+        }
+        assert_eq!(read_u32(superblock, SUPERBLOCK_FREE_BLOCKS), free_blocks);
+        assert_eq!(read_u32(superblock, SUPERBLOCK_FREE_INODES), free_inodes);
+        (seen.len(), metadata)
+    }
+
+    /// Claims the blocks under an indirect block of `depth` levels, which
+    /// maps the logical blocks from `first` on.
+    fn claim_tree(volume: &LockedVolume, block: u32, depth: u32, first: u64, claim: &mut impl FnMut(u32, Option<u64>)) {
+        claim(block, None);
+        let entries = volume.read_block(block).unwrap();
+        let span = (volume.geometry().block_size / 4).pow(depth - 1);
+        for i in 0..entries.len() / 4 {
+            let entry = read_u32(&entries, i * 4);
+            let logical = first + i as u64 * span;
+            if entry == 0 {
+                continue;
+            } else if depth == 1 {
+                claim(entry, Some(logical));
+            } else {
+                claim_tree(volume, entry, depth - 1, logical, claim);
+            }
+        }
+    }
+
+    #[test_case]
+    fn image_is_readable() {
+        let ext2 = Ext2Fs::new(disk("ext2-image", IMAGE)).unwrap();
+        assert!(!ext2.is_read_only());
+        let (inodes, _) = check(&ext2);
+        fs::mkdir("/ext2-image").unwrap();
+        fs::mount("/ext2-image", ext2).unwrap();
+
+        assert_eq!(fs::read_file("/ext2-image/bin/hello").unwrap(), programs::HELLO);
+        let hello = fs::lookup("/ext2-image/bin/hello").unwrap().inode().metadata();
+        assert_eq!((hello.file_type, hello.mode, hello.links), (FileType::Regular, 0o755, 1));
+        assert_eq!(fs::read_link("/ext2-image/etc/welcome").unwrap(), "/etc/motd");
+        assert_eq!(fs::read_link("/ext2-image/etc/dangling").unwrap(),
+            "../../a/rather/long/target/that/does/not/fit/in/the/inode/itself");
+        assert_eq!(fs::lookup("/ext2-image/etc/dangling").err(), Some(FsError::NotFound));
+        assert_eq!(names(fs::lookup("/ext2-image").unwrap().inode().read_dir().unwrap()), ["bin", "etc", "lost+found"]);
+        // The root, lost+found, 2 directories, 1 program, 1 file and 2 links
+        assert_eq!(inodes, 8);
+
+        fs::unmount("/ext2-image").unwrap();
+        fs::unlink("/ext2-image").unwrap();
+    }
+
+    /// Changes the files of the test image in every way, and checks the
+    /// volume on the way.
+    fn exercise(device: Arc<dyn BlockDevice>) {
+        let ext2 = Ext2Fs::new(device.clone()).unwrap();
+        let (_, metadata) = check(&ext2);
+        fs::mkdir("/ext2-test").unwrap();
+        fs::mount("/ext2-test", ext2.clone()).unwrap();
+        let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE;
+
+        // Enough to need the indirect block
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i * 7) as u8).collect();
+        let file = fs::open("/ext2-test/etc/data", flags).unwrap();
+        assert_eq!(file.write(&data), Ok(data.len()));
+        assert_eq!(fs::read_file("/ext2-test/etc/data").unwrap(), data);
+        // Far writes go through the double and triple indirect blocks and
+        // leave holes
+        let inode = file.dentry().inode();
+        assert_eq!(inode.write_at(300 * 1024, b"double"), Ok(6));
+        assert_eq!(inode.write_at(70 * 1024 * 1024, b"triple"), Ok(6));
+        let mut buffer = [0xFF; 16];
+        assert_eq!(inode.read_at(300 * 1024 - 10, &mut buffer), Ok(16));
+        assert_eq!(&buffer, b"\0\0\0\0\0\0\0\0\0\0double");
+        assert_eq!(inode.read_at(70 * 1024 * 1024, &mut buffer), Ok(6));
+        assert_eq!(&buffer[..6], b"triple");
+        check(&ext2);
+        inode.truncate(100).unwrap();
+        inode.truncate(2000).unwrap();
+        let content = fs::read_file("/ext2-test/etc/data").unwrap();
+        assert_eq!(&content[..100], &data[..100]);
+        assert_eq!(content[100..], [0; 1900][..]);
+
+        fs::mkdir("/ext2-test/directory").unwrap();
+        // Enough entries to grow the directory past its first block
+        for i in 0..40 {
+            fs::open(&format!("/ext2-test/directory/a file with a rather long name, number {}", i), flags).unwrap();
+        }
+        for i in 0..20 {
+            fs::unlink(&format!("/ext2-test/directory/a file with a rather long name, number {}", i)).unwrap();
+        }
+        fs::mkdir("/ext2-test/directory/nested").unwrap();
+        fs::symlink("../etc/motd", "/ext2-test/directory/short").unwrap();
+        fs::symlink(&"long/".repeat(20), "/ext2-test/directory/long").unwrap();
+        assert_eq!(fs::read_file("/ext2-test/directory/short").unwrap(), b"Welcome to krill!\n");
+        assert_eq!(fs::unlink("/ext2-test/directory").err(), Some(FsError::DirectoryNotEmpty));
+        assert_eq!(fs::lookup("/ext2-test/directory").unwrap().inode().metadata().links, 3);
+        // Removed while open, the program stays readable until closed
+        let hello = fs::open("/ext2-test/bin/hello", OpenFlags::READ).unwrap();
+        fs::unlink("/ext2-test/bin/hello").unwrap();
+        let mut content = vec![0; programs::HELLO.len() + 1];
+        assert_eq!(hello.read(&mut content), Ok(programs::HELLO.len()));
+        assert_eq!(&content[..programs::HELLO.len()], programs::HELLO);
+        drop(hello);
+        drop(file);
+        fs::unmount("/ext2-test").unwrap();
+        // The root, lost+found, bin, etc, 3 files, 2 links, directory with 20
+        // files, nested and 2 links
+        assert_eq!(check(&ext2), (32, metadata));
+
+        // Everything was written through to the disk
+        let ext2 = Ext2Fs::new(device).unwrap();
+        let root = ext2.root();
+        let directory = root.lookup("directory").unwrap();
+        assert_eq!(directory.read_dir().unwrap().len(), 23);
+        assert_eq!(directory.lookup("long").unwrap().read_link().unwrap(), "long/".repeat(20));
+        assert_eq!(root.lookup("etc").unwrap().lookup("data").unwrap().metadata().size, 2000);
+        assert_eq!(root.lookup("bin").unwrap().lookup("hello").err(), Some(FsError::NotFound));
+
+        fill(&root, "big");
+        assert_eq!(root.create("full", FileType::Directory).err(), Some(FsError::NoSpace));
+        check(&ext2);
+        root.unlink("big").unwrap();
+        assert_eq!(check(&ext2), (32, metadata));
+        fs::unlink("/ext2-test").unwrap();
+    }
+
+    #[test_case]
+    fn volume_is_writable() {
+        exercise(disk("ext2-test", IMAGE));
+    }
+
+    /// Leaves `e2fsck` a volume to check, when `user/fsck.sh` runs the tests.
+    #[test_case]
+    fn written_image_passes_fsck() {
+        if let Some(device) = fsck_disk("vdc") {
+            exercise(device);
+        }
+    }
+}
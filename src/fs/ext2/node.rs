@@ -0,0 +1,509 @@
+//! Files, directories and symbolic links of an ext2 volume.
+//!
+//! A node keeps a copy of its inode, which is written back after every
+//! change. Like on Unix, an inode whose last link goes away is only released
+//! once its node is dropped, so files stay usable while they are open.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::fs::{DirEntry, FileType, FsError, Inode, Metadata, Result};
+
+use super::dir::Record;
+use super::inode::{DiskInode, FAST_SYMLINK_LENGTH, FLAG_INDEX, S_IFBLK, S_IFCHR, S_IFDIR, S_IFLNK, S_IFREG};
+use super::{dir, inode, LockedVolume, Volume};
+
+pub struct Ext2Node {
+    number: u32,
+    volume: Arc<Volume>,
+    /// Type bits of the mode, which never change
+    kind: u16,
+    state: Mutex<NodeState>,
+}
+
+struct NodeState {
+    inode: DiskInode,
+    /// The last link is gone, the inode is released with the node
+    unlinked: bool,
+}
+
+impl Ext2Node {
+    pub(super) fn new(volume: Arc<Volume>, number: u32, data: Vec<u8>) -> Arc<Ext2Node> {
+        let inode = DiskInode::new(data);
+        Arc::new(Ext2Node {
+            number,
+            volume,
+            kind: inode.file_type(),
+            state: Mutex::new(NodeState { inode, unlinked: false }),
+        })
+    }
+
+    pub(super) fn is_directory(&self) -> bool {
+        self.kind == S_IFDIR
+    }
+
+    /// Group where the blocks and inodes created by this node go.
+    fn goal(&self) -> u32 {
+        self.volume.geometry.group_of_inode(self.number)
+    }
+
+    fn save(&self, volume: &LockedVolume, state: &NodeState) -> Result<()> {
+        volume.write_inode(self.number, state.inode.data())
+    }
+
+    /// Reads the records of this directory.
+    fn load_directory(&self, volume: &LockedVolume, state: &NodeState) -> Result<Vec<Record>> {
+        if !self.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        if state.unlinked {
+            return Err(FsError::NotFound);
+        }
+        let mut data = vec![0; state.inode.size() as usize];
+        read_content(volume, &state.inode, 0, &mut data)?;
+        dir::parse(&data, volume.geometry().block_size as usize)
+    }
+
+    /// Reads the records of this directory, to change them.
+    fn load_writable_directory(&self, volume: &LockedVolume, state: &NodeState) -> Result<Vec<Record>> {
+        let records = self.load_directory(volume, state)?;
+        if volume.geometry().read_only {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(records)
+    }
+
+    fn check_writable(&self, volume: &LockedVolume) -> Result<()> {
+        match self.kind {
+            S_IFDIR => Err(FsError::IsADirectory),
+            S_IFREG if volume.geometry().read_only => Err(FsError::PermissionDenied),
+            S_IFREG => Ok(()),
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    /// Largest size of a regular file.
+    fn max_size(&self) -> u64 {
+        let geometry = &self.volume.geometry;
+        let limit = if geometry.large_files { u64::MAX } else { i32::MAX as u64 };
+        (inode::max_blocks(geometry.block_size) * geometry.block_size).min(limit)
+    }
+
+    /// Writes into the content of this directory, within its blocks.
+    fn write_directory(&self, volume: &mut LockedVolume, state: &mut NodeState, offset: usize, data: &[u8]) -> Result<()> {
+        write_content(volume, self.goal(), &mut state.inode, offset as u64, data)?;
+        Ok(())
+    }
+
+    /// Adds a record for `number` to this directory.
+    fn add_entry(&self, volume: &mut LockedVolume, state: &mut NodeState, records: &[Record], name: &str, number: u32, file_type: FileType) -> Result<()> {
+        let geometry = volume.geometry();
+        let code = if geometry.has_file_type { dir::file_type_code(file_type) } else { dir::TYPE_UNKNOWN };
+        let block_size = geometry.block_size as usize;
+        match dir::find_space(records, dir::record_length(name.len())) {
+            Some((record, 0)) => {
+                let new = dir::encode(number, name, code, record.length);
+                self.write_directory(volume, state, record.offset, &new)?;
+            }
+            Some((record, used)) => {
+                // The record keeps the space of its name, the new one takes
+                // the rest
+                let new = dir::encode(number, name, code, record.length - used);
+                self.write_directory(volume, state, record.offset + used, &new)?;
+                self.write_directory(volume, state, record.offset + 4, &(used as u16).to_le_bytes())?;
+            }
+            None => {
+                let size = state.inode.size();
+                if size + block_size as u64 > u32::MAX as u64 {
+                    return Err(FsError::NoSpace);
+                }
+                let mut block = vec![0; block_size];
+                let new = dir::encode(number, name, code, block_size);
+                block[..new.len()].copy_from_slice(&new);
+                match write_content(volume, self.goal(), &mut state.inode, size, &block) {
+                    Ok(written) if written == block_size => {}
+                    result => {
+                        // Give back the indirect blocks allocated on the way
+                        inode::free_blocks(volume, &mut state.inode, size / block_size as u64)?;
+                        self.save(volume, state)?;
+                        return Err(result.err().unwrap_or(FsError::NoSpace));
+                    }
+                }
+                state.inode.set_size(size + block_size as u64);
+            }
+        }
+        self.edited(volume, state)
+    }
+
+    /// Removes a record from this directory, merging it into the record before
+    /// it in its block.
+    fn remove_entry(&self, volume: &mut LockedVolume, state: &mut NodeState, records: &[Record], record: &Record) -> Result<()> {
+        match record.previous.and_then(|offset| records.iter().find(|previous| previous.offset == offset)) {
+            Some(previous) => {
+                let length = (previous.length + record.length) as u16;
+                self.write_directory(volume, state, previous.offset + 4, &length.to_le_bytes())?;
+            }
+            None => self.write_directory(volume, state, record.offset, &0u32.to_le_bytes())?,
+        }
+        self.edited(volume, state)
+    }
+
+    /// Drops the hash tree index of this directory, which is stale once the
+    /// records change, and writes the inode back.
+    fn edited(&self, volume: &LockedVolume, state: &mut NodeState) -> Result<()> {
+        let flags = state.inode.flags();
+        state.inode.set_flags(flags & !FLAG_INDEX);
+        self.save(volume, state)
+    }
+
+    /// Allocates an inode, fills it with the content `content` makes for its
+    /// number and links it in this directory.
+    fn add_inode(
+        &self, volume: &mut LockedVolume, state: &mut NodeState, records: &[Record],
+        name: &str, mut inode: DiskInode, content: impl FnOnce(u32) -> Vec<u8>,
+    ) -> Result<Arc<dyn Inode>> {
+        let file_type = file_type(inode.file_type()).ok_or(FsError::NotSupported)?;
+        let number = volume.allocate_inode(self.goal(), file_type == FileType::Directory)?;
+        let content = content(number);
+        let result = write_content(volume, self.goal(), &mut inode, 0, &content)
+            .and_then(|written| if written < content.len() { Err(FsError::NoSpace) } else { Ok(()) })
+            .and_then(|_| volume.write_inode(number, inode.data()))
+            .and_then(|_| self.add_entry(volume, state, records, name, number, file_type));
+        if let Err(error) = result {
+            release(volume, number, &mut inode)?;
+            return Err(error);
+        }
+        Ok(volume.node(number)?)
+    }
+}
+
+impl Drop for Ext2Node {
+    fn drop(&mut self) {
+        if !self.state.lock().unlinked {
+            return;
+        }
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        volume.state.nodes.remove(&self.number);
+        if let Err(error) = release(&mut volume, self.number, &mut state.inode) {
+            println!("ext2: can't release inode {}: {:?}", self.number, error);
+        }
+    }
+}
+
+/// Frees the blocks and the inode `number`, which has no link left.
+fn release(volume: &mut LockedVolume, number: u32, inode: &mut DiskInode) -> Result<()> {
+    if inode.attribute_block() != 0 {
+        return Err(FsError::NotSupported);
+    }
+    let is_directory = inode.file_type() == S_IFDIR;
+    if !inode.is_fast_symlink(volume.geometry().block_size) {
+        inode::free_blocks(volume, inode, 0)?;
+    }
+    inode.set_links(0);
+    inode.set_size(0);
+    inode.set_deletion_time();
+    volume.write_inode(number, inode.data())?;
+    volume.free_inode(number, is_directory)
+}
+
+/// The VFS file type of the type bits of a mode.
+fn file_type(kind: u16) -> Option<FileType> {
+    match kind {
+        S_IFREG => Some(FileType::Regular),
+        S_IFDIR => Some(FileType::Directory),
+        S_IFLNK => Some(FileType::Symlink),
+        S_IFCHR => Some(FileType::CharDevice),
+        S_IFBLK => Some(FileType::BlockDevice),
+        _ => None,
+    }
+}
+
+/// Reads the content of an inode. Holes read as zeroes.
+pub(super) fn read_content(volume: &LockedVolume, inode: &DiskInode, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    let block_size = volume.geometry().block_size;
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let start = (position % block_size) as usize;
+        let count = (buffer.len() - done).min(block_size as usize - start);
+        let chunk = &mut buffer[done..done + count];
+        match inode::map_block(volume, inode, position / block_size)? {
+            0 => chunk.iter_mut().for_each(|byte| *byte = 0),
+            block => volume.read(volume.block_offset(block) + start as u64, chunk)?,
+        }
+        done += count;
+    }
+    Ok(())
+}
+
+/// Writes the content of an inode, allocating the blocks it lacks in the
+/// group `goal`. Returns how much was written before the volume got full.
+fn write_content(volume: &mut LockedVolume, goal: u32, inode: &mut DiskInode, offset: u64, buffer: &[u8]) -> Result<usize> {
+    let block_size = volume.geometry().block_size;
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let start = (position % block_size) as usize;
+        let count = (buffer.len() - done).min(block_size as usize - start);
+        let chunk = &buffer[done..done + count];
+        let (block, fresh) = match inode::allocate_block(volume, inode, position / block_size, goal) {
+            Ok(block) => block,
+            Err(FsError::NoSpace) if done > 0 => break,
+            Err(error) => return Err(error),
+        };
+        if fresh && count < block_size as usize {
+            // Fresh blocks still hold what deleted files left there
+            let mut data = vec![0; block_size as usize];
+            data[start..start + count].copy_from_slice(chunk);
+            volume.write(volume.block_offset(block), &data)?;
+        } else {
+            volume.write(volume.block_offset(block) + start as u64, chunk)?;
+        }
+        done += count;
+    }
+    Ok(done)
+}
+
+impl Inode for Ext2Node {
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        Metadata {
+            inode: self.number as u64,
+            file_type: file_type(self.kind).unwrap_or(FileType::Regular),
+            size: state.inode.size(),
+            mode: state.inode.mode() & 0o7777,
+            links: state.inode.links() as u32,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        match self.kind {
+            S_IFREG => {}
+            S_IFDIR => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::NotSupported),
+        }
+        let volume = self.volume.lock();
+        let state = self.state.lock();
+        let size = state.inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((size - offset) as usize);
+        read_content(&volume, &state.inode, offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize> {
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        self.check_writable(&volume)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        offset.checked_add(buffer.len() as u64)
+            .filter(|&end| end <= self.max_size())
+            .ok_or(FsError::NoSpace)?;
+        // Past the end of the file, the blocks are holes or were zeroed when
+        // the file shrank, so there is nothing to fill
+        let result = write_content(&mut volume, self.goal(), &mut state.inode, offset, buffer);
+        if let Ok(written) = result {
+            let size = state.inode.size();
+            state.inode.set_size(size.max(offset + written as u64));
+        }
+        // Indirect blocks may have been allocated even on failure
+        self.save(&volume, &state)?;
+        result
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        self.check_writable(&volume)?;
+        if size > self.max_size() {
+            return Err(FsError::NoSpace);
+        }
+        if size < state.inode.size() {
+            let block_size = volume.geometry().block_size;
+            inode::free_blocks(&mut volume, &mut state.inode, (size + block_size - 1) / block_size)?;
+            // Keep the rest of the last block zeroed, for when the file grows
+            // back
+            let tail = size % block_size;
+            if tail != 0 {
+                let block = inode::map_block(&volume, &state.inode, size / block_size)?;
+                if block != 0 {
+                    volume.write(volume.block_offset(block) + tail, &vec![0; (block_size - tail) as usize])?;
+                }
+            }
+        }
+        state.inode.set_size(size);
+        self.save(&volume, &state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let mut volume = self.volume.lock();
+        let state = self.state.lock();
+        let records = self.load_directory(&volume, &state)?;
+        let record = records.iter()
+            .find(|record| record.inode != 0 && record.name == name)
+            .ok_or(FsError::NotFound)?;
+        let node = volume.node(record.inode)?;
+        // Fifos and sockets have no place in the VFS
+        if file_type(node.kind).is_none() {
+            return Err(FsError::NotSupported);
+        }
+        Ok(node)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        dir::validate_name(name)?;
+        let (mode, links) = match file_type {
+            FileType::Regular => (S_IFREG | 0o644, 1),
+            FileType::Directory => (S_IFDIR | 0o755, 2),
+            _ => return Err(FsError::NotSupported),
+        };
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        let records = self.load_writable_directory(&volume, &state)?;
+        if records.iter().any(|record| record.inode != 0 && record.name == name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let geometry = volume.geometry();
+        let mut inode = DiskInode::create(geometry.inode_size as usize, mode, links);
+        if file_type == FileType::Regular {
+            return self.add_inode(&mut volume, &mut state, &records, name, inode, |_| Vec::new());
+        }
+
+        let block_size = geometry.block_size as usize;
+        let code = if geometry.has_file_type { dir::TYPE_DIRECTORY } else { dir::TYPE_UNKNOWN };
+        let parent = self.number;
+        inode.set_size(block_size as u64);
+        let node = self.add_inode(&mut volume, &mut state, &records, name, inode, |number| {
+            let mut block = vec![0; block_size];
+            let dot = dir::encode(number, ".", code, 12);
+            let dot_dot = dir::encode(parent, "..", code, block_size - 12);
+            block[..dot.len()].copy_from_slice(&dot);
+            block[12..12 + dot_dot.len()].copy_from_slice(&dot_dot);
+            block
+        })?;
+        // The `..` of the new directory links back here
+        let links = state.inode.links();
+        state.inode.set_links(links + 1);
+        self.save(&volume, &state)?;
+        Ok(node)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>> {
+        dir::validate_name(name)?;
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        let geometry = volume.geometry();
+        if target.is_empty() || target.len() >= geometry.block_size as usize {
+            return Err(FsError::InvalidArgument);
+        }
+        let records = self.load_writable_directory(&volume, &state)?;
+        if records.iter().any(|record| record.inode != 0 && record.name == name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let mut inode = DiskInode::create(geometry.inode_size as usize, S_IFLNK | 0o777, 1);
+        inode.set_size(target.len() as u64);
+        let content = if target.len() < FAST_SYMLINK_LENGTH {
+            inode.set_inline_data(target.as_bytes());
+            Vec::new()
+        } else {
+            target.as_bytes().to_vec()
+        };
+        self.add_inode(&mut volume, &mut state, &records, name, inode, |_| content)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        let records = self.load_writable_directory(&volume, &state)?;
+        let record = records.iter()
+            .find(|record| record.inode != 0 && record.name == name)
+            .ok_or(FsError::NotFound)?;
+        let child = volume.node(record.inode)?;
+        let mut child_state = child.state.lock();
+
+        let links = if child.is_directory() {
+            let child_records = child.load_directory(&volume, &child_state)?;
+            if child_records.iter().any(|record| record.inode != 0 && record.name != "." && record.name != "..") {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+            0
+        } else {
+            child_state.inode.links().saturating_sub(1)
+        };
+        if links == 0 && child_state.inode.attribute_block() != 0 {
+            // The block of extended attributes may be shared
+            return Err(FsError::NotSupported);
+        }
+
+        self.remove_entry(&mut volume, &mut state, &records, record)?;
+        if child.is_directory() {
+            let links = state.inode.links();
+            state.inode.set_links(links.saturating_sub(1));
+            self.save(&volume, &state)?;
+        }
+        child_state.inode.set_links(links);
+        child.save(&volume, &child_state)?;
+        child_state.unlinked = links == 0;
+        // The inode is released now if no one else has the node, which takes
+        // the volume lock
+        drop(child_state);
+        drop(state);
+        drop(volume);
+        drop(child);
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let volume = self.volume.lock();
+        let state = self.state.lock();
+        let mut entries = Vec::new();
+        for record in self.load_directory(&volume, &state)? {
+            if record.inode == 0 || record.name == "." || record.name == ".." {
+                continue;
+            }
+            let file_type = match record.file_type {
+                dir::TYPE_UNKNOWN => file_type(DiskInode::new(volume.read_inode(record.inode)?).file_type()),
+                code => dir::file_type(code),
+            };
+            if let Some(file_type) = file_type {
+                entries.push(DirEntry { name: record.name, inode: record.inode as u64, file_type });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_link(&self) -> Result<String> {
+        if self.kind != S_IFLNK {
+            return Err(FsError::InvalidArgument);
+        }
+        let volume = self.volume.lock();
+        let state = self.state.lock();
+        let size = state.inode.size() as usize;
+        let target = if state.inode.is_fast_symlink(volume.geometry().block_size) {
+            state.inode.inline_data().get(..size).ok_or(FsError::Io)?.to_vec()
+        } else {
+            if size >= volume.geometry().block_size as usize {
+                return Err(FsError::Io);
+            }
+            let mut target = vec![0; size];
+            read_content(&volume, &state.inode, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| FsError::Io)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.volume.device.flush()?)
+    }
+}
@@ -0,0 +1,410 @@
+//! Directory entries: 8.3 short entries and the VFAT long name entries that
+//! precede them.
+//! https://wiki.osdev.org/FAT#Directories
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fs::{FsError, Result};
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+pub const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+pub const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// Read-only, hidden, system and volume ID at once
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+/// First name byte of a free entry followed by no used entry
+const END_MARKER: u8 = 0x00;
+/// First name byte of a deleted entry
+pub const DELETED_MARKER: u8 = 0xE5;
+/// Stands for a first name byte of 0xE5, which is the deleted marker
+const KANJI_MARKER: u8 = 0x05;
+
+/// Windows NT case bits: the base name or the extension is shown in lowercase
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// Set in the sequence number of the last long name entry of a name
+const LAST_LONG_ENTRY: u8 = 0x40;
+const CHARS_PER_LONG_ENTRY: usize = 13;
+/// Where the UCS-2 characters of a long name entry are
+const LONG_NAME_OFFSETS: [usize; CHARS_PER_LONG_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// In UTF-16 code units
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// There is no clock, entries are all dated 2020-01-01 00:00
+const DATE: u16 = (2020 - 1980) << 9 | 1 << 5 | 1;
+const TIME: u16 = 0;
+
+/// Characters allowed in short names besides letters and digits
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+/// Characters not allowed in long names, besides control characters
+const FORBIDDEN: &str = "\"*/:<>?\\|";
+
+/// A file or directory of a directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The long name, or the short one if there is none
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Index of the first slot of the entry, where its long name starts
+    pub first_slot: usize,
+    /// Index of the short entry, the last slot of the entry
+    pub slot: usize,
+}
+
+impl Entry {
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+
+    /// Names are compared without case, and both names of an entry match.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || format_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// A long name being gathered, from its last part to its first.
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    /// Sequence number of the next entry, 0 once complete
+    next: u8,
+    first_slot: usize,
+}
+
+/// Parses the entries of a directory, `.` and `..` included. Deleted entries
+/// and volume labels are skipped.
+pub fn parse(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            END_MARKER => break,
+            DELETED_MARKER => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = raw[11];
+        if attributes & 0x3F == ATTRIBUTE_LONG_NAME {
+            long_name = add_long_entry(long_name.take(), slot, raw);
+            continue;
+        }
+        if attributes & ATTRIBUTE_VOLUME_ID != 0 {
+            long_name = None;
+            continue;
+        }
+
+        let mut short_name = [0; 11];
+        short_name.copy_from_slice(&raw[..11]);
+        if short_name[0] == KANJI_MARKER {
+            short_name[0] = DELETED_MARKER;
+        }
+        let (name, first_slot) = match long_name.take() {
+            Some(long_name) if long_name.next == 0 && long_name.checksum == checksum(&raw[..11]) => {
+                let length = long_name.chars.iter().position(|&c| c == 0).unwrap_or(long_name.chars.len());
+                let name = core::char::decode_utf16(long_name.chars[..length].iter().cloned())
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, long_name.first_slot)
+            }
+            _ => (format_short_name(&short_name, raw[12]), slot),
+        };
+        entries.push(Entry {
+            name,
+            short_name,
+            attributes,
+            first_cluster: (read_u16(raw, 20) as u32) << 16 | read_u16(raw, 26) as u32,
+            size: read_u32(raw, 28),
+            first_slot,
+            slot,
+        });
+    }
+    entries
+}
+
+/// Adds a long name entry to the name being gathered. Entries out of
+/// sequence discard the name.
+fn add_long_entry(long_name: Option<LongName>, slot: usize, raw: &[u8]) -> Option<LongName> {
+    let sequence = raw[0];
+    let mut long_name = if sequence & LAST_LONG_ENTRY != 0 {
+        let count = sequence & !LAST_LONG_ENTRY;
+        if count == 0 || count as usize * CHARS_PER_LONG_ENTRY > MAX_NAME_LENGTH + CHARS_PER_LONG_ENTRY {
+            return None;
+        }
+        LongName { chars: vec![0; count as usize * CHARS_PER_LONG_ENTRY], checksum: raw[13], next: count, first_slot: slot }
+    } else {
+        match long_name {
+            Some(long_name) if long_name.next == sequence && long_name.checksum == raw[13] => long_name,
+            _ => return None,
+        }
+    };
+    if long_name.next == 0 {
+        return None;
+    }
+    let start = (long_name.next as usize - 1) * CHARS_PER_LONG_ENTRY;
+    for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+        long_name.chars[start + i] = read_u16(raw, offset);
+    }
+    long_name.next -= 1;
+    Some(long_name)
+}
+
+/// Index of the first of `count` consecutive free slots.
+pub fn find_free_slots(data: &[u8], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        if raw[0] == END_MARKER || raw[0] == DELETED_MARKER {
+            run += 1;
+            if run == count {
+                return Some(slot + 1 - count);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+/// Checksum of a short name, stored in its long name entries.
+pub fn checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Displays a short name as `NAME.EXT`, lowercased according to the case
+/// bits.
+fn format_short_name(short_name: &[u8; 11], case: u8) -> String {
+    let convert = |bytes: &[u8], lowercase: bool| -> String {
+        let length = bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |i| i + 1);
+        bytes[..length].iter()
+            .map(|&byte| (if lowercase { byte.to_ascii_lowercase() } else { byte }) as char)
+            .collect()
+    };
+    let base = convert(&short_name[..8], case & LOWERCASE_BASE != 0);
+    let extension = convert(&short_name[8..], case & LOWERCASE_EXTENSION != 0);
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+/// Checks that `name` can be stored and returns it in UTF-16.
+pub fn validate_name(name: &str) -> Result<Vec<u16>> {
+    // Windows drops trailing dots and spaces, refuse them instead
+    if name.is_empty() || name.ends_with('.') || name.ends_with(' ')
+        || name.chars().any(|c| c < ' ' || FORBIDDEN.contains(c)) {
+        return Err(FsError::InvalidPath);
+    }
+    let utf16: Vec<u16> = name.encode_utf16().collect();
+    if utf16.len() > MAX_NAME_LENGTH {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(utf16)
+}
+
+fn is_short_name_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&byte)
+}
+
+/// The short name of `name` with its case bits, if `name` is a valid 8.3 name
+/// and needs no long name.
+pub fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    let mut case = 0;
+    for (part, target, lowercase) in [(base, 0, LOWERCASE_BASE), (extension, 8, LOWERCASE_EXTENSION)].iter() {
+        let bytes = part.as_bytes();
+        let has_lower = bytes.iter().any(u8::is_ascii_lowercase);
+        if has_lower && bytes.iter().any(u8::is_ascii_uppercase) {
+            // Mixed case needs a long name
+            return None;
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            let byte = byte.to_ascii_uppercase();
+            if !is_short_name_char(byte) {
+                return None;
+            }
+            short_name[target + i] = byte;
+        }
+        if has_lower {
+            case |= lowercase;
+        }
+    }
+    if short_name[0] == DELETED_MARKER {
+        return None;
+    }
+    Some((short_name, case))
+}
+
+/// Makes up a short name for a long one, `LONGNA~1.TXT` style, that `exists`
+/// doesn't know of yet.
+pub fn generate_short_name(name: &str, exists: impl Fn(&[u8; 11]) -> bool) -> Result<[u8; 11]> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .filter(|&byte| byte != b' ' && byte != b'.')
+            .map(|byte| {
+                let byte = byte.to_ascii_uppercase();
+                if is_short_name_char(byte) { byte } else { b'_' }
+            })
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rfind('.') {
+        Some(i) => (convert(&name[..i]), convert(&name[i + 1..])),
+        None => (convert(name), Vec::new()),
+    };
+    // Non-ASCII characters are several bytes, and all become `_`
+    let base = if base.is_empty() { vec![b'_'] } else { base };
+
+    let mut short_name = [b' '; 11];
+    for (i, &byte) in extension.iter().take(3).enumerate() {
+        short_name[8 + i] = byte;
+    }
+    for number in 1..1_000_000 {
+        let tail = format!("~{}", number);
+        let length = base.len().min(8 - tail.len());
+        short_name[..8].copy_from_slice(b"        ");
+        short_name[..length].copy_from_slice(&base[..length]);
+        short_name[length..length + tail.len()].copy_from_slice(tail.as_bytes());
+        if !exists(&short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FsError::AlreadyExists)
+}
+
+/// Encodes a short entry.
+pub fn short_entry(short_name: &[u8; 11], case: u8, attributes: u8, first_cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    raw[..11].copy_from_slice(short_name);
+    if raw[0] == DELETED_MARKER {
+        raw[0] = KANJI_MARKER;
+    }
+    raw[11] = attributes;
+    raw[12] = case;
+    write_u16(&mut raw, 14, TIME);
+    write_u16(&mut raw, 16, DATE);
+    write_u16(&mut raw, 18, DATE);
+    write_u16(&mut raw, 22, TIME);
+    write_u16(&mut raw, 24, DATE);
+    set_location(&mut raw, first_cluster, size);
+    raw
+}
+
+/// Sets the first cluster and the size of an encoded short entry.
+pub fn set_location(raw: &mut [u8], first_cluster: u32, size: u32) {
+    write_u16(raw, 20, (first_cluster >> 16) as u16);
+    write_u16(raw, 26, first_cluster as u16);
+    write_u32(raw, 28, size);
+}
+
+/// Encodes the long name entries of `name`, in the order they are stored.
+pub fn long_entries(name: &[u16], checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let count = (name.len() + CHARS_PER_LONG_ENTRY - 1) / CHARS_PER_LONG_ENTRY;
+    (0..count).rev()
+        .map(|i| {
+            let mut raw = [0; ENTRY_SIZE];
+            raw[0] = (i + 1) as u8 | if i == count - 1 { LAST_LONG_ENTRY } else { 0 };
+            raw[11] = ATTRIBUTE_LONG_NAME;
+            raw[13] = checksum;
+            for (j, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let index = i * CHARS_PER_LONG_ENTRY + j;
+                // The name ends with a null character, then is padded
+                let c = match index.cmp(&name.len()) {
+                    core::cmp::Ordering::Less => name[index],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                write_u16(&mut raw, offset, c);
+            }
+            raw
+        })
+        .collect()
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+pub fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn long_names_round_trip() {
+        let name: Vec<u16> = "A rather long file name.text".encode_utf16().collect();
+        let short_name = generate_short_name("A rather long file name.text", |name| name == b"ARATHE~1TEX").unwrap();
+        assert_eq!(&short_name, b"ARATHE~2TEX");
+
+        let mut data = Vec::new();
+        for raw in long_entries(&name, checksum(&short_name)) {
+            data.extend_from_slice(&raw);
+        }
+        data.extend_from_slice(&short_entry(&short_name, 0, ATTRIBUTE_ARCHIVE, 0x12345, 42));
+        let (lower, case) = exact_short_name("readme.md").unwrap();
+        data.extend_from_slice(&short_entry(&lower, case, ATTRIBUTE_ARCHIVE, 3, 0));
+
+        let entries = parse(&data);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "A rather long file name.text");
+        assert_eq!((entries[0].first_slot, entries[0].slot), (0, 3));
+        assert_eq!((entries[0].first_cluster, entries[0].size), (0x12345, 42));
+        assert!(entries[0].matches("ARATHE~2.TEX") && entries[0].matches("a RATHER long file name.TEXT"));
+        assert_eq!(entries[1].name, "readme.md");
+
+        // A short entry that doesn't match the checksum keeps its own name
+        data[3 * ENTRY_SIZE] = b'B';
+        assert_eq!(parse(&data)[0].name, "BRATHE~2.TEX");
+        assert_eq!(find_free_slots(&data, 1), None);
+        data[0] = DELETED_MARKER;
+        assert_eq!(find_free_slots(&data, 1), Some(0));
+    }
+
+    #[test_case]
+    fn short_names_are_validated() {
+        assert_eq!(exact_short_name("KERNEL.ELF"), Some((*b"KERNEL  ELF", 0)));
+        assert_eq!(exact_short_name("Kernel.elf"), None);
+        assert_eq!(exact_short_name("kernel.elf").unwrap().1, LOWERCASE_BASE | LOWERCASE_EXTENSION);
+        assert_eq!(exact_short_name("too-long.name"), None);
+        assert_eq!(exact_short_name("a.b.c"), None);
+        assert_eq!(exact_short_name(".hidden"), None);
+        assert_eq!(&generate_short_name(".hidden file", |_| false).unwrap(), b"HIDDEN~1   ");
+        assert_eq!(&generate_short_name("é+ü.c", |_| false).unwrap(), b"_____~1 C  ");
+        assert_eq!(validate_name("what?").err(), Some(FsError::InvalidPath));
+        assert_eq!(validate_name("trailing.").err(), Some(FsError::InvalidPath));
+    }
+}
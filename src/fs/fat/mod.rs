@@ -0,0 +1,771 @@
+//! FAT12, FAT16 and FAT32 file systems, with VFAT long file names.
+//! https://wiki.osdev.org/FAT
+//! https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc
+//!
+//! The type of a volume only depends on its number of clusters. The file
+//! allocation table is loaded a sector at a time as it is used, and every
+//! change is written through to all its copies, so the volume is consistent
+//! after each operation. The FAT32 free cluster count is written back on sync.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::{Mutex, MutexGuard};
+
+use crate::block;
+use crate::block::BlockDevice;
+
+use super::{FileSystem, FsError, Inode, Result};
+
+use self::dir::{read_u16, read_u32, write_u32};
+use self::node::FatNode;
+
+mod dir;
+mod node;
+
+/// Offset of the boot sector signature
+const SIGNATURE_OFFSET: usize = 510;
+const SIGNATURE: u16 = 0xAA55;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+/// Stands for an unknown free count or next free cluster
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The first two FAT entries are reserved, clusters are numbered from 2
+const FIRST_CLUSTER: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Value of the entries ending a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Entries from this value on end a chain
+    fn end_of_chain_min(self) -> u32 {
+        self.end_of_chain() & !7
+    }
+}
+
+/// Layout of a volume, from its BIOS parameter block. Offsets and sizes are
+/// in bytes.
+#[derive(Debug)]
+struct Geometry {
+    fat_type: FatType,
+    cluster_size: u64,
+    fat_offset: u64,
+    fat_size: u64,
+    fat_count: u32,
+    sector_size: u64,
+    /// The fixed root directory of FAT12 and FAT16
+    root_offset: u64,
+    root_size: u64,
+    /// The root directory of FAT32
+    root_cluster: u32,
+    data_offset: u64,
+    cluster_count: u32,
+    fsinfo_offset: Option<u64>,
+}
+
+impl Geometry {
+    fn parse(boot_sector: &[u8], device_size: u64) -> Result<Geometry> {
+        if read_u16(boot_sector, SIGNATURE_OFFSET) != SIGNATURE || (boot_sector[0] != 0xEB && boot_sector[0] != 0xE9) {
+            return Err(FsError::InvalidArgument);
+        }
+        let sector_size = read_u16(boot_sector, 0x0B) as u64;
+        let sectors_per_cluster = boot_sector[0x0D] as u64;
+        let reserved_sectors = read_u16(boot_sector, 0x0E) as u64;
+        let fat_count = boot_sector[0x10] as u32;
+        let root_entries = read_u16(boot_sector, 0x11) as u64;
+        let total_sectors = match read_u16(boot_sector, 0x13) {
+            0 => read_u32(boot_sector, 0x20) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match read_u16(boot_sector, 0x16) {
+            0 => read_u32(boot_sector, 0x24) as u64,
+            sectors => sectors as u64,
+        };
+        if !(512..=4096).contains(&sector_size) || !sector_size.is_power_of_two()
+            || sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0 || fat_count == 0 || fat_sectors == 0
+            || total_sectors * sector_size > device_size {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let root_sectors = (root_entries * dir::ENTRY_SIZE as u64 + sector_size - 1) / sector_size;
+        let data_sector = reserved_sectors + fat_count as u64 * fat_sectors + root_sectors;
+        if data_sector >= total_sectors {
+            return Err(FsError::InvalidArgument);
+        }
+        let cluster_count = (total_sectors - data_sector) / sectors_per_cluster;
+        let fat_type = if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        let fat_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        // The table must have an entry for every cluster, the reserved ones
+        // included
+        if (cluster_count + 2) * fat_bits > fat_sectors * sector_size * 8 || cluster_count > 0x0FFF_FFF5 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let (root_cluster, fsinfo_offset) = if fat_type == FatType::Fat32 {
+            let root_cluster = read_u32(boot_sector, 0x2C);
+            if root_entries != 0 || root_cluster < FIRST_CLUSTER || root_cluster >= cluster_count as u32 + FIRST_CLUSTER {
+                return Err(FsError::InvalidArgument);
+            }
+            let fsinfo_sector = read_u16(boot_sector, 0x30) as u64;
+            let fsinfo_offset = if fsinfo_sector == 0 || fsinfo_sector >= reserved_sectors {
+                None
+            } else {
+                Some(fsinfo_sector * sector_size)
+            };
+            (root_cluster, fsinfo_offset)
+        } else {
+            if root_entries == 0 {
+                return Err(FsError::InvalidArgument);
+            }
+            (0, None)
+        };
+
+        Ok(Geometry {
+            fat_type,
+            cluster_size: sectors_per_cluster * sector_size,
+            fat_offset: reserved_sectors * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fat_count,
+            sector_size,
+            root_offset: (reserved_sectors + fat_count as u64 * fat_sectors) * sector_size,
+            root_size: root_sectors * sector_size,
+            root_cluster,
+            data_offset: data_sector * sector_size,
+            cluster_count: cluster_count as u32,
+            fsinfo_offset,
+        })
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size
+    }
+}
+
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<FatNode>,
+}
+
+impl FatFs {
+    /// Mounts the FAT volume of `device`. Fails with `InvalidArgument` if the
+    /// device holds no FAT volume.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<FatFs>> {
+        let mut boot_sector = vec![0; 512];
+        block::read_bytes(&*device, 0, &mut boot_sector)?;
+        let geometry = Geometry::parse(&boot_sector, device.size())?;
+
+        let mut free_count = None;
+        let mut next_free = FIRST_CLUSTER;
+        if let Some(offset) = geometry.fsinfo_offset {
+            let mut fsinfo = vec![0; 512];
+            block::read_bytes(&*device, offset, &mut fsinfo)?;
+            if read_u32(&fsinfo, 0) == FSINFO_LEAD_SIGNATURE && read_u32(&fsinfo, 484) == FSINFO_STRUCT_SIGNATURE {
+                let count = read_u32(&fsinfo, FSINFO_FREE_COUNT);
+                if count <= geometry.cluster_count {
+                    free_count = Some(count);
+                }
+                let next = read_u32(&fsinfo, FSINFO_NEXT_FREE);
+                if geometry.is_valid_cluster(next) {
+                    next_free = next;
+                }
+            }
+        }
+
+        let root_cluster = geometry.root_cluster;
+        let volume = Arc::new(Volume {
+            device,
+            geometry,
+            state: Mutex::new(VolumeState { fat: BTreeMap::new(), free_count, next_free, nodes: BTreeMap::new() }),
+        });
+        let root = FatNode::root(volume.clone(), root_cluster);
+        Ok(Arc::new(FatFs { volume, root }))
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.geometry.fat_type
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.volume.lock().sync()
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    state: Mutex<VolumeState>,
+}
+
+struct VolumeState {
+    /// Sectors of the first copy of the table loaded so far
+    fat: BTreeMap<u64, Vec<u8>>,
+    /// Only known on FAT32, from the FSInfo sector
+    free_count: Option<u32>,
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    /// Nodes in use, by inode number, so that a file has a single node
+    nodes: BTreeMap<u64, Weak<FatNode>>,
+}
+
+impl Volume {
+    /// Locks the volume. Node states are locked after it.
+    fn lock(self: &Arc<Volume>) -> LockedVolume<'_> {
+        LockedVolume { volume: self, state: self.state.lock() }
+    }
+}
+
+/// A locked volume, through which all the accesses to the disk go.
+struct LockedVolume<'a> {
+    volume: &'a Arc<Volume>,
+    state: MutexGuard<'a, VolumeState>,
+}
+
+impl<'a> LockedVolume<'a> {
+    fn geometry(&self) -> &'a Geometry {
+        &self.volume.geometry
+    }
+
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        Ok(block::read_bytes(&*self.volume.device, offset, buffer)?)
+    }
+
+    fn write(&self, offset: u64, buffer: &[u8]) -> Result<()> {
+        Ok(block::write_bytes(&*self.volume.device, offset, buffer)?)
+    }
+
+    /// Loads a sector of the table if needed.
+    fn fat_sector(&mut self, sector: u64) -> Result<&mut Vec<u8>> {
+        let geometry = self.geometry();
+        if !self.state.fat.contains_key(&sector) {
+            let mut data = vec![0; geometry.sector_size as usize];
+            self.read(geometry.fat_offset + sector * geometry.sector_size, &mut data)?;
+            self.state.fat.insert(sector, data);
+        }
+        Ok(self.state.fat.get_mut(&sector).unwrap())
+    }
+
+    /// Reads bytes of the table, FAT12 entries can straddle two sectors.
+    fn read_fat(&mut self, offset: u64, bytes: &mut [u8]) -> Result<()> {
+        let sector_size = self.geometry().sector_size;
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let position = offset + i as u64;
+            *byte = self.fat_sector(position / sector_size)?[(position % sector_size) as usize];
+        }
+        Ok(())
+    }
+
+    /// Writes bytes of the table to every copy of it.
+    fn write_fat(&mut self, offset: u64, bytes: &[u8]) -> Result<()> {
+        let geometry = self.geometry();
+        let first_sector = offset / geometry.sector_size;
+        let last_sector = (offset + bytes.len() as u64 - 1) / geometry.sector_size;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let position = offset + i as u64;
+            self.fat_sector(position / geometry.sector_size)?[(position % geometry.sector_size) as usize] = byte;
+        }
+        for sector in first_sector..=last_sector {
+            let data = &self.state.fat[&sector];
+            for copy in 0..geometry.fat_count as u64 {
+                let offset = geometry.fat_offset + copy * geometry.fat_size + sector * geometry.sector_size;
+                block::write_bytes(&*self.volume.device, offset, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32> {
+        match self.geometry().fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read_fat(cluster as u64 * 3 / 2, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes) as u32;
+                Ok(if cluster & 1 == 0 { value & 0xFFF } else { value >> 4 })
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read_fat(cluster as u64 * 2, &mut bytes)?;
+                Ok(u16::from_le_bytes(bytes) as u32)
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read_fat(cluster as u64 * 4, &mut bytes)?;
+                // The top 4 bits are reserved
+                Ok(u32::from_le_bytes(bytes) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<()> {
+        match self.geometry().fat_type {
+            FatType::Fat12 => {
+                let offset = cluster as u64 * 3 / 2;
+                let mut bytes = [0; 2];
+                self.read_fat(offset, &mut bytes)?;
+                let old = u16::from_le_bytes(bytes);
+                let new = if cluster & 1 == 0 {
+                    old & 0xF000 | value as u16 & 0xFFF
+                } else {
+                    old & 0x000F | (value as u16) << 4
+                };
+                self.write_fat(offset, &new.to_le_bytes())
+            }
+            FatType::Fat16 => self.write_fat(cluster as u64 * 2, &(value as u16).to_le_bytes()),
+            FatType::Fat32 => {
+                let offset = cluster as u64 * 4;
+                let mut bytes = [0; 4];
+                self.read_fat(offset, &mut bytes)?;
+                let new = u32::from_le_bytes(bytes) & 0xF000_0000 | value & 0x0FFF_FFFF;
+                self.write_fat(offset, &new.to_le_bytes())
+            }
+        }
+    }
+
+    /// Clusters of the chain starting at `first`, none if it is 0.
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>> {
+        let geometry = self.geometry();
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            // A chain longer than the volume loops
+            if !geometry.is_valid_cluster(cluster) || chain.len() >= geometry.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            chain.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            cluster = if next >= geometry.fat_type.end_of_chain_min() { 0 } else { next };
+            if next == 0 {
+                // A free cluster inside a chain
+                return Err(FsError::Io);
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Allocates a cluster at the end of the chain ending with `last`.
+    fn allocate_cluster(&mut self, last: Option<u32>) -> Result<u32> {
+        let geometry = self.geometry();
+        let start = self.state.next_free;
+        for i in 0..geometry.cluster_count {
+            let cluster = FIRST_CLUSTER + (start - FIRST_CLUSTER + i) % geometry.cluster_count;
+            if self.fat_entry(cluster)? == 0 {
+                self.set_fat_entry(cluster, geometry.fat_type.end_of_chain())?;
+                if let Some(last) = last {
+                    self.set_fat_entry(last, cluster)?;
+                }
+                if let Some(count) = &mut self.state.free_count {
+                    *count = count.saturating_sub(1);
+                }
+                self.state.next_free = if geometry.is_valid_cluster(cluster + 1) { cluster + 1 } else { FIRST_CLUSTER };
+                return Ok(cluster);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_clusters(&mut self, clusters: &[u32]) -> Result<()> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+            if let Some(count) = &mut self.state.free_count {
+                *count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Grows or shrinks the chain starting at `first` to `count` clusters, and
+    /// returns the new chain. Nothing changes if the volume is full.
+    fn resize_chain(&mut self, first: u32, count: usize) -> Result<Vec<u32>> {
+        let mut chain = self.chain(first)?;
+        if count <= chain.len() {
+            if count == 0 {
+                self.free_clusters(&chain)?;
+            } else if count < chain.len() {
+                self.set_fat_entry(chain[count - 1], self.geometry().fat_type.end_of_chain())?;
+                self.free_clusters(&chain[count..])?;
+            }
+            chain.truncate(count);
+            return Ok(chain);
+        }
+
+        let length = chain.len();
+        while chain.len() < count {
+            match self.allocate_cluster(chain.last().cloned()) {
+                Ok(cluster) => chain.push(cluster),
+                Err(error) => {
+                    if length > 0 {
+                        self.set_fat_entry(chain[length - 1], self.geometry().fat_type.end_of_chain())?;
+                    }
+                    self.free_clusters(&chain[length..])?;
+                    return Err(error);
+                }
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Reads from the content of the clusters of `chain`, from `offset`.
+    fn read_chain(&self, chain: &[u32], offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let cluster_size = self.geometry().cluster_size;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let cluster = *chain.get((position / cluster_size) as usize).ok_or(FsError::Io)?;
+            let start = position % cluster_size;
+            let count = ((cluster_size - start) as usize).min(buffer.len() - done);
+            self.read(self.geometry().cluster_offset(cluster) + start, &mut buffer[done..done + count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn write_chain(&self, chain: &[u32], offset: u64, buffer: &[u8]) -> Result<()> {
+        let cluster_size = self.geometry().cluster_size;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let cluster = *chain.get((position / cluster_size) as usize).ok_or(FsError::Io)?;
+            let start = position % cluster_size;
+            let count = ((cluster_size - start) as usize).min(buffer.len() - done);
+            self.write(self.geometry().cluster_offset(cluster) + start, &buffer[done..done + count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn zero_chain(&self, chain: &[u32], offset: u64, length: u64) -> Result<()> {
+        let zeroes = vec![0; self.geometry().cluster_size as usize];
+        let mut done = 0;
+        while done < length {
+            let count = (length - done).min(zeroes.len() as u64);
+            self.write_chain(chain, offset + done, &zeroes[..count as usize])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes the FAT32 free cluster count back and flushes the device.
+    fn sync(&mut self) -> Result<()> {
+        if let Some(offset) = self.geometry().fsinfo_offset {
+            let mut fsinfo = vec![0; 512];
+            self.read(offset, &mut fsinfo)?;
+            if read_u32(&fsinfo, 0) == FSINFO_LEAD_SIGNATURE && read_u32(&fsinfo, 484) == FSINFO_STRUCT_SIGNATURE {
+                write_u32(&mut fsinfo, FSINFO_FREE_COUNT, self.state.free_count.unwrap_or(FSINFO_UNKNOWN));
+                write_u32(&mut fsinfo, FSINFO_NEXT_FREE, self.state.next_free);
+                self.write(offset, &fsinfo)?;
+            }
+        }
+        Ok(self.volume.device.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use crate::block::ram::RamDisk;
+    use crate::fs;
+    use crate::fs::testing::{disk, fill, fsck_disk, names};
+    use crate::fs::{FileType, OpenFlags, SeekFrom};
+    use crate::programs;
+
+    use super::dir::{write_u16, ENTRY_SIZE};
+    use super::*;
+
+    const SECTOR_SIZE: usize = 512;
+
+    /// `user/build.sh` makes it with mkfs.fat and mtools
+    static IMAGE: &[u8] = include_bytes!("../../../user/bin/test.fat");
+
+    /// Formats a disk like `mkfs.fat` would. The type follows from the
+    /// number of clusters.
+    fn format(sectors: u64, sectors_per_cluster: u8) -> Arc<dyn BlockDevice> {
+        let disk = RamDisk::new("fat-test", SECTOR_SIZE, sectors);
+        let clusters = |fat_sectors: u64, reserved: u64, root_sectors: u64| {
+            (sectors - reserved - 2 * fat_sectors - root_sectors) / sectors_per_cluster as u64
+        };
+        // FAT32 has no fixed root directory and more reserved sectors
+        let fat32 = clusters(0, 32, 0) >= 65525;
+        let (reserved, root_entries) = if fat32 { (32, 0) } else { (1, 512) };
+        let root_sectors = root_entries * ENTRY_SIZE as u64 / SECTOR_SIZE as u64;
+        let mut fat_sectors = 1;
+        loop {
+            let count = clusters(fat_sectors, reserved, root_sectors) + 2;
+            let bits = if fat32 { 32 } else if count < 4085 + 2 { 12 } else { 16 };
+            let needed = (count * bits + SECTOR_SIZE as u64 * 8 - 1) / (SECTOR_SIZE as u64 * 8);
+            if needed <= fat_sectors {
+                break;
+            }
+            fat_sectors = needed;
+        }
+
+        let mut boot_sector = vec![0; SECTOR_SIZE];
+        boot_sector[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot_sector[3..11].copy_from_slice(b"mkfs.fat");
+        write_u16(&mut boot_sector, 0x0B, SECTOR_SIZE as u16);
+        boot_sector[0x0D] = sectors_per_cluster;
+        write_u16(&mut boot_sector, 0x0E, reserved as u16);
+        boot_sector[0x10] = 2;
+        write_u16(&mut boot_sector, 0x11, root_entries as u16);
+        boot_sector[0x15] = 0xF8;
+        write_u32(&mut boot_sector, 0x20, sectors as u32);
+        if fat32 {
+            write_u32(&mut boot_sector, 0x24, fat_sectors as u32);
+            write_u32(&mut boot_sector, 0x2C, FIRST_CLUSTER);
+            write_u16(&mut boot_sector, 0x30, 1);
+        } else {
+            write_u16(&mut boot_sector, 0x16, fat_sectors as u16);
+        }
+        write_u16(&mut boot_sector, SIGNATURE_OFFSET, SIGNATURE);
+        disk.write_blocks(0, &boot_sector).unwrap();
+
+        let free_count = clusters(fat_sectors, reserved, root_sectors) - 1;
+        let mut fat = vec![0; SECTOR_SIZE];
+        if fat32 {
+            let mut fsinfo = vec![0; SECTOR_SIZE];
+            write_u32(&mut fsinfo, 0, FSINFO_LEAD_SIGNATURE);
+            write_u32(&mut fsinfo, 484, FSINFO_STRUCT_SIGNATURE);
+            write_u32(&mut fsinfo, FSINFO_FREE_COUNT, free_count as u32);
+            write_u32(&mut fsinfo, FSINFO_NEXT_FREE, 3);
+            write_u32(&mut fsinfo, 508, 0xAA55_0000);
+            disk.write_blocks(1, &fsinfo).unwrap();
+            // The root directory takes the first cluster
+            fat[..12].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F]);
+        } else if clusters(fat_sectors, reserved, root_sectors) < 4085 {
+            fat[..3].copy_from_slice(&[0xF8, 0xFF, 0xFF]);
+        } else {
+            fat[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+        }
+        disk.write_blocks(reserved, &fat).unwrap();
+        disk.write_blocks(reserved + fat_sectors, &fat).unwrap();
+        Arc::new(disk)
+    }
+
+    /// Checks the volume like `fsck.fat`: every chain is well formed, no
+    /// cluster is in two chains or lost, sizes match chain lengths, and the
+    /// copies of the table and the free count agree. Returns the number of
+    /// files and directories.
+    fn check(fs: &FatFs) -> usize {
+        let mut volume = fs.volume.lock();
+        let geometry = volume.geometry();
+        let mut owner = vec![false; geometry.cluster_count as usize];
+        let mut claim = |chain: &[u32]| {
+            for &cluster in chain {
+                let owned = &mut owner[(cluster - FIRST_CLUSTER) as usize];
+                assert!(!*owned, "cluster {} is cross-linked", cluster);
+                *owned = true;
+            }
+        };
+        let root = geometry.root_cluster;
+        claim(&volume.chain(root).unwrap());
+        let mut count = 0;
+        let mut directories = vec![(root, 0)];
+        while let Some((first, parent)) = directories.pop() {
+            let data = if first == 0 {
+                let mut data = vec![0; geometry.root_size as usize];
+                volume.read(geometry.root_offset, &mut data).unwrap();
+                data
+            } else {
+                let chain = volume.chain(first).unwrap();
+                let mut data = vec![0; chain.len() * geometry.cluster_size as usize];
+                volume.read_chain(&chain, 0, &mut data).unwrap();
+                data
+            };
+            for entry in dir::parse(&data) {
+                match entry.name.as_str() {
+                    "." => assert_eq!(entry.first_cluster, first, "bad . entry"),
+                    ".." => assert_eq!(entry.first_cluster, parent, "bad .. entry"),
+                    _ => {
+                        count += 1;
+                        let chain = volume.chain(entry.first_cluster).unwrap();
+                        claim(&chain);
+                        if entry.is_directory() {
+                            assert!(!chain.is_empty(), "{} has no cluster", entry.name);
+                            // `..` is 0 for the root, even on FAT32
+                            directories.push((entry.first_cluster, if first == root { 0 } else { first }));
+                        } else {
+                            let clusters = (entry.size as u64 + geometry.cluster_size - 1) / geometry.cluster_size;
+                            assert_eq!(chain.len() as u64, clusters, "{} has a bad size", entry.name);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut free = 0;
+        for (i, &owned) in owner.iter().enumerate() {
+            let entry = volume.fat_entry(i as u32 + FIRST_CLUSTER).unwrap();
+            assert_eq!(entry != 0, owned, "cluster {} is lost", i as u32 + FIRST_CLUSTER);
+            if entry == 0 {
+                free += 1;
+            }
+        }
+        let mut copies = vec![vec![0; geometry.sector_size as usize]; geometry.fat_count as usize];
+        for sector in 0..geometry.fat_size / geometry.sector_size {
+            for (copy, data) in copies.iter_mut().enumerate() {
+                volume.read(geometry.fat_offset + copy as u64 * geometry.fat_size + sector * geometry.sector_size, data).unwrap();
+            }
+            assert!(copies.iter().all(|copy| *copy == copies[0]), "the copies of the table differ");
+        }
+        if geometry.fat_type == FatType::Fat32 {
+            assert_eq!(volume.state.free_count, Some(free));
+        }
+        count
+    }
+
+    /// Mounts the volume, plays with it, and checks what is left after a
+    /// remount. The files already there are left alone.
+    fn exercise(device: Arc<dyn BlockDevice>, fat_type: FatType) {
+        let path = format!("/fat-test-{:?}", fat_type);
+        let fat = FatFs::new(device.clone()).unwrap();
+        assert_eq!(fat.fat_type(), fat_type);
+        let existing = check(&fat);
+        let mut root_names = names(fat.root().read_dir().unwrap());
+        root_names.extend(["A directory", "A long file name.data", "README.TXT", "lower.txt"].iter().map(|&name| String::from(name)));
+        root_names.sort();
+        fs::mkdir(&path).unwrap();
+        fs::mount(&path, fat.clone()).unwrap();
+
+        let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE;
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+        let file = fs::open(&format!("{}/A long file name.data", path), flags).unwrap();
+        assert_eq!(file.write(&data), Ok(data.len()));
+        assert_eq!(file.seek(SeekFrom::Start(0)), Ok(0));
+        let mut buffer = vec![0; 6000];
+        assert_eq!(file.read(&mut buffer), Ok(data.len()));
+        assert_eq!(&buffer[..data.len()], &data[..]);
+
+        // Writing past the end leaves zeroes, truncating frees the clusters
+        assert_eq!(file.seek(SeekFrom::Start(8000)), Ok(8000));
+        assert_eq!(file.write(b"end"), Ok(3));
+        assert_eq!(fs::read_file(&format!("{}/a LONG file name.data", path)).unwrap()[5000..8000], [0; 3000][..]);
+        file.dentry().inode().truncate(100).unwrap();
+        file.dentry().inode().truncate(1000).unwrap();
+        let content = fs::read_file(&format!("{}/A long file name.data", path)).unwrap();
+        assert_eq!(&content[..100], &data[..100]);
+        assert_eq!(content[100..], [0; 900][..]);
+
+        fs::open(&format!("{}/README.TXT", path), flags).unwrap().write(b"short").unwrap();
+        fs::open(&format!("{}/lower.txt", path), flags).unwrap();
+        fs::mkdir(&format!("{}/A directory", path)).unwrap();
+        // Enough long names to grow the directory past its first cluster
+        for i in 0..40 {
+            fs::open(&format!("{}/A directory/File number {}", path, i), flags).unwrap();
+        }
+        for i in 0..20 {
+            fs::unlink(&format!("{}/A directory/File number {}", path, i)).unwrap();
+        }
+        fs::mkdir(&format!("{}/A directory/nested", path)).unwrap();
+        assert_eq!(fs::unlink(&format!("{}/A directory", path)).err(), Some(FsError::DirectoryNotEmpty));
+        assert_eq!(fs::open(&format!("{}/bad|name", path), flags).err(), Some(FsError::InvalidPath));
+        drop(file);
+        fs::unmount(&path).unwrap();
+        assert_eq!(check(&fat), existing + 25);
+
+        // A new mount sees the same files
+        let fat = FatFs::new(device).unwrap();
+        let root = fat.root();
+        assert_eq!(names(root.read_dir().unwrap()), root_names);
+        assert_eq!(root.lookup("A long file name.data").unwrap().metadata().size, 1000);
+        assert_eq!(root.lookup("ALONGF~1.DAT").unwrap().metadata().size, 1000);
+        let directory = root.lookup("A directory").unwrap();
+        assert_eq!(directory.metadata().file_type, FileType::Directory);
+        assert_eq!(directory.read_dir().unwrap().len(), 21);
+        assert_eq!(directory.lookup("File number 39").unwrap().metadata().size, 0);
+
+        // The disks are in memory, only the small one is filled
+        if fat_type == FatType::Fat12 {
+            fill(&root, "big");
+            root.unlink("big").unwrap();
+        }
+        fat.sync().unwrap();
+        assert_eq!(check(&fat), existing + 25);
+        fs::unlink(&path).unwrap();
+    }
+
+    #[test_case]
+    fn mkfs_image_is_usable() {
+        let fat = FatFs::new(disk("fat-image", IMAGE)).unwrap();
+        assert_eq!(check(&fat), 5);
+        let root = fat.root();
+        assert_eq!(names(root.read_dir().unwrap()), ["A long file name.txt", "bin", "etc"]);
+        fs::mkdir("/fat-image").unwrap();
+        fs::mount("/fat-image", fat).unwrap();
+        assert_eq!(fs::read_file("/fat-image/bin/hello").unwrap(), programs::HELLO);
+        assert_eq!(fs::read_file("/fat-image/etc/motd").unwrap(), b"Welcome to krill!\n");
+        assert_eq!(fs::read_file("/fat-image/ALONGF~1.TXT").unwrap(), b"Welcome to krill!\n");
+        fs::unmount("/fat-image").unwrap();
+        fs::unlink("/fat-image").unwrap();
+
+        exercise(disk("fat-image", IMAGE), FatType::Fat12);
+    }
+
+    /// Leaves `fsck.fat` a volume to check, when `user/fsck.sh` runs the
+    /// tests.
+    #[test_case]
+    fn written_image_passes_fsck() {
+        if let Some(device) = fsck_disk("vdb") {
+            exercise(device, FatType::Fat12);
+        }
+    }
+
+    #[test_case]
+    fn fat12_volume_is_usable() {
+        exercise(format(800, 1), FatType::Fat12);
+    }
+
+    #[test_case]
+    fn fat16_volume_is_usable() {
+        exercise(format(20 * 1024, 2), FatType::Fat16);
+    }
+
+    #[test_case]
+    fn fat32_volume_is_usable() {
+        exercise(format(70 * 1024, 1), FatType::Fat32);
+    }
+}
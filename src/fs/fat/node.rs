@@ -0,0 +1,366 @@
+//! Files and directories of a FAT volume.
+//!
+//! A node is identified by the position of its short entry, which never moves
+//! while the entry exists. Its first cluster and size are kept in the node and
+//! written through to the entry.
+
+use core::iter;
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::fs::{DirEntry, FileType, FsError, Inode, Metadata, Result};
+
+use super::dir::{ATTRIBUTE_ARCHIVE, ATTRIBUTE_DIRECTORY, ATTRIBUTE_READ_ONLY, DELETED_MARKER, ENTRY_SIZE};
+use super::{dir, Geometry, LockedVolume, Volume};
+
+/// The root has no entry, other inode numbers are entry offsets divided by
+/// the entry size, which land past the boot sector
+const ROOT_INODE: u64 = 1;
+
+/// Directories can't have more entries than this
+const MAX_DIRECTORY_SIZE: usize = 65536 * ENTRY_SIZE;
+
+pub struct FatNode {
+    inode: u64,
+    volume: Arc<Volume>,
+    is_directory: bool,
+    read_only: bool,
+    /// Offset of the short entry on the device, `None` for the root
+    entry_offset: Option<u64>,
+    state: Mutex<NodeState>,
+}
+
+struct NodeState {
+    /// 0 for an empty file, and for the fixed root directory of FAT12 and
+    /// FAT16
+    first_cluster: u32,
+    /// Always 0 for directories
+    size: u32,
+    /// Unlinked while still open, the clusters are already freed
+    deleted: bool,
+}
+
+/// The content of a directory.
+struct Directory {
+    data: Vec<u8>,
+    /// Where the content is, empty for the fixed root directory
+    chain: Vec<u32>,
+}
+
+impl Directory {
+    /// Offset on the device of the entry in `slot`.
+    fn slot_offset(&self, geometry: &Geometry, slot: usize) -> u64 {
+        let offset = (slot * ENTRY_SIZE) as u64;
+        if self.chain.is_empty() {
+            geometry.root_offset + offset
+        } else {
+            let cluster = self.chain[(offset / geometry.cluster_size) as usize];
+            geometry.cluster_offset(cluster) + offset % geometry.cluster_size
+        }
+    }
+}
+
+impl FatNode {
+    pub(super) fn root(volume: Arc<Volume>, first_cluster: u32) -> Arc<FatNode> {
+        Arc::new(FatNode {
+            inode: ROOT_INODE,
+            volume,
+            is_directory: true,
+            read_only: false,
+            entry_offset: None,
+            state: Mutex::new(NodeState { first_cluster, size: 0, deleted: false }),
+        })
+    }
+
+    /// Returns the node of the entry at `offset`, creating it if no one uses
+    /// it yet.
+    fn node(&self, volume: &mut LockedVolume, offset: u64, entry: &dir::Entry) -> Arc<FatNode> {
+        let inode = offset / ENTRY_SIZE as u64;
+        if let Some(node) = volume.state.nodes.get(&inode).and_then(Weak::upgrade) {
+            return node;
+        }
+        let is_directory = entry.is_directory();
+        let node = Arc::new(FatNode {
+            inode,
+            volume: self.volume.clone(),
+            is_directory,
+            read_only: entry.attributes & ATTRIBUTE_READ_ONLY != 0,
+            entry_offset: Some(offset),
+            state: Mutex::new(NodeState {
+                first_cluster: entry.first_cluster,
+                size: if is_directory { 0 } else { entry.size },
+                deleted: false,
+            }),
+        });
+        volume.state.nodes.insert(inode, Arc::downgrade(&node));
+        node
+    }
+
+    fn load_directory(&self, volume: &mut LockedVolume) -> Result<Directory> {
+        if !self.is_directory {
+            return Err(FsError::NotADirectory);
+        }
+        let state = self.state.lock();
+        if state.deleted {
+            return Err(FsError::NotFound);
+        }
+        let geometry = volume.geometry();
+        if state.first_cluster == 0 {
+            if self.entry_offset.is_some() {
+                // Only the root can have no cluster
+                return Err(FsError::Io);
+            }
+            let mut data = vec![0; geometry.root_size as usize];
+            volume.read(geometry.root_offset, &mut data)?;
+            return Ok(Directory { data, chain: Vec::new() });
+        }
+        let chain = volume.chain(state.first_cluster)?;
+        let mut data = vec![0; chain.len() * geometry.cluster_size as usize];
+        volume.read_chain(&chain, 0, &mut data)?;
+        Ok(Directory { data, chain })
+    }
+
+    /// Adds a zeroed cluster to a directory.
+    fn grow_directory(&self, volume: &mut LockedVolume, directory: &mut Directory) -> Result<()> {
+        let geometry = volume.geometry();
+        if directory.chain.is_empty() || directory.data.len() + geometry.cluster_size as usize > MAX_DIRECTORY_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let size = directory.data.len() as u64;
+        directory.chain = volume.resize_chain(directory.chain[0], directory.chain.len() + 1)?;
+        volume.zero_chain(&directory.chain, size, geometry.cluster_size)?;
+        directory.data.resize(directory.data.len() + geometry.cluster_size as usize, 0);
+        Ok(())
+    }
+
+    /// Allocates the cluster of a new directory with its `.` and `..`
+    /// entries.
+    fn new_directory_cluster(&self, volume: &mut LockedVolume) -> Result<u32> {
+        let geometry = volume.geometry();
+        let cluster = volume.resize_chain(0, 1)?[0];
+        volume.zero_chain(&[cluster], 0, geometry.cluster_size)?;
+        // `..` is 0 when it is the root, even on FAT32
+        let parent = if self.entry_offset.is_none() { 0 } else { self.state.lock().first_cluster };
+        let offset = geometry.cluster_offset(cluster);
+        volume.write(offset, &dir::short_entry(b".          ", 0, ATTRIBUTE_DIRECTORY, cluster, 0))?;
+        volume.write(offset + ENTRY_SIZE as u64, &dir::short_entry(b"..         ", 0, ATTRIBUTE_DIRECTORY, parent, 0))?;
+        Ok(cluster)
+    }
+
+    /// Finds an entry of this directory by name.
+    fn find(&self, volume: &mut LockedVolume, name: &str) -> Result<(Directory, dir::Entry)> {
+        let directory = self.load_directory(volume)?;
+        let entry = dir::parse(&directory.data).into_iter()
+            .find(|entry| !entry.is_dot() && entry.matches(name))
+            .ok_or(FsError::NotFound)?;
+        Ok((directory, entry))
+    }
+
+    /// Writes the first cluster and the size back to the entry.
+    fn update_entry(&self, volume: &LockedVolume, state: &NodeState) -> Result<()> {
+        if let Some(offset) = self.entry_offset {
+            let mut raw = [0; ENTRY_SIZE];
+            volume.read(offset, &mut raw)?;
+            dir::set_location(&mut raw, state.first_cluster, state.size);
+            volume.write(offset, &raw)?;
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self, state: &NodeState) -> Result<()> {
+        if self.is_directory {
+            Err(FsError::IsADirectory)
+        } else if self.read_only {
+            Err(FsError::PermissionDenied)
+        } else if state.deleted {
+            Err(FsError::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn cluster_count(size: u64, geometry: &Geometry) -> usize {
+    ((size + geometry.cluster_size - 1) / geometry.cluster_size) as usize
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        Metadata {
+            inode: self.inode,
+            file_type: if self.is_directory { FileType::Directory } else { FileType::Regular },
+            size: state.size as u64,
+            mode: if self.is_directory { 0o755 } else if self.read_only { 0o444 } else { 0o644 },
+            links: if self.is_directory { 2 } else { 1 },
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        if self.is_directory {
+            return Err(FsError::IsADirectory);
+        }
+        let mut volume = self.volume.lock();
+        let state = self.state.lock();
+        if offset >= state.size as u64 {
+            return Ok(0);
+        }
+        let count = buffer.len().min((state.size as u64 - offset) as usize);
+        let chain = volume.chain(state.first_cluster)?;
+        volume.read_chain(&chain, offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize> {
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        self.check_writable(&state)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        // Sizes are 32 bits
+        let end = offset.checked_add(buffer.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(FsError::NoSpace)?;
+        let size = state.size as u64;
+        let chain = volume.resize_chain(state.first_cluster, cluster_count(end.max(size), volume.geometry()))?;
+        state.first_cluster = chain.first().cloned().unwrap_or(0);
+        if offset > size {
+            // Writing past the end leaves a hole of zeroes
+            volume.zero_chain(&chain, size, offset - size)?;
+        }
+        volume.write_chain(&chain, offset, buffer)?;
+        state.size = end.max(size) as u32;
+        self.update_entry(&volume, &state)?;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        self.check_writable(&state)?;
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let old_size = state.size as u64;
+        let chain = volume.resize_chain(state.first_cluster, cluster_count(size, volume.geometry()))?;
+        state.first_cluster = chain.first().cloned().unwrap_or(0);
+        if size > old_size {
+            volume.zero_chain(&chain, old_size, size - old_size)?;
+        }
+        state.size = size as u32;
+        self.update_entry(&volume, &state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let mut volume = self.volume.lock();
+        let (directory, entry) = self.find(&mut volume, name)?;
+        let offset = directory.slot_offset(volume.geometry(), entry.slot);
+        Ok(self.node(&mut volume, offset, &entry))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let attributes = match file_type {
+            FileType::Directory => ATTRIBUTE_DIRECTORY,
+            FileType::Regular => ATTRIBUTE_ARCHIVE,
+            _ => return Err(FsError::NotSupported),
+        };
+        let utf16 = dir::validate_name(name)?;
+        let mut volume = self.volume.lock();
+        let mut directory = self.load_directory(&mut volume)?;
+        let entries = dir::parse(&directory.data);
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // Names that fit in 8.3 get no long name
+        let (short_name, case, long_entries) = match dir::exact_short_name(name) {
+            Some((short_name, case)) => (short_name, case, Vec::new()),
+            None => {
+                let short_name = dir::generate_short_name(name, |short_name| {
+                    entries.iter().any(|entry| entry.short_name == *short_name)
+                })?;
+                (short_name, 0, dir::long_entries(&utf16, dir::checksum(&short_name)))
+            }
+        };
+        let slot_count = long_entries.len() + 1;
+        let first_slot = loop {
+            match dir::find_free_slots(&directory.data, slot_count) {
+                Some(slot) => break slot,
+                None => self.grow_directory(&mut volume, &mut directory)?,
+            }
+        };
+
+        let first_cluster = if file_type == FileType::Directory { self.new_directory_cluster(&mut volume)? } else { 0 };
+        let short_entry = dir::short_entry(&short_name, case, attributes, first_cluster, 0);
+        for (i, raw) in long_entries.iter().chain(iter::once(&short_entry)).enumerate() {
+            volume.write(directory.slot_offset(volume.geometry(), first_slot + i), raw)?;
+        }
+
+        let entry = dir::Entry {
+            name: name.into(),
+            short_name,
+            attributes,
+            first_cluster,
+            size: 0,
+            first_slot,
+            slot: first_slot + long_entries.len(),
+        };
+        let offset = directory.slot_offset(volume.geometry(), entry.slot);
+        Ok(self.node(&mut volume, offset, &entry))
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut volume = self.volume.lock();
+        let (directory, entry) = self.find(&mut volume, name)?;
+        let geometry = volume.geometry();
+        let inode = directory.slot_offset(geometry, entry.slot) / ENTRY_SIZE as u64;
+        // An open node may have grown its chain since the entry was read
+        let node = volume.state.nodes.get(&inode).and_then(Weak::upgrade);
+        let first_cluster = node.as_ref().map_or(entry.first_cluster, |node| node.state.lock().first_cluster);
+        let chain = volume.chain(first_cluster)?;
+
+        if entry.is_directory() {
+            let mut data = vec![0; chain.len() * geometry.cluster_size as usize];
+            volume.read_chain(&chain, 0, &mut data)?;
+            if dir::parse(&data).iter().any(|entry| !entry.is_dot()) {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        for slot in entry.first_slot..=entry.slot {
+            volume.write(directory.slot_offset(geometry, slot), &[DELETED_MARKER])?;
+        }
+        volume.free_clusters(&chain)?;
+
+        if let Some(node) = node {
+            let mut state = node.state.lock();
+            state.deleted = true;
+            state.first_cluster = 0;
+            state.size = 0;
+        }
+        volume.state.nodes.remove(&inode);
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let mut volume = self.volume.lock();
+        let directory = self.load_directory(&mut volume)?;
+        let geometry = volume.geometry();
+        Ok(dir::parse(&directory.data).into_iter()
+            .filter(|entry| !entry.is_dot())
+            .map(|entry| DirEntry {
+                inode: directory.slot_offset(geometry, entry.slot) / ENTRY_SIZE as u64,
+                file_type: if entry.is_directory() { FileType::Directory } else { FileType::Regular },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.volume.lock().sync()
+    }
+}
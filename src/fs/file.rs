@@ -0,0 +1,165 @@
+//! Open files and file descriptor tables.
+
+use core::ops::BitOr;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{Dentry, DirEntry, FileType, FsError, Metadata, Result};
+
+/// Most files a task can have open at once
+const MAX_OPEN_FILES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Creates the file if it doesn't exist
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// With `CREATE`, fails if the file exists
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 3);
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+    /// Every write goes to the end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 5);
+
+    pub const fn from_bits(bits: u32) -> OpenFlags {
+        OpenFlags(bits & 0x3F)
+    }
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file. Descriptors duplicated or inherited from it share the
+/// offset.
+pub struct File {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl File {
+    pub fn new(dentry: Arc<Dentry>, flags: OpenFlags) -> Arc<File> {
+        Arc::new(File { dentry, flags, offset: Mutex::new(0) })
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.dentry.inode().metadata()
+    }
+
+    /// Reads at the current offset and advances it.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadFileDescriptor);
+        }
+        if self.dentry.file_type() == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        // The lock isn't held during the read, which may block on a device
+        let offset = *self.offset.lock();
+        let count = self.dentry.inode().read_at(offset, buffer)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    /// Writes at the current offset, or at the end in append mode, and
+    /// advances the offset.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let offset = if self.flags.contains(OpenFlags::APPEND) {
+            self.metadata().size
+        } else {
+            *self.offset.lock()
+        };
+        let count = self.dentry.inode().write_at(offset, buffer)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    pub fn seek(&self, position: SeekFrom) -> Result<u64> {
+        let mut offset = self.offset.lock();
+        let new_offset = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(delta) => add_signed(*offset, delta),
+            SeekFrom::End(delta) => add_signed(self.metadata().size, delta),
+        };
+        *offset = new_offset.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    pub fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        self.dentry.inode().read_dir()
+    }
+}
+
+fn add_signed(value: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        value.checked_add(delta as u64)
+    } else {
+        value.checked_sub(delta.wrapping_neg() as u64)
+    }
+}
+
+/// The open files of a task, indexed by file descriptor.
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<File>>>,
+}
+
+impl FileTable {
+    pub const fn new() -> FileTable {
+        FileTable { files: Vec::new() }
+    }
+
+    /// Stores `file` in the lowest free descriptor and returns it.
+    pub fn insert(&mut self, file: Arc<File>) -> Result<usize> {
+        if let Some(fd) = self.files.iter().position(|slot| slot.is_none()) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_OPEN_FILES {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<Arc<File>> {
+        self.files.get(fd).and_then(|slot| slot.clone()).ok_or(FsError::BadFileDescriptor)
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Result<Arc<File>> {
+        self.files.get_mut(fd).and_then(|slot| slot.take()).ok_or(FsError::BadFileDescriptor)
+    }
+}
@@ -0,0 +1,175 @@
+//! Unpacks the initial RAM file system, a cpio archive in the `newc` format.
+//! https://www.kernel.org/doc/html/latest/driver-api/early-userspace/buffer-format.html
+//!
+//! Every entry is a 110-byte ASCII header made of the magic `070701` and 13
+//! 8-digit hexadecimal fields, followed by the NUL terminated name and the
+//! data, both padded to 4 bytes. The archive ends with an entry named
+//! `TRAILER!!!`.
+
+use core::str;
+
+use alloc::format;
+
+use super::{mkdir, open, symlink, FsError, OpenFlags, Result};
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of the mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug)]
+struct Entry<'a> {
+    name: &'a str,
+    mode: u32,
+    data: &'a [u8],
+}
+
+/// Iterates over the entries of an archive, up to the trailer.
+struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Entries<'a> {
+    fn field(header: &[u8], index: usize) -> Result<u32> {
+        let start = MAGIC.len() + index * 8;
+        let digits = str::from_utf8(&header[start..start + 8]).map_err(|_| FsError::InvalidArgument)?;
+        u32::from_str_radix(digits, 16).map_err(|_| FsError::InvalidArgument)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>> {
+        let header = self.archive.get(self.offset..self.offset + HEADER_SIZE).ok_or(FsError::InvalidArgument)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+        let mode = Self::field(header, 1)?;
+        let file_size = Self::field(header, 6)? as usize;
+        let name_size = Self::field(header, 11)? as usize;
+
+        let name_start = self.offset + HEADER_SIZE;
+        let name = self.archive.get(name_start..name_start + name_size).ok_or(FsError::InvalidArgument)?;
+        // Drop the NUL terminator
+        let name = match name.split_last() {
+            Some((&0, name)) => name,
+            _ => return Err(FsError::InvalidArgument),
+        };
+        let name = str::from_utf8(name).map_err(|_| FsError::InvalidPath)?;
+        if name == TRAILER {
+            return Ok(None);
+        }
+
+        let data_start = align(name_start + name_size);
+        let data = self.archive.get(data_start..data_start + file_size).ok_or(FsError::InvalidArgument)?;
+        self.offset = align(data_start + file_size);
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>>;
+
+    fn next(&mut self) -> Option<Result<Entry<'a>>> {
+        self.next_entry().transpose()
+    }
+}
+
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Extracts `archive` into the directory `path`. Parent directories must
+/// come before their content, as `cpio -o` writes them. Existing directories
+/// are reused and existing files overwritten.
+///
+/// Returns the number of unpacked entries.
+pub fn unpack(archive: &[u8], path: &str) -> Result<usize> {
+    let mut count = 0;
+    for entry in (Entries { archive, offset: 0 }) {
+        let entry = entry?;
+        let name = entry.name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        let target = format!("{}/{}", path.trim_end_matches('/'), name);
+        match entry.mode & S_IFMT {
+            S_IFDIR => match mkdir(&target) {
+                Ok(_) | Err(FsError::AlreadyExists) => {}
+                Err(error) => return Err(error),
+            },
+            S_IFREG => {
+                let file = open(&target, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+                if file.write(entry.data)? != entry.data.len() {
+                    return Err(FsError::NoSpace);
+                }
+            }
+            S_IFLNK => {
+                let link = str::from_utf8(entry.data).map_err(|_| FsError::InvalidPath)?;
+                symlink(link, &target)?;
+            }
+            _ => {
+                println!("initramfs: skipping {}, unsupported file type {:#o}", name, entry.mode & S_IFMT);
+                continue;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::fs::{lookup, read_link, unlink, FileType};
+
+    use super::*;
+
+    fn push_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(MAGIC);
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align(archive.len()), 0);
+    }
+
+    #[test_case]
+    fn archive_is_unpacked() {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, ".", S_IFDIR | 0o755, &[]);
+        push_entry(&mut archive, "initramfs-test", S_IFDIR | 0o755, &[]);
+        push_entry(&mut archive, "initramfs-test/motd", S_IFREG | 0o644, b"Welcome to krill\n");
+        push_entry(&mut archive, "initramfs-test/link", S_IFLNK | 0o777, b"motd");
+        push_entry(&mut archive, TRAILER, 0, &[]);
+
+        assert_eq!(unpack(&archive, "/"), Ok(3));
+        let motd = lookup("/initramfs-test/link").unwrap();
+        assert_eq!(motd.file_type(), FileType::Regular);
+        let mut buffer = [0; 32];
+        assert_eq!(motd.inode().read_at(0, &mut buffer), Ok(17));
+        assert_eq!(&buffer[..17], b"Welcome to krill\n");
+        assert_eq!(read_link("/initramfs-test/link").unwrap(), "motd");
+
+        unlink("/initramfs-test/link").unwrap();
+        unlink("/initramfs-test/motd").unwrap();
+        unlink("/initramfs-test").unwrap();
+    }
+
+    #[test_case]
+    fn truncated_archive_is_rejected() {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "truncated", S_IFREG | 0o644, b"data");
+        archive.truncate(HEADER_SIZE + 4);
+        assert_eq!(unpack(&archive, "/"), Err(FsError::InvalidArgument));
+        assert_eq!(unpack(b"070707", "/"), Err(FsError::InvalidArgument));
+    }
+}
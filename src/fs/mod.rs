@@ -0,0 +1,180 @@
+//! Virtual file system.
+//! https://wiki.osdev.org/VFS
+//!
+//! File systems hand out `Inode`s. The VFS wraps them in `Dentry`s, which
+//! remember their name and parent so that paths can be walked in both
+//! directions, and which are cached once looked up. File systems are attached
+//! to the tree with `mount`, and tasks access files through the descriptor
+//! table every task owns. The VFS checks the permission bits of the modes
+//! before calling into the file systems.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::block;
+
+pub use self::file::{File, FileTable, OpenFlags, SeekFrom};
+pub use self::vfs::{Dentry, lookup, mkdir, mount, mounts, open, read_file, read_link, symlink, sync, unlink, unmount};
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+mod file;
+pub mod initramfs;
+#[cfg(test)]
+mod testing;
+pub mod tmpfs;
+mod vfs;
+
+pub type Result<T> = core::result::Result<T, FsError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidPath,
+    /// Too many symbolic links were followed while resolving a path
+    TooManySymlinks,
+    NotSupported,
+    PermissionDenied,
+    BadFileDescriptor,
+    TooManyOpenFiles,
+    Busy,
+    NoSpace,
+    InvalidArgument,
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    /// Unique within its file system
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Unix permission bits
+    pub mode: u16,
+    pub links: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// A file, directory, symbolic link or device of a file system.
+///
+/// Inodes are shared between tasks, so they use interior mutability. Every
+/// operation defaults to `NotSupported`, file systems only implement the ones
+/// that make sense for each kind of inode.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Finds the entry `name` of a directory. `.` and `..` are handled by
+    /// the VFS.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates a regular file or a directory in a directory.
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Removes an entry of a directory. Directories must be empty.
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_link(&self) -> Result<String> {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Writes cached data back to the storage.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Mounts a tmpfs as the root, unpacks the initramfs into it and mounts the
+/// device files on `/dev`.
+pub fn init(initramfs: &[u8]) {
+    vfs::init(tmpfs::TmpFs::new());
+    match initramfs::unpack(initramfs, "/") {
+        Ok(count) => println!("initramfs: {} entries unpacked", count),
+        Err(error) => println!("initramfs: can't unpack: {:?}", error),
+    }
+    match mkdir("/dev") {
+        Ok(_) | Err(FsError::AlreadyExists) => {}
+        Err(error) => panic!("Can't create /dev: {:?}", error),
+    }
+    mount("/dev", devfs::DevFs::new()).expect("Can't mount /dev");
+}
+
+/// Mounts the file systems found on the block devices on `/mnt/<device>`.
+pub fn mount_block_devices() {
+    for device in block::devices() {
+        let (fs, details): (Arc<dyn FileSystem>, String) = if let Ok(fat) = fat::FatFs::new(device.clone()) {
+            let details = format!("{:?}", fat.fat_type());
+            (fat, details)
+        } else if let Ok(ext2) = ext2::Ext2Fs::new(device.clone()) {
+            let details = String::from(if ext2.is_read_only() { "read-only" } else { "read-write" });
+            (ext2, details)
+        } else {
+            continue;
+        };
+        let path = format!("/mnt/{}", device.name());
+        let result = match mkdir("/mnt") {
+            Ok(_) | Err(FsError::AlreadyExists) => mkdir(&path).and_then(|_| mount(&path, fs.clone())),
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(()) => println!("{}: /dev/{} ({}) mounted on {}", fs.name(), device.name(), details, path),
+            Err(error) => println!("{}: can't mount /dev/{} on {}: {:?}", fs.name(), device.name(), path, error),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Helpers shared by the tests of the file systems.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block;
+use crate::block::ram::RamDisk;
+use crate::block::BlockDevice;
+
+use super::{DirEntry, FileType, FsError, Inode};
+
+/// A disk in memory holding a copy of `image`.
+pub fn disk(name: &str, image: &[u8]) -> Arc<dyn BlockDevice> {
+    let disk = RamDisk::new(name, 512, (image.len() / 512) as u64);
+    disk.write_blocks(0, image).unwrap();
+    Arc::new(disk)
+}
+
+/// The disk `user/fsck.sh` attaches as `name`, a copy of a test image the
+/// tests write to and `fsck` checks afterwards. The volume mounted on it at
+/// boot is unmounted, so that it isn't used twice at once. `None` when the
+/// tests run without it.
+pub fn fsck_disk(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let device = block::find(name)?;
+    super::unmount(&format!("/mnt/{}", name)).ok()?;
+    Some(device)
+}
+
+/// The names of directory entries, sorted.
+pub fn names(entries: Vec<DirEntry>) -> Vec<String> {
+    let mut names: Vec<String> = entries.into_iter().map(|entry| entry.name).collect();
+    names.sort();
+    names
+}
+
+/// Writes a file `name` in `directory` until the volume is full. The last
+/// write must fail without leaving anything half allocated, so the size of
+/// the file covers what was written. The file is left to the caller.
+pub fn fill(directory: &Arc<dyn Inode>, name: &str) -> Arc<dyn Inode> {
+    let file = directory.create(name, FileType::Regular).unwrap();
+    let chunk = vec![0xAA; 64 * 1024];
+    let mut size = 0;
+    let error = loop {
+        match file.write_at(size, &chunk) {
+            Ok(written) => size += written as u64,
+            Err(error) => break error,
+        }
+    };
+    assert_eq!(error, FsError::NoSpace);
+    assert_eq!(file.metadata().size, size);
+    file
+}
@@ -0,0 +1,249 @@
+//! File system kept in the kernel heap. Its content is lost on reboot.
+//!
+//! It is the root file system, the initramfs is unpacked into it at boot.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata, Result};
+
+/// Largest file size, to keep a single file from eating the whole heap
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+pub struct TmpFs {
+    root: Arc<Directory>,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<TmpFs> {
+        Arc::new(TmpFs { root: Directory::new(Arc::new(AtomicU64::new(1))) })
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[derive(Clone)]
+enum Entry {
+    Directory(Arc<Directory>),
+    File(Arc<RegularFile>),
+    Symlink(Arc<Symlink>),
+}
+
+impl Entry {
+    fn inode(&self) -> Arc<dyn Inode> {
+        match self {
+            Entry::Directory(directory) => directory.clone(),
+            Entry::File(file) => file.clone(),
+            Entry::Symlink(symlink) => symlink.clone(),
+        }
+    }
+}
+
+struct Directory {
+    inode: u64,
+    /// Shared by all the inodes of the file system
+    next_inode: Arc<AtomicU64>,
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl Directory {
+    fn new(next_inode: Arc<AtomicU64>) -> Arc<Directory> {
+        Arc::new(Directory {
+            inode: next_inode.fetch_add(1, Ordering::Relaxed),
+            next_inode,
+            entries: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn next_inode(&self) -> u64 {
+        self.next_inode.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn insert(&self, name: &str, entry: Entry) -> Result<Arc<dyn Inode>> {
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = entry.inode();
+        entries.insert(name.to_string(), entry);
+        Ok(inode)
+    }
+}
+
+impl Inode for Directory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o755,
+            links: 2,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.entries.lock().get(name).map(Entry::inode).ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let entry = match file_type {
+            FileType::Directory => Entry::Directory(Directory::new(self.next_inode.clone())),
+            FileType::Regular => Entry::File(Arc::new(RegularFile {
+                inode: self.next_inode(),
+                data: Mutex::new(Vec::new()),
+            })),
+            _ => return Err(FsError::NotSupported),
+        };
+        self.insert(name, entry)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>> {
+        let symlink = Symlink { inode: self.next_inode(), target: target.to_string() };
+        self.insert(name, Entry::Symlink(Arc::new(symlink)))
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut entries = self.entries.lock();
+        if let Some(Entry::Directory(directory)) = entries.get(name) {
+            if !directory.entries.lock().is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        // Open files keep their inode alive until they are closed
+        entries.remove(name).map(|_| ()).ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Ok(self.entries.lock().iter()
+            .map(|(name, entry)| {
+                let metadata = entry.inode().metadata();
+                DirEntry { name: name.clone(), inode: metadata.inode, file_type: metadata.file_type }
+            })
+            .collect())
+    }
+}
+
+struct RegularFile {
+    inode: u64,
+    data: Mutex<Vec<u8>>,
+}
+
+impl Inode for RegularFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            file_type: FileType::Regular,
+            size: self.data.lock().len() as u64,
+            mode: 0o644,
+            links: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let data = self.data.lock();
+        if offset >= data.len() as u64 {
+            return Ok(0);
+        }
+        let available = &data[offset as usize..];
+        let count = buffer.len().min(available.len());
+        buffer[..count].copy_from_slice(&available[..count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize> {
+        let end = offset.checked_add(buffer.len() as u64).ok_or(FsError::InvalidArgument)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let mut data = self.data.lock();
+        if end as usize > data.len() {
+            // Writing past the end leaves a hole of zeroes
+            data.resize(end as usize, 0);
+        }
+        data[offset as usize..end as usize].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        self.data.lock().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+struct Symlink {
+    inode: u64,
+    target: String,
+}
+
+impl Inode for Symlink {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            file_type: FileType::Symlink,
+            size: self.target.len() as u64,
+            mode: 0o777,
+            links: 1,
+        }
+    }
+
+    fn read_link(&self) -> Result<String> {
+        Ok(self.target.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::{lookup, mkdir, open, read_link, symlink, unlink, FsError, OpenFlags, SeekFrom};
+
+    #[test_case]
+    fn files_keep_their_content() {
+        let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE;
+        let file = open("/tmpfs-test", flags).unwrap();
+        assert_eq!(file.write(b"hello tmpfs"), Ok(11));
+        assert_eq!(file.seek(SeekFrom::Start(6)), Ok(6));
+        let mut buffer = [0; 16];
+        assert_eq!(file.read(&mut buffer), Ok(5));
+        assert_eq!(&buffer[..5], b"tmpfs");
+
+        let file = open("/tmpfs-test", OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+        assert_eq!(file.metadata().size, 0);
+        unlink("/tmpfs-test").unwrap();
+        assert_eq!(lookup("/tmpfs-test").err(), Some(FsError::NotFound));
+    }
+
+    #[test_case]
+    fn symlinks_are_followed() {
+        mkdir("/symlink-test").unwrap();
+        mkdir("/symlink-test/directory").unwrap();
+        symlink("directory", "/symlink-test/relative").unwrap();
+        symlink("/symlink-test/directory", "/symlink-test/absolute").unwrap();
+        symlink("loop", "/symlink-test/loop").unwrap();
+
+        assert_eq!(lookup("/symlink-test/relative").unwrap().path(), "/symlink-test/directory");
+        assert_eq!(lookup("/symlink-test/absolute/..").unwrap().path(), "/symlink-test");
+        assert_eq!(read_link("/symlink-test/relative").unwrap(), "directory");
+        assert_eq!(lookup("/symlink-test/loop").err(), Some(FsError::TooManySymlinks));
+
+        for name in &["relative", "absolute", "loop", "directory"] {
+            unlink(&alloc::format!("/symlink-test/{}", name)).unwrap();
+        }
+        unlink("/symlink-test").unwrap();
+    }
+}
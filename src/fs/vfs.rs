@@ -0,0 +1,415 @@
+//! Dentries, mount table and path resolution.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::{Mutex, Once};
+
+use super::{File, FileSystem, FileType, FsError, Inode, OpenFlags, Result};
+
+/// How many symbolic links a single path resolution may follow
+const MAX_SYMLINKS: usize = 8;
+
+/// Permission bits, in the owner, group or others class of a mode
+const MAY_READ: u16 = 0o4;
+const MAY_WRITE: u16 = 0o2;
+/// To walk through a directory
+const MAY_SEARCH: u16 = 0o1;
+
+static ROOT: Once<Arc<Dentry>> = Once::new();
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+    /// Directory the file system is mounted over
+    mountpoint: Arc<Dentry>,
+}
+
+/// An inode as seen at a given place of the tree.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    /// `None` for the root. The root of a mounted file system has the parent
+    /// of its mount point, so that `..` leaves the file system.
+    parent: Option<Arc<Dentry>>,
+    /// Entries looked up so far
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    /// Root of the file system mounted over this directory
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    fn new(name: &str, inode: Arc<dyn Inode>, parent: Option<Arc<Dentry>>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            name: name.to_string(),
+            inode,
+            parent,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.inode.metadata().file_type
+    }
+
+    /// Absolute path of this entry.
+    pub fn path(&self) -> String {
+        match &self.parent {
+            None => String::from("/"),
+            Some(parent) => {
+                let mut path = parent.path();
+                if !path.ends_with('/') {
+                    path.push('/');
+                }
+                path.push_str(&self.name);
+                path
+            }
+        }
+    }
+
+    /// Looks up a child through the cache, and steps into the file system
+    /// mounted on it, if any.
+    fn child(self: &Arc<Dentry>, name: &str) -> Result<Arc<Dentry>> {
+        let mut children = self.children.lock();
+        let child = match children.get(name) {
+            Some(child) => child.clone(),
+            None => {
+                let child = Dentry::new(name, self.inode.lookup(name)?, Some(self.clone()));
+                children.insert(name.to_string(), child.clone());
+                child
+            }
+        };
+        drop(children);
+        Ok(follow_mounts(child))
+    }
+
+    fn is_mountpoint(&self) -> bool {
+        self.mounted.lock().is_some()
+    }
+
+    /// Whether something besides the cache holds this entry or one below it,
+    /// like an open file. `owners` is the number of references expected on
+    /// this entry besides the ones of its cached children.
+    fn is_in_use(self: &Arc<Dentry>, owners: usize) -> bool {
+        let children = self.children.lock();
+        // Every cached child holds its parent
+        Arc::strong_count(self) > owners + children.len()
+            || children.values().any(|child| child.is_in_use(1))
+    }
+}
+
+/// Checks the permission bits of an entry. Tasks have no user yet and act as
+/// the owner of every file, so the owner class decides.
+fn check_access(dentry: &Dentry, access: u16) -> Result<()> {
+    if (dentry.inode.metadata().mode >> 6) & access == access {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+fn follow_mounts(mut dentry: Arc<Dentry>) -> Arc<Dentry> {
+    loop {
+        let mounted = dentry.mounted.lock().clone();
+        match mounted {
+            Some(root) => dentry = root,
+            None => return dentry,
+        }
+    }
+}
+
+fn root() -> Arc<Dentry> {
+    follow_mounts(ROOT.r#try().expect("VFS not initialized").clone())
+}
+
+pub(super) fn init(fs: Arc<dyn FileSystem>) {
+    let root = ROOT.call_once(|| Dentry::new("/", fs.root(), None));
+    MOUNTS.lock().push(Mount { path: String::from("/"), fs, mountpoint: root.clone() });
+}
+
+fn resolve(path: &str, follow_last: bool) -> Result<Arc<Dentry>> {
+    let mut symlinks = 0;
+    resolve_at(&root(), path, follow_last, &mut symlinks)
+}
+
+/// Walks `path` from `start`, or from the root for absolute paths.
+fn resolve_at(start: &Arc<Dentry>, path: &str, follow_last: bool, symlinks: &mut usize) -> Result<Arc<Dentry>> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let mut current = if path.starts_with('/') { root() } else { start.clone() };
+    let components: Vec<&str> = path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    for (i, component) in components.iter().enumerate() {
+        if current.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        check_access(&current, MAY_SEARCH)?;
+        if *component == ".." {
+            if let Some(parent) = current.parent.clone() {
+                current = follow_mounts(parent);
+            }
+            continue;
+        }
+        let child = current.child(component)?;
+        let is_last = i == components.len() - 1;
+        if child.file_type() == FileType::Symlink && (follow_last || !is_last) {
+            *symlinks += 1;
+            if *symlinks > MAX_SYMLINKS {
+                return Err(FsError::TooManySymlinks);
+            }
+            // Relative targets start from the directory holding the link
+            let target = child.inode.read_link()?;
+            current = resolve_at(&current, &target, true, symlinks)?;
+        } else {
+            current = child;
+        }
+    }
+    Ok(current)
+}
+
+/// Resolves the directory holding the last component of `path` and returns
+/// it with that component.
+fn resolve_parent(path: &str) -> Result<(Arc<Dentry>, &str)> {
+    let path = path.trim_end_matches('/');
+    let (directory, name) = match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (".", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    let directory = resolve(directory, true)?;
+    if directory.file_type() != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((directory, name))
+}
+
+/// Resolves a path, following symbolic links.
+pub fn lookup(path: &str) -> Result<Arc<Dentry>> {
+    resolve(path, true)
+}
+
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<File>> {
+    let dentry = if flags.contains(OpenFlags::CREATE) {
+        let (directory, name) = resolve_parent(path)?;
+        match directory.child(name) {
+            Ok(_) if flags.contains(OpenFlags::EXCLUSIVE) => return Err(FsError::AlreadyExists),
+            Ok(_) => resolve(path, true)?,
+            Err(FsError::NotFound) => {
+                check_access(&directory, MAY_WRITE | MAY_SEARCH)?;
+                directory.inode.create(name, FileType::Regular)?;
+                // Whatever its mode, the file can be used as asked
+                return Ok(File::new(directory.child(name)?, flags));
+            }
+            Err(error) => return Err(error),
+        }
+    } else {
+        resolve(path, true)?
+    };
+
+    if flags.contains(OpenFlags::READ) {
+        check_access(&dentry, MAY_READ)?;
+    }
+    if flags.contains(OpenFlags::WRITE) {
+        check_access(&dentry, MAY_WRITE)?;
+    }
+    let file_type = dentry.file_type();
+    if file_type == FileType::Directory && flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::IsADirectory);
+    }
+    if file_type == FileType::Regular && flags.contains(OpenFlags::TRUNCATE) && flags.contains(OpenFlags::WRITE) {
+        dentry.inode.truncate(0)?;
+    }
+    Ok(File::new(dentry, flags))
+}
+
+/// Reads a whole file.
+pub fn read_file(path: &str) -> Result<Vec<u8>> {
+    let file = open(path, OpenFlags::READ)?;
+    let mut data = Vec::new();
+    data.resize(file.metadata().size as usize, 0);
+    let mut count = 0;
+    while count < data.len() {
+        match file.read(&mut data[count..])? {
+            0 => break,
+            read => count += read,
+        }
+    }
+    data.truncate(count);
+    Ok(data)
+}
+
+pub fn mkdir(path: &str) -> Result<Arc<Dentry>> {
+    let (directory, name) = resolve_parent(path)?;
+    match directory.child(name) {
+        Ok(_) => return Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => {}
+        Err(error) => return Err(error),
+    }
+    check_access(&directory, MAY_WRITE | MAY_SEARCH)?;
+    directory.inode.create(name, FileType::Directory)?;
+    directory.child(name)
+}
+
+/// Creates a symbolic link at `path` pointing to `target`.
+pub fn symlink(target: &str, path: &str) -> Result<()> {
+    let (directory, name) = resolve_parent(path)?;
+    match directory.child(name) {
+        Ok(_) => return Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => {}
+        Err(error) => return Err(error),
+    }
+    check_access(&directory, MAY_WRITE | MAY_SEARCH)?;
+    directory.inode.symlink(name, target)?;
+    Ok(())
+}
+
+pub fn read_link(path: &str) -> Result<String> {
+    resolve(path, false)?.inode.read_link()
+}
+
+/// Removes a file, a symbolic link or an empty directory.
+pub fn unlink(path: &str) -> Result<()> {
+    let (directory, name) = resolve_parent(path)?;
+    check_access(&directory, MAY_WRITE | MAY_SEARCH)?;
+    let mut children = directory.children.lock();
+    if children.get(name).map_or(false, |child| child.is_mountpoint()) {
+        return Err(FsError::Busy);
+    }
+    directory.inode.unlink(name)?;
+    children.remove(name);
+    Ok(())
+}
+
+/// Attaches `fs` over the directory at `path`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let mountpoint = resolve(path, true)?;
+    if mountpoint.file_type() != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    // `resolve` steps into mounted file systems, so this is never already a
+    // mount point
+    let root = Dentry::new(&mountpoint.name, fs.root(), mountpoint.parent.clone());
+    *mountpoint.mounted.lock() = Some(root.clone());
+    MOUNTS.lock().push(Mount { path: mountpoint.path(), fs, mountpoint });
+    Ok(())
+}
+
+/// Detaches the file system mounted last at `path`, unless file systems are
+/// mounted in it or files of it are open.
+pub fn unmount(path: &str) -> Result<()> {
+    let path = resolve(path, true)?.path();
+    let mut mounts = MOUNTS.lock();
+    let index = mounts.iter().rposition(|mount| mount.path == path).ok_or(FsError::InvalidArgument)?;
+    if index == 0 {
+        // The root can't go away
+        return Err(FsError::Busy);
+    }
+    let nested = format!("{}/", path);
+    if mounts[index + 1..].iter().any(|mount| mount.path.starts_with(&nested)) {
+        return Err(FsError::Busy);
+    }
+    let root = mounts[index].mountpoint.mounted.lock().clone().expect("Mount point without file system");
+    // Held by the mount point and here
+    if root.is_in_use(2) {
+        return Err(FsError::Busy);
+    }
+    // Stays mounted if its data can't be written back
+    mounts[index].fs.sync()?;
+    let mount = mounts.remove(index);
+    *mount.mountpoint.mounted.lock() = None;
+    Ok(())
+}
+
+/// Writes the cached data of every mounted file system back to the storage.
+/// Returns the first error, after trying them all.
+pub fn sync() -> Result<()> {
+    let mounts: Vec<Arc<dyn FileSystem>> = MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    let mut result = Ok(());
+    for fs in mounts {
+        let synced = fs.sync();
+        if result.is_ok() {
+            result = synced;
+        }
+    }
+    result
+}
+
+/// Mount points with the name of the file system mounted there.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.lock().iter().map(|mount| (mount.path.clone(), mount.fs.name())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::tmpfs::TmpFs;
+
+    use super::*;
+
+    #[test_case]
+    fn dot_dot_crosses_mount_points() {
+        assert_eq!(lookup("/dev/../dev/./null").unwrap().path(), "/dev/null");
+        assert_eq!(lookup("/dev/..").unwrap().path(), "/");
+        assert_eq!(lookup("/..").unwrap().path(), "/");
+        assert_eq!(lookup("/dev/null/..").err(), Some(FsError::NotADirectory));
+    }
+
+    #[test_case]
+    fn permissions_are_checked() {
+        // The keyboard can only be read
+        assert!(open("/dev/keyboard", OpenFlags::READ).is_ok());
+        assert_eq!(open("/dev/keyboard", OpenFlags::READ | OpenFlags::WRITE).err(), Some(FsError::PermissionDenied));
+    }
+
+    #[test_case]
+    fn mount_points_are_busy() {
+        assert!(mounts().iter().any(|(path, _)| path == "/dev"));
+        assert_eq!(unlink("/dev").err(), Some(FsError::Busy));
+    }
+
+    #[test_case]
+    fn busy_file_systems_stay_mounted() {
+        mkdir("/vfs-mount").unwrap();
+        mount("/vfs-mount", TmpFs::new()).unwrap();
+        let file = open("/vfs-mount/file", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        mkdir("/vfs-mount/nested").unwrap();
+        mount("/vfs-mount/nested", TmpFs::new()).unwrap();
+        assert_eq!(unmount("/vfs-mount").err(), Some(FsError::Busy));
+        unmount("/vfs-mount/nested").unwrap();
+        assert_eq!(unmount("/vfs-mount").err(), Some(FsError::Busy));
+        drop(file);
+        unmount("/vfs-mount").unwrap();
+        unlink("/vfs-mount").unwrap();
+    }
+
+    #[test_case]
+    fn directories_are_created_and_removed() {
+        mkdir("/vfs-test").unwrap();
+        mkdir("/vfs-test/nested").unwrap();
+        assert_eq!(mkdir("/vfs-test").err(), Some(FsError::AlreadyExists));
+        assert_eq!(unlink("/vfs-test").err(), Some(FsError::DirectoryNotEmpty));
+        unlink("/vfs-test/nested/").unwrap();
+        unlink("/vfs-test").unwrap();
+        assert_eq!(lookup("/vfs-test").err(), Some(FsError::NotFound));
+    }
+}
@@ -3,9 +3,16 @@
 
 use core::mem::size_of;
 
-use crate::inline_asm::{lgdt, ltr, reload_cs};
+use crate::inline_asm::{lgdt, ltr, reload_cs, reload_data_segments};
 use crate::tss::{TaskStateSegment, TSS};
 
+// The user segments are in the order `sysret` expects: data right before code
+pub const KERNEL_CODE_SELECTOR: u16 = 1 << 3;
+pub const KERNEL_DATA_SELECTOR: u16 = 2 << 3;
+pub const USER_DATA_SELECTOR: u16 = 3 << 3 | 3;
+pub const USER_CODE_SELECTOR: u16 = 4 << 3 | 3;
+pub const TSS_SELECTOR: u16 = 5 << 3;
+
 lazy_static! {
     pub static ref GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
 }
@@ -16,6 +23,8 @@ pub struct GlobalDescriptorTable {
     pub null_descriptor: Descriptor,
     pub code_segment: Descriptor,
     pub data_segment: Descriptor,
+    pub user_data_segment: Descriptor,
+    pub user_code_segment: Descriptor,
     pub tss_segment: TSSDescriptor,
 }
 
@@ -25,6 +34,8 @@ impl GlobalDescriptorTable {
             null_descriptor: Descriptor::new(0, 0, 0, 0),
             code_segment: Descriptor::new(0, 0xFFFFF, 0x9A, 0xA),
             data_segment: Descriptor::new(0, 0xFFFFF, 0x92, 0xA),
+            user_data_segment: Descriptor::new(0, 0xFFFFF, 0xF2, 0xA),
+            user_code_segment: Descriptor::new(0, 0xFFFFF, 0xFA, 0xA),
             tss_segment: TSSDescriptor::new(
                 &*TSS as *const _ as u64,
                 (size_of::<TaskStateSegment>() - 1) as u32,
@@ -46,11 +57,11 @@ impl GlobalDescriptorTable {
             address: self as *const _ as u64,
         });
         // Reload the CS
-        let code_segment_selector = 1 << 3;
-        reload_cs(code_segment_selector);
+        reload_cs(KERNEL_CODE_SELECTOR as u64);
+        // The bootloader's selectors would point into the wrong entries
+        reload_data_segments(KERNEL_DATA_SELECTOR);
         // Load the TSS
-        let tss_segment_selector = 3 << 3;
-        ltr(tss_segment_selector);
+        ltr(TSS_SELECTOR);
     }
 }
 
@@ -108,4 +119,33 @@ impl TSSDescriptor {
             base_hi_64: base >> 32,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inline_asm::get_cs;
+
+    use super::*;
+
+    #[test_case]
+    fn code_segment_is_reloaded() {
+        assert_eq!(get_cs(), KERNEL_CODE_SELECTOR);
+    }
+
+    #[test_case]
+    fn user_segments_have_dpl_3() {
+        let gdt = *GDT;
+        assert_eq!(gdt.user_code_segment.access >> 5 & 0b11, 3);
+        assert_eq!(gdt.user_data_segment.access >> 5 & 0b11, 3);
+    }
+
+    #[test_case]
+    fn tss_descriptor_points_to_tss() {
+        let descriptor = GDT.tss_segment;
+        let base = descriptor.base_lo_32 as u64
+            | (descriptor.base_mi_32 as u64) << 16
+            | (descriptor.base_hi_32 as u64) << 24
+            | descriptor.base_hi_64 << 32;
+        assert_eq!(base, &*TSS as *const _ as u64);
+    }
+}
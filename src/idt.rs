@@ -4,11 +4,12 @@ use core::fmt::{Display, Formatter};
 use core::fmt;
 use core::mem::size_of;
 
-use pc_keyboard::{DecodedKey, KeyCode, KeyState};
-
-use crate::inline_asm::{get_cs, lidt, without_interrupts};
-use crate::pic::{PIC_LINE_KEYBOARD, PIC_LINE_TIMER, send_eoi};
-use crate::ps2::read_keyboard_scancode;
+use crate::{apic, irq, task};
+use crate::inline_asm::{get_cs, lidt, read_cr2};
+use crate::irq::{Irq, IRQ_COUNT};
+use crate::paging::PageFaultErrorCode;
+use crate::syscall::entry::syscall_interrupt_entry;
+use crate::syscall::SYSCALL_VECTOR;
 
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
@@ -66,9 +67,13 @@ pub struct InterruptDescriptorTable {
     pub security_exception: Descriptor,
     reserved_3: Descriptor,
     // Hardware interrupts
-    pic_timer: Descriptor,
-    pic_keyboard: Descriptor,
-    interrupts: [Descriptor; 256 - 30],
+    irqs: [Descriptor; IRQ_COUNT],
+    apic_timer: Descriptor,
+    apic_error: Descriptor,
+    interrupts_1: [Descriptor; SYSCALL_VECTOR as usize - 50],
+    syscall: Descriptor,
+    interrupts_2: [Descriptor; 256 - SYSCALL_VECTOR as usize - 2],
+    apic_spurious: Descriptor,
 }
 
 impl InterruptDescriptorTable {
@@ -77,13 +82,13 @@ impl InterruptDescriptorTable {
         InterruptDescriptorTable {
             divide_error: Descriptor::new(divide_error_handler as u64, code_segment, 0b1000_1110),
             debug: Descriptor::new(debug_handler as u64, code_segment, 0b1000_1110),
-            non_maskable_interrupt: Descriptor::new(non_maskable_interrupt_handler as u64, code_segment, 0b1000_1110),
+            non_maskable_interrupt: Descriptor::new(non_maskable_interrupt_handler as u64, code_segment, 0b1000_1110).with_ist(1),
             breakpoint: Descriptor::new(breakpoint_handler as u64, code_segment, 0b1000_1110),
             overflow: Descriptor::new(overflow_handler as u64, code_segment, 0b1000_1110),
             bound_range_exceeded: Descriptor::new(bound_range_exceeded_handler as u64, code_segment, 0b1000_1110),
             invalid_opcode: Descriptor::new(invalid_opcode_handler as u64, code_segment, 0b1000_1110),
             device_not_available: Descriptor::new(device_not_available_handler as u64, code_segment, 0b1000_1110),
-            double_fault: Descriptor::new(double_fault_handler as u64, code_segment, 0b1000_1110),
+            double_fault: Descriptor::new(double_fault_handler as u64, code_segment, 0b1000_1110).with_ist(1),
             coprocessor_segment_overrun: Descriptor::new(coprocessor_segment_overrun_handler as u64, code_segment, 0b1000_1110),
             invalid_tss: Descriptor::new(invalid_tss_handler as u64, code_segment, 0b1000_1110),
             segment_not_present: Descriptor::new(segment_not_present_handler as u64, code_segment, 0b1000_1110),
@@ -93,15 +98,26 @@ impl InterruptDescriptorTable {
             reserved_1: Descriptor::new(unused_handler as u64, 0, 0),
             x87_floating_point: Descriptor::new(x87_floating_point_handler as u64, code_segment, 0b1000_1110),
             alignment_check: Descriptor::new(alignment_check_handler as u64, code_segment, 0b1000_1110),
-            machine_check: Descriptor::new(machine_check_handler as u64, code_segment, 0b1000_1110),
+            machine_check: Descriptor::new(machine_check_handler as u64, code_segment, 0b1000_1110).with_ist(1),
             simd_floating_point: Descriptor::new(simd_floating_point_handler as u64, code_segment, 0b1000_1110),
             virtualization: Descriptor::new(virtualization_handler as u64, code_segment, 0b1000_1110),
             reserved_2: [Descriptor::new(unused_handler as u64, 0, 0); 9],
             security_exception: Descriptor::new(security_exception_handler as u64, code_segment, 0b1000_1110),
             reserved_3: Descriptor::new(unused_handler as u64, 0, 0),
-            pic_timer: Descriptor::new(pic_timer_handler as u64, code_segment, 0b10001110),
-            pic_keyboard: Descriptor::new(pic_keyboard_handler as u64, code_segment, 0b10001110),
-            interrupts: [Descriptor::new(unused_handler as u64, 0, 0); 256 - 30],
+            irqs: {
+                let mut irqs = [Descriptor::new(unused_handler as u64, 0, 0); IRQ_COUNT];
+                for (descriptor, handler) in irqs.iter_mut().zip(IRQ_HANDLERS.iter()) {
+                    *descriptor = Descriptor::new(*handler as u64, code_segment, 0b1000_1110);
+                }
+                irqs
+            },
+            apic_timer: Descriptor::new(apic_timer_handler as u64, code_segment, 0b1000_1110),
+            apic_error: Descriptor::new(apic_error_handler as u64, code_segment, 0b1000_1110),
+            interrupts_1: [Descriptor::new(unused_handler as u64, 0, 0); SYSCALL_VECTOR as usize - 50],
+            // DPL 3 so that user code can use `int 0x80`
+            syscall: Descriptor::new(syscall_interrupt_entry as u64, code_segment, 0b1110_1110),
+            interrupts_2: [Descriptor::new(unused_handler as u64, 0, 0); 256 - SYSCALL_VECTOR as usize - 2],
+            apic_spurious: Descriptor::new(apic_spurious_handler as u64, code_segment, 0b1000_1110),
         }
     }
 
@@ -132,24 +148,44 @@ pub struct Descriptor {
 }
 
 impl Descriptor {
+    /// The handler runs on the stack of the interrupted code, which lets the
+    /// scheduler switch tasks from inside a handler.
     fn new(handler_address: u64, segment_selector: u16, type_and_attributes: u8) -> Descriptor {
         Self {
             handler_address_low: handler_address as u16,
             segment_selector,
-            ist: 1, // Use the first stack of TSS for all the interrupts
+            ist: 0,
             type_and_attributes,
             handler_address_middle: (handler_address >> 16) as u16,
             handler_address_high: (handler_address >> 32) as u32,
             zero: 0,
         }
     }
+
+    /// Switches to the given TSS interrupt stack (1-7) before running the
+    /// handler, for exceptions that can't trust the current stack.
+    fn with_ist(mut self, ist: u8) -> Descriptor {
+        self.ist = ist;
+        self
+    }
+}
+
+/// Exceptions raised in ring 3 only take down the faulty task.
+fn kill_if_user(frame: &StackFrame, exception: &str) {
+    let (ip, cs) = (frame.ip, frame.cs);
+    if cs & 3 == 3 {
+        println!("Task {} killed by {} at {:#x}", task::current_id(), exception, ip);
+        task::exit();
+    }
 }
 
 extern "x86-interrupt" fn divide_error_handler(frame: StackFrame) {
+    kill_if_user(&frame, "divide error");
     panic!("divide_error\nStack frame:\n{}", frame);
 }
 
-extern "x86-interrupt" fn debug_handler(_frame: StackFrame) {
+extern "x86-interrupt" fn debug_handler(frame: StackFrame) {
+    kill_if_user(&frame, "debug exception");
     panic!("debug_handler");
 }
 
@@ -162,19 +198,23 @@ extern "x86-interrupt" fn breakpoint_handler(_frame: StackFrame) {
     println!("Breakpoint handler");
 }
 
-extern "x86-interrupt" fn overflow_handler(_frame: StackFrame) {
+extern "x86-interrupt" fn overflow_handler(frame: StackFrame) {
+    kill_if_user(&frame, "overflow");
     panic!("overflow_handler");
 }
 
-extern "x86-interrupt" fn bound_range_exceeded_handler(_frame: StackFrame) {
+extern "x86-interrupt" fn bound_range_exceeded_handler(frame: StackFrame) {
+    kill_if_user(&frame, "bound range exceeded");
     panic!("bound_range_exceeded_handler");
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(_frame: StackFrame) {
+extern "x86-interrupt" fn invalid_opcode_handler(frame: StackFrame) {
+    kill_if_user(&frame, "invalid opcode");
     panic!("invalid_opcode_handler");
 }
 
-extern "x86-interrupt" fn device_not_available_handler(_frame: StackFrame) {
+extern "x86-interrupt" fn device_not_available_handler(frame: StackFrame) {
+    kill_if_user(&frame, "device not available");
     panic!("device_not_available_handler");
 }
 
@@ -190,27 +230,34 @@ extern "x86-interrupt" fn invalid_tss_handler(_frame: StackFrame, error_code: u6
     panic!("invalid_tss_handler with error code {}", error_code);
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(_frame: StackFrame, error_code: u64) {
+extern "x86-interrupt" fn segment_not_present_handler(frame: StackFrame, error_code: u64) {
+    kill_if_user(&frame, "segment not present");
     panic!("segment_not_present_handler with error code {}", error_code);
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler(_frame: StackFrame, error_code: u64) {
+extern "x86-interrupt" fn stack_segment_fault_handler(frame: StackFrame, error_code: u64) {
+    kill_if_user(&frame, "stack segment fault");
     panic!("stack_segment_fault_handler with error code {}", error_code);
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(_frame: StackFrame, error_code: u64) {
+extern "x86-interrupt" fn general_protection_fault_handler(frame: StackFrame, error_code: u64) {
+    kill_if_user(&frame, "general protection fault");
     panic!("general_protection_fault_handler with error code {}", error_code);
 }
 
-extern "x86-interrupt" fn page_fault_handler(_frame: StackFrame, error_code: u64) {
-    panic!("page_fault_handler with error code {}", error_code);
+extern "x86-interrupt" fn page_fault_handler(frame: StackFrame, error_code: u64) {
+    kill_if_user(&frame, "page fault");
+    panic!("page_fault_handler at address {:#x}: {}\nStack frame:\n{}",
+           read_cr2(), PageFaultErrorCode(error_code), frame);
 }
 
-extern "x86-interrupt" fn x87_floating_point_handler(_frame: StackFrame) {
+extern "x86-interrupt" fn x87_floating_point_handler(frame: StackFrame) {
+    kill_if_user(&frame, "x87 floating point exception");
     panic!("x87_floating_point")
 }
 
-extern "x86-interrupt" fn alignment_check_handler(_frame: StackFrame, error_code: u64) {
+extern "x86-interrupt" fn alignment_check_handler(frame: StackFrame, error_code: u64) {
+    kill_if_user(&frame, "alignment check");
     panic!("alignment_check {}", error_code)
 }
 
@@ -218,7 +265,8 @@ extern "x86-interrupt" fn machine_check_handler(_frame: StackFrame) {
     panic!("machine_check")
 }
 
-extern "x86-interrupt" fn simd_floating_point_handler(_frame: StackFrame) {
+extern "x86-interrupt" fn simd_floating_point_handler(frame: StackFrame) {
+    kill_if_user(&frame, "SIMD floating point exception");
     panic!("simd_floating_point")
 }
 
@@ -235,33 +283,54 @@ extern "x86-interrupt" fn unused_handler(_frame: StackFrame) {
 }
 
 // Hardware interrupt handlers
-extern "x86-interrupt" fn pic_timer_handler(_frame: StackFrame) {
-    print!(".");
-    send_eoi(PIC_LINE_TIMER);
-}
-
-extern "x86-interrupt" fn pic_keyboard_handler(_frame: StackFrame) {
-    let scancode = read_keyboard_scancode();
-    use crate::ps2::KEYBOARD;
-
-    without_interrupts(|| {
-        let mut keyboard = KEYBOARD.lock();
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(decoded_key) = keyboard.process_keyevent(key_event.clone()) {
-                if key_event.state == KeyState::Down {
-                    match key_event.code {
-                        KeyCode::Enter => vga_println!(),
-                        KeyCode::Backspace => {},
-                        KeyCode::Tab => {},
-                        _ => {
-                            if let DecodedKey::Unicode(char) = decoded_key {
-                                vga_print!("{}", char);
-                            }
-                        }
-                    }
-                }
+macro_rules! irq_handlers {
+    ($($line:expr => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_frame: StackFrame) {
+                irq::dispatch(Irq($line));
             }
+        )*
+
+        const IRQ_HANDLERS: [extern "x86-interrupt" fn(StackFrame); IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_handlers!(
+    0 => irq0_handler, 1 => irq1_handler, 2 => irq2_handler, 3 => irq3_handler,
+    4 => irq4_handler, 5 => irq5_handler, 6 => irq6_handler, 7 => irq7_handler,
+    8 => irq8_handler, 9 => irq9_handler, 10 => irq10_handler, 11 => irq11_handler,
+    12 => irq12_handler, 13 => irq13_handler, 14 => irq14_handler, 15 => irq15_handler
+);
+
+extern "x86-interrupt" fn apic_timer_handler(_frame: StackFrame) {
+    apic::timer_interrupt();
+}
+
+extern "x86-interrupt" fn apic_error_handler(_frame: StackFrame) {
+    println!("APIC error {:#x}", apic::local::error_status());
+    apic::send_eoi();
+}
+
+extern "x86-interrupt" fn apic_spurious_handler(_frame: StackFrame) {
+    // Spurious interrupts must not be acknowledged
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inline_asm::int3;
+    use crate::testing::ShouldPanic;
+
+    #[test_case]
+    fn breakpoint_returns() {
+        int3();
+    }
+
+    fn divide_error_panics() {
+        unsafe {
+            llvm_asm!("int $$0" :::: "volatile");
         }
-    });
-    send_eoi(PIC_LINE_KEYBOARD);
-}
\ No newline at end of file
+    }
+
+    #[test_case]
+    const DIVIDE_ERROR_PANICS: ShouldPanic = should_panic!(divide_error_panics);
+}
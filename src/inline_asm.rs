@@ -14,6 +14,38 @@ pub(crate) fn inb(address: u16) -> u8 {
     value
 }
 
+#[inline]
+pub(crate) fn outw(address: u16, value: u16) {
+    unsafe {
+        llvm_asm!("outw $1, $0" :: "N{dx}"(address), "{ax}"(value) :: "volatile");
+    }
+}
+
+#[inline]
+pub(crate) fn inw(address: u16) -> u16 {
+    let value: u16;
+    unsafe {
+        llvm_asm!("inw $1, $0" : "={ax}"(value) : "N{dx}"(address) :: "volatile");
+    }
+    value
+}
+
+#[inline]
+pub(crate) fn outl(address: u16, value: u32) {
+    unsafe {
+        llvm_asm!("outl $1, $0" :: "N{dx}"(address), "{eax}"(value) :: "volatile");
+    }
+}
+
+#[inline]
+pub(crate) fn inl(address: u16) -> u32 {
+    let value: u32;
+    unsafe {
+        llvm_asm!("inl $1, $0" : "={eax}"(value) : "N{dx}"(address) :: "volatile");
+    }
+    value
+}
+
 #[inline]
 pub(crate) fn get_cs() -> u16 {
     let mut segment;
@@ -48,6 +80,17 @@ pub(crate) fn reload_cs(segment_selector: u64) {
     }
 }
 
+/// Loads SS, DS and ES. FS and GS are left alone, loading them would clear
+/// their base on some CPUs.
+#[inline]
+pub(crate) fn reload_data_segments(segment_selector: u16) {
+    unsafe {
+        llvm_asm!("mov $0, %ss; \
+                mov $0, %ds; \
+                mov $0, %es" :: "r" (segment_selector) : "memory");
+    }
+}
+
 #[inline]
 pub(crate) fn ltr(segment_selector: u16) {
     unsafe {
@@ -55,6 +98,55 @@ pub(crate) fn ltr(segment_selector: u16) {
     }
 }
 
+#[inline]
+pub(crate) fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe {
+        llvm_asm!("mov %cr3, $0" : "=r"(value));
+    }
+    value
+}
+
+#[inline]
+pub(crate) fn write_cr3(value: u64) {
+    unsafe {
+        llvm_asm!("mov $0, %cr3" :: "r"(value) : "memory");
+    }
+}
+
+#[inline]
+pub(crate) fn read_cr2() -> u64 {
+    let value: u64;
+    unsafe {
+        llvm_asm!("mov %cr2, $0" : "=r"(value));
+    }
+    value
+}
+
+#[inline]
+pub(crate) fn invlpg(address: u64) {
+    unsafe {
+        llvm_asm!("invlpg ($0)" :: "r"(address) : "memory");
+    }
+}
+
+#[inline]
+pub(crate) fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        llvm_asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile");
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+#[inline]
+pub(crate) fn wrmsr(msr: u32, value: u64) {
+    let (low, high) = (value as u32, (value >> 32) as u32);
+    unsafe {
+        llvm_asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
+    }
+}
+
 #[inline]
 pub(crate) fn disable_interrupts() {
     unsafe {
@@ -69,6 +161,13 @@ pub(crate) fn enable_interrupts() {
     }
 }
 
+#[inline]
+pub(crate) fn int3() {
+    unsafe {
+        llvm_asm!("int3" :::: "volatile");
+    }
+}
+
 #[inline]
 pub(crate) fn io_wait() {
     unsafe {
@@ -87,22 +186,15 @@ pub(crate) fn are_interrupts_enabled() -> bool {
 }
 
 #[inline]
-pub(crate) fn without_interrupts<F>(f: F) where F: Fn() {
-    let were_interrupts_enabled = are_interrupts_enabled();
-    if were_interrupts_enabled {
-        disable_interrupts();
-    }
-    f();
-    if were_interrupts_enabled {
-        enable_interrupts();
+pub(crate) fn hlt() {
+    unsafe {
+        llvm_asm!("hlt" :::: "volatile");
     }
 }
 
 #[inline]
 pub(crate) fn hlt_loop() -> ! {
     loop {
-        unsafe {
-            llvm_asm!("hlt" :::: "volatile");
-        }
+        hlt();
     }
 }
\ No newline at end of file
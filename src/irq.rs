@@ -0,0 +1,157 @@
+//! Hardware interrupt lines, independent of the interrupt controller.
+//!
+//! Drivers register a handler for an ISA IRQ line and don't need to know
+//! whether the 8259 PIC or the I/O APIC delivers it: the line is unmasked on
+//! the active controller and the handler is called with the EOI taken care of.
+
+use core::fmt;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::sync::IrqMutex;
+use crate::{apic, pic, task};
+
+/// IDT vector of IRQ 0. IRQs 0-15 use vectors 32-47 on both controllers.
+pub const IRQ_BASE_VECTOR: u8 = 32;
+pub const IRQ_COUNT: usize = 16;
+
+/// Called in interrupt context with interrupts disabled. Handlers must not
+/// block, but they can wake tasks up.
+pub type IrqHandler = fn(Irq);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Irq(pub u8);
+
+impl Irq {
+    pub const TIMER: Irq = Irq(0);
+    pub const KEYBOARD: Irq = Irq(1);
+    pub const CASCADE: Irq = Irq(2);
+    pub const COM2: Irq = Irq(3);
+    pub const COM1: Irq = Irq(4);
+    pub const RTC: Irq = Irq(8);
+    pub const MOUSE: Irq = Irq(12);
+    pub const PRIMARY_ATA: Irq = Irq(14);
+    pub const SECONDARY_ATA: Irq = Irq(15);
+
+    pub fn vector(self) -> u8 {
+        IRQ_BASE_VECTOR + self.0
+    }
+
+    /// What the line is wired to on a PC, for the lines with a fixed use.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Irq::TIMER => Some("timer"),
+            Irq::KEYBOARD => Some("keyboard"),
+            Irq::CASCADE => Some("cascade"),
+            Irq::COM2 => Some("COM2"),
+            Irq::COM1 => Some("COM1"),
+            Irq::RTC => Some("RTC"),
+            Irq::MOUSE => Some("mouse"),
+            Irq::PRIMARY_ATA => Some("primary ATA"),
+            Irq::SECONDARY_ATA => Some("secondary ATA"),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Irq {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "IRQ {}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Controller {
+    Pic = 0,
+    Apic = 1,
+}
+
+static CONTROLLER: AtomicU8 = AtomicU8::new(Controller::Pic as u8);
+static HANDLERS: IrqMutex<[Option<IrqHandler>; IRQ_COUNT]> = IrqMutex::new([None; IRQ_COUNT]);
+
+lazy_static! {
+    static ref IRQ_COUNTS: [AtomicU64; IRQ_COUNT] = Default::default();
+}
+
+pub fn controller() -> Controller {
+    match CONTROLLER.load(Ordering::Relaxed) {
+        0 => Controller::Pic,
+        _ => Controller::Apic,
+    }
+}
+
+/// Switches IRQ delivery to another controller, carrying the unmasked lines over.
+pub fn set_controller(controller: Controller) {
+    let handlers = HANDLERS.lock();
+    CONTROLLER.store(controller as u8, Ordering::Relaxed);
+    for (line, handler) in handlers.iter().enumerate() {
+        if handler.is_some() {
+            unmask(Irq(line as u8));
+        }
+    }
+}
+
+/// Installs `handler` for `irq` and unmasks the line.
+pub fn register_handler(irq: Irq, handler: IrqHandler) {
+    let mut handlers = HANDLERS.lock();
+    if handlers[irq.0 as usize].is_some() {
+        panic!("{} already has a handler", irq);
+    }
+    handlers[irq.0 as usize] = Some(handler);
+    unmask(irq);
+}
+
+/// Masks `irq` and removes its handler.
+pub fn unregister_handler(irq: Irq) {
+    let mut handlers = HANDLERS.lock();
+    mask(irq);
+    handlers[irq.0 as usize] = None;
+}
+
+pub fn mask(irq: Irq) {
+    match controller() {
+        Controller::Pic => pic::mask(irq),
+        Controller::Apic => apic::mask(irq),
+    }
+}
+
+pub fn unmask(irq: Irq) {
+    match controller() {
+        Controller::Pic => pic::unmask(irq),
+        Controller::Apic => apic::unmask(irq),
+    }
+}
+
+fn end_of_interrupt(irq: Irq) {
+    match controller() {
+        Controller::Pic => pic::send_eoi(irq),
+        Controller::Apic => apic::send_eoi(),
+    }
+}
+
+/// Number of times each IRQ fired since boot.
+pub fn counts() -> [u64; IRQ_COUNT] {
+    let mut counts = [0; IRQ_COUNT];
+    for (count, irq_count) in counts.iter_mut().zip(IRQ_COUNTS.iter()) {
+        *count = irq_count.load(Ordering::Relaxed);
+    }
+    counts
+}
+
+/// Entry point of the IRQ vectors of the IDT.
+pub(crate) fn dispatch(irq: Irq) {
+    if controller() == Controller::Pic && pic::is_spurious(irq) {
+        return;
+    }
+    IRQ_COUNTS[irq.0 as usize].fetch_add(1, Ordering::Relaxed);
+
+    let handler = HANDLERS.lock()[irq.0 as usize];
+    if let Some(handler) = handler {
+        handler(irq);
+    }
+    end_of_interrupt(irq);
+    // Only switch tasks after the EOI, the next task may not come back here
+    // for a while
+    task::reschedule_if_needed();
+}
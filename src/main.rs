@@ -4,9 +4,16 @@
 #![feature(format_args_nl)]
 #![feature(abi_x86_interrupt)]
 #![feature(stmt_expr_attributes)]
+#![feature(alloc_error_handler)]
+#![feature(global_asm)]
+#![feature(const_mut_refs)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![no_std]
 #![no_main]
 
+extern crate alloc;
 #[macro_use]
 extern crate lazy_static;
 extern crate pc_keyboard;
@@ -14,9 +21,13 @@ extern crate spin;
 
 use core::panic::PanicInfo;
 
-use crate::inline_asm::hlt_loop;
+use bootloader::{BootInfo, entry_point};
+
+use crate::apic::ApicConfig;
+use crate::elf::ExecError;
+use crate::inline_asm::enable_interrupts;
 use crate::pic::init_pic;
-use crate::serial::{COM1, COM2, COM3, COM4, Serial};
+use crate::serial::{COM1, LineConfig, Serial};
 
 mod libstd;
 mod inline_asm;
@@ -29,29 +40,87 @@ mod gdt;
 mod tss;
 mod pic;
 mod ps2;
+mod memory;
+mod paging;
+mod allocator;
+mod sync;
+mod task;
+mod pit;
+mod irq;
+mod apic;
+mod acpi;
+mod address_space;
+mod elf;
+mod fs;
+mod block;
+mod virtio;
+mod programs;
+mod pci;
+mod syscall;
+mod shell;
+#[cfg(test)]
+#[macro_use]
+mod testing;
+
+/// Frequency of the system tick, in Hz
+const TIMER_FREQUENCY: u32 = 1000;
+
+entry_point!(kernel_main);
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    Serial(COM1).init(38400);
-    Serial(COM2).init(38400);
-    Serial(COM3).init(38400);
-    Serial(COM4).init(38400);
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    serial::init(LineConfig::new(38400));
 
     gdt::GDT.load();
     idt::IDT.load();
+    syscall::init();
 
-    {
-        let mut vga_text_state = vga::VGA_TEXT_STATE.lock();
-        vga_text_state.clear_screen();
-        vga_text_state.enable_cursor();
+    memory::init(&boot_info.memory_map, boot_info.physical_memory_offset);
+    paging::init();
+    allocator::init().expect("Heap initialization failed");
+    task::init();
+    init_pic();
+    pit::init(TIMER_FREQUENCY);
+    ps2::init();
+    let apic_config = match acpi::init() {
+        Ok(acpi) => acpi.madt.as_ref().map(|madt| madt.apic_config()),
+        Err(error) => {
+            println!("ACPI unavailable: {:?}", error);
+            None
+        }
+    };
+    match apic::init(&apic_config.unwrap_or_else(ApicConfig::legacy), TIMER_FREQUENCY) {
+        Ok(()) => println!("Interrupts delivered by the APIC"),
+        Err(error) => println!("Interrupts delivered by the 8259 PIC, APIC unavailable: {:?}", error),
     }
-    vga_print!("Keyboard support: ");
+    pci::init();
+    pci::dump(&mut Serial(COM1)).unwrap();
+    fs::init(programs::INITRAMFS);
+    block::ata::init();
+    block::virtio::init();
+    fs::mount_block_devices();
+    // The boot log is written synchronously up to here, nothing is lost if
+    // the boot hangs
+    serial::init_interrupts();
+    enable_interrupts();
 
-    init_pic();
-    hlt_loop();
+    #[cfg(test)]
+    test_main();
+
+    vga::init();
+    let hello = fs::read_file("/bin/hello").map_err(ExecError::from)
+        .and_then(|image| elf::exec("hello", &image, &["hello"], &[]));
+    if let Err(error) = hello {
+        println!("Can't start /bin/hello: {:?}", error);
+    }
+    for terminal in 0..vga::TERMINAL_COUNT {
+        task::spawn("shell", move || shell::run(terminal));
+    }
+
+    task::idle();
 }
 
 /// Function called on panic
+#[cfg(not(test))]
 #[panic_handler]
 #[allow(unused_must_use)]
 fn panic(info: &PanicInfo) -> ! {
@@ -79,5 +148,12 @@ fn panic(info: &PanicInfo) -> ! {
         println!("{}", s);
     }
     println!("-------------------------------------------------");
+    serial::flush_all();
     loop {}
 }
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::panic_handler(info)
+}
@@ -0,0 +1,281 @@
+//! Physical memory management.
+//! https://wiki.osdev.org/Page_Frame_Allocation
+
+use core::fmt;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+
+use crate::paging::translate_addr;
+use crate::tss::interrupt_stack_bounds;
+
+pub const FRAME_SIZE: u64 = 4096;
+/// The frame allocator ignores physical memory above this address.
+const MAX_PHYSICAL_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+const FRAME_COUNT: usize = (MAX_PHYSICAL_MEMORY / FRAME_SIZE) as usize;
+
+pub static FRAME_ALLOCATOR: Mutex<BitmapFrameAllocator> = Mutex::new(BitmapFrameAllocator::new());
+
+/// Virtual address at which the bootloader maps the whole physical memory.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct PhysAddr(pub u64);
+
+impl PhysAddr {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Virtual address through which this physical address can be accessed.
+    pub fn to_virt(self) -> VirtAddr {
+        VirtAddr(self.0 + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+    }
+}
+
+impl Display for PhysAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct VirtAddr(pub u64);
+
+impl VirtAddr {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+}
+
+impl Display for VirtAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// A 4 KiB physical memory frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysFrame {
+    pub start: PhysAddr,
+}
+
+impl PhysFrame {
+    pub fn containing_address(address: PhysAddr) -> PhysFrame {
+        PhysFrame { start: PhysAddr(address.0 & !(FRAME_SIZE - 1)) }
+    }
+
+    fn from_number(number: usize) -> PhysFrame {
+        PhysFrame { start: PhysAddr(number as u64 * FRAME_SIZE) }
+    }
+
+    fn number(self) -> usize {
+        (self.start.0 / FRAME_SIZE) as usize
+    }
+}
+
+pub trait FrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame>;
+    fn deallocate_frame(&mut self, frame: PhysFrame);
+}
+
+/// Keeps one bit per frame, set if the frame is free. Starting out with every
+/// frame in use keeps the bitmap in .bss instead of bloating the kernel image.
+pub struct BitmapFrameAllocator {
+    bitmap: [u64; FRAME_COUNT / 64],
+    /// Every frame below this one is known to be in use.
+    next_free: usize,
+    usable_frames: usize,
+    free_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    pub const fn new() -> BitmapFrameAllocator {
+        BitmapFrameAllocator {
+            bitmap: [0; FRAME_COUNT / 64],
+            next_free: 0,
+            usable_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Marks the usable regions of the memory map as free.
+    fn add_memory_map(&mut self, memory_map: &MemoryMap) {
+        for region in memory_map.iter() {
+            if region.region_type != MemoryRegionType::Usable {
+                continue;
+            }
+            let start = region.range.start_frame_number as usize;
+            let end = (region.range.end_frame_number as usize).min(FRAME_COUNT);
+            for number in start..end {
+                if self.is_used(number) {
+                    self.set_used(number, false);
+                    self.usable_frames += 1;
+                    self.free_frames += 1;
+                }
+            }
+        }
+        self.next_free = 0;
+    }
+
+    /// Marks all the frames that overlap `start..end` as used.
+    pub fn reserve_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let first = PhysFrame::containing_address(start).number();
+        let last = ((end.0 + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        for number in first..last.min(FRAME_COUNT) {
+            if !self.is_used(number) {
+                self.set_used(number, true);
+                self.free_frames -= 1;
+            }
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames, for devices that
+    /// access memory directly. They are freed one by one.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut start = self.next_free;
+        for number in self.next_free..FRAME_COUNT {
+            if self.is_used(number) {
+                start = number + 1;
+                continue;
+            }
+            if number + 1 - start == count {
+                for number in start..start + count {
+                    self.set_used(number, true);
+                }
+                self.free_frames -= count;
+                if start == self.next_free {
+                    self.next_free = start + count;
+                }
+                return Some(PhysFrame::from_number(start));
+            }
+        }
+        None
+    }
+
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    fn is_used(&self, number: usize) -> bool {
+        self.bitmap[number / 64] & (1 << (number % 64)) == 0
+    }
+
+    fn set_used(&mut self, number: usize, used: bool) {
+        if used {
+            self.bitmap[number / 64] &= !(1 << (number % 64));
+        } else {
+            self.bitmap[number / 64] |= 1 << (number % 64);
+        }
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let mut word = self.next_free / 64;
+        while word < self.bitmap.len() {
+            if self.bitmap[word] != 0 {
+                let number = word * 64 + self.bitmap[word].trailing_zeros() as usize;
+                self.set_used(number, true);
+                self.free_frames -= 1;
+                self.next_free = number + 1;
+                return Some(PhysFrame::from_number(number));
+            }
+            word += 1;
+        }
+        None
+    }
+
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let number = frame.number();
+        if !self.is_used(number) {
+            panic!("Double free of frame {}", frame.start);
+        }
+        self.set_used(number, false);
+        self.free_frames += 1;
+        if number < self.next_free {
+            self.next_free = number;
+        }
+    }
+}
+
+/// Sets up the frame allocator from the memory map handed over by the bootloader.
+///
+/// Only `Usable` regions are handed out, so the kernel image, its stack and the
+/// bootloader page tables stay reserved. The interrupt stack of the TSS lives in
+/// the kernel's .bss and is reserved explicitly in case the bootloader reports
+/// part of it as usable.
+pub fn init(memory_map: &'static MemoryMap, physical_memory_offset: u64) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.add_memory_map(memory_map);
+
+    let (stack_start, stack_end) = interrupt_stack_bounds();
+    let mut page = stack_start & !(FRAME_SIZE - 1);
+    while page < stack_end {
+        if let Some(frame) = translate_addr(VirtAddr(page)) {
+            allocator.reserve_range(frame, PhysAddr(frame.0 + FRAME_SIZE));
+        }
+        page += FRAME_SIZE;
+    }
+
+    println!("Physical memory map:");
+    for region in memory_map.iter() {
+        println!("  {:#012x}-{:#012x} {:?}",
+                 region.range.start_addr(),
+                 region.range.end_addr(),
+                 region.region_type);
+    }
+    println!("Usable memory: {} KiB, free: {} KiB",
+             allocator.usable_frames() as u64 * FRAME_SIZE / 1024,
+             allocator.free_frames() as u64 * FRAME_SIZE / 1024);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn allocate_and_free_frame() {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let free_frames = allocator.free_frames();
+        let frame = allocator.allocate_frame().expect("out of frames");
+        assert_eq!(frame.start.0 % FRAME_SIZE, 0);
+        assert_eq!(allocator.free_frames(), free_frames - 1);
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.free_frames(), free_frames);
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+        allocator.deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn allocate_contiguous_frames() {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let free_frames = allocator.free_frames();
+        let first = allocator.allocate_contiguous(4).expect("out of frames");
+        assert_eq!(allocator.free_frames(), free_frames - 4);
+        for i in 0..4 {
+            let frame = PhysFrame::from_number(first.number() + i);
+            assert!(allocator.is_used(frame.number()));
+            allocator.deallocate_frame(frame);
+        }
+        assert_eq!(allocator.free_frames(), free_frames);
+    }
+}
@@ -0,0 +1,480 @@
+//! Four-level page table management.
+//! https://wiki.osdev.org/Paging
+//! https://os.phil-opp.com/paging-introduction/
+
+use core::fmt;
+use core::fmt::{Display, Formatter};
+use core::ops::{BitOr, BitOrAssign};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::inline_asm::{invlpg, read_cr3, write_cr3};
+use crate::memory::{FRAME_ALLOCATOR, FrameAllocator, PhysAddr, VirtAddr};
+
+const ENTRY_COUNT: usize = 512;
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// Virtual address window in which `map_mmio` maps device memory
+const MMIO_START: u64 = 0x5555_0000_0000;
+const MMIO_SIZE: u64 = 0x1_0000_0000;
+
+static NEXT_MMIO_ADDRESS: AtomicU64 = AtomicU64::new(MMIO_START);
+/// PML4 set up by the bootloader, holding the kernel mappings
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PageTableFlags(u64);
+
+impl PageTableFlags {
+    pub const PRESENT: PageTableFlags = PageTableFlags(1);
+    pub const WRITABLE: PageTableFlags = PageTableFlags(1 << 1);
+    pub const USER_ACCESSIBLE: PageTableFlags = PageTableFlags(1 << 2);
+    pub const WRITE_THROUGH: PageTableFlags = PageTableFlags(1 << 3);
+    pub const NO_CACHE: PageTableFlags = PageTableFlags(1 << 4);
+    pub const ACCESSED: PageTableFlags = PageTableFlags(1 << 5);
+    pub const DIRTY: PageTableFlags = PageTableFlags(1 << 6);
+    pub const HUGE_PAGE: PageTableFlags = PageTableFlags(1 << 7);
+    pub const GLOBAL: PageTableFlags = PageTableFlags(1 << 8);
+    pub const NO_EXECUTE: PageTableFlags = PageTableFlags(1 << 63);
+
+    pub const fn empty() -> PageTableFlags {
+        PageTableFlags(0)
+    }
+
+    pub fn contains(self, other: PageTableFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn remove(&mut self, other: PageTableFlags) {
+        self.0 &= !other.0;
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl BitOr for PageTableFlags {
+    type Output = PageTableFlags;
+
+    fn bitor(self, rhs: PageTableFlags) -> PageTableFlags {
+        PageTableFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PageTableFlags {
+    fn bitor_assign(&mut self, rhs: PageTableFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn set_unused(&mut self) {
+        self.0 = 0;
+    }
+
+    pub fn flags(&self) -> PageTableFlags {
+        PageTableFlags(self.0 & !ADDRESS_MASK)
+    }
+
+    pub fn address(&self) -> PhysAddr {
+        PhysAddr(self.0 & ADDRESS_MASK)
+    }
+
+    pub fn set(&mut self, address: PhysAddr, flags: PageTableFlags) {
+        self.0 = (address.0 & ADDRESS_MASK) | flags.0;
+    }
+
+    pub fn set_flags(&mut self, flags: PageTableFlags) {
+        self.0 = (self.0 & ADDRESS_MASK) | flags.0;
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    pub entries: [PageTableEntry; ENTRY_COUNT],
+}
+
+impl PageTable {
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.set_unused();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+}
+
+impl PageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 4096,
+            PageSize::Size2MiB => 2 * 1024 * 1024,
+        }
+    }
+
+    /// Level of the page table in which pages of this size are mapped.
+    fn level(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 1,
+            PageSize::Size2MiB => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub start: VirtAddr,
+    pub size: PageSize,
+}
+
+impl Page {
+    pub fn containing_address(address: VirtAddr, size: PageSize) -> Page {
+        Page { start: VirtAddr(address.0 & !(size.bytes() - 1)), size }
+    }
+
+    /// Index of this page in the page table of the given level (4 = PML4).
+    fn table_index(&self, level: usize) -> usize {
+        ((self.start.0 >> (12 + 9 * (level - 1))) & 0x1FF) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    NotAligned,
+    PageAlreadyMapped(PhysAddr),
+    /// A larger page already covers the requested page.
+    ParentEntryHugePage,
+    FrameAllocationFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmapError {
+    PageNotMapped,
+    ParentEntryHugePage,
+    /// The page is mapped with a different page size.
+    SizeMismatch,
+}
+
+/// Edits the page tables of one address space, identified by its PML4.
+///
+/// Page tables are accessed through the physical memory mapping set up by the
+/// bootloader, so no recursive mapping is needed.
+pub struct Mapper {
+    pml4: PhysAddr,
+}
+
+impl Mapper {
+    /// The address space currently loaded in CR3.
+    pub fn active() -> Mapper {
+        Mapper { pml4: PhysAddr(read_cr3() & ADDRESS_MASK) }
+    }
+
+    pub fn new(pml4: PhysAddr) -> Mapper {
+        Mapper { pml4 }
+    }
+
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4
+    }
+
+    pub fn map_to(&mut self, page: Page, frame: PhysAddr, flags: PageTableFlags,
+                  allocator: &mut impl FrameAllocator) -> Result<(), MapError> {
+        if frame.0 % page.size.bytes() != 0 {
+            return Err(MapError::NotAligned);
+        }
+        // Intermediate tables must allow whatever the leaf allows
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            | if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            PageTableFlags::USER_ACCESSIBLE
+        } else {
+            PageTableFlags::empty()
+        };
+
+        let mut table = table_at(self.pml4);
+        for level in ((page.size.level() + 1)..=4).rev() {
+            let entry = &mut table.entries[page.table_index(level)];
+            if entry.is_unused() {
+                let frame = allocator.allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+                table_at(frame.start).zero();
+                entry.set(frame.start, parent_flags);
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(MapError::ParentEntryHugePage);
+            } else {
+                entry.set_flags(entry.flags() | parent_flags);
+            }
+            table = table_at(entry.address());
+        }
+
+        let entry = &mut table.entries[page.table_index(page.size.level())];
+        if !entry.is_unused() {
+            return Err(MapError::PageAlreadyMapped(entry.address()));
+        }
+        let mut flags = flags | PageTableFlags::PRESENT;
+        if page.size == PageSize::Size2MiB {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+        entry.set(frame, flags);
+        self.flush(page);
+        Ok(())
+    }
+
+    /// Removes the mapping of `page` and returns the frame it pointed to.
+    /// Intermediate tables are kept even if they become empty.
+    pub fn unmap(&mut self, page: Page) -> Result<PhysAddr, UnmapError> {
+        let entry = self.leaf_entry(page)?;
+        let frame = entry.address();
+        entry.set_unused();
+        self.flush(page);
+        Ok(frame)
+    }
+
+    pub fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> Result<(), UnmapError> {
+        let entry = self.leaf_entry(page)?;
+        let mut flags = flags | PageTableFlags::PRESENT;
+        if page.size == PageSize::Size2MiB {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+        entry.set_flags(flags);
+        self.flush(page);
+        Ok(())
+    }
+
+    /// Translates a virtual address, following 1 GiB and 2 MiB pages.
+    pub fn translate_addr(&self, address: VirtAddr) -> Option<PhysAddr> {
+        self.translate(address).map(|(frame, _)| frame)
+    }
+
+    /// Translates a virtual address and returns the effective flags of the
+    /// mapping: `WRITABLE` and `USER_ACCESSIBLE` only if every level allows it.
+    pub fn translate(&self, address: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        let mut table = table_at(self.pml4);
+        let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for level in (1..=4).rev() {
+            let index = ((address.0 >> (12 + 9 * (level - 1))) & 0x1FF) as usize;
+            let entry = &table.entries[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            for restriction in [PageTableFlags::WRITABLE, PageTableFlags::USER_ACCESSIBLE].iter() {
+                if !entry.flags().contains(*restriction) {
+                    flags.remove(*restriction);
+                }
+            }
+            if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                let offset_mask = (1u64 << (12 + 9 * (level - 1))) - 1;
+                let frame = PhysAddr(entry.address().0 & !offset_mask | (address.0 & offset_mask));
+                return Some((frame, flags | PageTableFlags::PRESENT));
+            }
+            table = table_at(entry.address());
+        }
+        None
+    }
+
+    fn leaf_entry(&mut self, page: Page) -> Result<&'static mut PageTableEntry, UnmapError> {
+        let mut table = table_at(self.pml4);
+        for level in ((page.size.level() + 1)..=4).rev() {
+            let entry = &table.entries[page.table_index(level)];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return Err(UnmapError::PageNotMapped);
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(UnmapError::ParentEntryHugePage);
+            }
+            table = table_at(entry.address());
+        }
+
+        let entry = &mut table.entries[page.table_index(page.size.level())];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(UnmapError::PageNotMapped);
+        }
+        let is_huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if is_huge != (page.size == PageSize::Size2MiB) {
+            return Err(UnmapError::SizeMismatch);
+        }
+        Ok(entry)
+    }
+
+    /// Invalidates the TLB entry of `page` if this address space is active.
+    fn flush(&self, page: Page) {
+        if self.pml4.0 == read_cr3() & ADDRESS_MASK {
+            invlpg(page.start.0);
+        }
+    }
+}
+
+pub(crate) fn table_at(address: PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *address.to_virt().as_mut_ptr::<PageTable>() }
+}
+
+/// Records the boot page tables as the kernel address space.
+///
+/// Address spaces share the kernel's PML4 entries as they are when they get
+/// created, so the entry of the MMIO window is created up front.
+pub fn init() {
+    KERNEL_PML4.store(read_cr3() & ADDRESS_MASK, Ordering::Relaxed);
+    let entry = &mut table_at(kernel_pml4()).entries[((MMIO_START >> 39) & 0x1FF) as usize];
+    if entry.is_unused() {
+        let frame = FRAME_ALLOCATOR.lock().allocate_frame().expect("No frame for the MMIO page table");
+        table_at(frame.start).zero();
+        entry.set(frame.start, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
+
+pub fn kernel_pml4() -> PhysAddr {
+    PhysAddr(KERNEL_PML4.load(Ordering::Relaxed))
+}
+
+/// Translates a virtual address in the active address space.
+pub fn translate_addr(address: VirtAddr) -> Option<PhysAddr> {
+    Mapper::active().translate_addr(address)
+}
+
+/// Maps `size` bytes of device memory starting at `address` as uncached and
+/// returns the virtual address of `address`. Mappings are never released.
+pub fn map_mmio(address: PhysAddr, size: u64) -> Result<VirtAddr, MapError> {
+    let page_size = PageSize::Size4KiB.bytes();
+    let first_frame = address.0 & !(page_size - 1);
+    let offset = address.0 - first_frame;
+    let mapped_size = (offset + size + page_size - 1) & !(page_size - 1);
+    let start = NEXT_MMIO_ADDRESS.fetch_add(mapped_size, Ordering::Relaxed);
+    if start + mapped_size > MMIO_START + MMIO_SIZE {
+        panic!("MMIO window exhausted");
+    }
+
+    let mut mapper = Mapper::new(kernel_pml4());
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..mapped_size / page_size {
+        let page = Page::containing_address(VirtAddr(start + i * page_size), PageSize::Size4KiB);
+        mapper.map_to(page, PhysAddr(first_frame + i * page_size),
+                      PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+                          | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE,
+                      &mut *allocator)?;
+    }
+    Ok(VirtAddr(start + offset))
+}
+
+/// Flushes the whole TLB, except for global pages.
+pub fn flush_all() {
+    write_cr3(read_cr3());
+}
+
+/// Error code pushed by the CPU on a page fault.
+/// https://wiki.osdev.org/Exceptions#Page_Fault
+#[derive(Debug, Clone, Copy)]
+pub struct PageFaultErrorCode(pub u64);
+
+impl PageFaultErrorCode {
+    pub const PROTECTION_VIOLATION: u64 = 1;
+    pub const WRITE: u64 = 1 << 1;
+    pub const USER_MODE: u64 = 1 << 2;
+    pub const RESERVED_WRITE: u64 = 1 << 3;
+    pub const INSTRUCTION_FETCH: u64 = 1 << 4;
+    pub const PROTECTION_KEY: u64 = 1 << 5;
+    pub const SHADOW_STACK: u64 = 1 << 6;
+
+    pub fn contains(&self, flag: u64) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+impl Display for PageFaultErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} in {} mode",
+               if self.contains(Self::PROTECTION_VIOLATION) { "protection violation" } else { "non-present page" },
+               if self.contains(Self::INSTRUCTION_FETCH) {
+                   "on instruction fetch"
+               } else if self.contains(Self::WRITE) {
+                   "on write"
+               } else {
+                   "on read"
+               },
+               if self.contains(Self::USER_MODE) { "user" } else { "kernel" })?;
+        if self.contains(Self::RESERVED_WRITE) {
+            write!(f, ", reserved bit set")?;
+        }
+        if self.contains(Self::PROTECTION_KEY) {
+            write!(f, ", protection key")?;
+        }
+        if self.contains(Self::SHADOW_STACK) {
+            write!(f, ", shadow stack")?;
+        }
+        write!(f, " ({:#x})", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn map_translate_unmap() {
+        let page = Page::containing_address(VirtAddr(0xDEAD_0000_0000), PageSize::Size4KiB);
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let frame = allocator.allocate_frame().unwrap();
+        let mut mapper = Mapper::active();
+        mapper.map_to(page, frame.start, PageTableFlags::WRITABLE, &mut *allocator).unwrap();
+        assert_eq!(translate_addr(VirtAddr(page.start.0 + 42)), Some(PhysAddr(frame.start.0 + 42)));
+
+        unsafe { page.start.as_mut_ptr::<u64>().write_volatile(0xF00D) };
+        assert_eq!(unsafe { frame.start.to_virt().as_ptr::<u64>().read_volatile() }, 0xF00D);
+
+        assert_eq!(mapper.unmap(page), Ok(frame.start));
+        assert_eq!(translate_addr(page.start), None);
+        allocator.deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn huge_pages_translate_interior_addresses() {
+        let page = Page::containing_address(VirtAddr(0xDEAD_4000_0000), PageSize::Size2MiB);
+        // Never accessed, only translated
+        let frame = PhysAddr(0x4000_0000);
+        let mut mapper = Mapper::active();
+        mapper.map_to(page, frame, PageTableFlags::WRITABLE, &mut *FRAME_ALLOCATOR.lock()).unwrap();
+        assert_eq!(mapper.map_to(page, PhysAddr(frame.0 + 0x1000), PageTableFlags::WRITABLE,
+                                 &mut *FRAME_ALLOCATOR.lock()),
+                   Err(MapError::NotAligned));
+
+        let interior = VirtAddr(page.start.0 + 0x12_3456);
+        assert_eq!(mapper.translate(interior),
+                   Some((PhysAddr(frame.0 + 0x12_3456), PageTableFlags::PRESENT | PageTableFlags::WRITABLE)));
+        let small = Page::containing_address(interior, PageSize::Size4KiB);
+        assert_eq!(mapper.unmap(small), Err(UnmapError::ParentEntryHugePage));
+
+        assert_eq!(mapper.unmap(page), Ok(frame));
+        assert_eq!(mapper.translate_addr(interior), None);
+    }
+
+    #[test_case]
+    fn flags_are_updated() {
+        let page = Page::containing_address(VirtAddr(0xDEAD_0001_0000), PageSize::Size4KiB);
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let frame = allocator.allocate_frame().unwrap();
+        let mut mapper = Mapper::active();
+        mapper.map_to(page, frame.start, PageTableFlags::WRITABLE, &mut *allocator).unwrap();
+
+        mapper.update_flags(page, PageTableFlags::NO_EXECUTE).unwrap();
+        let entry = mapper.leaf_entry(page).unwrap();
+        assert_eq!(entry.flags(), PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE);
+        assert_eq!(entry.address(), frame.start);
+        assert_eq!(mapper.translate(page.start), Some((frame.start, PageTableFlags::PRESENT)));
+        assert_eq!(mapper.update_flags(Page::containing_address(page.start, PageSize::Size2MiB),
+                                       PageTableFlags::empty()),
+                   Err(UnmapError::SizeMismatch));
+
+        assert_eq!(mapper.unmap(page), Ok(frame.start));
+        assert_eq!(mapper.update_flags(page, PageTableFlags::WRITABLE), Err(UnmapError::PageNotMapped));
+        allocator.deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,121 @@
+//! Access to the PCI configuration space.
+//! https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_.231
+//! https://wiki.osdev.org/PCI_Express#Enhanced_Configuration_Mechanism
+
+use core::ptr::{read_volatile, write_volatile};
+
+use alloc::vec::Vec;
+
+use crate::acpi::mcfg::Mcfg;
+use crate::inline_asm::{inl, outl};
+use crate::memory::{PhysAddr, VirtAddr};
+use crate::paging::{map_mmio, MapError};
+
+use super::PciAddress;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Memory mapped configuration space of a range of buses.
+pub struct EcamRegion {
+    base: VirtAddr,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+pub enum ConfigAccess {
+    /// Configuration mechanism #1, through ports 0xCF8 and 0xCFC. Only
+    /// reaches the first 256 bytes of segment 0.
+    Ports,
+    Ecam(Vec<EcamRegion>),
+}
+
+impl ConfigAccess {
+    /// Maps the ECAM regions described by the MCFG.
+    pub fn ecam(mcfg: &Mcfg) -> Result<ConfigAccess, MapError> {
+        let mut regions = Vec::new();
+        for entry in mcfg.entries.iter() {
+            let bus_count = entry.end_bus as u64 - entry.start_bus as u64 + 1;
+            // The base address corresponds to bus 0, even if the range
+            // starts later
+            let start = entry.base_address + ((entry.start_bus as u64) << 20);
+            let base = map_mmio(PhysAddr(start), bus_count << 20)?;
+            regions.push(EcamRegion {
+                base: VirtAddr(base.0 - ((entry.start_bus as u64) << 20)),
+                segment: entry.segment_group,
+                start_bus: entry.start_bus,
+                end_bus: entry.end_bus,
+            });
+        }
+        Ok(ConfigAccess::Ecam(regions))
+    }
+
+    /// Buses that can be enumerated, per segment.
+    pub fn bus_ranges(&self) -> Vec<(u16, u8, u8)> {
+        match self {
+            ConfigAccess::Ports => {
+                let mut ranges = Vec::new();
+                ranges.push((0, 0, 255));
+                ranges
+            }
+            ConfigAccess::Ecam(regions) => regions.iter()
+                .map(|region| (region.segment, region.start_bus, region.end_bus))
+                .collect(),
+        }
+    }
+
+    fn ecam_address(regions: &[EcamRegion], address: PciAddress, offset: u16) -> Option<*mut u32> {
+        let region = regions.iter().find(|region| {
+            region.segment == address.segment && address.bus >= region.start_bus && address.bus <= region.end_bus
+        })?;
+        let offset = (address.bus as u64) << 20
+            | (address.device as u64) << 15
+            | (address.function as u64) << 12
+            | (offset & 0xFFC) as u64;
+        Some((region.base.0 + offset) as *mut u32)
+    }
+
+    fn port_address(address: PciAddress, offset: u16) -> u32 {
+        1 << 31
+            | (address.bus as u32) << 16
+            | (address.device as u32) << 11
+            | (address.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Reads the dword at `offset`, which is rounded down to a multiple of 4.
+    /// Returns all ones for registers that can't be reached, like a read from
+    /// a missing device does.
+    pub fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        match self {
+            ConfigAccess::Ports => {
+                if address.segment != 0 || offset >= 256 {
+                    return 0xFFFF_FFFF;
+                }
+                outl(CONFIG_ADDRESS, Self::port_address(address, offset));
+                inl(CONFIG_DATA)
+            }
+            ConfigAccess::Ecam(regions) => match Self::ecam_address(regions, address, offset) {
+                Some(pointer) => unsafe { read_volatile(pointer) },
+                None => 0xFFFF_FFFF,
+            },
+        }
+    }
+
+    pub fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        match self {
+            ConfigAccess::Ports => {
+                if address.segment == 0 && offset < 256 {
+                    outl(CONFIG_ADDRESS, Self::port_address(address, offset));
+                    outl(CONFIG_DATA, value);
+                }
+            }
+            ConfigAccess::Ecam(regions) => {
+                if let Some(pointer) = Self::ecam_address(regions, address, offset) {
+                    unsafe { write_volatile(pointer, value) }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,386 @@
+//! PCI bus enumeration and device registry.
+//! https://wiki.osdev.org/PCI
+//!
+//! All the buses are scanned once at boot. Drivers then look up their devices
+//! in the registry by vendor, device or class ID.
+
+use core::fmt;
+use core::fmt::{Display, Formatter, Write};
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::acpi;
+
+use self::config::ConfigAccess;
+
+pub mod config;
+
+// Configuration space registers
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const CLASS_REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0C;
+const BAR0: u16 = 0x10;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT: u16 = 0x3C;
+
+const STATUS_CAPABILITIES_LIST: u32 = 1 << (16 + 4);
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+const HEADER_TYPE_PCI_BRIDGE: u8 = 0x01;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+// Capability IDs
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+static PCI: Once<Pci> = Once::new();
+
+struct Pci {
+    access: ConfigAccess,
+    devices: Vec<PciDevice>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64_bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset in the configuration space
+    pub offset: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    /// Legacy interrupt line, as set up by the firmware. 0xFF if none.
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 if the device doesn't use interrupts
+    pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability>,
+}
+
+impl PciDevice {
+    pub fn read_config(&self, offset: u16) -> u32 {
+        access().read(self.address, offset)
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        access().write(self.address, offset, value)
+    }
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        (self.read_config(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        (self.read_config(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_config(COMMAND) as u16
+    }
+
+    /// Sets bits of the command register, leaving the status bits untouched.
+    pub fn enable(&self, command_bits: u16) {
+        // Writing ones to the status register clears its bits
+        let command = self.read_config(COMMAND) & 0xFFFF;
+        self.write_config(COMMAND, command | command_bits as u32);
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().find(|capability| capability.id == id).copied()
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+/// One line summary, like `lspci -nn` would print.
+impl Display for PciDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+               self.address, self.class_name(), self.class, self.subclass,
+               self.vendor_id, self.device_id, self.revision)
+    }
+}
+
+/// Selects devices in the registry. Fields left to `None` match anything.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    pub fn device(vendor_id: u16, device_id: u16) -> PciMatch {
+        PciMatch { vendor_id: Some(vendor_id), device_id: Some(device_id), ..PciMatch::default() }
+    }
+
+    pub fn class(class: u8, subclass: u8) -> PciMatch {
+        PciMatch { class: Some(class), subclass: Some(subclass), ..PciMatch::default() }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |id| id == device.vendor_id)
+            && self.device_id.map_or(true, |id| id == device.device_id)
+            && self.class.map_or(true, |class| class == device.class)
+            && self.subclass.map_or(true, |subclass| subclass == device.subclass)
+            && self.prog_if.map_or(true, |prog_if| prog_if == device.prog_if)
+    }
+}
+
+fn access() -> &'static ConfigAccess {
+    &PCI.r#try().expect("PCI not initialized").access
+}
+
+/// All the devices found at boot.
+pub fn devices() -> &'static [PciDevice] {
+    PCI.r#try().map_or(&[], |pci| &pci.devices[..])
+}
+
+pub fn find(pci_match: &PciMatch) -> impl Iterator<Item=&'static PciDevice> {
+    let pci_match = *pci_match;
+    devices().iter().filter(move |device| pci_match.matches(device))
+}
+
+/// Enumerates the PCI buses, through ECAM if the MCFG is present.
+pub fn init() {
+    let mcfg = acpi::info().and_then(|acpi| acpi.mcfg.as_ref());
+    let access = match mcfg.map(ConfigAccess::ecam) {
+        Some(Ok(access)) => access,
+        Some(Err(error)) => {
+            println!("PCI: can't map ECAM ({:?}), falling back to I/O ports", error);
+            ConfigAccess::Ports
+        }
+        None => ConfigAccess::Ports,
+    };
+
+    let mut devices = Vec::new();
+    for (segment, start_bus, end_bus) in access.bus_ranges() {
+        for bus in start_bus..=end_bus {
+            for device in 0..32 {
+                scan_device(&access, PciAddress { segment, bus, device, function: 0 }, &mut devices);
+            }
+        }
+    }
+    PCI.call_once(|| Pci { access, devices });
+}
+
+fn scan_device(access: &ConfigAccess, address: PciAddress, devices: &mut Vec<PciDevice>) {
+    if access.read(address, VENDOR_ID) as u16 == 0xFFFF {
+        return;
+    }
+    let header_type = (access.read(address, HEADER_TYPE) >> 16) as u8;
+    let function_count = if header_type & HEADER_TYPE_MULTIFUNCTION != 0 { 8 } else { 1 };
+    for function in 0..function_count {
+        let address = PciAddress { function, ..address };
+        if access.read(address, VENDOR_ID) as u16 != 0xFFFF {
+            devices.push(read_device(access, address));
+        }
+    }
+}
+
+fn read_device(access: &ConfigAccess, address: PciAddress) -> PciDevice {
+    let id = access.read(address, VENDOR_ID);
+    let class_revision = access.read(address, CLASS_REVISION);
+    let header_type = (access.read(address, HEADER_TYPE) >> 16) as u8 & HEADER_TYPE_MASK;
+    let interrupt = access.read(address, INTERRUPT);
+
+    let bar_count = match header_type {
+        HEADER_TYPE_GENERAL => 6,
+        HEADER_TYPE_PCI_BRIDGE => 2,
+        _ => 0,
+    };
+
+    PciDevice {
+        address,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: (class_revision >> 24) as u8,
+        subclass: (class_revision >> 16) as u8,
+        prog_if: (class_revision >> 8) as u8,
+        revision: class_revision as u8,
+        header_type,
+        bars: read_bars(access, address, bar_count),
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+        capabilities: read_capabilities(access, address),
+    }
+}
+
+/// Decodes the BARs, finding their size by writing all ones and reading back
+/// which bits stuck. Decoding is turned off in the meantime.
+fn read_bars(access: &ConfigAccess, address: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = access.read(address, COMMAND) & 0xFFFF;
+    access.write(address, COMMAND, command & !((COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) as u32));
+
+    let mut i = 0;
+    while i < count {
+        let offset = BAR0 + 4 * i as u16;
+        let value = access.read(address, offset);
+        access.write(address, offset, 0xFFFF_FFFF);
+        let mask = access.read(address, offset);
+        access.write(address, offset, value);
+
+        if value & 1 == 1 {
+            // I/O BARs may only implement the low 16 bits
+            let size = (!(mask & 0xFFFC) & 0xFFFF).wrapping_add(1);
+            if mask & 0xFFFC != 0 {
+                bars[i] = Some(Bar::Io { port: (value & 0xFFFC) as u16, size });
+            }
+            i += 1;
+            continue;
+        }
+
+        let is_64_bit = (value >> 1) & 0b11 == 0b10;
+        let prefetchable = value & (1 << 3) != 0;
+        let (address_value, size) = if is_64_bit && i + 1 < count {
+            let high_offset = offset + 4;
+            let high = access.read(address, high_offset);
+            access.write(address, high_offset, 0xFFFF_FFFF);
+            let high_mask = access.read(address, high_offset);
+            access.write(address, high_offset, high);
+            let full_mask = (high_mask as u64) << 32 | (mask & !0xF) as u64;
+            ((high as u64) << 32 | (value & !0xF) as u64, (!full_mask).wrapping_add(1))
+        } else {
+            ((value & !0xF) as u64, (!(mask & !0xF)).wrapping_add(1) as u64)
+        };
+        if size != 0 && mask & !0xF != 0 {
+            bars[i] = Some(Bar::Memory { address: address_value, size, prefetchable, is_64_bit });
+        }
+        i += if is_64_bit { 2 } else { 1 };
+    }
+
+    access.write(address, COMMAND, command);
+    bars
+}
+
+fn read_capabilities(access: &ConfigAccess, address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if access.read(address, COMMAND) & STATUS_CAPABILITIES_LIST == 0 {
+        return capabilities;
+    }
+    let mut offset = (access.read(address, CAPABILITIES_POINTER) & 0xFC) as u16;
+    // Bounded in case of a malformed, looping list
+    while offset != 0 && capabilities.len() < 48 {
+        let header = access.read(address, offset);
+        capabilities.push(Capability { id: header as u8, offset });
+        offset = ((header >> 8) & 0xFC) as u16;
+    }
+    capabilities
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        _ => "Unknown device",
+    }
+}
+
+/// Writes the devices like `lspci -v` would.
+pub fn dump(out: &mut dyn Write) -> fmt::Result {
+    for device in devices() {
+        writeln!(out, "{}", device)?;
+        if device.interrupt_pin != 0 {
+            writeln!(out, "        Interrupt: pin {} routed to IRQ {}",
+                     (b'A' + device.interrupt_pin - 1) as char, device.interrupt_line)?;
+        }
+        for (i, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory { address, size, prefetchable, is_64_bit }) => {
+                    writeln!(out, "        BAR{}: Memory at {:#x} ({}-bit, {}) [size={:#x}]",
+                             i, address, if *is_64_bit { 64 } else { 32 },
+                             if *prefetchable { "prefetchable" } else { "non-prefetchable" }, size)?;
+                }
+                Some(Bar::Io { port, size }) => {
+                    writeln!(out, "        BAR{}: I/O ports at {:#x} [size={:#x}]", i, port, size)?;
+                }
+                None => {}
+            }
+        }
+        for capability in device.capabilities.iter() {
+            writeln!(out, "        Capability [{:02x}] {:#x}", capability.offset, capability.id)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn host_bridge_is_enumerated() {
+        let host_bridge = find(&PciMatch::class(0x06, 0x00)).next().expect("no host bridge");
+        assert_eq!(host_bridge.address.bus, 0);
+        assert_ne!(host_bridge.vendor_id, 0xFFFF);
+        assert_eq!(host_bridge.read_config_u16(VENDOR_ID), host_bridge.vendor_id);
+    }
+}
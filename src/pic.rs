@@ -1,4 +1,7 @@
-use crate::inline_asm::{enable_interrupts, inb, io_wait, outb};
+//! https://wiki.osdev.org/8259_PIC
+
+use crate::inline_asm::{inb, io_wait, outb};
+use crate::irq::{Irq, IRQ_BASE_VECTOR};
 
 const PIC1_CMD: u16 = 0x20;
 const PIC2_CMD: u16 = 0xA0;
@@ -8,25 +11,80 @@ const PIC2_DATA: u16 = PIC2_CMD + 1;
 const EOI: u8 = 0x20;
 const INIT: u8 = 0x11;
 const ICW4_8086: u8 = 0x01; // 8086/88 (MCS-80/85) mode
+const READ_IRR: u8 = 0x0A;
+const READ_ISR: u8 = 0x0B;
 
-pub const PIC1_OFFSET: u8 = 32;
+pub const PIC1_OFFSET: u8 = IRQ_BASE_VECTOR;
 pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
 
-pub const PIC_LINE_TIMER: u8 = 32;
-pub const PIC_LINE_KEYBOARD: u8 = 33;
-
+/// Remaps the PICs and masks every line but the cascade. Lines are unmasked
+/// when a handler is registered through the `irq` module.
 pub fn init_pic() {
     remap(PIC1_OFFSET, PIC2_OFFSET);
-    enable_interrupts();
+    outb(PIC1_DATA, !(1 << Irq::CASCADE.0));
+    outb(PIC2_DATA, 0xFF);
 }
 
-pub fn send_eoi(irq: u8) {
-    if irq >= 8 {
+/// Masks every line, used when the I/O APIC takes over.
+pub fn disable() {
+    outb(PIC1_DATA, 0xFF);
+    outb(PIC2_DATA, 0xFF);
+}
+
+pub fn send_eoi(irq: Irq) {
+    if irq.0 >= 8 {
         outb(PIC2_CMD, EOI);
     }
     outb(PIC1_CMD, EOI);
 }
 
+pub fn mask(irq: Irq) {
+    let (port, line) = port_and_line(irq);
+    outb(port, inb(port) | (1 << line));
+}
+
+pub fn unmask(irq: Irq) {
+    let (port, line) = port_and_line(irq);
+    outb(port, inb(port) & !(1 << line));
+}
+
+/// Whether `irq` was raised and not delivered to the CPU yet.
+pub fn is_pending(irq: Irq) -> bool {
+    let (_, line) = port_and_line(irq);
+    let command = if irq.0 < 8 { PIC1_CMD } else { PIC2_CMD };
+    outb(command, READ_IRR);
+    inb(command) & (1 << line) != 0
+}
+
+fn port_and_line(irq: Irq) -> (u16, u8) {
+    if irq.0 < 8 {
+        (PIC1_DATA, irq.0)
+    } else {
+        (PIC2_DATA, irq.0 - 8)
+    }
+}
+
+/// Checks for the spurious IRQs 7 and 15, which aren't in service and must
+/// not be acknowledged, except on the master for a spurious IRQ 15.
+/// https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+pub fn is_spurious(irq: Irq) -> bool {
+    match irq.0 {
+        7 => {
+            outb(PIC1_CMD, READ_ISR);
+            inb(PIC1_CMD) & (1 << 7) == 0
+        }
+        15 => {
+            outb(PIC2_CMD, READ_ISR);
+            let spurious = inb(PIC2_CMD) & (1 << 7) == 0;
+            if spurious {
+                outb(PIC1_CMD, EOI);
+            }
+            spurious
+        }
+        _ => false,
+    }
+}
+
 pub fn remap(pic1_offset: u8, pic2_offset: u8) {
     // Save pic masks
     let (pic1_mask, pic2_mask) = (inb(PIC1_DATA), inb(PIC2_DATA));
@@ -51,4 +109,4 @@ pub fn remap(pic1_offset: u8, pic2_offset: u8) {
     // Restore pic masks
     outb(PIC1_DATA, pic1_mask);
     outb(PIC2_DATA, pic2_mask);
-}
\ No newline at end of file
+}
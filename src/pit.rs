@@ -0,0 +1,272 @@
+//! Programmable Interval Timer (Intel 8253/8254).
+//! https://wiki.osdev.org/Programmable_Interval_Timer
+//!
+//! Channel 0 drives the system tick through IRQ 0. Channel 2 is wired to the
+//! PC speaker, and its output can be polled through port 0x61, which makes it
+//! usable for delays and timer calibration without relying on interrupts.
+
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::inline_asm::{hlt, inb, outb};
+use crate::irq;
+use crate::irq::Irq;
+use crate::pic;
+use crate::sync::without_interrupts;
+use crate::task;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B: gate and output of channel 2, speaker enable
+const PORT_B: u16 = 0x61;
+
+// Command register bits
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const SELECT_CHANNEL2: u8 = 0b10 << 6;
+const ACCESS_LATCH: u8 = 0b00 << 4;
+const ACCESS_LOBYTE_HIBYTE: u8 = 0b11 << 4;
+const MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0b000 << 1;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+const MODE_SQUARE_WAVE: u8 = 0b011 << 1;
+
+// Port B bits
+const PORT_B_CHANNEL2_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER_ENABLE: u8 = 1 << 1;
+const PORT_B_CHANNEL2_OUTPUT: u8 = 1 << 5;
+
+/// Frequency of the oscillator feeding the PIT, in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Channel 0 reload value, 0 until `init` is called
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+/// Whether ticks come from another timer than channel 0, e.g. the APIC timer
+static EXTERNAL_TICK_SOURCE: AtomicBool = AtomicBool::new(false);
+/// Last value returned by `uptime_ns`, which must not go backwards
+static LAST_UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 to fire IRQ 0 at `frequency` Hz.
+///
+/// The frequency is rounded to the nearest value the PIT can generate, which
+/// lies between 19 Hz and `BASE_FREQUENCY`.
+pub fn init(frequency: u32) {
+    if frequency == 0 || frequency > BASE_FREQUENCY {
+        panic!("Invalid PIT frequency {} Hz", frequency);
+    }
+    let divisor = ((BASE_FREQUENCY + frequency / 2) / frequency).max(1).min(0x10000);
+    DIVISOR.store(divisor, Ordering::Relaxed);
+
+    without_interrupts(|| {
+        outb(COMMAND, SELECT_CHANNEL0 | ACCESS_LOBYTE_HIBYTE | MODE_RATE_GENERATOR);
+        // A reload value of 0 stands for 65536
+        outb(CHANNEL0_DATA, divisor as u8);
+        outb(CHANNEL0_DATA, (divisor >> 8) as u8);
+    });
+    irq::register_handler(Irq::TIMER, timer_irq_handler);
+}
+
+fn timer_irq_handler(_irq: Irq) {
+    on_tick();
+    task::tick();
+}
+
+/// Called when another timer takes over the system tick at the same
+/// frequency. The channel 0 count is then unrelated to the tick phase and
+/// can't refine the uptime anymore.
+pub fn set_external_tick_source() {
+    EXTERNAL_TICK_SOURCE.store(true, Ordering::Relaxed);
+}
+
+/// Actual tick frequency in Hz.
+pub fn frequency() -> u32 {
+    match DIVISOR.load(Ordering::Relaxed) {
+        0 => 0,
+        divisor => BASE_FREQUENCY / divisor,
+    }
+}
+
+/// Duration of one tick in nanoseconds.
+pub fn tick_period_ns() -> u64 {
+    DIVISOR.load(Ordering::Relaxed) as u64 * NANOS_PER_SECOND / BASE_FREQUENCY as u64
+}
+
+/// Counts a system tick.
+pub(crate) fn on_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Ticks since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Monotonic time since `init`, in nanoseconds.
+///
+/// The current count of channel 0 is read to get a resolution finer than a
+/// tick. When the counter reloaded before its IRQ was handled, the tick is
+/// counted here.
+pub fn uptime_ns() -> u64 {
+    let divisor = DIVISOR.load(Ordering::Relaxed) as u64;
+    if divisor == 0 {
+        return 0;
+    }
+    if EXTERNAL_TICK_SOURCE.load(Ordering::Relaxed) {
+        return ticks() * tick_period_ns();
+    }
+    without_interrupts(|| {
+        let mut ticks = TICKS.load(Ordering::Relaxed);
+        let count = read_channel0_count() as u64;
+        // A pending IRQ with a count close to 0 was read before the reload
+        if pic::is_pending(Irq::TIMER) && count > divisor / 2 {
+            ticks += 1;
+        }
+        // The counter counts down from the divisor. The rate generator
+        // reloads at 1, so a count of 0 can't be observed except for a
+        // divisor of 65536.
+        let elapsed_counts = divisor - count.min(divisor);
+        let mut uptime = ticks * tick_period_ns() + elapsed_counts * NANOS_PER_SECOND / BASE_FREQUENCY as u64;
+        // The count went up since the last reading without a tick being
+        // counted, it reloaded
+        let last = LAST_UPTIME_NS.load(Ordering::Relaxed);
+        if uptime < last {
+            uptime = (uptime + tick_period_ns()).max(last);
+        }
+        LAST_UPTIME_NS.store(uptime, Ordering::Relaxed);
+        uptime
+    })
+}
+
+pub fn uptime_ms() -> u64 {
+    uptime_ns() / 1_000_000
+}
+
+fn read_channel0_count() -> u16 {
+    outb(COMMAND, SELECT_CHANNEL0 | ACCESS_LATCH);
+    let lo = inb(CHANNEL0_DATA) as u16;
+    let hi = inb(CHANNEL0_DATA) as u16;
+    (hi << 8) | lo
+}
+
+/// Converts milliseconds to ticks, rounding up.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let period = tick_period_ns();
+    if period == 0 {
+        return 0;
+    }
+    (ms * 1_000_000 + period - 1) / period
+}
+
+/// Puts the current task to sleep for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u64) {
+    if task::is_idle() {
+        // The idle task can't sleep, halt until enough ticks went by instead
+        let target = ticks() + ms_to_ticks(ms);
+        while ticks() < target {
+            hlt();
+        }
+    } else {
+        task::sleep(ms_to_ticks(ms));
+    }
+}
+
+/// Spins for at least `us` microseconds without relying on interrupts. They
+/// stay enabled if they were, so that ticks keep being counted.
+///
+/// Uses channel 2 in one-shot mode, so it must not be used while the PC
+/// speaker is beeping, nor by two tasks at once.
+pub fn busy_wait_us(us: u64) {
+    // Longest one-shot delay the 16 bit counter allows, in microseconds
+    const MAX_CHUNK_US: u64 = 50_000;
+    let mut remaining = us;
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_CHUNK_US);
+        let counts = (chunk * BASE_FREQUENCY as u64 + 999_999) / 1_000_000;
+        channel2_one_shot(counts as u16);
+        remaining -= chunk;
+    }
+}
+
+pub fn busy_wait_ms(ms: u64) {
+    busy_wait_us(ms * 1000);
+}
+
+/// Runs channel 2 for `counts` PIT periods and spins until it is done.
+///
+/// This is the reference delay used to calibrate other timers: call
+/// `channel2_start`, read the timer being calibrated, wait for
+/// `channel2_expired` and read it again.
+pub fn channel2_one_shot(counts: u16) {
+    without_interrupts(|| channel2_start(counts));
+    while !channel2_expired() {
+        spin_loop_hint();
+    }
+}
+
+/// Starts channel 2 in one-shot mode for `counts` PIT periods.
+pub fn channel2_start(counts: u16) {
+    // Speaker off, gate low while the counter is loaded
+    let port_b = inb(PORT_B) & !(PORT_B_SPEAKER_ENABLE | PORT_B_CHANNEL2_GATE);
+    outb(PORT_B, port_b);
+    outb(COMMAND, SELECT_CHANNEL2 | ACCESS_LOBYTE_HIBYTE | MODE_INTERRUPT_ON_TERMINAL_COUNT);
+    outb(CHANNEL2_DATA, counts as u8);
+    outb(CHANNEL2_DATA, (counts >> 8) as u8);
+    // Counting starts on the rising edge of the gate
+    outb(PORT_B, port_b | PORT_B_CHANNEL2_GATE);
+}
+
+/// Whether the count started by `channel2_start` reached zero.
+pub fn channel2_expired() -> bool {
+    inb(PORT_B) & PORT_B_CHANNEL2_OUTPUT != 0
+}
+
+/// Starts a tone of `frequency` Hz on the PC speaker.
+pub fn speaker_on(frequency: u32) {
+    if frequency == 0 {
+        speaker_off();
+        return;
+    }
+    let divisor = (BASE_FREQUENCY / frequency).max(1).min(0xFFFF);
+    without_interrupts(|| {
+        outb(COMMAND, SELECT_CHANNEL2 | ACCESS_LOBYTE_HIBYTE | MODE_SQUARE_WAVE);
+        outb(CHANNEL2_DATA, divisor as u8);
+        outb(CHANNEL2_DATA, (divisor >> 8) as u8);
+        outb(PORT_B, inb(PORT_B) | PORT_B_CHANNEL2_GATE | PORT_B_SPEAKER_ENABLE);
+    });
+}
+
+pub fn speaker_off() {
+    outb(PORT_B, inb(PORT_B) & !(PORT_B_CHANNEL2_GATE | PORT_B_SPEAKER_ENABLE));
+}
+
+/// Beeps for `duration_ms` milliseconds, sleeping in the meantime.
+pub fn beep(frequency: u32, duration_ms: u64) {
+    speaker_on(frequency);
+    sleep_ms(duration_ms);
+    speaker_off();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn busy_wait_advances_uptime() {
+        let start = uptime_ns();
+        busy_wait_ms(20);
+        assert!(uptime_ns() - start >= 20_000_000);
+    }
+
+    #[test_case]
+    fn uptime_is_monotonic() {
+        // Many channel 0 reloads, some of them read before their IRQ
+        let mut last = uptime_ns();
+        let end = last + 20_000_000;
+        while last < end {
+            let uptime = uptime_ns();
+            assert!(uptime >= last);
+            last = uptime;
+        }
+    }
+}
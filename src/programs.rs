@@ -0,0 +1,8 @@
+//! User programs embedded in the kernel image, until they can be loaded from
+//! a disk. They are built by `user/build.sh`.
+
+pub static HELLO: &[u8] = include_bytes!("../user/bin/hello");
+
+/// cpio archive unpacked into the root file system at boot. It holds the
+/// programs in `/bin` and the files of `user/initramfs`.
+pub static INITRAMFS: &[u8] = include_bytes!("../user/bin/initramfs.cpio");
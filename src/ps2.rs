@@ -1,16 +1,202 @@
-use pc_keyboard::{HandleControl, Keyboard, layouts, ScancodeSet1};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, KeyState, layouts, ScancodeSet1};
 use spin::Mutex;
 
 use crate::inline_asm::inb;
+use crate::irq;
+use crate::irq::Irq;
+use crate::sync::{InterruptGuard, IrqMutex};
+use crate::task;
+use crate::task::TaskId;
+use crate::vga;
+use crate::vga::TerminalWriter;
 
 const _PS2_CMD: u16 = 0x64;
 const PS2_DATA: u16 = 0x60;
+/// Keys pressed beyond this are dropped until someone reads them
+const INPUT_CAPACITY: usize = 256;
+/// Lines scrolled by Shift+PageUp and Shift+PageDown, half a screen
+const SCROLL_LINES: isize = 12;
 
 lazy_static! {
     pub static ref KEYBOARD: Mutex<Keyboard<layouts::Azerty, ScancodeSet1>> =
         Mutex::new(Keyboard::new(layouts::Azerty, ScancodeSet1, HandleControl::Ignore));
+    /// Keys typed on each virtual terminal
+    static ref INPUT: IrqMutex<Vec<KeyboardInput>> = IrqMutex::new((0..vga::TERMINAL_COUNT)
+        .map(|_| KeyboardInput { keys: VecDeque::with_capacity(INPUT_CAPACITY), waiters: Vec::new() })
+        .collect());
+}
+
+static ALT_PRESSED: AtomicBool = AtomicBool::new(false);
+static SHIFT_PRESSED: AtomicBool = AtomicBool::new(false);
+
+/// A key press, decoded with the keyboard layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Tab,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+}
+
+/// Keys pressed on the keyboard, waiting to be read.
+struct KeyboardInput {
+    keys: VecDeque<Key>,
+    /// Tasks blocked in `read_key` or `read_input`
+    waiters: Vec<TaskId>,
+}
+
+impl KeyboardInput {
+    fn push(&mut self, key: Key) {
+        if self.keys.len() >= INPUT_CAPACITY {
+            return;
+        }
+        self.keys.push_back(key);
+        for waiter in self.waiters.drain(..) {
+            task::wake(waiter);
+        }
+    }
+}
+
+pub fn init() {
+    irq::register_handler(Irq::KEYBOARD, keyboard_irq_handler);
 }
 
 pub fn read_keyboard_scancode() -> u8 {
     inb(PS2_DATA)
 }
+
+/// Queues the pressed keys for the active terminal. Whoever reads them decides
+/// what they do, the handler doesn't echo anything.
+fn keyboard_irq_handler(_irq: Irq) {
+    let scancode = read_keyboard_scancode();
+    let mut keyboard = KEYBOARD.lock();
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        let pressed = key_event.state == KeyState::Down;
+        match key_event.code {
+            KeyCode::AltLeft | KeyCode::AltRight => ALT_PRESSED.store(pressed, Ordering::Relaxed),
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => SHIFT_PRESSED.store(pressed, Ordering::Relaxed),
+            _ => {}
+        }
+        if pressed && handle_terminal_key(key_event.code) {
+            return;
+        }
+        if let Some(decoded_key) = keyboard.process_keyevent(key_event.clone()) {
+            if key_event.state == KeyState::Down {
+                let key = match key_event.code {
+                    KeyCode::Enter => Key::Enter,
+                    KeyCode::Backspace => Key::Backspace,
+                    KeyCode::Delete => Key::Delete,
+                    KeyCode::Tab => Key::Tab,
+                    KeyCode::ArrowLeft => Key::Left,
+                    KeyCode::ArrowRight => Key::Right,
+                    KeyCode::ArrowUp => Key::Up,
+                    KeyCode::ArrowDown => Key::Down,
+                    KeyCode::Home => Key::Home,
+                    KeyCode::End => Key::End,
+                    _ => match decoded_key {
+                        DecodedKey::Unicode(char) if !char.is_control() => Key::Char(char),
+                        _ => return,
+                    },
+                };
+                vga::follow_output();
+                INPUT.lock()[vga::active_terminal()].push(key);
+            }
+        }
+    }
+}
+
+/// Switches terminals on Alt+F1 to Alt+F6 and scrolls through the history of
+/// the active one on Shift+PageUp and Shift+PageDown. Returns whether the key
+/// was one of those.
+fn handle_terminal_key(code: KeyCode) -> bool {
+    let alt = ALT_PRESSED.load(Ordering::Relaxed);
+    let shift = SHIFT_PRESSED.load(Ordering::Relaxed);
+    match code {
+        KeyCode::F1 if alt => vga::switch_terminal(0),
+        KeyCode::F2 if alt => vga::switch_terminal(1),
+        KeyCode::F3 if alt => vga::switch_terminal(2),
+        KeyCode::F4 if alt => vga::switch_terminal(3),
+        KeyCode::F5 if alt => vga::switch_terminal(4),
+        KeyCode::F6 if alt => vga::switch_terminal(5),
+        KeyCode::PageUp if shift => vga::scroll_view(SCROLL_LINES),
+        KeyCode::PageDown if shift => vga::scroll_view(-SCROLL_LINES),
+        _ => return false,
+    }
+    true
+}
+
+/// Returns the next key typed on a terminal, blocking until there is one.
+pub fn read_key(terminal: usize) -> Key {
+    loop {
+        // The keyboard interrupt must not slip in between the check and
+        // `block`, or the wake up would be lost
+        let _guard = InterruptGuard::new();
+        {
+            let mut inputs = INPUT.lock();
+            let input = &mut inputs[terminal];
+            if let Some(key) = input.keys.pop_front() {
+                return key;
+            }
+            input.waiters.push(task::current_id());
+        }
+        task::block();
+    }
+}
+
+/// Reads text typed on a terminal into `buffer` and echoes it there, blocking
+/// until at least one byte is available. Keys that aren't text are dropped.
+/// Returns the number of bytes read.
+pub fn read_input(terminal: usize, buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+    loop {
+        let _guard = InterruptGuard::new();
+        let mut count = 0;
+        {
+            let mut inputs = INPUT.lock();
+            let input = &mut inputs[terminal];
+            while let Some(&key) = input.keys.front() {
+                let char = match key {
+                    Key::Char(char) => char,
+                    Key::Enter => '\n',
+                    _ => {
+                        input.keys.pop_front();
+                        continue;
+                    }
+                };
+                if count + char.len_utf8() > buffer.len() {
+                    if count > 0 {
+                        break;
+                    }
+                    // A character can't be split across reads
+                    input.keys.pop_front();
+                    continue;
+                }
+                count += char.encode_utf8(&mut buffer[count..]).len();
+                input.keys.pop_front();
+            }
+            if count == 0 {
+                input.waiters.push(task::current_id());
+            }
+        }
+        if count > 0 {
+            TerminalWriter(terminal).write_str(core::str::from_utf8(&buffer[..count]).unwrap()).unwrap();
+            return count;
+        }
+        task::block();
+    }
+}
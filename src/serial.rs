@@ -1,58 +1,689 @@
+//! 8250/16550 UART driver.
 //! https://wiki.osdev.org/Serial_Ports
+//! https://www.lammertbies.nl/comm/info/serial-uart
+//!
+//! `init` probes the four legacy ports and only sets up the ones that pass a
+//! loopback self-test. Ports that are missing ignore writes, so the console
+//! macros work whatever the machine has. Ports are polled until
+//! `init_interrupts` is called. From then on, bytes written are queued and
+//! sent by the IRQ handler as the transmitter empties, and bytes received are
+//! queued until someone reads them. When the transmit queue is full, e.g.
+//! because interrupts stayed disabled for long, the oldest byte is sent by
+//! polling to make room, so writing never loses output.
 
 use core::fmt;
-use core::fmt::Write;
+use core::fmt::{Display, Formatter, Write};
 
-use crate::inline_asm::{inb, outb};
+use alloc::vec::Vec;
+
+use crate::inline_asm::{are_interrupts_enabled, inb, outb};
+use crate::irq;
+use crate::irq::Irq;
+use crate::pit::busy_wait_us;
+use crate::sync::{InterruptGuard, IrqMutex};
+use crate::task;
+use crate::task::TaskId;
 
 pub const COM1: u16 = 0x3F8;
 pub const COM2: u16 = 0x2F8;
 pub const COM3: u16 = 0x3E8;
 pub const COM4: u16 = 0x2E8;
 
+/// Frequency of the UART clock divided by 16, the highest baud rate
+const BASE_BAUD_RATE: u32 = 115200;
+
+// Registers, relative to the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+/// Interrupt identification when read, FIFO control when written
+const INTERRUPT_ID: u16 = 2;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+const SCRATCH: u16 = 7;
+
+// Interrupt enable bits
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+const IER_TRANSMITTER_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+// Interrupt identification values
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const IIR_FIFO_64_BYTES: u8 = 1 << 5;
+const IIR_FIFO_ENABLED: u8 = 1 << 6;
+/// Clear on a 16550, whose FIFO doesn't work
+const IIR_FIFO_WORKING: u8 = 1 << 7;
+const IIR_ID_MASK: u8 = 0b111 << 1;
+const IIR_MODEM_STATUS: u8 = 0b000 << 1;
+const IIR_TRANSMITTER_EMPTY: u8 = 0b001 << 1;
+const IIR_RECEIVED_DATA: u8 = 0b010 << 1;
+const IIR_LINE_STATUS: u8 = 0b011 << 1;
+/// Bytes sat in the receive FIFO below the trigger level for a while
+const IIR_CHARACTER_TIMEOUT: u8 = 0b110 << 1;
+
+// FIFO control bits
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RECEIVE: u8 = 1 << 1;
+const FCR_CLEAR_TRANSMIT: u8 = 1 << 2;
+/// 16750 only, writable while `LCR_DLAB` is set
+const FCR_64_BYTES: u8 = 1 << 5;
+const FCR_TRIGGER_14: u8 = 0b11 << 6;
+
+// Line control bits
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+const LCR_PARITY_ENABLE: u8 = 1 << 3;
+const LCR_EVEN_PARITY: u8 = 1 << 4;
+const LCR_STICK_PARITY: u8 = 1 << 5;
+/// Divisor latch access: data and interrupt enable registers hold the divisor
+const LCR_DLAB: u8 = 1 << 7;
+
+// Modem control bits
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+/// Connects the interrupt line of the UART to the interrupt controller
+const MCR_OUT2: u8 = 1 << 3;
+/// Internally connects the transmitter to the receiver
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+// Line status bits
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN_ERROR: u8 = 1 << 1;
+const LSR_PARITY_ERROR: u8 = 1 << 2;
+const LSR_FRAMING_ERROR: u8 = 1 << 3;
+const LSR_BREAK: u8 = 1 << 4;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 5;
+
+/// How long the loopback self-test waits for its byte to come back, in
+/// microseconds
+const SELF_TEST_TIMEOUT_US: u64 = 10_000;
+const SELF_TEST_BYTE: u8 = 0xAE;
+/// Size of the transmit and receive queues of each port
+const QUEUE_SIZE: usize = 4096;
+
+static PORTS: [IrqMutex<PortState>; 4] = [
+    IrqMutex::new(PortState::new()),
+    IrqMutex::new(PortState::new()),
+    IrqMutex::new(PortState::new()),
+    IrqMutex::new(PortState::new()),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// The baud rate doesn't divide 115200
+    InvalidBaudRate(u32),
+    NotPresent,
+    /// The byte sent in loopback mode came back different
+    SelfTestFailed,
+}
+
+/// UART models, told apart by their FIFO and scratch register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Uart {
+    /// No scratch register, no FIFO
+    U8250,
+    U16450,
+    /// FIFO too buggy to be used
+    U16550,
+    U16550A,
+    /// 64 byte FIFO
+    U16750,
+}
+
+impl Uart {
+    /// Bytes the transmitter takes at once when it is empty.
+    fn fifo_size(self) -> usize {
+        match self {
+            Uart::U8250 | Uart::U16450 | Uart::U16550 => 1,
+            Uart::U16550A => 16,
+            Uart::U16750 => 64,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five = 5,
+    Six = 6,
+    Seven = 7,
+    Eight = 8,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always set
+    Mark,
+    /// Parity bit always clear
+    Space,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// 1.5 stop bits with 5 data bits
+    Two,
+}
+
+/// Format of the characters on the line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    /// Must divide 115200
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineConfig {
+    /// 8 data bits, no parity, one stop bit.
+    pub const fn new(baud_rate: u32) -> LineConfig {
+        LineConfig {
+            baud_rate,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+
+    fn line_control(&self) -> u8 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => LCR_PARITY_ENABLE,
+            Parity::Even => LCR_PARITY_ENABLE | LCR_EVEN_PARITY,
+            Parity::Mark => LCR_PARITY_ENABLE | LCR_STICK_PARITY,
+            Parity::Space => LCR_PARITY_ENABLE | LCR_STICK_PARITY | LCR_EVEN_PARITY,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => LCR_TWO_STOP_BITS,
+        };
+        (self.data_bits as u8 - 5) | stop_bits | parity
+    }
+}
+
+/// Short form, e.g. `38400 8N1`.
+impl Display for LineConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud_rate, self.data_bits as u8, parity, stop_bits)
+    }
+}
+
+/// Errors reported by the line status register since the port was set up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineErrors {
+    /// A received byte was overwritten before it was read from the UART
+    pub overrun: u64,
+    pub parity: u64,
+    pub framing: u64,
+    pub breaks: u64,
+    /// Bytes received while the receive queue was full
+    pub dropped: u64,
+}
+
+/// A fixed-size byte queue. It doesn't allocate, so ports work before the
+/// heap is set up.
+struct ByteQueue {
+    bytes: [u8; QUEUE_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl ByteQueue {
+    const fn new() -> ByteQueue {
+        ByteQueue { bytes: [0; QUEUE_SIZE], start: 0, len: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == QUEUE_SIZE
+    }
+
+    /// Returns false if the queue is full.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.bytes[(self.start + self.len) % QUEUE_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+struct PortState {
+    /// Set once the port is detected and set up
+    uart: Option<Uart>,
+    config: Option<LineConfig>,
+    /// Whether the IRQ handler services the port, otherwise it is polled
+    interrupt_driven: bool,
+    /// Whether the transmitter empty interrupt is enabled, which it is while
+    /// there are bytes to send
+    transmitting: bool,
+    transmit: ByteQueue,
+    receive: ByteQueue,
+    errors: LineErrors,
+    /// Tasks blocked in `read`
+    waiters: Vec<TaskId>,
+}
+
+impl PortState {
+    const fn new() -> PortState {
+        PortState {
+            uart: None,
+            config: None,
+            interrupt_driven: false,
+            transmitting: false,
+            transmit: ByteQueue::new(),
+            receive: ByteQueue::new(),
+            errors: LineErrors { overrun: 0, parity: 0, framing: 0, breaks: 0, dropped: 0 },
+            waiters: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, port: u16, byte: u8) {
+        if self.uart.is_none() {
+            return;
+        }
+        if !self.interrupt_driven {
+            transmit_polled(port, byte);
+            return;
+        }
+        if self.transmit.is_full() {
+            transmit_polled(port, self.transmit.pop().unwrap());
+        }
+        self.transmit.push(byte);
+        if !self.transmitting {
+            // The interrupt fires right away if the transmitter is idle
+            self.transmitting = true;
+            outb(port + INTERRUPT_ENABLE, IER_RECEIVED_DATA | IER_LINE_STATUS | IER_TRANSMITTER_EMPTY);
+        }
+    }
+
+    /// Sends the queued bytes by polling.
+    fn flush(&mut self, port: u16) {
+        while let Some(byte) = self.transmit.pop() {
+            transmit_polled(port, byte);
+        }
+    }
+
+    /// Fills the transmitter from the queue, and stops the transmitter empty
+    /// interrupt once the queue is empty.
+    fn transmit(&mut self, port: u16) {
+        let fifo_size = self.uart.map_or(1, Uart::fifo_size);
+        if inb(port + LINE_STATUS) & LSR_TRANSMITTER_EMPTY != 0 {
+            for _ in 0..fifo_size {
+                match self.transmit.pop() {
+                    Some(byte) => outb(port + DATA, byte),
+                    None => break,
+                }
+            }
+        }
+        if self.transmit.is_empty() && self.transmitting {
+            self.transmitting = false;
+            outb(port + INTERRUPT_ENABLE, IER_RECEIVED_DATA | IER_LINE_STATUS);
+        }
+    }
+
+    /// Moves the received bytes to the queue and wakes the readers up.
+    fn receive(&mut self, port: u16) {
+        loop {
+            let status = self.line_status(port);
+            if status & LSR_DATA_READY == 0 {
+                break;
+            }
+            let byte = inb(port + DATA);
+            if !self.receive.push(byte) {
+                self.errors.dropped += 1;
+            }
+        }
+        for waiter in self.waiters.drain(..) {
+            task::wake(waiter);
+        }
+    }
+
+    /// Reads the line status register, which clears the error bits, and
+    /// counts the errors.
+    fn line_status(&mut self, port: u16) -> u8 {
+        let status = inb(port + LINE_STATUS);
+        if status & LSR_OVERRUN_ERROR != 0 {
+            self.errors.overrun += 1;
+        }
+        if status & LSR_PARITY_ERROR != 0 {
+            self.errors.parity += 1;
+        }
+        if status & LSR_FRAMING_ERROR != 0 {
+            self.errors.framing += 1;
+        }
+        if status & LSR_BREAK != 0 {
+            self.errors.breaks += 1;
+        }
+        status
+    }
+
+    /// Reads the received bytes into `buffer`. Returns the number of bytes
+    /// read.
+    fn read(&mut self, port: u16, buffer: &mut [u8]) -> usize {
+        if self.uart.is_none() {
+            return 0;
+        }
+        let mut count = 0;
+        while count < buffer.len() {
+            let byte = if self.interrupt_driven {
+                self.receive.pop()
+            } else if self.line_status(port) & LSR_DATA_READY != 0 {
+                Some(inb(port + DATA))
+            } else {
+                None
+            };
+            match byte {
+                Some(byte) => buffer[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
 #[derive(Debug)]
 pub struct Serial(pub u16);
 
 impl Serial {
-    pub fn init(&self, baud_rate: u32) {
-        if 115200 % baud_rate != 0 {
-            panic!("Invalid baud rate {}. Value must divide 115200.", baud_rate);
+    /// Detects the UART and sets it up. The port is left alone if it doesn't
+    /// pass the self-test.
+    pub fn init(&self, config: LineConfig) -> Result<Uart, SerialError> {
+        if config.baud_rate == 0 || BASE_BAUD_RATE % config.baud_rate != 0 {
+            return Err(SerialError::InvalidBaudRate(config.baud_rate));
+        }
+        // Nothing answers on the bus
+        if inb(self.0 + LINE_STATUS) == 0xFF {
+            return Err(SerialError::NotPresent);
         }
 
-        outb(self.0 + 1, 0x00); // Disable interrupts
-        outb(self.0 + 3, 0x80); // Enable DLAB
-        {
-            // Set baud rate
-            let baud_rate_divisor = (115200 / baud_rate) as u16;
-            let baud_rate_lo = (baud_rate_divisor & 0x00FF) as u8;
-            let baud_rate_hi = (baud_rate_divisor >> 8) as u8;
-            outb(self.0 + 0, baud_rate_lo);
-            outb(self.0 + 1, baud_rate_hi);
+        let mut state = self.state().lock();
+        // What is queued was meant for the previous line format
+        state.flush(self.0);
+        outb(self.0 + INTERRUPT_ENABLE, 0x00);
+        outb(self.0 + LINE_CONTROL, LCR_DLAB);
+        let divisor = (BASE_BAUD_RATE / config.baud_rate) as u16;
+        outb(self.0 + DATA, divisor as u8);
+        outb(self.0 + INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        let uart = self.identify();
+        outb(self.0 + LINE_CONTROL, config.line_control());
+        if let Err(error) = self.self_test() {
+            // The port was reprogrammed, whatever it was set up with before
+            // no longer holds
+            state.uart = None;
+            state.config = None;
+            return Err(error);
         }
-        outb(self.0 + 3, 0x03); // 8 bits, no parity, one stop bit
-        outb(self.0 + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
-        outb(self.0 + 4, 0x0B); // IRQs enabled, RTS/DSR set
+
+        let fifo_control = if uart.fifo_size() > 1 {
+            FCR_ENABLE | FCR_CLEAR_RECEIVE | FCR_CLEAR_TRANSMIT | FCR_TRIGGER_14
+        } else {
+            0
+        };
+        outb(self.0 + FIFO_CONTROL, fifo_control);
+        outb(self.0 + MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+        state.uart = Some(uart);
+        state.config = Some(config);
+        if state.interrupt_driven {
+            state.transmitting = false;
+            outb(self.0 + INTERRUPT_ENABLE, IER_RECEIVED_DATA | IER_LINE_STATUS);
+        }
+        Ok(uart)
+    }
+
+    /// Tells the model from the FIFO it has. Must be called with `LCR_DLAB`
+    /// set, for the 64 byte FIFO of the 16750 to be enabled.
+    fn identify(&self) -> Uart {
+        outb(self.0 + FIFO_CONTROL, FCR_ENABLE | FCR_CLEAR_RECEIVE | FCR_CLEAR_TRANSMIT | FCR_64_BYTES
+            | FCR_TRIGGER_14);
+        let id = inb(self.0 + INTERRUPT_ID);
+        if id & IIR_FIFO_WORKING == 0 {
+            if id & IIR_FIFO_ENABLED != 0 {
+                return Uart::U16550;
+            }
+            // The 8250 has no scratch register
+            outb(self.0 + SCRATCH, 0x5A);
+            return if inb(self.0 + SCRATCH) == 0x5A { Uart::U16450 } else { Uart::U8250 };
+        }
+        if id & IIR_FIFO_64_BYTES != 0 { Uart::U16750 } else { Uart::U16550A }
+    }
+
+    /// Sends a byte to the receiver in loopback mode and checks it comes
+    /// back.
+    fn self_test(&self) -> Result<(), SerialError> {
+        outb(self.0 + MODEM_CONTROL, MCR_RTS | MCR_OUT1 | MCR_OUT2 | MCR_LOOPBACK);
+        // Drop what was received before
+        for _ in 0..Uart::U16750.fifo_size() {
+            if inb(self.0 + LINE_STATUS) & LSR_DATA_READY == 0 {
+                break;
+            }
+            inb(self.0 + DATA);
+        }
+        outb(self.0 + DATA, SELF_TEST_BYTE);
+        let mut waited = 0;
+        let result = loop {
+            if inb(self.0 + LINE_STATUS) & LSR_DATA_READY != 0 {
+                break match inb(self.0 + DATA) {
+                    SELF_TEST_BYTE => Ok(()),
+                    _ => Err(SerialError::SelfTestFailed),
+                };
+            }
+            if waited >= SELF_TEST_TIMEOUT_US {
+                break Err(SerialError::NotPresent);
+            }
+            busy_wait_us(100);
+            waited += 100;
+        };
+        outb(self.0 + MODEM_CONTROL, 0);
+        result
+    }
+
+    /// "ttyS0" to "ttyS3", like Linux names the ports.
+    pub fn name(&self) -> &'static str {
+        ["ttyS0", "ttyS1", "ttyS2", "ttyS3"][index(self.0)]
+    }
+
+    pub fn uart(&self) -> Option<Uart> {
+        self.state().lock().uart
+    }
+
+    /// The line format the port was set up with.
+    pub fn config(&self) -> Option<LineConfig> {
+        self.state().lock().config
+    }
+
+    pub fn errors(&self) -> LineErrors {
+        self.state().lock().errors
+    }
+
+    /// Queues bytes for transmission. Doesn't wait for them to be sent
+    /// unless the queue is full.
+    pub fn write(&self, bytes: &[u8]) {
+        let mut state = self.state().lock();
+        for &byte in bytes {
+            state.write(self.0, byte);
+        }
+    }
+
+    /// Sends the queued bytes, waiting for the transmitter rather than for
+    /// the interrupts. Called before the machine stops or resets.
+    pub fn flush(&self) {
+        self.state().lock().flush(self.0);
+    }
+
+    /// Reads the received bytes into `buffer`, blocking until at least one
+    /// byte is available. Returns right away when the caller can't block,
+    /// i.e. before interrupts are used, in the idle task or with interrupts
+    /// disabled. Returns the number of bytes read.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
+        let can_block = !task::is_idle() && are_interrupts_enabled();
+        loop {
+            // The IRQ must not slip in between the check and `block`, or the
+            // wake up would be lost
+            let _guard = InterruptGuard::new();
+            {
+                let mut state = self.state().lock();
+                let count = state.read(self.0, buffer);
+                if count > 0 || !can_block || !state.interrupt_driven {
+                    return count;
+                }
+                state.waiters.push(task::current_id());
+            }
+            task::block();
+        }
+    }
+
+    fn state(&self) -> &'static IrqMutex<PortState> {
+        &PORTS[index(self.0)]
     }
 }
 
 impl Write for Serial {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
-            write_char(self.0, byte);
-        }
+        self.write(s.as_bytes());
         Ok(())
     }
 }
 
-#[inline]
-fn write_char(com: u16, c: u8) {
-    while is_transmit_empty(com) == 0 {}
-    outb(com, c);
+fn index(port: u16) -> usize {
+    match port {
+        COM1 => 0,
+        COM2 => 1,
+        COM3 => 2,
+        COM4 => 3,
+        _ => panic!("No serial port at {:#x}", port),
+    }
 }
 
-#[inline]
-fn is_transmit_empty(com: u16) -> u8 {
-    inb(com + 5) & 0x20
+/// Ports sharing each IRQ line.
+const IRQ_PORTS: [(Irq, [u16; 2]); 2] = [(Irq::COM1, [COM1, COM3]), (Irq::COM2, [COM2, COM4])];
+
+/// Sets up the ports found with the same line format, and reports them on
+/// COM1 once they are all probed.
+pub fn init(config: LineConfig) {
+    let mut results = [Err(SerialError::NotPresent); 4];
+    for (result, &port) in results.iter_mut().zip([COM1, COM2, COM3, COM4].iter()) {
+        *result = Serial(port).init(config);
+    }
+    for (result, &port) in results.iter().zip([COM1, COM2, COM3, COM4].iter()) {
+        let serial = Serial(port);
+        match result {
+            Ok(uart) => writeln!(Serial(COM1), "{}: {:?} at {:#x}, {}", serial.name(), uart, port, config),
+            Err(SerialError::NotPresent) => Ok(()),
+            Err(error) => writeln!(Serial(COM1), "{}: can't set up the port at {:#x}: {:?}",
+                                   serial.name(), port, error),
+        }.unwrap();
+    }
+}
+
+/// The ports that were found, in order.
+pub fn ports() -> impl Iterator<Item=Serial> {
+    [COM1, COM2, COM3, COM4].iter()
+        .map(|&port| Serial(port))
+        .filter(|serial| serial.uart().is_some())
+}
+
+/// Switches the ports from polling to interrupts. Output queued from then on
+/// is sent once interrupts are enabled.
+pub fn init_interrupts() {
+    for &(irq, ports) in IRQ_PORTS.iter() {
+        let mut found = false;
+        for &port in ports.iter() {
+            let mut state = PORTS[index(port)].lock();
+            if state.uart.is_none() {
+                continue;
+            }
+            state.interrupt_driven = true;
+            state.transmitting = false;
+            outb(port + INTERRUPT_ENABLE, IER_RECEIVED_DATA | IER_LINE_STATUS);
+            found = true;
+        }
+        if found {
+            irq::register_handler(irq, serial_irq_handler);
+        }
+    }
+}
+
+/// Sends what is left in the transmit queues. Called before the machine
+/// stops or resets, when the IRQ may never come.
+pub fn flush_all() {
+    for serial in ports() {
+        serial.flush();
+    }
+}
+
+fn serial_irq_handler(irq: Irq) {
+    let ports = IRQ_PORTS.iter().find(|(port_irq, _)| *port_irq == irq).unwrap().1;
+    for &port in ports.iter() {
+        let mut state = PORTS[index(port)].lock();
+        if state.uart.is_none() {
+            continue;
+        }
+        // Several conditions can be pending, they are reported by priority
+        loop {
+            let id = inb(port + INTERRUPT_ID);
+            if id & IIR_NO_INTERRUPT != 0 {
+                break;
+            }
+            match id & IIR_ID_MASK {
+                IIR_LINE_STATUS => {
+                    state.line_status(port);
+                }
+                IIR_RECEIVED_DATA | IIR_CHARACTER_TIMEOUT => state.receive(port),
+                IIR_TRANSMITTER_EMPTY => state.transmit(port),
+                IIR_MODEM_STATUS => {
+                    inb(port + MODEM_STATUS);
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+fn transmit_polled(port: u16, byte: u8) {
+    while !is_transmit_empty(port) {}
+    outb(port + DATA, byte);
+}
+
+fn is_transmit_empty(port: u16) -> bool {
+    inb(port + LINE_STATUS) & LSR_TRANSMITTER_EMPTY != 0
 }
 
 #[macro_export]
@@ -62,7 +693,7 @@ macro_rules! println {
         {
             use crate::serial::{Serial, COM1};
             use core::fmt::Write;
-            use crate::inline_asm::without_interrupts;
+            use crate::sync::without_interrupts;
             without_interrupts(|| {
                 Serial(COM1).write_fmt(format_args_nl!($($arg)*)).unwrap();
             })
@@ -76,7 +707,7 @@ macro_rules! print {
         {
             use crate::serial::{Serial, COM1};
             use core::fmt::Write;
-            use crate::inline_asm::without_interrupts;
+            use crate::sync::without_interrupts;
             without_interrupts(|| {
                 Serial(COM1).write_fmt(format_args!($($arg)*)).unwrap();
             })
@@ -105,4 +736,50 @@ macro_rules! dbg {
     ($($val:expr),+ $(,)?) => {
         ($(dbg!($val)),+,)
     };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pit;
+
+    use super::*;
+
+    #[test_case]
+    fn write_to_com1() {
+        assert!(Serial(COM1).write_str("serial test output\n").is_ok());
+    }
+
+    #[test_case]
+    fn transmitter_drains() {
+        Serial(COM1).write_str("x\n").unwrap();
+        Serial(COM1).flush();
+        while !is_transmit_empty(COM1) {}
+    }
+
+    #[test_case]
+    fn loopback_bytes_are_received() {
+        let serial = Serial(COM1);
+        // Nothing reaches the line in loopback mode
+        serial.flush();
+        outb(COM1 + MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2 | MCR_LOOPBACK);
+        serial.write(b"loop");
+        let mut buffer = [0; 4];
+        let mut count = 0;
+        let deadline = pit::uptime_ms() + 1000;
+        while count < buffer.len() && pit::uptime_ms() < deadline {
+            count += serial.read(&mut buffer[count..]);
+        }
+        serial.flush();
+        outb(COM1 + MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+        assert_eq!(&buffer[..count], b"loop");
+        assert_eq!(serial.errors(), LineErrors::default());
+    }
+
+    #[test_case]
+    fn ports_are_detected() {
+        // QEMU emulates a 16550A, and the tests only give it one port
+        assert_eq!(Serial(COM1).uart(), Some(Uart::U16550A));
+        assert_eq!(ports().map(|serial| serial.0).collect::<Vec<_>>(), [COM1]);
+        assert_eq!(Serial(COM2).init(LineConfig::new(7)), Err(SerialError::InvalidBaudRate(7)));
+    }
+}
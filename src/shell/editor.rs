@@ -0,0 +1,308 @@
+//! Line editing with history and completion.
+//!
+//! The editor only knows about keys and a console it can write to and move
+//! the cursor on, the line is redrawn from the cursor on after each change.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::ps2::Key;
+
+/// Longest line, short enough to fit on the screen with the prompt
+const MAX_LINE_LENGTH: usize = 256;
+/// Lines remembered for Up and Down
+const HISTORY_SIZE: usize = 32;
+
+pub trait Console {
+    fn write(&mut self, text: &str);
+    /// Moves the cursor by `offset` characters, to the left if negative.
+    fn move_cursor(&mut self, offset: isize);
+}
+
+/// Completes the first word of a line. Returns the words starting with the
+/// given prefix.
+pub type Completer = fn(&str) -> Vec<&'static str>;
+
+pub struct LineEditor {
+    prompt: &'static str,
+    complete: Completer,
+    line: Vec<char>,
+    /// Position in `line`
+    cursor: usize,
+    history: VecDeque<String>,
+    /// Entry of the history being shown, `history.len()` for the line being
+    /// typed
+    history_index: usize,
+    /// The line being typed, while an entry of the history is shown
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    pub fn new(prompt: &'static str, complete: Completer) -> LineEditor {
+        LineEditor {
+            prompt,
+            complete,
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_index: 0,
+            draft: Vec::new(),
+        }
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        self.prompt
+    }
+
+    /// Applies a key to the line. Returns the line once Enter is pressed.
+    pub fn handle_key(&mut self, key: Key, console: &mut dyn Console) -> Option<String> {
+        match key {
+            Key::Char(char) => self.insert(&[char], console),
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    console.move_cursor(-1);
+                    self.line.remove(self.cursor);
+                    self.redraw_tail(1, console);
+                }
+            }
+            Key::Delete => {
+                if self.cursor < self.line.len() {
+                    self.line.remove(self.cursor);
+                    self.redraw_tail(1, console);
+                }
+            }
+            Key::Left => self.move_to(self.cursor.saturating_sub(1), console),
+            Key::Right => self.move_to((self.cursor + 1).min(self.line.len()), console),
+            Key::Home => self.move_to(0, console),
+            Key::End => self.move_to(self.line.len(), console),
+            Key::Up => {
+                if self.history_index > 0 {
+                    if self.history_index == self.history.len() {
+                        self.draft = self.line.clone();
+                    }
+                    self.history_index -= 1;
+                    let entry = self.history[self.history_index].chars().collect();
+                    self.replace_line(entry, console);
+                }
+            }
+            Key::Down => {
+                if self.history_index < self.history.len() {
+                    self.history_index += 1;
+                    let line = if self.history_index == self.history.len() {
+                        core::mem::take(&mut self.draft)
+                    } else {
+                        self.history[self.history_index].chars().collect()
+                    };
+                    self.replace_line(line, console);
+                }
+            }
+            Key::Tab => self.complete(console),
+            Key::Enter => {
+                self.move_to(self.line.len(), console);
+                console.write("\n");
+                let line: String = self.line.drain(..).collect();
+                self.cursor = 0;
+                self.draft.clear();
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == HISTORY_SIZE {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+                self.history_index = self.history.len();
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, chars: &[char], console: &mut dyn Console) {
+        if self.line.len() + chars.len() > MAX_LINE_LENGTH {
+            return;
+        }
+        for (i, &char) in chars.iter().enumerate() {
+            self.line.insert(self.cursor + i, char);
+        }
+        self.redraw_tail(0, console);
+        self.move_to(self.cursor + chars.len(), console);
+    }
+
+    /// Writes the line from the cursor on, and blanks the `erased` characters
+    /// after its end. The cursor doesn't move.
+    fn redraw_tail(&self, erased: usize, console: &mut dyn Console) {
+        let mut text: String = self.line[self.cursor..].iter().collect();
+        text.extend(core::iter::repeat(' ').take(erased));
+        console.write(&text);
+        console.move_cursor(-((self.line.len() - self.cursor + erased) as isize));
+    }
+
+    fn move_to(&mut self, cursor: usize, console: &mut dyn Console) {
+        console.move_cursor(cursor as isize - self.cursor as isize);
+        self.cursor = cursor;
+    }
+
+    /// Shows another line, with the cursor at its end.
+    fn replace_line(&mut self, line: Vec<char>, console: &mut dyn Console) {
+        let erased = self.line.len().saturating_sub(line.len());
+        self.move_to(0, console);
+        self.line = line;
+        self.redraw_tail(erased, console);
+        self.move_to(self.line.len(), console);
+    }
+
+    /// Completes the command before the cursor. When several commands match,
+    /// the common prefix is completed, and the commands are listed if there
+    /// is nothing to complete.
+    fn complete(&mut self, console: &mut dyn Console) {
+        let prefix: String = self.line[..self.cursor].iter().collect();
+        if prefix.contains(' ') {
+            return;
+        }
+        let candidates = (self.complete)(&prefix);
+        let common = match candidates.split_first() {
+            Some((first, rest)) => rest.iter().fold(*first, |common, candidate| {
+                let length = common.chars().zip(candidate.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a.len_utf8())
+                    .sum();
+                &common[..length]
+            }),
+            None => return,
+        };
+        let mut completion: Vec<char> = common.chars().skip(self.cursor).collect();
+        if candidates.len() == 1 && self.line.get(self.cursor) != Some(&' ') {
+            completion.push(' ');
+        }
+        if !completion.is_empty() {
+            self.insert(&completion, console);
+            return;
+        }
+        if candidates.len() > 1 {
+            let cursor = self.cursor;
+            self.move_to(self.line.len(), console);
+            let line: String = self.line.iter().collect();
+            console.write(&format!("\n{}\n{}{}", candidates.join("  "), self.prompt, line));
+            self.move_to(cursor, console);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    /// A single line screen.
+    struct TestConsole {
+        screen: Vec<char>,
+        cursor: usize,
+        /// Lines that were ended with a newline
+        lines: Vec<String>,
+    }
+
+    impl Console for TestConsole {
+        fn write(&mut self, text: &str) {
+            for char in text.chars() {
+                if char == '\n' {
+                    self.lines.push(self.screen.drain(..).collect::<String>().trim_end().into());
+                    self.cursor = 0;
+                    continue;
+                }
+                if self.cursor == self.screen.len() {
+                    self.screen.push(char);
+                } else {
+                    self.screen[self.cursor] = char;
+                }
+                self.cursor += 1;
+            }
+        }
+
+        fn move_cursor(&mut self, offset: isize) {
+            self.cursor = (self.cursor as isize + offset) as usize;
+            assert!(self.cursor <= self.screen.len());
+        }
+    }
+
+    fn complete(prefix: &str) -> Vec<&'static str> {
+        vec!["echo", "help", "halt"].into_iter().filter(|word| word.starts_with(prefix)).collect()
+    }
+
+    fn type_keys(editor: &mut LineEditor, console: &mut TestConsole, keys: &[Key]) -> Option<String> {
+        let mut line = None;
+        for &key in keys {
+            line = editor.handle_key(key, console);
+        }
+        line
+    }
+
+    fn chars(text: &str) -> Vec<Key> {
+        text.chars().map(Key::Char).collect()
+    }
+
+    fn console() -> TestConsole {
+        TestConsole { screen: Vec::new(), cursor: 0, lines: Vec::new() }
+    }
+
+    fn screen(console: &TestConsole) -> String {
+        console.screen.iter().collect::<String>().trim_end().into()
+    }
+
+    #[test_case]
+    fn lines_are_edited() {
+        let mut editor = LineEditor::new("> ", complete);
+        let mut console = console();
+        type_keys(&mut editor, &mut console, &chars("ehco"));
+        type_keys(&mut editor, &mut console, &[Key::Left, Key::Left, Key::Left, Key::Delete]);
+        type_keys(&mut editor, &mut console, &[Key::Right, Key::Char('h'), Key::End, Key::Backspace]);
+        assert_eq!(screen(&console), "ech");
+        type_keys(&mut editor, &mut console, &[Key::Char('o')]);
+        assert_eq!(screen(&console), "echo");
+        assert_eq!(console.cursor, 4);
+        type_keys(&mut editor, &mut console, &[Key::Home, Key::Delete, Key::Char('E')]);
+        assert_eq!((screen(&console).as_str(), console.cursor), ("Echo", 1));
+        let line = type_keys(&mut editor, &mut console, &[Key::Enter]);
+        assert_eq!(line.as_deref(), Some("Echo"));
+        assert_eq!(console.lines, ["Echo"]);
+    }
+
+    #[test_case]
+    fn history_is_browsed() {
+        let mut editor = LineEditor::new("> ", complete);
+        let mut console = console();
+        for line in ["first", "second", "second", ""].iter() {
+            type_keys(&mut editor, &mut console, &chars(line));
+            type_keys(&mut editor, &mut console, &[Key::Enter]);
+        }
+        type_keys(&mut editor, &mut console, &chars("draft"));
+        type_keys(&mut editor, &mut console, &[Key::Up, Key::Up, Key::Up]);
+        assert_eq!(screen(&console), "first");
+        type_keys(&mut editor, &mut console, &[Key::Down]);
+        assert_eq!(screen(&console), "second");
+        type_keys(&mut editor, &mut console, &[Key::Down]);
+        assert_eq!((screen(&console).as_str(), console.cursor), ("draft", 5));
+        type_keys(&mut editor, &mut console, &[Key::Up, Key::Backspace]);
+        let line = type_keys(&mut editor, &mut console, &[Key::Enter]);
+        assert_eq!(line.as_deref(), Some("secon"));
+    }
+
+    #[test_case]
+    fn commands_are_completed() {
+        let mut editor = LineEditor::new("> ", complete);
+        let mut console = console();
+        type_keys(&mut editor, &mut console, &[Key::Char('e'), Key::Tab]);
+        assert_eq!(screen(&console), "echo");
+        assert_eq!(console.cursor, 5);
+        type_keys(&mut editor, &mut console, &[Key::Home, Key::Delete, Key::Char('h')]);
+        assert_eq!(screen(&console), "hcho");
+        type_keys(&mut editor, &mut console, &[Key::Char('a'), Key::Tab]);
+        assert_eq!(screen(&console), "halt cho");
+        // Two candidates and nothing to complete, both are listed
+        type_keys(&mut editor, &mut console, &[Key::Enter, Key::Char('h'), Key::Tab]);
+        assert_eq!(console.lines, ["halt cho", "h", "help  halt"]);
+        assert_eq!((screen(&console).as_str(), console.cursor), ("> h", 3));
+    }
+}
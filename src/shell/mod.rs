@@ -0,0 +1,203 @@
+//! Interactive shell on the VGA console.
+//!
+//! A shell runs on each virtual terminal as a kernel task, reading the keys
+//! typed there. The line is edited in place, Up and Down go through the
+//! previous lines and Tab completes command names. Commands are built into
+//! the kernel.
+
+use core::fmt;
+use core::fmt::Write;
+
+use alloc::vec::Vec;
+
+use crate::ps2;
+use crate::serial::ports;
+use crate::vga;
+use crate::vga::TerminalWriter;
+use crate::{acpi, allocator, fs, irq, memory, pci, pit};
+
+use self::editor::{Console, LineEditor};
+
+mod editor;
+
+const PROMPT: &str = "\x1b[1;32mkrill>\x1b[0m ";
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&mut dyn Write, &[&str]) -> fmt::Result,
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help", help: "List the commands", run: help },
+    Command { name: "clear", help: "Clear the screen", run: clear },
+    Command { name: "echo", help: "Print the arguments", run: echo },
+    Command { name: "irqstats", help: "Show how many times each IRQ fired", run: irqstats },
+    Command { name: "lspci", help: "List the PCI devices, with details if -v is given", run: lspci },
+    Command { name: "mem", help: "Show the heap and physical memory usage", run: mem },
+    Command { name: "reboot", help: "Sync the file systems and restart the machine", run: reboot },
+    Command { name: "serial", help: "Show the serial ports and their line errors", run: serial },
+    Command { name: "uptime", help: "Show the time since boot", run: uptime },
+    Command { name: "vgabench", help: "Measure how fast text is drawn on the screen", run: vgabench },
+];
+
+/// Lines written by each of the four passes of `vgabench`
+const BENCHMARK_LINES: usize = 1000;
+
+impl Console for TerminalWriter {
+    fn write(&mut self, text: &str) {
+        self.write_str(text).unwrap();
+    }
+
+    fn move_cursor(&mut self, offset: isize) {
+        vga::move_cursor(self.0, offset);
+    }
+}
+
+/// Reads and runs the commands typed on a terminal, forever.
+pub fn run(terminal: usize) {
+    let mut editor = LineEditor::new(PROMPT, complete);
+    let mut console = TerminalWriter(terminal);
+    writeln!(console, "Type help for the list of commands").unwrap();
+    loop {
+        console.write(editor.prompt());
+        let line = loop {
+            if let Some(line) = editor.handle_key(ps2::read_key(terminal), &mut console) {
+                break line;
+            }
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, arguments) = match words.split_first() {
+            Some((name, arguments)) => (*name, arguments),
+            None => continue,
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(&mut console, arguments).unwrap(),
+            None => writeln!(console, "{}: command not found", name).unwrap(),
+        }
+    }
+}
+
+fn complete(prefix: &str) -> Vec<&'static str> {
+    COMMANDS.iter()
+        .map(|command| command.name)
+        .filter(|name| name.starts_with(prefix))
+        .collect()
+}
+
+fn help(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    for command in COMMANDS {
+        writeln!(out, "  {:<10}{}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn clear(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    // Erase the screen and put the cursor at the top left
+    write!(out, "\x1b[2J\x1b[H")
+}
+
+fn echo(out: &mut dyn Write, arguments: &[&str]) -> fmt::Result {
+    writeln!(out, "{}", arguments.join(" "))
+}
+
+fn irqstats(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    writeln!(out, "Interrupt controller: {:?}", irq::controller())?;
+    for (line, &count) in irq::counts().iter().enumerate() {
+        if count > 0 {
+            let irq = irq::Irq(line as u8);
+            writeln!(out, "  {:<8}{:>12}  {}", irq, count, irq.name().unwrap_or(""))?;
+        }
+    }
+    Ok(())
+}
+
+fn lspci(out: &mut dyn Write, arguments: &[&str]) -> fmt::Result {
+    if arguments.contains(&"-v") {
+        return pci::dump(out);
+    }
+    for device in pci::devices() {
+        writeln!(out, "{}", device)?;
+    }
+    Ok(())
+}
+
+fn mem(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    let heap = allocator::stats();
+    writeln!(out, "Heap: {} KiB used of {} KiB, peak {} KiB, {} allocations, {} failed",
+             heap.allocated_bytes / 1024, heap.size / 1024, heap.peak_allocated_bytes / 1024,
+             heap.allocations, heap.failed_allocations)?;
+    let (usable, free) = {
+        let frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        (frame_allocator.usable_frames() as u64, frame_allocator.free_frames() as u64)
+    };
+    writeln!(out, "Physical memory: {} KiB used of {} KiB",
+             (usable - free) * memory::FRAME_SIZE / 1024, usable * memory::FRAME_SIZE / 1024)
+}
+
+fn reboot(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    if let Err(error) = fs::sync() {
+        return writeln!(out, "Can't sync the file systems: {:?}", error);
+    }
+    acpi::reboot();
+}
+
+fn serial(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    for serial in ports() {
+        if let (Some(uart), Some(config)) = (serial.uart(), serial.config()) {
+            let errors = serial.errors();
+            writeln!(out, "{} at {:#x}: {:?}, {}", serial.name(), serial.0, uart, config)?;
+            writeln!(out, "  overrun {}  parity {}  framing {}  break {}  dropped {}",
+                     errors.overrun, errors.parity, errors.framing, errors.breaks, errors.dropped)?;
+        }
+    }
+    Ok(())
+}
+
+fn uptime(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    let ms = pit::uptime_ms();
+    let seconds = ms / 1000;
+    writeln!(out, "up {}:{:02}:{:02}.{:03}", seconds / 3600, seconds / 60 % 60, seconds % 60, ms % 1000)
+}
+
+/// Writes the same text a character and a line at a time, scrolling by
+/// copying the screen up and with the hardware. A character at a time with
+/// copying is how the console used to draw text.
+fn vgabench(out: &mut dyn Write, _arguments: &[&str]) -> fmt::Result {
+    let result = measure_throughputs(out);
+    vga::set_hardware_scrolling(true);
+    let throughputs = result?;
+    writeln!(out, "KiB/s          copying  hardware")?;
+    writeln!(out, "By character  {:>8}  {:>8}", throughputs[0][0], throughputs[0][1])?;
+    writeln!(out, "By line       {:>8}  {:>8}", throughputs[1][0], throughputs[1][1])
+}
+
+/// Throughputs a character then a line at a time, each scrolling by copying
+/// then with the hardware.
+fn measure_throughputs(out: &mut dyn Write) -> Result<[[u64; 2]; 2], fmt::Error> {
+    let mut throughputs = [[0; 2]; 2];
+    for (column, &hardware_scrolling) in [false, true].iter().enumerate() {
+        vga::set_hardware_scrolling(hardware_scrolling);
+        for (row, &by_char) in [true, false].iter().enumerate() {
+            throughputs[row][column] = measure_throughput(out, by_char)?;
+        }
+    }
+    Ok(throughputs)
+}
+
+/// Writes `BENCHMARK_LINES` lines and returns the KiB written per second.
+fn measure_throughput(out: &mut dyn Write, by_char: bool) -> Result<u64, fmt::Error> {
+    let line = "The quick brown fox jumps over the lazy dog, 0123456789 times over and over.\n";
+    let start = pit::uptime_ns();
+    for _ in 0..BENCHMARK_LINES {
+        if by_char {
+            for char in line.chars() {
+                out.write_char(char)?;
+            }
+        } else {
+            out.write_str(line)?;
+        }
+    }
+    let ns = (pit::uptime_ns() - start).max(1);
+    Ok((BENCHMARK_LINES * line.len()) as u64 * 1_000_000_000 / ns / 1024)
+}
@@ -0,0 +1,175 @@
+//! Interrupt-safe critical sections.
+//!
+//! A plain spin lock taken both by normal code and by an interrupt handler
+//! deadlocks as soon as the interrupt fires while the lock is held. The types
+//! in this module keep interrupts disabled for as long as they are alive and
+//! restore the previous interrupt state afterwards, so they nest properly.
+//!
+//! `SleepMutex` is for the long critical sections that sleep, such as a
+//! command to a disk: the tasks waiting for it block instead of spinning.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+use alloc::vec::Vec;
+
+use spin::{Mutex, MutexGuard};
+
+use crate::inline_asm::{are_interrupts_enabled, disable_interrupts, enable_interrupts};
+use crate::task;
+use crate::task::TaskId;
+
+/// Disables interrupts until dropped.
+pub struct InterruptGuard {
+    were_interrupts_enabled: bool,
+    // Interrupts are a per-CPU state, the guard must stay on this CPU
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    pub fn new() -> InterruptGuard {
+        let were_interrupts_enabled = are_interrupts_enabled();
+        if were_interrupts_enabled {
+            disable_interrupts();
+        }
+        InterruptGuard {
+            were_interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.were_interrupts_enabled {
+            enable_interrupts();
+        }
+    }
+}
+
+/// Runs `f` with interrupts disabled.
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R {
+    let _guard = InterruptGuard::new();
+    f()
+}
+
+/// A spin lock that can be shared with interrupt handlers.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        // Disable interrupts first so that a handler can't spin on a lock held
+        // by the code it interrupted
+        let interrupt_guard = InterruptGuard::new();
+        IrqMutexGuard {
+            guard: self.inner.lock(),
+            _interrupt_guard: interrupt_guard,
+        }
+    }
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    // Fields are dropped in order: unlock, then restore interrupts
+    guard: MutexGuard<'a, T>,
+    _interrupt_guard: InterruptGuard,
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+/// A lock whose owner may sleep while holding it. Other tasks block until it
+/// is released, the idle task, which can't block, yields instead.
+pub struct SleepMutex<T> {
+    state: IrqMutex<SleepState>,
+    value: UnsafeCell<T>,
+}
+
+struct SleepState {
+    locked: bool,
+    waiters: Vec<TaskId>,
+}
+
+// The value is only reached through the guard of the task holding the lock
+unsafe impl<T: Send> Sync for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    pub const fn new(value: T) -> SleepMutex<T> {
+        SleepMutex {
+            state: IrqMutex::new(SleepState { locked: false, waiters: Vec::new() }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SleepMutexGuard<T> {
+        loop {
+            // The release must not come between the check and `block`, or
+            // the wake up would be lost
+            let guard = InterruptGuard::new();
+            let idle = task::is_idle();
+            {
+                let mut state = self.state.lock();
+                if !state.locked {
+                    state.locked = true;
+                    return SleepMutexGuard { mutex: self };
+                }
+                if !idle {
+                    state.waiters.push(task::current_id());
+                }
+            }
+            if idle {
+                drop(guard);
+                task::yield_now();
+            } else {
+                task::block();
+            }
+        }
+    }
+}
+
+pub struct SleepMutexGuard<'a, T> {
+    mutex: &'a SleepMutex<T>,
+}
+
+impl<'a, T> Deref for SleepMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SleepMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SleepMutexGuard<'a, T> {
+    /// Wakes all the waiters, the first one to run takes the lock.
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.locked = false;
+        for waiter in state.waiters.drain(..) {
+            task::wake(waiter);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Kernel entry points for system calls.
+//!
+//! `syscall` doesn't switch stacks, so the entry stub swaps the user stack
+//! for the kernel stack of the current task itself. `int 0x80` goes through
+//! the IDT and lands on the same stack through the TSS. Both paths save the
+//! registers of the caller in a `SyscallFrame` and hand it to
+//! `syscall_handler`.
+
+global_asm!(r#"
+.intel_syntax noprefix
+
+// Entered with the user RIP in rcx and RFLAGS in r11, interrupts disabled
+// by SFMASK
+.global syscall_entry
+syscall_entry:
+    mov [rip + SYSCALL_USER_RSP], rsp
+    mov rsp, [rip + SYSCALL_KERNEL_RSP]
+    push qword ptr [rip + SYSCALL_USER_RSP]
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    mov rdi, rsp
+    call syscall_handler
+    // The handler may have enabled interrupts, and we are about to run on
+    // the user stack
+    cli
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+
+// Same frame minus the user stack pointer, which is part of the interrupt
+// frame. Nine pushes after the 40 byte interrupt frame keep the stack
+// 16-byte aligned for the call.
+.global syscall_interrupt_entry
+syscall_interrupt_entry:
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    mov rdi, rsp
+    call syscall_handler
+    cli
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r11
+    pop rcx
+    iretq
+
+.att_syntax prefix
+"#);
+
+extern "C" {
+    pub fn syscall_entry();
+    pub fn syscall_interrupt_entry();
+}
+
+/// Top of the kernel stack of the current task, loaded by `syscall_entry`.
+#[no_mangle]
+static mut SYSCALL_KERNEL_RSP: u64 = 0;
+/// Scratch space for the user stack pointer until it is pushed on the kernel
+/// stack. Interrupts are disabled in the meantime.
+#[no_mangle]
+static mut SYSCALL_USER_RSP: u64 = 0;
+
+/// Registers saved on syscall entry, from the lowest address up.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// Syscall number on entry, return value on exit
+    pub rax: u64,
+    pub r11: u64,
+    pub rcx: u64,
+}
+
+/// Called on every task switch with interrupts disabled. `stack_top` must be
+/// 16-byte aligned.
+pub fn set_kernel_stack(stack_top: u64) {
+    unsafe {
+        SYSCALL_KERNEL_RSP = stack_top;
+    }
+}
@@ -0,0 +1,312 @@
+//! System calls.
+//! https://wiki.osdev.org/System_Calls
+//!
+//! User code puts the syscall number in rax and up to six arguments in rdi,
+//! rsi, rdx, r10, r8 and r9, like on Linux, then executes `syscall` or
+//! `int 0x80`. The result comes back in rax: a value on success, or a
+//! negated `Error` code. Only rcx and r11 are clobbered by `syscall`.
+
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::fs::{FsError, OpenFlags};
+use crate::gdt::{KERNEL_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::inline_asm::{enable_interrupts, rdmsr, wrmsr};
+use crate::memory::{FRAME_SIZE, VirtAddr};
+use crate::paging::{Mapper, MapError, PageTableFlags};
+use crate::task::user::{is_user_range, map_user_memory};
+use crate::{fs, pit, task};
+
+use self::entry::{syscall_entry, SyscallFrame};
+
+pub mod entry;
+
+pub use self::entry::set_kernel_stack;
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+
+const EFER_SYSCALL_ENABLE: u64 = 1 << 0;
+/// Interrupts, trap and direction flags and alignment check are cleared on entry
+const FMASK: u64 = 1 << 8 | 1 << 9 | 1 << 10 | 1 << 18;
+
+/// Vector of the `int 0x80` fallback
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+// Syscall numbers
+pub const READ: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const EXIT: u64 = 2;
+pub const YIELD: u64 = 3;
+pub const SLEEP: u64 = 4;
+pub const MMAP: u64 = 5;
+pub const GETPID: u64 = 6;
+pub const OPEN: u64 = 7;
+pub const CLOSE: u64 = 8;
+
+// mmap protection flags
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Where `mmap` places mappings when no address is given
+const MMAP_START: u64 = 0x0000_2000_0000_0000;
+static NEXT_MMAP_ADDRESS: AtomicU64 = AtomicU64::new(MMAP_START);
+
+type SyscallFn = fn(&[u64; 6]) -> Result<u64, Error>;
+
+/// Indexed by syscall number.
+static SYSCALL_TABLE: [SyscallFn; 9] = [
+    sys_read,
+    sys_write,
+    sys_exit,
+    sys_yield,
+    sys_sleep,
+    sys_mmap,
+    sys_getpid,
+    sys_open,
+    sys_close,
+];
+
+/// Returned to user space as `-(error as i64)`. The values match Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Error {
+    NotFound = 2,
+    Io = 5,
+    BadFileDescriptor = 9,
+    OutOfMemory = 12,
+    PermissionDenied = 13,
+    BadAddress = 14,
+    Busy = 16,
+    AlreadyExists = 17,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    TooManyOpenFiles = 24,
+    NoSpace = 28,
+    NameTooLong = 36,
+    NoSuchSyscall = 38,
+    DirectoryNotEmpty = 39,
+    TooManySymlinks = 40,
+    NotSupported = 95,
+}
+
+impl From<FsError> for Error {
+    fn from(error: FsError) -> Error {
+        match error {
+            FsError::NotFound => Error::NotFound,
+            FsError::NotADirectory => Error::NotADirectory,
+            FsError::IsADirectory => Error::IsADirectory,
+            FsError::AlreadyExists => Error::AlreadyExists,
+            FsError::DirectoryNotEmpty => Error::DirectoryNotEmpty,
+            FsError::InvalidPath | FsError::InvalidArgument => Error::InvalidArgument,
+            FsError::TooManySymlinks => Error::TooManySymlinks,
+            FsError::NotSupported => Error::NotSupported,
+            FsError::PermissionDenied => Error::PermissionDenied,
+            FsError::BadFileDescriptor => Error::BadFileDescriptor,
+            FsError::TooManyOpenFiles => Error::TooManyOpenFiles,
+            FsError::Busy => Error::Busy,
+            FsError::NoSpace => Error::NoSpace,
+            FsError::Io => Error::Io,
+        }
+    }
+}
+
+/// Enables `syscall`/`sysret`. The `int 0x80` gate is part of the IDT.
+pub fn init() {
+    wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_SYSCALL_ENABLE);
+    // sysret loads CS from STAR[63:48] + 16 and SS from STAR[63:48] + 8
+    let sysret_base = (USER_DATA_SELECTOR as u64 - 8) & !0b11;
+    wrmsr(IA32_STAR, sysret_base << 48 | (KERNEL_CODE_SELECTOR as u64) << 32);
+    wrmsr(IA32_LSTAR, syscall_entry as u64);
+    wrmsr(IA32_FMASK, FMASK);
+}
+
+#[no_mangle]
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    // Syscalls may block, and the scheduler relies on the timer
+    enable_interrupts();
+    let arguments = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = match dispatch(frame.rax, &arguments) {
+        Ok(value) => value,
+        Err(error) => (error as u64).wrapping_neg(),
+    };
+}
+
+pub fn dispatch(number: u64, arguments: &[u64; 6]) -> Result<u64, Error> {
+    let syscall = SYSCALL_TABLE.get(number as usize).ok_or(Error::NoSuchSyscall)?;
+    syscall(arguments)
+}
+
+/// Checks that `size` bytes at `address` are mapped for user mode, and
+/// writable if `write` is set.
+fn validate_user_range(address: u64, size: u64, write: bool) -> Result<(), Error> {
+    if !is_user_range(address, size) {
+        return Err(Error::BadAddress);
+    }
+    let mapper = Mapper::active();
+    let mut page = address & !(FRAME_SIZE - 1);
+    while page < address + size {
+        let (_, flags) = mapper.translate(VirtAddr(page)).ok_or(Error::BadAddress)?;
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            || (write && !flags.contains(PageTableFlags::WRITABLE)) {
+            return Err(Error::BadAddress);
+        }
+        page += FRAME_SIZE;
+    }
+    Ok(())
+}
+
+fn user_slice<'a>(address: u64, size: u64) -> Result<&'a [u8], Error> {
+    validate_user_range(address, size, false)?;
+    Ok(unsafe { slice::from_raw_parts(address as *const u8, size as usize) })
+}
+
+fn user_slice_mut<'a>(address: u64, size: u64) -> Result<&'a mut [u8], Error> {
+    validate_user_range(address, size, true)?;
+    Ok(unsafe { slice::from_raw_parts_mut(address as *mut u8, size as usize) })
+}
+
+/// Longest path accepted by `open`
+const MAX_PATH_LENGTH: u64 = 4096;
+
+/// read(fd, buffer, size) -> bytes read
+fn sys_read(arguments: &[u64; 6]) -> Result<u64, Error> {
+    let buffer = user_slice_mut(arguments[1], arguments[2])?;
+    let file = task::with_files(|files| files.get(arguments[0] as usize))?;
+    Ok(file.read(buffer)? as u64)
+}
+
+/// write(fd, buffer, size) -> bytes written
+fn sys_write(arguments: &[u64; 6]) -> Result<u64, Error> {
+    let buffer = user_slice(arguments[1], arguments[2])?;
+    let file = task::with_files(|files| files.get(arguments[0] as usize))?;
+    Ok(file.write(buffer)? as u64)
+}
+
+/// exit(code) -> never returns.
+fn sys_exit(arguments: &[u64; 6]) -> Result<u64, Error> {
+    println!("Task {} exited with code {}", task::current_id(), arguments[0] as i64);
+    task::exit();
+}
+
+/// yield() -> 0
+fn sys_yield(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    task::yield_now();
+    Ok(0)
+}
+
+/// sleep(milliseconds) -> 0
+fn sys_sleep(arguments: &[u64; 6]) -> Result<u64, Error> {
+    pit::sleep_ms(arguments[0]);
+    Ok(0)
+}
+
+/// mmap(address, size, protection) -> address of the zeroed mapping.
+///
+/// An address of 0 lets the kernel pick one, otherwise it must be page
+/// aligned and free. Memory is always readable. Nothing stays mapped when it
+/// fails partway through.
+fn sys_mmap(arguments: &[u64; 6]) -> Result<u64, Error> {
+    let (address, size, protection) = (arguments[0], arguments[1], arguments[2]);
+    if size == 0 || address % FRAME_SIZE != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let size = size.checked_add(FRAME_SIZE - 1).ok_or(Error::InvalidArgument)? & !(FRAME_SIZE - 1);
+    let address = match address {
+        0 => NEXT_MMAP_ADDRESS.fetch_add(size, Ordering::Relaxed),
+        address => address,
+    };
+    if !is_user_range(address, size) {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut flags = PageTableFlags::empty();
+    if protection & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    match map_user_memory(VirtAddr(address), size, flags) {
+        Ok(()) => Ok(address),
+        Err(MapError::FrameAllocationFailed) => Err(Error::OutOfMemory),
+        Err(_) => Err(Error::InvalidArgument),
+    }
+}
+
+/// open(path, path_length, flags) -> file descriptor. The path doesn't need
+/// to be NUL terminated, `flags` are `OpenFlags` bits.
+fn sys_open(arguments: &[u64; 6]) -> Result<u64, Error> {
+    if arguments[1] > MAX_PATH_LENGTH {
+        return Err(Error::NameTooLong);
+    }
+    let path = user_slice(arguments[0], arguments[1])?;
+    let path = core::str::from_utf8(path).map_err(|_| Error::InvalidArgument)?;
+    let file = fs::open(path, OpenFlags::from_bits(arguments[2] as u32))?;
+    Ok(task::with_files(|files| files.insert(file))? as u64)
+}
+
+/// close(fd) -> 0
+fn sys_close(arguments: &[u64; 6]) -> Result<u64, Error> {
+    task::with_files(|files| files.remove(arguments[0] as usize))?;
+    Ok(0)
+}
+
+/// getpid() -> ID of the calling task
+fn sys_getpid(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    Ok(task::current_id().as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{FrameAllocator, FRAME_ALLOCATOR, PhysFrame};
+    use crate::paging::{Page, PageSize};
+    use crate::task::user::USER_SPACE_START;
+
+    use super::*;
+
+    #[test_case]
+    fn unknown_syscall_fails() {
+        assert_eq!(dispatch(0xFFFF, &[0; 6]), Err(Error::NoSuchSyscall));
+    }
+
+    #[test_case]
+    fn kernel_pointers_are_rejected() {
+        let buffer = [0u8; 4];
+        let arguments = [1, buffer.as_ptr() as u64, buffer.len() as u64, 0, 0, 0];
+        assert_eq!(dispatch(WRITE, &arguments), Err(Error::BadAddress));
+    }
+
+    #[test_case]
+    fn user_task_makes_syscalls() {
+        let page = VirtAddr(USER_SPACE_START + 0x10_0000);
+        map_user_memory(page, FRAME_SIZE, PageTableFlags::WRITABLE).expect("can't map user memory");
+        let code: [u8; 23] = [
+            0xB8, 0x06, 0x00, 0x00, 0x00,             // mov eax, GETPID
+            0xCD, 0x80,                               // int 0x80
+            0x48, 0x89, 0x05, 0xF2, 0x07, 0x00, 0x00, // mov [rip + 0x7F2], rax (offset 0x800)
+            0xB8, 0x02, 0x00, 0x00, 0x00,             // mov eax, EXIT
+            0x31, 0xFF,                               // xor edi, edi
+            0x0F, 0x05,                               // syscall
+        ];
+        unsafe {
+            page.as_mut_ptr::<[u8; 23]>().write(code);
+        }
+
+        let id = task::spawn_user("syscall-test", page, VirtAddr(page.as_u64() + FRAME_SIZE));
+        while task::exists(id) {
+            task::yield_now();
+            task::reap();
+        }
+        let pid = unsafe { VirtAddr(page.as_u64() + 0x800).as_ptr::<u64>().read_volatile() };
+        assert_eq!(pid, id.as_u64());
+
+        let frame = Mapper::active()
+            .unmap(Page::containing_address(page, PageSize::Size4KiB))
+            .expect("user page vanished");
+        FRAME_ALLOCATOR.lock().deallocate_frame(PhysFrame::containing_address(frame));
+    }
+}
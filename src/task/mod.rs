@@ -0,0 +1,289 @@
+//! Kernel threads and preemptive multitasking.
+//!
+//! Every task has its own kernel stack. The PIT interrupt calls `tick`, which
+//! preempts the running task, and tasks can give up the CPU themselves with
+//! `yield_now`, `sleep` and `exit`. All the scheduler state is behind an
+//! `IrqMutex`, and interrupts stay disabled across a context switch.
+//!
+//! Tasks that exited are freed by `reap`, which the idle loop calls: freeing
+//! their stacks, address spaces and files can't happen in interrupt context.
+//!
+//! User tasks are kernel threads that jumped to ring 3, see `user`.
+
+use core::fmt;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::address_space::AddressSpace;
+use crate::fs::FileTable;
+use crate::inline_asm::hlt;
+use crate::memory::VirtAddr;
+use crate::pit;
+use crate::sync::{InterruptGuard, IrqMutex};
+
+use self::scheduler::Scheduler;
+use self::switch::{init_stack, switch_context};
+
+mod scheduler;
+mod switch;
+pub mod user;
+
+const STACK_SIZE: usize = 16 * 4096;
+/// How long a task runs before being preempted
+const TIME_SLICE_MS: u64 = 10;
+
+/// Set when the current task should be preempted
+static NEED_RESCHEDULE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref SCHEDULER: IrqMutex<Option<Scheduler>> = IrqMutex::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl Display for TaskId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Sleeping,
+    Blocked,
+    Dead,
+}
+
+pub struct Task {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    /// Saved stack pointer while the task is not running
+    rsp: u64,
+    /// `None` for the boot task, which runs on the bootloader's stack
+    stack: Option<Vec<u8>>,
+    /// `None` for tasks running in the kernel address space
+    address_space: Option<AddressSpace>,
+    files: FileTable,
+}
+
+impl Task {
+    fn new(name: &'static str, entry: switch::TaskEntry) -> Box<Task> {
+        let mut stack = vec![0; STACK_SIZE];
+        let rsp = init_stack(&mut stack, entry);
+        Box::new(Task {
+            id: TaskId::new(),
+            name,
+            state: TaskState::Ready,
+            rsp,
+            stack: Some(stack),
+            address_space: None,
+            files: FileTable::new(),
+        })
+    }
+
+    /// Highest address of the kernel stack.
+    pub fn stack_top(&self) -> Option<u64> {
+        self.stack.as_ref().map(|stack| stack.as_ptr() as u64 + stack.len() as u64)
+    }
+}
+
+/// Turns the current execution context into the idle task and starts the
+/// scheduler.
+pub fn init() {
+    let idle = Box::new(Task {
+        id: TaskId::new(),
+        name: "idle",
+        state: TaskState::Running,
+        rsp: 0,
+        stack: None,
+        address_space: None,
+        files: FileTable::new(),
+    });
+    *SCHEDULER.lock() = Some(Scheduler::new(idle));
+}
+
+fn with_scheduler<F, R>(f: F) -> R where F: FnOnce(&mut Scheduler) -> R {
+    let mut scheduler = SCHEDULER.lock();
+    f(scheduler.as_mut().expect("Scheduler not initialized"))
+}
+
+/// Starts a new kernel thread running `f`.
+pub fn spawn<F>(name: &'static str, f: F) -> TaskId where F: FnOnce() + Send + 'static {
+    add(Task::new(name, Box::new(f)))
+}
+
+fn add(task: Box<Task>) -> TaskId {
+    let id = task.id;
+    with_scheduler(|scheduler| scheduler.add(task));
+    id
+}
+
+/// Starts a task running `entry` in ring 3 with `stack` as stack pointer.
+/// Both must already be mapped in user space.
+pub fn spawn_user(name: &'static str, entry: VirtAddr, stack: VirtAddr) -> TaskId {
+    spawn(name, move || user::enter_user_mode(entry, stack))
+}
+
+/// Starts a process: a user task with its own address space and open files,
+/// which are released when the task exits.
+pub fn spawn_process(name: &'static str, address_space: AddressSpace, files: FileTable,
+                     entry: VirtAddr, stack: VirtAddr) -> TaskId {
+    let mut task = Task::new(name, Box::new(move || user::enter_user_mode(entry, stack)));
+    task.address_space = Some(address_space);
+    task.files = files;
+    add(task)
+}
+
+/// Switches to the next ready task, if any.
+pub fn schedule() {
+    // Interrupts must stay disabled until the switch is done, so the guard
+    // outlives the scheduler lock
+    let _guard = InterruptGuard::new();
+    let switch = with_scheduler(|scheduler| scheduler.switch_next());
+    if let Some((previous_rsp, next_rsp)) = switch {
+        unsafe {
+            switch_context(previous_rsp, next_rsp);
+        }
+    }
+}
+
+/// Gives up the CPU to the next ready task.
+pub fn yield_now() {
+    schedule();
+}
+
+/// Puts the current task to sleep for at least `ticks` timer ticks.
+pub fn sleep(ticks: u64) {
+    let _guard = InterruptGuard::new();
+    with_scheduler(|scheduler| scheduler.sleep_current(ticks));
+    schedule();
+}
+
+/// Blocks the current task until another task or an interrupt handler calls
+/// `wake` on it.
+pub fn block() {
+    let _guard = InterruptGuard::new();
+    with_scheduler(|scheduler| scheduler.block_current());
+    schedule();
+}
+
+pub fn wake(id: TaskId) {
+    with_scheduler(|scheduler| scheduler.wake(id));
+}
+
+/// Terminates the current task. It is freed by the next `reap`.
+pub fn exit() -> ! {
+    let _guard = InterruptGuard::new();
+    with_scheduler(|scheduler| {
+        if scheduler.current().stack.is_none() {
+            panic!("The idle task can't exit");
+        }
+        scheduler.current_mut().state = TaskState::Dead;
+    });
+    schedule();
+    unreachable!("Dead task was scheduled");
+}
+
+pub fn current_id() -> TaskId {
+    with_scheduler(|scheduler| scheduler.current().id)
+}
+
+/// Runs `f` on the file descriptor table of the current task.
+pub fn with_files<F, R>(f: F) -> R where F: FnOnce(&mut FileTable) -> R {
+    with_scheduler(|scheduler| f(&mut scheduler.current_mut().files))
+}
+
+/// Whether the task is still known to the scheduler, i.e. wasn't reaped.
+pub fn exists(id: TaskId) -> bool {
+    with_scheduler(|scheduler| scheduler.task(id).is_some())
+}
+
+/// Frees the tasks that exited. Must be called from task context, with no
+/// lock held that closing their files could need.
+pub fn reap() {
+    let dead = with_scheduler(|scheduler| scheduler.take_dead());
+    // Dropped with the scheduler unlocked and interrupts enabled
+    drop(dead);
+}
+
+/// What the boot task does once the kernel is up: it runs when no other task
+/// is ready, frees the tasks that exited and waits for interrupts.
+pub fn idle() -> ! {
+    loop {
+        reap();
+        hlt();
+    }
+}
+
+/// Whether the caller runs in the idle task, which must never block.
+pub fn is_idle() -> bool {
+    with_scheduler(|scheduler| scheduler.is_idle())
+}
+
+/// Timer ticks since the scheduler started.
+pub fn ticks() -> u64 {
+    with_scheduler(|scheduler| scheduler.ticks())
+}
+
+/// Called on every timer tick, in interrupt context. The actual task switch
+/// happens in `reschedule_if_needed`, once the interrupt is acknowledged.
+pub fn tick() {
+    let time_slice = pit::ms_to_ticks(TIME_SLICE_MS).max(1);
+    let mut scheduler = SCHEDULER.lock();
+    if let Some(scheduler) = scheduler.as_mut() {
+        if scheduler.tick(time_slice) {
+            NEED_RESCHEDULE.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Switches tasks if a timer tick asked for it. Called at the end of
+/// interrupt handlers, after the EOI.
+pub fn reschedule_if_needed() {
+    if NEED_RESCHEDULE.swap(false, Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[test_case]
+    fn spawned_task_runs_and_exits() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = spawn("test", || {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        while COUNTER.load(Ordering::SeqCst) < 2 {
+            yield_now();
+        }
+        // Let the task finish exiting, then free it
+        yield_now();
+        reap();
+        assert!(with_scheduler(|scheduler| scheduler.task(id).is_none()));
+    }
+}
@@ -0,0 +1,221 @@
+//! Round-robin scheduler.
+//!
+//! Timer ticks and interrupt handlers change the scheduler state, so its
+//! methods called from there must neither allocate nor free memory: the
+//! interrupted code may hold the heap or frame allocator lock. The run queue
+//! has room for every task, and tasks that exited are only freed from task
+//! context, see `take_dead`.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use crate::inline_asm::write_cr3;
+use crate::paging::{kernel_pml4, Mapper};
+
+use super::{Task, TaskId, TaskState};
+use super::user;
+
+pub struct Scheduler {
+    tasks: BTreeMap<TaskId, Box<Task>>,
+    /// Ready tasks, each at most once. Its capacity is kept at the number of
+    /// tasks so that waking one up never allocates.
+    run_queue: VecDeque<TaskId>,
+    /// Sleeping tasks with the tick at which they wake up
+    sleeping: Vec<(u64, TaskId)>,
+    current: TaskId,
+    /// Runs when no other task is ready. This is the boot context.
+    idle: TaskId,
+    ticks: u64,
+    /// Ticks the current task has been running for
+    current_ticks: u64,
+}
+
+impl Scheduler {
+    pub fn new(idle: Box<Task>) -> Scheduler {
+        let idle_id = idle.id;
+        let mut tasks = BTreeMap::new();
+        tasks.insert(idle_id, idle);
+        Scheduler {
+            tasks,
+            run_queue: VecDeque::new(),
+            sleeping: Vec::new(),
+            current: idle_id,
+            idle: idle_id,
+            ticks: 0,
+            current_ticks: 0,
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.current == self.idle
+    }
+
+    pub fn current(&self) -> &Task {
+        &self.tasks[&self.current]
+    }
+
+    pub fn current_mut(&mut self) -> &mut Task {
+        self.tasks.get_mut(&self.current).unwrap()
+    }
+
+    pub fn task(&self, id: TaskId) -> Option<&Task> {
+        self.tasks.get(&id).map(|task| &**task)
+    }
+
+    pub fn tasks(&self) -> impl Iterator<Item=&Task> {
+        self.tasks.values().map(|task| &**task)
+    }
+
+    pub fn add(&mut self, task: Box<Task>) {
+        let id = task.id;
+        self.tasks.insert(id, task);
+        self.run_queue.reserve(self.tasks.len().saturating_sub(self.run_queue.len()));
+        self.run_queue.push_back(id);
+    }
+
+    /// Puts the current task to sleep until `ticks` more ticks have elapsed.
+    pub fn sleep_current(&mut self, ticks: u64) {
+        let wake_up = self.ticks + ticks;
+        let current = self.current;
+        self.current_mut().state = TaskState::Sleeping;
+        self.sleeping.push((wake_up, current));
+    }
+
+    /// Blocks the current task until `wake` is called on it.
+    pub fn block_current(&mut self) {
+        self.current_mut().state = TaskState::Blocked;
+    }
+
+    /// Makes a blocked or sleeping task ready again.
+    pub fn wake(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            if task.state == TaskState::Blocked || task.state == TaskState::Sleeping {
+                task.state = TaskState::Ready;
+                self.sleeping.retain(|&(_, sleeper)| sleeper != id);
+                if id != self.current {
+                    self.run_queue.push_back(id);
+                }
+            }
+        }
+    }
+
+    /// Advances the tick counter and wakes up the tasks that slept long enough.
+    ///
+    /// Returns whether the current task should be preempted, either because
+    /// it used up its time slice or because the CPU was idle.
+    pub fn tick(&mut self, time_slice: u64) -> bool {
+        self.ticks += 1;
+        self.current_ticks += 1;
+        let ticks = self.ticks;
+        let mut i = 0;
+        while i < self.sleeping.len() {
+            let (wake_up, id) = self.sleeping[i];
+            if wake_up <= ticks {
+                self.sleeping.swap_remove(i);
+                if let Some(task) = self.tasks.get_mut(&id) {
+                    task.state = TaskState::Ready;
+                    self.run_queue.push_back(id);
+                }
+            } else {
+                i += 1;
+            }
+        }
+        let idle_with_work = self.current == self.idle && !self.run_queue.is_empty();
+        self.current_ticks >= time_slice || idle_with_work
+    }
+
+    /// Picks the next task to run and makes it current.
+    ///
+    /// Returns where to save the stack pointer of the current task and the
+    /// stack pointer of the next one, or `None` if the current task keeps
+    /// running.
+    pub fn switch_next(&mut self) -> Option<(*mut u64, u64)> {
+        let next = loop {
+            match self.run_queue.pop_front() {
+                Some(id) if self.tasks.get(&id).map(|task| task.state) == Some(TaskState::Ready) => break id,
+                Some(_) => continue,
+                None => {
+                    if self.current().state == TaskState::Running {
+                        return None;
+                    }
+                    break self.idle;
+                }
+            }
+        };
+
+        let previous = self.current;
+        self.current_ticks = 0;
+        if previous == next {
+            self.current_mut().state = TaskState::Running;
+            return None;
+        }
+        {
+            let previous_task = self.current_mut();
+            if previous_task.state == TaskState::Running {
+                previous_task.state = TaskState::Ready;
+                if previous != self.idle {
+                    self.run_queue.push_back(previous);
+                }
+            }
+        }
+
+        self.current = next;
+        let next_task = self.tasks.get_mut(&next).unwrap();
+        next_task.state = TaskState::Running;
+        if let Some(stack_top) = next_task.stack_top() {
+            // Interrupts and syscalls from user mode land on the task's own
+            // kernel stack
+            user::set_kernel_stack(stack_top);
+        }
+        let pml4 = next_task.address_space.as_ref().map_or_else(kernel_pml4, |space| space.pml4());
+        if Mapper::active().pml4() != pml4 {
+            write_cr3(pml4.as_u64());
+        }
+        let next_rsp = next_task.rsp;
+        let previous_rsp = &mut self.tasks.get_mut(&previous).unwrap().rsp as *mut u64;
+        Some((previous_rsp, next_rsp))
+    }
+
+    /// Removes the tasks that exited, for the caller to free once the
+    /// scheduler is unlocked. The current task is kept, even if it exited,
+    /// because we are still running on its stack and in its address space.
+    pub fn take_dead(&mut self) -> Vec<Box<Task>> {
+        let current = self.current;
+        let dead: Vec<TaskId> = self.tasks.values()
+            .filter(|task| task.state == TaskState::Dead && task.id != current)
+            .map(|task| task.id)
+            .collect();
+        dead.iter().filter_map(|id| self.tasks.remove(id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn run_queue_has_room_for_every_task() {
+        let mut scheduler = Scheduler::new(Task::new("idle", Box::new(|| {})));
+        let ids: Vec<TaskId> = (0..20).map(|_| {
+            let task = Task::new("test", Box::new(|| {}));
+            let id = task.id;
+            scheduler.add(task);
+            id
+        }).collect();
+        assert!(scheduler.run_queue.capacity() >= scheduler.tasks.len());
+
+        // Exited tasks stay until they are taken
+        for id in ids.iter() {
+            scheduler.tasks.get_mut(id).unwrap().state = TaskState::Dead;
+        }
+        assert_eq!(scheduler.tasks.len(), 21);
+        assert_eq!(scheduler.take_dead().len(), 20);
+        assert!(scheduler.take_dead().is_empty());
+        assert_eq!(scheduler.tasks.len(), 1);
+    }
+}
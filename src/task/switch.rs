@@ -0,0 +1,89 @@
+//! Context switching between kernel stacks.
+//!
+//! A suspended task's stack holds its callee-saved registers and RFLAGS, and
+//! the saved stack pointer is all the scheduler needs to resume it. The
+//! caller-saved registers are already spilled by the compiler around the call
+//! to `switch_context`.
+
+use core::mem::size_of;
+
+use alloc::boxed::Box;
+
+use crate::inline_asm::enable_interrupts;
+
+global_asm!(r#"
+.intel_syntax noprefix
+
+// extern "C" fn switch_context(old_rsp: *mut u64, new_rsp: u64)
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdi], rsp
+    mov rsp, rsi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+// First code run by a new task. The entry closure is passed in r12 by
+// `init_stack`.
+.global task_trampoline
+task_trampoline:
+    mov rdi, r12
+    call task_start
+    ud2
+
+.att_syntax prefix
+"#);
+
+extern "C" {
+    /// Saves the current context, stores the stack pointer in `old_rsp` and
+    /// resumes the context saved at `new_rsp`.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn task_trampoline();
+}
+
+pub type TaskEntry = Box<dyn FnOnce() + Send + 'static>;
+
+/// Prepares a fresh stack so that switching to it calls `entry` and returns
+/// the stack pointer to resume.
+pub fn init_stack(stack: &mut [u8], entry: TaskEntry) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xF;
+    // Double boxing gives a thin pointer that fits in a register
+    let entry = Box::into_raw(Box::new(entry)) as u64;
+
+    let frame: [u64; 8] = [
+        0x2,                     // RFLAGS, interrupts disabled
+        0,                       // r15
+        0,                       // r14
+        0,                       // r13
+        entry,                   // r12
+        0,                       // rbx
+        0,                       // rbp
+        task_trampoline as u64,  // return address
+    ];
+    let rsp = top - (frame.len() * size_of::<u64>()) as u64;
+    unsafe {
+        (rsp as *mut [u64; 8]).write(frame);
+    }
+    rsp
+}
+
+#[no_mangle]
+extern "C" fn task_start(entry: *mut TaskEntry) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    // The task was switched to with interrupts disabled
+    enable_interrupts();
+    entry();
+    super::exit();
+}
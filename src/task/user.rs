@@ -0,0 +1,122 @@
+//! Unprivileged (ring 3) tasks.
+//! https://wiki.osdev.org/Getting_to_Ring_3
+//!
+//! A user task starts as a kernel thread that drops to ring 3 with `iretq`.
+//! Its kernel stack is then only used by interrupts and exceptions coming
+//! from user mode, through the TSS `rsp0` the scheduler keeps up to date.
+//!
+//! User memory lives in the lower half, between `USER_SPACE_START` and
+//! `USER_SPACE_END`, away from the kernel image, the heap and the MMIO window.
+
+use crate::address_space::map_user_pages;
+use crate::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::memory::VirtAddr;
+use crate::paging::{Mapper, MapError, PageTableFlags};
+use crate::syscall;
+use crate::tss::TSS;
+
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+global_asm!(r#"
+.intel_syntax noprefix
+
+// extern "C" fn jump_to_user_mode(entry: u64, stack: u64, code_selector: u64, data_selector: u64) -> !
+.global jump_to_user_mode
+jump_to_user_mode:
+    mov ds, cx
+    mov es, cx
+    // Interrupt frame popped by iretq
+    push rcx
+    push rsi
+    push 0x202 // RFLAGS, interrupts enabled
+    push rdx
+    push rdi
+    // Don't leak kernel values to user space
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8, r8
+    xor r9, r9
+    xor r10, r10
+    xor r11, r11
+    xor r12, r12
+    xor r13, r13
+    xor r14, r14
+    xor r15, r15
+    iretq
+
+.att_syntax prefix
+"#);
+
+extern "C" {
+    fn jump_to_user_mode(entry: u64, stack: u64, code_selector: u64, data_selector: u64) -> !;
+}
+
+/// Makes interrupts and syscalls from user mode use the kernel stack ending
+/// at `stack_top`.
+pub fn set_kernel_stack(stack_top: u64) {
+    let stack_top = stack_top & !0xF;
+    TSS.set_kernel_stack(stack_top);
+    syscall::set_kernel_stack(stack_top);
+}
+
+/// Whether `start..start + size` lies entirely in user space.
+pub fn is_user_range(start: u64, size: u64) -> bool {
+    match start.checked_add(size) {
+        Some(end) => start >= USER_SPACE_START && end <= USER_SPACE_END,
+        None => false,
+    }
+}
+
+/// Maps zeroed user memory in the active address space, see
+/// `address_space::map_user_pages`.
+pub fn map_user_memory(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    map_user_pages(&mut Mapper::active(), start, size, flags)
+}
+
+/// Leaves the kernel for good and continues at `entry` in ring 3, with
+/// interrupts enabled and `stack` as stack pointer.
+pub fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    unsafe {
+        jump_to_user_mode(entry.as_u64(), stack.as_u64(), USER_CODE_SELECTOR as u64, USER_DATA_SELECTOR as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{FRAME_ALLOCATOR, FRAME_SIZE, FrameAllocator, PhysFrame};
+    use crate::paging::{Page, PageSize};
+    use crate::task::{exists, reap, spawn_user, yield_now};
+
+    use super::*;
+
+    #[test_case]
+    fn user_task_runs_and_is_killed_on_fault() {
+        let page = VirtAddr(USER_SPACE_START);
+        map_user_memory(page, FRAME_SIZE, PageTableFlags::WRITABLE).expect("can't map user memory");
+        // mov byte [rip + 0x7F9], 1 (the byte at offset 0x800), then hlt,
+        // which is privileged and raises a general protection fault
+        let code: [u8; 8] = [0xC6, 0x05, 0xF9, 0x07, 0x00, 0x00, 0x01, 0xF4];
+        unsafe {
+            page.as_mut_ptr::<[u8; 8]>().write(code);
+        }
+
+        let id = spawn_user("user-test", page, VirtAddr(page.as_u64() + FRAME_SIZE));
+        while exists(id) {
+            yield_now();
+            reap();
+        }
+        let flag = unsafe { VirtAddr(page.as_u64() + 0x800).as_ptr::<u8>().read_volatile() };
+        assert_eq!(flag, 1);
+
+        let frame = Mapper::active()
+            .unmap(Page::containing_address(page, PageSize::Size4KiB))
+            .expect("user page vanished");
+        FRAME_ALLOCATOR.lock().deallocate_frame(PhysFrame::containing_address(frame));
+    }
+}
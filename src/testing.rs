@@ -0,0 +1,180 @@
+//! In-kernel test framework built on top of `custom_test_frameworks`.
+//!
+//! `cargo test` builds a kernel image in which `test_main` runs every
+//! `#[test_case]` and reports the results over COM1. QEMU is then shut down
+//! through the isa-debug-exit device so that `bootimage runner` can turn the
+//! result into a process exit code.
+//!
+//! https://os.phil-opp.com/testing/
+
+use core::panic::PanicInfo;
+
+use crate::inline_asm::{are_interrupts_enabled, enable_interrupts, hlt_loop, outl};
+use crate::serial;
+
+/// I/O port of the isa-debug-exit device, see `test-args` in `Cargo.toml`.
+const ISA_DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// Stack the tests resume on after a `ShouldPanic` test panicked. The
+/// panicking test's stack, possibly an exception stack, is abandoned.
+const RESUME_STACK_SIZE: usize = 16 * 4096;
+static mut RESUME_STACK: [u8; RESUME_STACK_SIZE] = [0; RESUME_STACK_SIZE];
+
+global_asm!(r#"
+.intel_syntax noprefix
+
+// extern "C" fn call_on_stack(stack_top: u64, f: extern "C" fn() -> !) -> !
+.global call_on_stack
+call_on_stack:
+    mov rsp, rdi
+    call rsi
+    ud2
+
+.att_syntax prefix
+"#);
+
+extern "C" {
+    fn call_on_stack(stack_top: u64, f: extern "C" fn() -> !) -> !;
+}
+
+/// Values written to the isa-debug-exit device. QEMU exits with
+/// `(value << 1) | 1`, so `Success` becomes 33 (`test-success-exit-code`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    serial::flush_all();
+    outl(ISA_DEBUG_EXIT_PORT, exit_code as u32);
+    // Only reached when the kernel doesn't run under QEMU
+    hlt_loop();
+}
+
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+
+    fn should_panic(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self();
+    }
+}
+
+/// A test that only passes if it panics. Build it with `should_panic!`.
+pub struct ShouldPanic {
+    pub name: &'static str,
+    pub test: fn(),
+}
+
+impl Testable for ShouldPanic {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.test)();
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+/// Declares a test that must panic:
+///
+/// ```ignore
+/// #[test_case]
+/// const DIVIDE_ERROR_PANICS: ShouldPanic = should_panic!(divide_error_panics);
+/// ```
+#[macro_export]
+macro_rules! should_panic {
+    ($test:ident) => {
+        $crate::testing::ShouldPanic {
+            name: concat!(module_path!(), "::", stringify!($test)),
+            test: $test,
+        }
+    };
+}
+
+/// The test run in progress. The panic handler needs it to resume the run
+/// after a `ShouldPanic` test.
+static mut TESTS: &[&dyn Testable] = &[];
+static mut CURRENT_TEST: usize = 0;
+/// Whether the tests run with interrupts enabled
+static mut INTERRUPTS_ENABLED: bool = false;
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    println!("Running {} tests", tests.len());
+    unsafe {
+        // The harness builds the slice out of constants, so it lives in .rodata
+        TESTS = core::mem::transmute::<&[&dyn Testable], &'static [&'static dyn Testable]>(tests);
+        INTERRUPTS_ENABLED = are_interrupts_enabled();
+    }
+    run_from(0);
+}
+
+/// Runs the tests starting at `index` and exits QEMU once all of them passed.
+fn run_from(index: usize) -> ! {
+    let tests = unsafe { TESTS };
+    for (i, test) in tests.iter().enumerate().skip(index) {
+        unsafe {
+            CURRENT_TEST = i;
+        }
+        print!("{}...\t", test.name());
+        test.run();
+        if test.should_panic() {
+            println!("[failed]");
+            println!("Error: test did not panic");
+            exit_qemu(QemuExitCode::Failed);
+        }
+        println!("[ok]");
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Runs the tests following the one that panicked, on the resume stack.
+extern "C" fn resume() -> ! {
+    // The panic may come from an exception handler, which runs with
+    // interrupts disabled
+    if unsafe { INTERRUPTS_ENABLED } {
+        enable_interrupts();
+    }
+    run_from(unsafe { CURRENT_TEST } + 1);
+}
+
+/// Called by the kernel panic handler when built with `cargo test`.
+///
+/// A panic in a `ShouldPanic` test counts as a success. The remaining tests
+/// then restart on a fresh stack rather than from the panic handler, whose
+/// stack and interrupt state belong to the failed code. Locks held by the
+/// panicking test stay locked, so such tests shouldn't take any.
+pub fn panic_handler(info: &PanicInfo) -> ! {
+    let tests = unsafe { TESTS };
+    let current = unsafe { CURRENT_TEST };
+
+    if let Some(test) = tests.get(current) {
+        if test.should_panic() {
+            println!("[ok]");
+            let stack_top = unsafe { &RESUME_STACK as *const _ as u64 } + RESUME_STACK_SIZE as u64;
+            unsafe {
+                call_on_stack(stack_top & !0xF, resume);
+            }
+        }
+    }
+
+    println!("[failed]");
+    println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -1,17 +1,53 @@
 //! https://wiki.osdev.org/Task_State_Segment
+//!
+//! The CPU loads `rsp0` when an interrupt or exception arrives in ring 3. The
+//! scheduler points it to the top of the kernel stack of the task it switches
+//! to.
+
+use core::cell::UnsafeCell;
+
+const INTERRUPT_STACK_SIZE: usize = 5 * 4096;
+static mut INTERRUPT_STACK: [u8; INTERRUPT_STACK_SIZE] = [0; INTERRUPT_STACK_SIZE];
 
 lazy_static! {
-    pub static ref TSS: TaskStateSegment = {
+    pub static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stacks[0] = {
-            const STACK_SIZE: usize = 5 * 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            unsafe { &STACK as *const _ as u64 + STACK_SIZE as u64}
-        };
-        tss
+        tss.interrupt_stacks[0] = interrupt_stack_bounds().1;
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+/// The TSS the GDT points to. The CPU reads it in place, so it is updated
+/// through a shared reference.
+#[repr(transparent)]
+pub struct Tss(UnsafeCell<TaskStateSegment>);
+
+// There is a single CPU, and `rsp0` is only written with interrupts disabled
+// during a task switch
+unsafe impl Sync for Tss {}
+
+impl Tss {
+    /// Stack the CPU switches to when an interrupt arrives in user mode.
+    pub fn set_kernel_stack(&self, stack_top: u64) {
+        let tss = unsafe { &mut *self.0.get() };
+        // Fields of a packed struct can't be borrowed, copy the array instead
+        let mut stack_pointers = tss.stack_pointers;
+        stack_pointers[0] = stack_top;
+        tss.stack_pointers = stack_pointers;
+    }
+
+    pub fn kernel_stack(&self) -> u64 {
+        let stack_pointers = unsafe { (*self.0.get()).stack_pointers };
+        stack_pointers[0]
+    }
+}
+
+/// Start and end virtual addresses of the interrupt stack.
+pub fn interrupt_stack_bounds() -> (u64, u64) {
+    let start = unsafe { &INTERRUPT_STACK as *const _ as u64 };
+    (start, start + INTERRUPT_STACK_SIZE as u64)
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 pub struct TaskStateSegment {
@@ -0,0 +1,201 @@
+//! Parser for the ANSI/VT100 escape sequences the console understands.
+//! https://vt100.net/emu/dec_ansi_parser
+//! https://en.wikipedia.org/wiki/ANSI_escape_code
+//!
+//! This is a subset of the DEC state machine: escape sequences, and control
+//! sequences with numeric parameters. Private sequences, e.g. `ESC [ ? 25 l`,
+//! are parsed and dropped.
+
+const ESCAPE: u8 = 0x1B;
+/// Cancel and substitute abort a sequence
+const CANCEL: u8 = 0x18;
+const SUBSTITUTE: u8 = 0x1A;
+
+/// Parameters kept per control sequence, the others are ignored
+const MAX_PARAMS: usize = 16;
+/// Parameters saturate to this value
+const MAX_PARAM_VALUE: u16 = 9999;
+
+/// What a byte turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(u8),
+    /// A C0 control character, e.g. a line feed
+    Control(u8),
+    /// `ESC` followed by the given byte
+    Escape(u8),
+    Csi(Csi),
+}
+
+/// A control sequence: `ESC [`, parameters separated by `;`, and the final
+/// byte naming the command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    count: usize,
+    pub command: u8,
+}
+
+impl Csi {
+    /// Parameter `i`, 0 if it is missing. 0 stands for the default value of
+    /// most commands.
+    pub fn param(&self, i: usize) -> u16 {
+        self.params().get(i).copied().unwrap_or(0)
+    }
+
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.count]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub struct Parser {
+    state: State,
+    csi: Csi,
+    /// The sequence is private or has intermediate bytes, none of which are
+    /// supported
+    ignored: bool,
+    /// More than `MAX_PARAMS` parameters were given, the extra ones are
+    /// dropped
+    overflowed: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            csi: Csi { params: [0; MAX_PARAMS], count: 0, command: 0 },
+            ignored: false,
+            overflowed: false,
+        }
+    }
+
+    /// Feeds a byte. Returns what to do once a character or a sequence is
+    /// complete.
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        if byte == CANCEL || byte == SUBSTITUTE {
+            self.state = State::Ground;
+            return None;
+        }
+        match self.state {
+            State::Ground => match byte {
+                ESCAPE => {
+                    self.state = State::Escape;
+                    None
+                }
+                0x00..=0x1F | 0x7F => Some(Action::Control(byte)),
+                _ => Some(Action::Print(byte)),
+            },
+            State::Escape => match byte {
+                ESCAPE => None,
+                b'[' => {
+                    self.state = State::Csi;
+                    self.csi.params = [0; MAX_PARAMS];
+                    self.csi.count = 0;
+                    self.ignored = false;
+                    self.overflowed = false;
+                    None
+                }
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(byte))
+                }
+            },
+            State::Csi => match byte {
+                ESCAPE => {
+                    self.state = State::Escape;
+                    None
+                }
+                b'0'..=b'9' => {
+                    if self.csi.count == 0 {
+                        self.csi.count = 1;
+                    }
+                    if self.overflowed {
+                        return None;
+                    }
+                    if let Some(param) = self.csi.params.get_mut(self.csi.count - 1) {
+                        *param = (*param as u32 * 10 + (byte - b'0') as u32).min(MAX_PARAM_VALUE as u32) as u16;
+                    }
+                    None
+                }
+                b';' => {
+                    // An empty first parameter still counts
+                    let count = self.csi.count.max(1) + 1;
+                    if count > MAX_PARAMS {
+                        self.overflowed = true;
+                    } else {
+                        self.csi.count = count;
+                    }
+                    None
+                }
+                // Private markers and intermediate bytes
+                b'<'..=b'?' | 0x20..=0x2F | b':' => {
+                    self.ignored = true;
+                    None
+                }
+                0x40..=0x7E => {
+                    self.state = State::Ground;
+                    self.csi.command = byte;
+                    if self.ignored { None } else { Some(Action::Csi(self.csi)) }
+                }
+                // Controls inside a sequence are dropped
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn parse(text: &[u8]) -> Vec<Action> {
+        let mut parser = Parser::new();
+        text.iter().filter_map(|&byte| parser.advance(byte)).collect()
+    }
+
+    fn csi(params: &[u16], command: u8) -> Action {
+        let mut csi = Csi { params: [0; MAX_PARAMS], count: params.len(), command };
+        csi.params[..params.len()].copy_from_slice(params);
+        Action::Csi(csi)
+    }
+
+    #[test_case]
+    fn sequences_are_parsed() {
+        let actions = parse(b"a\x1b[1;31mb\x1b[H\n\x1b7\x1b[?25l\x1b[;5H\x1b[99999A\x1b[1\x18c");
+        assert_eq!(actions, [
+            Action::Print(b'a'),
+            csi(&[1, 31], b'm'),
+            Action::Print(b'b'),
+            csi(&[], b'H'),
+            Action::Control(b'\n'),
+            Action::Escape(b'7'),
+            csi(&[0, 5], b'H'),
+            csi(&[9999], b'A'),
+            Action::Print(b'c'),
+        ]);
+        if let Action::Csi(csi) = actions[6] {
+            assert_eq!((csi.param(0), csi.param(1), csi.param(2)), (0, 5, 0));
+        }
+    }
+
+    #[test_case]
+    fn extra_parameters_are_dropped() {
+        let mut sequence = b"\x1b[".to_vec();
+        for i in 1..=MAX_PARAMS + 2 {
+            sequence.extend_from_slice(format!("{};", i).as_bytes());
+        }
+        sequence.push(b'm');
+        let params: Vec<u16> = (1..=MAX_PARAMS as u16).collect();
+        assert_eq!(parse(&sequence), [csi(&params, b'm')]);
+    }
+}
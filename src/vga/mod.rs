@@ -0,0 +1,187 @@
+//! This file provides VGA text mode functionality.
+//!
+//! The screen is shared by virtual terminals, switched with Alt+F1 to Alt+F6.
+//! Each keeps its screen, cursor and colours in memory with a scrollback, and
+//! only the active one is drawn into the VGA buffer. Text is drawn once per
+//! write, only the cells that changed are copied and the screen is scrolled
+//! by the hardware.
+//!
+//! Text written to a terminal can contain ANSI escape sequences: SGR colours
+//! and bold, cursor movement, erasing, and saving and restoring the cursor.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::sync::IrqMutex;
+
+use self::screen::Screen;
+use self::terminal::Terminal;
+
+mod ansi;
+mod screen;
+mod terminal;
+
+/// Number of virtual terminals, one per function key from F1
+pub const TERMINAL_COUNT: usize = 6;
+
+lazy_static! {
+    static ref DISPLAY: IrqMutex<Display> = IrqMutex::new(Display {
+        terminals: (0..TERMINAL_COUNT).map(|_| Terminal::new()).collect(),
+        screen: Screen::new(),
+    });
+}
+
+/// The terminal on the screen, and receiving the keys typed
+static ACTIVE_TERMINAL: AtomicUsize = AtomicUsize::new(0);
+
+const BUFFER_HEIGHT: u16 = 25;
+const BUFFER_WIDTH: u16 = 80;
+const BUFFER_SIZE: u16 = BUFFER_WIDTH * BUFFER_HEIGHT;
+
+struct Display {
+    terminals: Vec<Terminal>,
+    screen: Screen,
+}
+
+/// Shows the first terminal and the cursor.
+pub fn init() {
+    with_terminal(active_terminal(), Terminal::invalidate);
+    screen::enable_cursor();
+}
+
+pub fn active_terminal() -> usize {
+    ACTIVE_TERMINAL.load(Ordering::Relaxed)
+}
+
+/// Puts another terminal on the screen.
+pub fn switch_terminal(terminal: usize) {
+    let mut display = DISPLAY.lock();
+    if terminal == active_terminal() || terminal >= display.terminals.len() {
+        return;
+    }
+    ACTIVE_TERMINAL.store(terminal, Ordering::Relaxed);
+    let display = &mut *display;
+    display.terminals[terminal].invalidate();
+    display.terminals[terminal].flush(&mut display.screen);
+}
+
+/// Scrolls the active terminal back into its history by `lines`, or forward
+/// if negative.
+pub fn scroll_view(lines: isize) {
+    with_terminal(active_terminal(), |terminal| terminal.scroll_view(lines));
+}
+
+/// Scrolls the active terminal back to its output.
+pub fn follow_output() {
+    with_terminal(active_terminal(), Terminal::follow_output);
+}
+
+/// Moves the cursor of a terminal by `offset` characters, to the previous or
+/// next lines past the edges.
+pub fn move_cursor(terminal: usize, offset: isize) {
+    with_terminal(terminal, |terminal| terminal.move_cursor(offset));
+}
+
+/// Chooses between scrolling with the CRTC start address and copying the
+/// screen up, to compare them.
+pub fn set_hardware_scrolling(enabled: bool) {
+    DISPLAY.lock().screen.hardware_scrolling = enabled;
+}
+
+/// Runs `f` on a terminal, then draws what changed if it is on the screen.
+fn with_terminal<T>(terminal: usize, f: impl FnOnce(&mut Terminal) -> T) -> T {
+    let mut display = DISPLAY.lock();
+    let display = &mut *display;
+    let result = f(&mut display.terminals[terminal]);
+    if terminal == active_terminal() {
+        display.terminals[terminal].flush(&mut display.screen);
+    }
+    result
+}
+
+/// Writes text to a terminal.
+#[derive(Debug, Clone, Copy)]
+pub struct TerminalWriter(pub usize);
+
+impl Write for TerminalWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        with_terminal(self.0, |terminal| terminal.write_str(s))
+    }
+}
+
+/// Prints to the first terminal, with a newline.
+#[macro_export]
+macro_rules! vga_println {
+    () => (vga_print!("\n"));
+    ($($arg:tt)*) => (
+        {
+            use core::fmt::Write;
+            use crate::vga::TerminalWriter;
+            TerminalWriter(0).write_fmt(format_args_nl!($($arg)*)).unwrap();
+        }
+    );
+}
+
+/// Prints to the first terminal.
+#[macro_export]
+macro_rules! vga_print {
+    ($($arg:tt)*) => (
+        {
+            use core::fmt::Write;
+            use crate::vga::TerminalWriter;
+            TerminalWriter(0).write_fmt(format_args!($($arg)*)).unwrap();
+        }
+    );
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Color {
+    Black = 0x0,
+    Blue = 0x1,
+    Green = 0x2,
+    Cyan = 0x3,
+    Red = 0x4,
+    Magenta = 0x5,
+    Brown = 0x6,
+    LightGray = 0x7,
+    DarkGray = 0x8,
+    LightBlue = 0x9,
+    LightGreen = 0xA,
+    LightCyan = 0xB,
+    LightRed = 0xC,
+    LightMagenta = 0xD,
+    LightBrown = 0xE,
+    White = 0xF,
+}
+
+impl Color {
+    fn color(self) -> u8 {
+        self as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+
+    #[test_case]
+    fn lines_scroll_on_the_screen() {
+        for &hardware_scrolling in [false, true].iter() {
+            set_hardware_scrolling(hardware_scrolling);
+            // More lines than the text memory holds
+            for i in 0..200 {
+                writeln!(TerminalWriter(0), "line {:>3}", i).unwrap();
+            }
+            let display = DISPLAY.lock();
+            let text = |row: u16| -> String {
+                (0..8).map(|x| display.screen.read(row * BUFFER_WIDTH + x) as u8 as char).collect()
+            };
+            assert_eq!(text(0), "line 176");
+            assert_eq!(text(BUFFER_HEIGHT - 2), "line 199");
+        }
+    }
+}
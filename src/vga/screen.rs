@@ -0,0 +1,132 @@
+//! The VGA text buffer and the CRTC registers driving it.
+//!
+//! The 32 KiB of text memory hold several screens. The CRTC start address
+//! selects the one shown, so scrolling moves the start down a line instead of
+//! copying the screen up, until the end of the memory is reached and the
+//! screen is drawn again at its beginning. The bootloader only maps the first
+//! page of the text memory, the whole of it is mapped again as MMIO.
+//!
+//! See https://wiki.osdev.org/Text_Mode_Cursor and
+//! http://www.osdever.net/FreeVGA/vga/crtcreg.htm
+
+use crate::inline_asm::{inb, outb};
+use crate::libstd::memcpy;
+use crate::memory::{PhysAddr, VirtAddr};
+use crate::paging::map_mmio;
+
+use super::{BUFFER_HEIGHT, BUFFER_SIZE, BUFFER_WIDTH};
+
+const BUFFER_ADDRESS: PhysAddr = PhysAddr(0xb8000);
+/// Cells in the text memory
+const MEMORY_SIZE: u16 = 0x4000;
+
+const CRTC_ADDRESS: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CURSOR_START: u8 = 0x0A;
+const CURSOR_END: u8 = 0x0B;
+const START_ADDRESS_HIGH: u8 = 0x0C;
+const START_ADDRESS_LOW: u8 = 0x0D;
+const CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+pub struct Screen {
+    /// Where the text memory is mapped
+    buffer: VirtAddr,
+    /// Cell of the text memory shown at the top left
+    start: u16,
+    /// Cursor position on the screen, `None` when hidden
+    cursor: Option<u16>,
+    /// Whether to scroll with the start address, or by copying the screen up
+    /// a line at a time as the console used to
+    pub hardware_scrolling: bool,
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        let buffer = map_mmio(BUFFER_ADDRESS, MEMORY_SIZE as u64 * 2).expect("Can't map the VGA text memory");
+        Screen { buffer, start: 0, cursor: Some(0), hardware_scrolling: true }
+    }
+
+    /// Address of the cell at index `i` of the screen.
+    fn cell(&self, i: u16) -> *mut u16 {
+        unsafe { self.buffer.as_mut_ptr::<u16>().offset((self.start + i) as isize) }
+    }
+
+    /// Copies character cells to the screen, from the index `i`.
+    pub fn write(&self, i: u16, cells: &[u16]) {
+        let destination = self.cell(i);
+        for (offset, &cell) in cells.iter().enumerate() {
+            // Volatile so that the loop isn't turned into a byte by byte
+            // memcpy
+            unsafe {
+                destination.add(offset).write_volatile(cell);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn read(&self, i: u16) -> u16 {
+        unsafe { self.cell(i).read_volatile() }
+    }
+
+    /// Scrolls the screen up by `lines`. The lines that appear at the bottom
+    /// hold stale cells until they are written. Returns false if the screen
+    /// must be drawn again instead.
+    pub fn scroll(&mut self, lines: u16) -> bool {
+        if !self.hardware_scrolling {
+            let line_bytes = BUFFER_WIDTH as usize * 2;
+            for _ in 0..lines.min(BUFFER_HEIGHT) {
+                unsafe {
+                    memcpy(self.cell(0) as *mut u8, self.cell(BUFFER_WIDTH) as *const u8,
+                           BUFFER_SIZE as usize * 2 - line_bytes);
+                }
+            }
+            return true;
+        }
+        let start = self.start + lines * BUFFER_WIDTH;
+        if start + BUFFER_SIZE > MEMORY_SIZE {
+            self.set_start(0);
+            return false;
+        }
+        self.set_start(start);
+        true
+    }
+
+    fn set_start(&mut self, start: u16) {
+        if start == self.start {
+            return;
+        }
+        self.start = start;
+        write_crtc(START_ADDRESS_HIGH, (start >> 8) as u8);
+        write_crtc(START_ADDRESS_LOW, start as u8);
+        // The cursor location is in the text memory, not on the screen
+        self.update_cursor();
+    }
+
+    pub fn set_cursor(&mut self, cursor: Option<u16>) {
+        if cursor != self.cursor {
+            self.cursor = cursor;
+            self.update_cursor();
+        }
+    }
+
+    fn update_cursor(&self) {
+        // A location past the end of the screen isn't shown
+        let i = self.start + self.cursor.unwrap_or(BUFFER_SIZE);
+        write_crtc(CURSOR_LOCATION_LOW, i as u8);
+        write_crtc(CURSOR_LOCATION_HIGH, (i >> 8) as u8);
+    }
+}
+
+/// Show block cursor.
+pub fn enable_cursor() {
+    outb(CRTC_ADDRESS, CURSOR_START);
+    outb(CRTC_DATA, inb(CRTC_DATA) & 0xC0);
+    outb(CRTC_ADDRESS, CURSOR_END);
+    outb(CRTC_DATA, (inb(CRTC_DATA) & 0xE0) | 15);
+}
+
+fn write_crtc(register: u8, value: u8) {
+    outb(CRTC_ADDRESS, register);
+    outb(CRTC_DATA, value);
+}
@@ -0,0 +1,593 @@
+//! A virtual terminal: a screen kept in memory, with its own cursor, colours
+//! and the lines that scrolled off its top.
+//!
+//! The terminal is the shadow buffer of the screen: it keeps track of what
+//! changed, and the active terminal flushes only that to the VGA buffer.
+
+use core::fmt::Write;
+use core::ops::Range;
+
+use alloc::collections::VecDeque;
+
+use super::ansi::{Action, Csi, Parser};
+use super::screen::Screen;
+use super::{Color, BUFFER_HEIGHT, BUFFER_SIZE, BUFFER_WIDTH};
+
+const TAB_WIDTH: u16 = 8;
+/// Lines kept once they scrolled off the top of the screen
+const SCROLLBACK_LINES: usize = 500;
+
+/// VGA colours in the order of the ANSI colour numbers, then their bright
+/// versions
+const ANSI_COLORS: [Color; 16] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::LightBrown,
+    Color::LightBlue, Color::LightMagenta, Color::LightCyan, Color::White,
+];
+const DEFAULT_FOREGROUND: u8 = 7;
+const DEFAULT_BACKGROUND: u8 = 0;
+
+/// A line of characters with their attribute bytes, as in the VGA buffer.
+type Line = [u16; BUFFER_WIDTH as usize];
+
+/// Colours of the text, as ANSI colour numbers.
+#[derive(Debug, Clone, Copy)]
+struct Attributes {
+    foreground: u8,
+    /// Only the 8 dark colours, the top bit of the VGA attribute makes the
+    /// text blink
+    background: u8,
+    bold: bool,
+}
+
+impl Attributes {
+    const fn new() -> Attributes {
+        Attributes { foreground: DEFAULT_FOREGROUND, background: DEFAULT_BACKGROUND, bold: false }
+    }
+
+    /// The VGA attribute byte. Bold is rendered with the bright colours.
+    fn color(&self) -> u8 {
+        let foreground = if self.bold { self.foreground | 8 } else { self.foreground };
+        ANSI_COLORS[foreground as usize].color() | ANSI_COLORS[self.background as usize].color() << 4
+    }
+
+    /// A character cell of the VGA buffer.
+    fn cell(&self, char: u8) -> u16 {
+        char as u16 | (self.color() as u16) << 8
+    }
+}
+
+pub struct Terminal {
+    /// The screen, as in the VGA buffer
+    cells: [u16; BUFFER_SIZE as usize],
+    cursor_x: u16,
+    cursor_y: u16,
+    /// A character was written in the last column: the cursor stays there
+    /// and the next printable character goes to the next line
+    wrap_pending: bool,
+    attributes: Attributes,
+    /// Cursor and attributes kept by `ESC 7` or `CSI s`
+    saved: (u16, u16, Attributes),
+    parser: Parser,
+    /// Lines scrolled off the screen, oldest first
+    scrollback: VecDeque<Line>,
+    /// Lines the view is scrolled back by, 0 to follow the output
+    view: usize,
+    /// Cells changed since the last flush
+    dirty: Range<u16>,
+    /// Lines scrolled since the last flush
+    scrolled: u16,
+    /// Whether the whole view must be drawn on the next flush
+    redraw: bool,
+}
+
+impl Terminal {
+    pub fn new() -> Terminal {
+        Terminal {
+            cells: [Attributes::new().cell(0); BUFFER_SIZE as usize],
+            cursor_x: 0,
+            cursor_y: 0,
+            wrap_pending: false,
+            attributes: Attributes::new(),
+            saved: (0, 0, Attributes::new()),
+            parser: Parser::new(),
+            scrollback: VecDeque::new(),
+            view: 0,
+            dirty: 0..0,
+            scrolled: 0,
+            redraw: true,
+        }
+    }
+
+    /// Makes the next flush draw the whole view, for a terminal put on the
+    /// screen.
+    pub fn invalidate(&mut self) {
+        self.redraw = true;
+    }
+
+    /// Draws the changes since the last flush on the screen. The cursor is
+    /// hidden while the view is scrolled back.
+    pub fn flush(&mut self, screen: &mut Screen) {
+        if self.view > 0 {
+            if self.redraw {
+                for row in 0..BUFFER_HEIGHT {
+                    screen.write(row * BUFFER_WIDTH, self.visible_line(row));
+                }
+            }
+            screen.set_cursor(None);
+        } else {
+            if !self.redraw && self.scrolled > 0 && !screen.scroll(self.scrolled) {
+                self.redraw = true;
+            }
+            if self.redraw {
+                screen.write(0, &self.cells);
+            } else if !self.dirty.is_empty() {
+                screen.write(self.dirty.start, &self.cells[self.dirty.start as usize..self.dirty.end as usize]);
+            }
+            screen.set_cursor(Some(self.get_buffer_index_at_cursor()));
+        }
+        self.dirty = 0..0;
+        self.scrolled = 0;
+        self.redraw = false;
+    }
+
+    fn mark_dirty(&mut self, start: u16, end: u16) {
+        if self.dirty.is_empty() {
+            self.dirty = start..end;
+        } else {
+            self.dirty = self.dirty.start.min(start)..self.dirty.end.max(end);
+        }
+    }
+
+    /// Scrolls the view back into the history by `lines`, or forward if
+    /// negative. It stops at the oldest line and at the screen.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let view = (self.view as isize + lines).max(0).min(self.scrollback.len() as isize) as usize;
+        if view != self.view {
+            self.view = view;
+            self.redraw = true;
+        }
+    }
+
+    /// Scrolls the view back to the screen.
+    pub fn follow_output(&mut self) {
+        self.scroll_view(-(self.view as isize));
+    }
+
+    /// The line shown on `row` of the view.
+    fn visible_line(&self, row: u16) -> &[u16] {
+        let line = self.scrollback.len() - self.view + row as usize;
+        match self.scrollback.get(line) {
+            Some(line) => line,
+            None => {
+                let start = (line - self.scrollback.len()) * BUFFER_WIDTH as usize;
+                &self.cells[start..start + BUFFER_WIDTH as usize]
+            }
+        }
+    }
+
+    fn set_column(&mut self, x: u16) {
+        self.cursor_x = x;
+        self.wrap_pending = false;
+    }
+
+    fn newline(&mut self) {
+        self.set_column(0);
+        self.cursor_y += 1;
+        if self.cursor_y >= BUFFER_HEIGHT {
+            self.cursor_y = BUFFER_HEIGHT - 1; // Stay on the last line
+            self.scroll_down_one_line();
+        }
+    }
+
+    /// Moves the top line of the screen to the scrollback. A scrolled back
+    /// view keeps showing the same lines. More than a screen of lines is
+    /// drawn again rather than scrolled.
+    fn scroll_down_one_line(&mut self) {
+        let mut line = [0; BUFFER_WIDTH as usize];
+        line.copy_from_slice(&self.cells[..BUFFER_WIDTH as usize]);
+        if self.scrollback.len() == SCROLLBACK_LINES {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(line);
+        // The lines of a scrolled back view are one more line back, also when
+        // the oldest line was dropped since the others moved down with it.
+        // Only a view of the oldest line moves with the output.
+        if self.view > 0 {
+            self.view = (self.view + 1).min(self.scrollback.len());
+        }
+
+        self.cells.copy_within(BUFFER_WIDTH as usize.., 0);
+        // The changes move up with the lines, those that went off the screen
+        // are in the scrollback
+        if self.dirty.end > BUFFER_WIDTH {
+            self.dirty = self.dirty.start.saturating_sub(BUFFER_WIDTH)..self.dirty.end - BUFFER_WIDTH;
+        } else {
+            self.dirty = 0..0;
+        }
+        if self.scrolled < BUFFER_HEIGHT {
+            self.scrolled += 1;
+        } else {
+            self.redraw = true;
+        }
+        self.erase((BUFFER_HEIGHT - 1) * BUFFER_WIDTH, BUFFER_SIZE);
+    }
+
+    /// Blanks the characters from `start` to `end` excluded, with the current
+    /// background colour.
+    fn erase(&mut self, start: u16, end: u16) {
+        let blank = self.attributes.cell(0);
+        for cell in &mut self.cells[start as usize..end as usize] {
+            *cell = blank;
+        }
+        self.mark_dirty(start, end);
+    }
+
+    /// Writes a character at the cursor. The wrap to the next line is
+    /// deferred to the next character, so that filling the last line doesn't
+    /// scroll the screen.
+    fn put_char(&mut self, char: u8) {
+        if self.wrap_pending {
+            self.newline();
+        }
+        let i = self.get_buffer_index_at_cursor();
+        self.cells[i as usize] = self.attributes.cell(char);
+        self.mark_dirty(i, i + 1);
+        if self.cursor_x == BUFFER_WIDTH - 1 {
+            self.wrap_pending = true;
+        } else {
+            self.cursor_x += 1;
+        }
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::Print(char) => self.put_char(char),
+            Action::Control(b'\n') => self.newline(),
+            Action::Control(b'\r') => self.set_column(0),
+            // Backspace
+            Action::Control(0x08) => self.set_column(self.cursor_x.saturating_sub(1)),
+            Action::Control(b'\t') => {
+                self.set_column(((self.cursor_x / TAB_WIDTH + 1) * TAB_WIDTH).min(BUFFER_WIDTH - 1));
+            }
+            Action::Control(_) => {}
+            Action::Escape(b'7') => self.save_cursor(),
+            Action::Escape(b'8') => self.restore_cursor(),
+            Action::Escape(_) => {}
+            Action::Csi(csi) => self.control_sequence(&csi),
+        }
+    }
+
+    fn control_sequence(&mut self, csi: &Csi) {
+        // Everything but colours and saving the cursor cancels a pending wrap
+        if csi.command != b'm' && csi.command != b's' {
+            self.wrap_pending = false;
+        }
+        // Movements of 0 characters move by 1
+        let count = csi.param(0).max(1);
+        match csi.command {
+            // Cursor up, down, forward and back. They stop at the edges.
+            b'A' => self.cursor_y = self.cursor_y.saturating_sub(count),
+            b'B' => self.cursor_y = (self.cursor_y + count).min(BUFFER_HEIGHT - 1),
+            b'C' => self.cursor_x = (self.cursor_x + count).min(BUFFER_WIDTH - 1),
+            b'D' => self.cursor_x = self.cursor_x.saturating_sub(count),
+            // Cursor position, from 1
+            b'H' | b'f' => {
+                self.cursor_y = (csi.param(0).max(1) - 1).min(BUFFER_HEIGHT - 1);
+                self.cursor_x = (csi.param(1).max(1) - 1).min(BUFFER_WIDTH - 1);
+            }
+            // Erase in display
+            b'J' => {
+                let cursor = self.get_buffer_index_at_cursor();
+                match csi.param(0) {
+                    0 => self.erase(cursor, BUFFER_SIZE),
+                    1 => self.erase(0, cursor + 1),
+                    2 => self.erase(0, BUFFER_SIZE),
+                    _ => {}
+                }
+            }
+            // Erase in line
+            b'K' => {
+                let cursor = self.get_buffer_index_at_cursor();
+                let line = self.cursor_y * BUFFER_WIDTH;
+                match csi.param(0) {
+                    0 => self.erase(cursor, line + BUFFER_WIDTH),
+                    1 => self.erase(line, cursor + 1),
+                    2 => self.erase(line, line + BUFFER_WIDTH),
+                    _ => {}
+                }
+            }
+            b'm' => self.select_graphic_rendition(csi.params()),
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        // No parameter resets the attributes
+        if params.is_empty() {
+            self.attributes = Attributes::new();
+        }
+        for &param in params {
+            match param {
+                0 => self.attributes = Attributes::new(),
+                1 => self.attributes.bold = true,
+                22 => self.attributes.bold = false,
+                30..=37 => self.attributes.foreground = (param - 30) as u8,
+                39 => self.attributes.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.attributes.background = (param - 40) as u8,
+                49 => self.attributes.background = DEFAULT_BACKGROUND,
+                90..=97 => self.attributes.foreground = (param - 90) as u8 + 8,
+                100..=107 => self.attributes.background = (param - 100) as u8,
+                _ => {}
+            }
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = (self.cursor_x, self.cursor_y, self.attributes);
+    }
+
+    fn restore_cursor(&mut self) {
+        let (x, y, attributes) = self.saved;
+        self.set_column(x);
+        self.cursor_y = y;
+        self.attributes = attributes;
+    }
+
+    /// Moves the cursor by `offset` characters, to the previous or next lines
+    /// past the edges. It doesn't leave the screen.
+    ///
+    /// A pending wrap counts as one character past the last column, like the
+    /// text would have moved it, so that a line editor can move over what it
+    /// wrote. Moving past the bottom right cell leaves the wrap pending.
+    pub fn move_cursor(&mut self, offset: isize) {
+        if offset == 0 {
+            return;
+        }
+        let position = self.get_buffer_index_at_cursor() as isize + self.wrap_pending as isize;
+        let i = (position + offset).max(0).min(BUFFER_SIZE as isize) as u16;
+        if i == BUFFER_SIZE {
+            self.cursor_x = BUFFER_WIDTH - 1;
+            self.cursor_y = BUFFER_HEIGHT - 1;
+            self.wrap_pending = true;
+        } else {
+            self.set_column(i % BUFFER_WIDTH);
+            self.cursor_y = i / BUFFER_WIDTH;
+        }
+    }
+
+    fn get_buffer_index_at_cursor(&self) -> u16 {
+        BUFFER_WIDTH * self.cursor_y + self.cursor_x
+    }
+}
+
+impl Write for Terminal {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if let Some(action) = self.parser.advance(byte) {
+                self.perform(action);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// The characters of a line, up to the first blank cell.
+    fn text(line: &[u16]) -> String {
+        line.iter().map(|&cell| cell as u8 as char).take_while(|&char| char != '\0').collect()
+    }
+
+    fn row(terminal: &Terminal, row: u16) -> String {
+        text(&terminal.cells[(row * BUFFER_WIDTH) as usize..((row + 1) * BUFFER_WIDTH) as usize])
+    }
+
+    /// A terminal whose screen is full of `#`, writing on a blue background.
+    fn filled() -> Terminal {
+        let mut terminal = Terminal::new();
+        for _ in 0..BUFFER_SIZE {
+            write!(terminal, "#").unwrap();
+        }
+        write!(terminal, "\x1b[44m").unwrap();
+        terminal
+    }
+
+    /// The cells blanked on a blue background, which must be contiguous.
+    fn erased(terminal: &Terminal) -> Range<u16> {
+        // Light gray on blue
+        let blank = 0x17 << 8;
+        let start = terminal.cells.iter().position(|&cell| cell == blank).unwrap_or(0);
+        let end = terminal.cells.iter().rposition(|&cell| cell == blank).map_or(0, |i| i + 1);
+        assert!(terminal.cells[start..end].iter().all(|&cell| cell == blank));
+        start as u16..end as u16
+    }
+
+    #[test_case]
+    fn colors_are_selected() {
+        let mut terminal = Terminal::new();
+        write!(terminal, "a\x1b[31;44mb\x1b[1mc\x1b[22;39md\x1b[0me\x1b[97;102mf\x1b[mg").unwrap();
+        assert_eq!(row(&terminal, 0), "abcdefg");
+        let colors: Vec<u8> = terminal.cells[..7].iter().map(|&cell| (cell >> 8) as u8).collect();
+        assert_eq!(colors, [0x07, 0x14, 0x1C, 0x17, 0x07, 0x2F, 0x07]);
+    }
+
+    #[test_case]
+    fn cursor_is_positioned() {
+        let mut terminal = Terminal::new();
+        write!(terminal, "\x1b[3;5HX").unwrap();
+        assert_eq!(terminal.cells[(2 * BUFFER_WIDTH + 4) as usize] as u8, b'X');
+        // Missing parameters are 1
+        write!(terminal, "\x1b[HY\x1b[;3fZ").unwrap();
+        assert_eq!(terminal.cells[0] as u8, b'Y');
+        assert_eq!(terminal.cells[2] as u8, b'Z');
+        // Positions and movements stop at the edges
+        write!(terminal, "\x1b[99;99H").unwrap();
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - 1);
+        write!(terminal, "\x1b[5A\x1b[3D").unwrap();
+        assert_eq!((terminal.cursor_x, terminal.cursor_y), (BUFFER_WIDTH - 4, BUFFER_HEIGHT - 6));
+        write!(terminal, "\x1b[B\x1b[2C\x1b[99B\x1b[99D").unwrap();
+        assert_eq!((terminal.cursor_x, terminal.cursor_y), (0, BUFFER_HEIGHT - 1));
+        assert!(terminal.scrollback.is_empty());
+    }
+
+    #[test_case]
+    fn display_and_lines_are_erased() {
+        let line = BUFFER_WIDTH;
+        let cases = [
+            ("J", line + 2..BUFFER_SIZE),
+            ("0J", line + 2..BUFFER_SIZE),
+            ("1J", 0..line + 3),
+            ("2J", 0..BUFFER_SIZE),
+            ("K", line + 2..2 * line),
+            ("1K", line..line + 3),
+            ("2K", line..2 * line),
+        ];
+        for (command, range) in cases.iter() {
+            let mut terminal = filled();
+            write!(terminal, "\x1b[2;3H\x1b[{}", command).unwrap();
+            assert_eq!(&erased(&terminal), range);
+            assert!(terminal.scrollback.is_empty());
+        }
+    }
+
+    #[test_case]
+    fn wrap_is_deferred() {
+        // Filling the screen doesn't scroll it, the next character does
+        let mut terminal = filled();
+        assert!(terminal.scrollback.is_empty());
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - 1);
+        write!(terminal, "a").unwrap();
+        assert_eq!(terminal.scrollback.len(), 1);
+        assert_eq!(row(&terminal, BUFFER_HEIGHT - 1), "a");
+
+        // A new line, carriage return or cursor movement cancels the wrap
+        let mut terminal = Terminal::new();
+        write!(terminal, "\x1b[1;80Hxy\x1b[3;80Hx\nz\x1b[5;80Hx\rw\x1b[7;80Hx\x1b[2Dv").unwrap();
+        assert_eq!(row(&terminal, 1), "y");
+        assert_eq!(row(&terminal, 3), "z");
+        assert_eq!(terminal.cells[(4 * BUFFER_WIDTH) as usize] as u8, b'w');
+        assert_eq!(terminal.cells[(7 * BUFFER_WIDTH - 3) as usize] as u8, b'v');
+        // Colours don't
+        write!(terminal, "\x1b[9;80Hx\x1b[31my").unwrap();
+        assert_eq!(row(&terminal, 9), "y");
+    }
+
+    #[test_case]
+    fn cursor_moves_over_wrapped_lines() {
+        // The moves of the line editor typing at the bottom of the screen
+        let mut terminal = Terminal::new();
+        write!(terminal, "\x1b[25H> ").unwrap();
+        for _ in 0..BUFFER_WIDTH - 2 {
+            write!(terminal, "a").unwrap();
+        }
+        assert!(terminal.wrap_pending);
+        // Backspace: back over the last character, which is blanked
+        terminal.move_cursor(-1);
+        write!(terminal, " ").unwrap();
+        terminal.move_cursor(-1);
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - 1);
+        assert!(!terminal.wrap_pending);
+        // Retype it and insert a character at the start of the line: the
+        // line wraps and the screen scrolls under the cursor
+        write!(terminal, "a").unwrap();
+        terminal.move_cursor(-(BUFFER_WIDTH as isize - 2));
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - BUFFER_WIDTH + 2);
+        for _ in 0..BUFFER_WIDTH - 1 {
+            write!(terminal, "b").unwrap();
+        }
+        assert_eq!(terminal.scrollback.len(), 1);
+        terminal.move_cursor(-(BUFFER_WIDTH as isize - 2));
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - 2 * BUFFER_WIDTH + 3);
+        // To the end of the line, then past the bottom right cell
+        terminal.move_cursor(BUFFER_WIDTH as isize - 2);
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - BUFFER_WIDTH + 1);
+        terminal.move_cursor(1000);
+        assert!(terminal.wrap_pending);
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - 1);
+        terminal.move_cursor(-(BUFFER_WIDTH as isize));
+        assert_eq!(terminal.get_buffer_index_at_cursor(), BUFFER_SIZE - BUFFER_WIDTH);
+    }
+
+    #[test_case]
+    fn scrolled_lines_are_kept() {
+        let mut terminal = Terminal::new();
+        for i in 0..30 {
+            writeln!(terminal, "line {}", i).unwrap();
+        }
+        assert_eq!(terminal.scrollback.len(), 6);
+        assert_eq!(text(&terminal.scrollback[0]), "line 0");
+        assert_eq!(text(terminal.visible_line(0)), "line 6");
+
+        terminal.scroll_view(4);
+        assert_eq!(text(terminal.visible_line(0)), "line 2");
+        assert_eq!(text(terminal.visible_line(4)), "line 6");
+        // The view doesn't move with the output, nor past the oldest line
+        write!(terminal, "line 30\n").unwrap();
+        assert_eq!(text(terminal.visible_line(0)), "line 2");
+        terminal.scroll_view(100);
+        assert_eq!(text(terminal.visible_line(0)), "line 0");
+
+        terminal.follow_output();
+        assert_eq!(text(terminal.visible_line(0)), "line 7");
+        assert_eq!(text(terminal.visible_line(BUFFER_HEIGHT - 2)), "line 30");
+    }
+
+    #[test_case]
+    fn full_scrollback_keeps_the_view() {
+        let mut terminal = Terminal::new();
+        for i in 0..SCROLLBACK_LINES + 100 {
+            writeln!(terminal, "line {}", i).unwrap();
+        }
+        assert_eq!(terminal.scrollback.len(), SCROLLBACK_LINES);
+        terminal.scroll_view(10);
+        let first = SCROLLBACK_LINES + 100 - 24 - 10;
+        assert_eq!(text(terminal.visible_line(0)), format!("line {}", first));
+        for i in 0..5 {
+            writeln!(terminal, "more {}", i).unwrap();
+        }
+        assert_eq!(terminal.scrollback.len(), SCROLLBACK_LINES);
+        assert_eq!(text(terminal.visible_line(0)), format!("line {}", first));
+        assert_eq!(text(terminal.visible_line(BUFFER_HEIGHT - 1)), format!("line {}", first + 24));
+
+        // The oldest line is dropped from under a view that shows it
+        terminal.scroll_view(SCROLLBACK_LINES as isize);
+        let oldest = text(terminal.visible_line(0));
+        writeln!(terminal).unwrap();
+        assert_eq!(terminal.view, SCROLLBACK_LINES);
+        assert_ne!(text(terminal.visible_line(0)), oldest);
+    }
+
+    #[test_case]
+    fn changes_are_tracked() {
+        let mut terminal = Terminal::new();
+        terminal.redraw = false;
+        write!(terminal, "ab\x1b[2;3Hc").unwrap();
+        assert_eq!(terminal.dirty, 0..BUFFER_WIDTH + 3);
+        assert_eq!(terminal.scrolled, 0);
+
+        // The changes scroll with the lines, and the new bottom line is dirty
+        write!(terminal, "\x1b[25Hd\n").unwrap();
+        assert_eq!(terminal.scrolled, 1);
+        assert_eq!(terminal.dirty, 0..BUFFER_SIZE);
+        terminal.dirty = 0..0;
+        write!(terminal, "\n\n").unwrap();
+        assert_eq!((terminal.scrolled, terminal.redraw), (3, false));
+        assert_eq!(terminal.dirty, BUFFER_SIZE - 2 * BUFFER_WIDTH..BUFFER_SIZE);
+
+        // Past a screen of lines, it is drawn again
+        for _ in 0..BUFFER_HEIGHT {
+            writeln!(terminal).unwrap();
+        }
+        assert!(terminal.redraw);
+    }
+}
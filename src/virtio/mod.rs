@@ -0,0 +1,362 @@
+//! Virtio devices over PCI.
+//! https://wiki.osdev.org/Virtio
+//!
+//! Both the legacy interface, with its registers in an I/O BAR, and the
+//! modern one, located by vendor-specific capabilities in memory BARs, are
+//! supported. Transitional devices offer both, the modern one is preferred.
+//! Device drivers negotiate features, set up their virtqueues, and get their
+//! interrupts through `register_interrupt`, as devices may share a line.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::inline_asm::{inb, inl, inw, outb, outl, outw};
+use crate::irq::Irq;
+use crate::memory::{PhysAddr, VirtAddr, FRAME_SIZE};
+use crate::paging::{map_mmio, MapError};
+use crate::pci::{Bar, PciDevice, CAPABILITY_VENDOR_SPECIFIC, COMMAND_BUS_MASTER, COMMAND_IO_SPACE, COMMAND_MEMORY_SPACE};
+use crate::pit::busy_wait_us;
+use crate::sync::IrqMutex;
+use crate::irq;
+
+pub use self::queue::{Buffer, VirtQueue, MAX_QUEUE_SIZE};
+
+mod queue;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+/// Device IDs of transitional devices are this plus the device type minus 1,
+/// modern-only ones use `MODERN_DEVICE_ID_BASE` plus the type.
+pub const TRANSITIONAL_DEVICE_ID_BASE: u16 = 0x1000;
+pub const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+pub const DEVICE_TYPE_BLOCK: u16 = 2;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// How long a device gets to reset
+const RESET_TIMEOUT_US: u64 = 100_000;
+
+/// Set by devices implementing the modern interface
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Legacy registers, in the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+/// Without MSI-X
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Types of the modern capabilities
+const CAPABILITY_COMMON_CONFIG: u8 = 1;
+const CAPABILITY_NOTIFY_CONFIG: u8 = 2;
+const CAPABILITY_ISR_CONFIG: u8 = 3;
+const CAPABILITY_DEVICE_CONFIG: u8 = 4;
+
+// Common configuration structure of the modern interface
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFFSET: u64 = 0x1E;
+const COMMON_QUEUE_DESCRIPTORS: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+lazy_static! {
+    static ref INTERRUPT_HANDLERS: IrqMutex<Vec<(Irq, Arc<dyn InterruptHandler>)>> = IrqMutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// Neither a legacy I/O BAR nor the modern capabilities
+    NoTransport,
+    Map(MapError),
+    /// The device didn't accept the features
+    FeaturesRejected,
+    /// The device doesn't have this queue
+    NoQueue(u16),
+    BadQueueSize(u16),
+    QueueFull,
+    /// The device didn't go back to its initial state
+    ResetTimeout,
+    OutOfMemory,
+}
+
+impl From<MapError> for VirtioError {
+    fn from(error: MapError) -> VirtioError {
+        VirtioError::Map(error)
+    }
+}
+
+/// Called in interrupt context when the line of a device fires. The line
+/// may be shared, so the device may not be the one that raised it.
+pub trait InterruptHandler: Send + Sync {
+    fn handle_interrupt(&self);
+}
+
+/// The registers of the modern interface, mapped from the memory BARs.
+#[derive(Debug)]
+pub struct ModernRegisters {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device: VirtAddr,
+}
+
+#[derive(Debug)]
+pub enum Transport {
+    Legacy { port: u16 },
+    Modern(ModernRegisters),
+}
+
+impl Transport {
+    /// Finds the registers of a device and enables it on the bus.
+    pub fn new(device: &PciDevice) -> Result<Transport, VirtioError> {
+        let transport = match Self::modern(device)? {
+            Some(registers) => Transport::Modern(registers),
+            None => match device.bars[0] {
+                Some(Bar::Io { port, .. }) => Transport::Legacy { port },
+                _ => return Err(VirtioError::NoTransport),
+            },
+        };
+        device.enable(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+        Ok(transport)
+    }
+
+    fn modern(device: &PciDevice) -> Result<Option<ModernRegisters>, VirtioError> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_config = None;
+        for capability in device.capabilities.iter().filter(|capability| capability.id == CAPABILITY_VENDOR_SPECIFIC) {
+            let config_type = device.read_config_u8(capability.offset + 3);
+            let bar = device.read_config_u8(capability.offset + 4) as usize;
+            let offset = device.read_config(capability.offset + 8) as u64;
+            let length = device.read_config(capability.offset + 12) as u64;
+            let address = match device.bars.get(bar) {
+                Some(Some(Bar::Memory { address, .. })) => PhysAddr(address + offset),
+                // The capabilities may also point to I/O BARs, which we don't
+                // handle
+                _ => continue,
+            };
+            let slot = match config_type {
+                CAPABILITY_COMMON_CONFIG => &mut common,
+                CAPABILITY_NOTIFY_CONFIG => {
+                    notify = Some((address, length, device.read_config(capability.offset + 16)));
+                    continue;
+                }
+                CAPABILITY_ISR_CONFIG => &mut isr,
+                CAPABILITY_DEVICE_CONFIG => &mut device_config,
+                _ => continue,
+            };
+            // The first capability of each type is the preferred one
+            if slot.is_none() {
+                *slot = Some((address, length));
+            }
+        }
+        let (common, notify, isr, device_config) = match (common, notify, isr, device_config) {
+            (Some(common), Some(notify), Some(isr), Some(device_config)) => (common, notify, isr, device_config),
+            _ => return Ok(None),
+        };
+        Ok(Some(ModernRegisters {
+            common: map_mmio(common.0, common.1)?,
+            notify: map_mmio(notify.0, notify.1)?,
+            notify_multiplier: notify.2,
+            isr: map_mmio(isr.0, isr.1)?,
+            device: map_mmio(device_config.0, device_config.1)?,
+        }))
+    }
+
+    pub fn is_modern(&self) -> bool {
+        match self {
+            Transport::Legacy { .. } => false,
+            Transport::Modern(_) => true,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy { port } => inb(port + LEGACY_DEVICE_STATUS),
+            Transport::Modern(registers) => unsafe { read_volatile(common(registers, COMMON_DEVICE_STATUS)) },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self {
+            Transport::Legacy { port } => outb(port + LEGACY_DEVICE_STATUS, status),
+            Transport::Modern(registers) => unsafe { write_volatile(common(registers, COMMON_DEVICE_STATUS), status) },
+        }
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    /// Resets the device and negotiates the features both the device and
+    /// the driver support, which are returned.
+    pub fn begin_init(&self, driver_features: u64) -> Result<u64, VirtioError> {
+        self.set_status(0);
+        let mut waited = 0;
+        while self.status() != 0 {
+            if waited >= RESET_TIMEOUT_US {
+                return Err(VirtioError::ResetTimeout);
+            }
+            busy_wait_us(100);
+            waited += 100;
+        }
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let features = match self {
+            Transport::Legacy { port } => {
+                let features = inl(port + LEGACY_DEVICE_FEATURES) as u64 & driver_features;
+                outl(port + LEGACY_DRIVER_FEATURES, features as u32);
+                features
+            }
+            Transport::Modern(registers) => unsafe {
+                let mut device_features = 0;
+                for select in 0..2 {
+                    write_volatile(common(registers, COMMON_DEVICE_FEATURE_SELECT), select as u32);
+                    device_features |= (read_volatile(common::<u32>(registers, COMMON_DEVICE_FEATURE)) as u64) << (32 * select);
+                }
+                let features = device_features & (driver_features | FEATURE_VERSION_1);
+                for select in 0..2 {
+                    write_volatile(common(registers, COMMON_DRIVER_FEATURE_SELECT), select as u32);
+                    write_volatile(common(registers, COMMON_DRIVER_FEATURE), (features >> (32 * select)) as u32);
+                }
+                features
+            },
+        };
+
+        if self.is_modern() {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.add_status(STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Tells the device the driver is ready.
+    pub fn finish_init(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Sets up a queue of at most `max_size` descriptors. Legacy devices
+    /// can't be told a size and may use a larger queue.
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<VirtQueue, VirtioError> {
+        match self {
+            Transport::Legacy { port } => {
+                outw(port + LEGACY_QUEUE_SELECT, index);
+                let size = inw(port + LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let queue = VirtQueue::new(index, size)?;
+                outl(port + LEGACY_QUEUE_ADDRESS, (queue.descriptor_address().as_u64() / FRAME_SIZE) as u32);
+                Ok(queue)
+            }
+            Transport::Modern(registers) => unsafe {
+                write_volatile(common(registers, COMMON_QUEUE_SELECT), index);
+                let size = read_volatile(common::<u16>(registers, COMMON_QUEUE_SIZE));
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let size = size.min(max_size);
+                write_volatile(common(registers, COMMON_QUEUE_SIZE), size);
+                let mut queue = VirtQueue::new(index, size)?;
+                write_volatile(common(registers, COMMON_QUEUE_DESCRIPTORS), queue.descriptor_address().as_u64());
+                write_volatile(common(registers, COMMON_QUEUE_DRIVER), queue.available_address().as_u64());
+                write_volatile(common(registers, COMMON_QUEUE_DEVICE), queue.used_address().as_u64());
+                let notify_offset = read_volatile(common::<u16>(registers, COMMON_QUEUE_NOTIFY_OFFSET));
+                queue.notify_offset = notify_offset as u64 * registers.notify_multiplier as u64;
+                write_volatile(common(registers, COMMON_QUEUE_ENABLE), 1u16);
+                Ok(queue)
+            },
+        }
+    }
+
+    /// Tells the device new buffers are available in `queue`.
+    pub fn notify(&self, queue: &VirtQueue) {
+        match self {
+            Transport::Legacy { port } => outw(port + LEGACY_QUEUE_NOTIFY, queue.index()),
+            Transport::Modern(registers) => unsafe {
+                write_volatile((registers.notify.as_u64() + queue.notify_offset) as *mut u16, queue.index());
+            },
+        }
+    }
+
+    /// Reads and clears the interrupt status. Bit 0 is set for used buffers,
+    /// bit 1 for a configuration change.
+    pub fn acknowledge_interrupt(&self) -> u8 {
+        match self {
+            Transport::Legacy { port } => inb(port + LEGACY_ISR_STATUS),
+            Transport::Modern(registers) => unsafe { read_volatile(registers.isr.as_ptr::<u8>()) },
+        }
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match self {
+            Transport::Legacy { port } => inl(port + LEGACY_DEVICE_CONFIG + offset),
+            Transport::Modern(registers) => unsafe {
+                read_volatile((registers.device.as_u64() + offset as u64) as *const u32)
+            },
+        }
+    }
+
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        // Not atomic, but the fields we read don't change
+        self.read_config_u32(offset) as u64 | (self.read_config_u32(offset + 4) as u64) << 32
+    }
+}
+
+/// Pointer to a field of the modern common configuration structure.
+fn common<T>(registers: &ModernRegisters, offset: u64) -> *mut T {
+    (registers.common.as_u64() + offset) as *mut T
+}
+
+/// Whether the device has an interrupt line we can handle. Without one, the
+/// driver must poll.
+pub fn has_interrupt(device: &PciDevice) -> bool {
+    device.interrupt_pin != 0 && (device.interrupt_line as usize) < irq::IRQ_COUNT
+}
+
+/// Calls `handler` whenever the interrupt line of `device` fires.
+pub fn register_interrupt(device: &PciDevice, handler: Arc<dyn InterruptHandler>) {
+    if !has_interrupt(device) {
+        return;
+    }
+    let line = Irq(device.interrupt_line);
+    let mut handlers = INTERRUPT_HANDLERS.lock();
+    let first = !handlers.iter().any(|(irq, _)| *irq == line);
+    handlers.push((line, handler));
+    drop(handlers);
+    if first {
+        irq::register_handler(line, interrupt_handler);
+    }
+}
+
+fn interrupt_handler(line: Irq) {
+    for (irq, handler) in INTERRUPT_HANDLERS.lock().iter() {
+        if *irq == line {
+            handler.handle_interrupt();
+        }
+    }
+}
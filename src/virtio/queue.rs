@@ -0,0 +1,291 @@
+//! Split virtqueues.
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-230005
+//!
+//! The driver puts chains of descriptors in the available ring, the device
+//! hands them back through the used ring once it processed them. The three
+//! parts are laid out contiguously as legacy devices expect, with the used
+//! ring on its own page.
+
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::memory::{FrameAllocator, PhysAddr, PhysFrame, VirtAddr, FRAME_ALLOCATOR, FRAME_SIZE};
+use crate::paging::translate_addr;
+
+use super::VirtioError;
+
+/// Continues in the descriptor `next`
+const DESCRIPTOR_NEXT: u16 = 1;
+/// Written by the device instead of read
+const DESCRIPTOR_WRITE: u16 = 2;
+
+/// Modern devices are told to use at most this many descriptors
+pub const MAX_QUEUE_SIZE: u16 = 256;
+/// Legacy devices impose the size of their queues, up to the limit of the
+/// specification
+const LEGACY_MAX_QUEUE_SIZE: u16 = 32768;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    length: u32,
+}
+
+/// A physically contiguous piece of memory given to the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// Whether the device writes to the buffer, otherwise it reads it
+    pub device_writable: bool,
+}
+
+impl Buffer {
+    /// Appends the buffers covering `length` bytes of kernel memory at
+    /// `address`, one per physically contiguous range.
+    pub fn from_memory(address: VirtAddr, length: usize, device_writable: bool, buffers: &mut Vec<Buffer>) {
+        let mut offset = 0;
+        while offset < length {
+            let virt = address.as_u64() + offset as u64;
+            let phys = translate_addr(VirtAddr(virt)).expect("Buffer isn't mapped");
+            let in_page = ((FRAME_SIZE - virt % FRAME_SIZE) as usize).min(length - offset);
+            match buffers.last_mut() {
+                Some(last) if last.device_writable == device_writable
+                    && last.address.as_u64() + last.length as u64 == phys.as_u64() => {
+                    last.length += in_page as u32;
+                }
+                _ => buffers.push(Buffer { address: phys, length: in_page as u32, device_writable }),
+            }
+            offset += in_page;
+        }
+    }
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    /// First of the contiguous frames holding the rings
+    memory: PhysFrame,
+    frame_count: usize,
+    descriptors: *mut Descriptor,
+    available: *mut u16,
+    used: *mut u16,
+    /// Head of the list of free descriptors, chained through `next`
+    free_head: u16,
+    free_count: u16,
+    /// Used ring index up to which completions were collected
+    last_used: u16,
+    /// Set by the transport, where to write to notify the device
+    pub(super) notify_offset: u64,
+}
+
+// The rings are only accessed through `&mut self`
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Allocates the rings of a queue of `size` descriptors, a power of two.
+    pub fn new(index: u16, size: u16) -> Result<VirtQueue, VirtioError> {
+        if size == 0 || size > LEGACY_MAX_QUEUE_SIZE || !size.is_power_of_two() {
+            return Err(VirtioError::BadQueueSize(size));
+        }
+        let (available_offset, used_offset, total_size) = Self::layout(size);
+        let frame_count = align(total_size, FRAME_SIZE as usize) / FRAME_SIZE as usize;
+        let memory = FRAME_ALLOCATOR.lock().allocate_contiguous(frame_count).ok_or(VirtioError::OutOfMemory)?;
+        let base = memory.start.to_virt().as_mut_ptr::<u8>();
+        unsafe {
+            base.write_bytes(0, frame_count * FRAME_SIZE as usize);
+        }
+
+        let descriptors = base as *mut Descriptor;
+        for i in 0..size {
+            unsafe {
+                (*descriptors.add(i as usize)).next = i + 1;
+            }
+        }
+        Ok(VirtQueue {
+            index,
+            size,
+            memory,
+            frame_count,
+            descriptors,
+            available: unsafe { base.add(available_offset) } as *mut u16,
+            used: unsafe { base.add(used_offset) } as *mut u16,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+            notify_offset: 0,
+        })
+    }
+
+    /// Offsets of the available and used rings, and the total size.
+    fn layout(size: u16) -> (usize, usize, usize) {
+        let size = size as usize;
+        let available_offset = size * size_of::<Descriptor>();
+        // flags, index, ring and used_event
+        let available_size = 2 * (3 + size);
+        let used_offset = align(available_offset + available_size, FRAME_SIZE as usize);
+        let used_size = 2 * 3 + size * size_of::<UsedElement>();
+        (available_offset, used_offset, used_offset + used_size)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn descriptor_address(&self) -> PhysAddr {
+        self.memory.start
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        PhysAddr(self.memory.start.as_u64() + Self::layout(self.size).0 as u64)
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        PhysAddr(self.memory.start.as_u64() + Self::layout(self.size).1 as u64)
+    }
+
+    /// Number of descriptors, the longest chain the queue takes.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    /// Makes a chain of `buffers` available to the device and returns the
+    /// head of the chain, which identifies it once used. The device still
+    /// needs to be notified.
+    ///
+    /// # Safety
+    /// The buffers must stay valid until the chain is used and freed.
+    pub unsafe fn add(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut last = head;
+        let mut index = head;
+        for buffer in buffers {
+            let descriptor = &mut *self.descriptors.add(index as usize);
+            descriptor.address = buffer.address.as_u64();
+            descriptor.length = buffer.length;
+            descriptor.flags = DESCRIPTOR_NEXT | (if buffer.device_writable { DESCRIPTOR_WRITE } else { 0 });
+            last = index;
+            index = descriptor.next;
+        }
+        (*self.descriptors.add(last as usize)).flags &= !DESCRIPTOR_NEXT;
+        self.free_head = index;
+        self.free_count -= buffers.len() as u16;
+
+        let available_index = read_volatile(self.available.add(1));
+        write_volatile(self.available.add(2 + (available_index % self.size) as usize), head);
+        // The device must see the ring entry before the new index
+        fence(Ordering::SeqCst);
+        write_volatile(self.available.add(1), available_index.wrapping_add(1));
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Takes the next chain the device is done with, with the number of bytes
+    /// the device wrote. The chain must be released with `free`.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        unsafe {
+            let used_index = read_volatile(self.used.add(1));
+            if used_index == self.last_used {
+                return None;
+            }
+            // Read the element only after seeing the index
+            fence(Ordering::SeqCst);
+            let ring = self.used.add(2) as *const UsedElement;
+            let element = read_volatile(ring.add((self.last_used % self.size) as usize));
+            self.last_used = self.last_used.wrapping_add(1);
+            Some((element.id as u16, element.length))
+        }
+    }
+
+    /// Returns the descriptors of a used chain to the free list.
+    pub fn free(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let descriptor = unsafe { &mut *self.descriptors.add(index as usize) };
+            self.free_count += 1;
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                descriptor.next = self.free_head;
+                break;
+            }
+            index = descriptor.next;
+        }
+        self.free_head = head;
+    }
+}
+
+impl Drop for VirtQueue {
+    /// The device must have been reset, or it could still write to the rings.
+    fn drop(&mut self) {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        for i in 0..self.frame_count {
+            allocator.deallocate_frame(PhysFrame::containing_address(PhysAddr(self.memory.start.as_u64() + i as u64 * FRAME_SIZE)));
+        }
+    }
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    /// Plays the device: marks the next available chain as used.
+    fn complete_next(queue: &mut VirtQueue, written: u32) -> u16 {
+        unsafe {
+            let used_index = read_volatile(queue.used.add(1));
+            let head = read_volatile(queue.available.add(2 + (used_index % queue.size) as usize));
+            let ring = queue.used.add(2) as *mut UsedElement;
+            write_volatile(ring.add((used_index % queue.size) as usize), UsedElement { id: head as u32, length: written });
+            write_volatile(queue.used.add(1), used_index.wrapping_add(1));
+            head
+        }
+    }
+
+    #[test_case]
+    fn chains_go_around_the_queue() {
+        let mut queue = VirtQueue::new(0, 8).unwrap();
+        let data = vec![0u8; 3 * FRAME_SIZE as usize];
+        let mut buffers = Vec::new();
+        Buffer::from_memory(VirtAddr(data.as_ptr() as u64), data.len(), true, &mut buffers);
+        assert!(!buffers.is_empty() && buffers.len() <= 3);
+        assert_eq!(buffers.iter().map(|buffer| buffer.length as usize).sum::<usize>(), data.len());
+
+        // More chains than descriptors, over several laps of the rings
+        for round in 0..20 {
+            let head = unsafe { queue.add(&buffers).unwrap() };
+            assert_eq!(queue.free_descriptors(), 8 - buffers.len() as u16);
+            assert_eq!(queue.pop_used(), None);
+            assert_eq!(complete_next(&mut queue, round), head);
+            assert_eq!(queue.pop_used(), Some((head, round)));
+            queue.free(head);
+            assert_eq!(queue.free_descriptors(), 8);
+        }
+        let too_many = vec![buffers[0]; 9];
+        assert_eq!(unsafe { queue.add(&too_many) }.err(), Some(VirtioError::QueueFull));
+        assert_eq!(VirtQueue::new(0, 6).err(), Some(VirtioError::BadQueueSize(6)));
+    }
+}
@@ -0,0 +1,58 @@
+#!/bin/sh
+# Builds the user programs and the initramfs embedded in the kernel by
+# src/programs.rs. The binaries are checked in so that building the kernel only
+# needs cargo.
+#
+# Programs are linked in the lower half, inside the user space range of
+# src/task/user.rs.
+set -e
+cd "$(dirname "$0")"
+for source in *.s; do
+    name="${source%.s}"
+    as --64 -o "bin/$name.o" "$source"
+    ld -static -nostdlib --build-id=none -z max-page-size=0x1000 -z noexecstack \
+        -Ttext-segment=0x8000400000 -e _start -o "bin/$name" "bin/$name.o"
+    rm "bin/$name.o"
+done
+
+# The initramfs holds the content of initramfs/ and the programs in /bin
+staging="$(mktemp -d)"
+cp -R initramfs/. "$staging"
+mkdir -p "$staging/bin"
+for source in *.s; do
+    cp "bin/${source%.s}" "$staging/bin/"
+done
+(cd "$staging" && find . | LC_ALL=C sort | cpio -o -H newc -R 0:0 --quiet) > bin/initramfs.cpio
+
+# The ext2 tests of src/fs/ext2 mount an image of the same tree, with a fast
+# and a slow symbolic link. Two small block groups make allocations cross
+# groups. Fixed times, UUID and hash seed keep the image reproducible.
+ln -s /etc/motd "$staging/etc/welcome"
+ln -s ../../a/rather/long/target/that/does/not/fit/in/the/inode/itself "$staging/etc/dangling"
+find "$staging" -exec touch -h -d @1577836800 {} +
+rm -f bin/test.ext2
+E2FSPROGS_FAKE_TIME=1577836800 mke2fs -q -t ext2 -b 1024 -N 64 -m 0 -g 256 \
+    -U 6b72696c-6c00-4000-8000-000000000001 \
+    -E root_owner=0:0,hash_seed=6b72696c-6c00-4000-8000-000000000002 \
+    -d "$staging" bin/test.ext2 384k
+# mke2fs copies the change times, which touch can't set
+(cd "$staging" && find . -mindepth 1 | sed 's/^\.\(.*\)/sif \1 ctime @1577836800/') |
+    debugfs -w -f - bin/test.ext2 > /dev/null 2>&1
+
+# The FAT tests of src/fs/fat mount an image made by mkfs.fat, with the
+# program, the message of the day and a long name. mtools copies the
+# modification times and dates the directories it makes from
+# SOURCE_DATE_EPOCH.
+rm -f bin/test.fat
+mkfs.fat -C -F 12 -S 512 -s 1 -R 1 -f 2 -r 512 -i 6b72696c -n KRILL --invariant bin/test.fat 256 > /dev/null
+export SOURCE_DATE_EPOCH=1577836800
+mcopy -s -m -i bin/test.fat "$staging/bin" ::
+mmd -i bin/test.fat ::etc
+mcopy -m -i bin/test.fat "$staging/etc/motd" ::etc
+mcopy -m -i bin/test.fat "$staging/etc/motd" "::A long file name.txt"
+rm -r "$staging"
+
+# The block device tests write to a blank disk, attached by the test runner
+# with writes going to a snapshot
+rm -f bin/scratch.img
+truncate -s 1M bin/scratch.img
@@ -0,0 +1,32 @@
+#!/bin/sh
+# Runs the kernel tests with copies of the FAT and ext2 images of
+# user/build.sh as extra disks, which the file system tests write to, then
+# checks what they left with fsck.fat and e2fsck. Needs what `cargo test`
+# needs, dosfstools and e2fsprogs.
+set -e
+cd "$(dirname "$0")/.."
+work="$(mktemp -d)"
+trap 'rm -r "$work"' EXIT
+cp user/bin/test.fat "$work/test.fat"
+cp user/bin/test.ext2 "$work/test.ext2"
+
+# Builds the boot image of the tests, which run a first time without the disks
+cargo test
+kernel="$(ls -t target/x86_64-krill/debug/deps/bootimage-krill-*.bin | head -n 1)"
+
+# The test arguments of Cargo.toml, with the disks as vdb and vdc
+status=0
+qemu-system-x86_64 -drive format=raw,file="$kernel" \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -drive file=user/bin/scratch.img,format=raw,if=virtio,snapshot=on \
+    -drive file=user/bin/scratch.img,format=raw,if=ide,index=1,snapshot=on \
+    -drive file="$work/test.fat",format=raw,if=virtio \
+    -drive file="$work/test.ext2",format=raw,if=virtio \
+    -serial stdio -display none || status=$?
+# isa-debug-exit makes QEMU exit with (0x10 << 1) | 1 once the tests passed
+if [ "$status" -ne 33 ]; then
+    echo "The tests failed" >&2
+    exit 1
+fi
+fsck.fat -n "$work/test.fat"
+e2fsck -fn "$work/test.ext2"
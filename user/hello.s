@@ -0,0 +1,56 @@
+# Prints a greeting and its arguments, one per line, then exits.
+#
+# Syscall numbers are the ones of src/syscall/mod.rs.
+
+.intel_syntax noprefix
+
+.set SYS_WRITE, 1
+.set SYS_EXIT, 2
+.set STDOUT, 1
+
+.text
+.global _start
+_start:
+    mov r12, [rsp]          # argc
+    lea r13, [rsp + 8]      # argv
+
+    mov eax, SYS_WRITE
+    mov edi, STDOUT
+    lea rsi, [rip + greeting]
+    mov edx, greeting_length
+    syscall
+
+    xor r14, r14
+next_argument:
+    cmp r14, r12
+    jae done
+    mov rsi, [r13 + r14 * 8]
+    xor edx, edx
+string_length:
+    cmp byte ptr [rsi + rdx], 0
+    je print_argument
+    inc rdx
+    jmp string_length
+print_argument:
+    mov eax, SYS_WRITE
+    mov edi, STDOUT
+    syscall
+    mov eax, SYS_WRITE
+    mov edi, STDOUT
+    lea rsi, [rip + newline]
+    mov edx, 1
+    syscall
+    inc r14
+    jmp next_argument
+
+done:
+    mov eax, SYS_EXIT
+    xor edi, edi
+    syscall
+
+.section .rodata
+greeting:
+    .ascii "Hello from user space!\n"
+.set greeting_length, . - greeting
+newline:
+    .ascii "\n"